@@ -0,0 +1,175 @@
+//! Latency of one action through the matching pipeline.
+//!
+//! Compares the former tokio/Mutex design - tokio channels around an
+//! `Arc<Mutex<HashMap<Symbol, OrderBook>>>` - with the single-writer engine
+//! thread fed by lock-free SPSC ring buffers. Each action is sent once the
+//! responses of the previous one arrived, so the figures are round trips
+//! without queueing.
+//!
+//! ```text
+//! $ cargo bench --bench latency -- [--actions N] [--pin CORE]
+//! ```
+
+use order_book::engine::Engine;
+use order_book::fixed::{Price, Quantity};
+use order_book::orderbook::{OrderBook, Response, Side, TimeInForce, UserAction};
+use order_book::spsc;
+use order_book::symbol::Symbol;
+
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use hdrhistogram::Histogram;
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc;
+
+/// Default number of measured actions
+const DEFAULT_ACTIONS: usize = 200_000;
+
+/// Actions sent before measuring
+const WARM_UP: usize = 10_000;
+
+/// Responses of one action
+type Responses = (Option<Response>, Option<Response>);
+
+/// Generates a deterministic flow of orders, cancels and flushes
+fn workload(n: usize) -> Vec<UserAction> {
+    let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+    let mut next = move || {
+        seed = seed
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(1_442_695_040_888_963_407);
+        (seed >> 33) as u32
+    };
+
+    (0..n as u32)
+        .map(|i| {
+            let r = next();
+            if i % 10_000 == 9_999 {
+                UserAction::Flush
+            } else if r % 5 == 0 && i > 0 {
+                UserAction::CancelOrder {
+                    user_id: r % 8,
+                    order_id: next() % i,
+                }
+            } else {
+                let side = if r % 2 == 0 { Side::Buy } else { Side::Sell };
+                UserAction::NewOrder {
+                    user_id: r % 8,
+                    symbol: Symbol::new("IBM"),
+                    // Bids below 100, asks from 100 up
+                    price: Price(match side {
+                        Side::Buy => 95 + r % 5,
+                        Side::Sell => 100 + r % 5,
+                    } as u64),
+                    qty: Quantity(100),
+                    side,
+                    order_id: i,
+                    time_in_force: TimeInForce::Gtc,
+                }
+            }
+        })
+        .collect()
+}
+
+fn histogram() -> Histogram<u64> {
+    Histogram::new_with_bounds(1, 60_000_000_000, 3).unwrap()
+}
+
+fn report(name: &str, histogram: &Histogram<u64>) {
+    println!(
+        "{:<14} p50 {:>8} ns   p99 {:>8} ns   p99.9 {:>8} ns   max {:>10} ns",
+        name,
+        histogram.value_at_quantile(0.5),
+        histogram.value_at_quantile(0.99),
+        histogram.value_at_quantile(0.999),
+        histogram.max()
+    );
+}
+
+/// The tokio/Mutex design the engine thread replaced
+fn tokio_mutex(actions: Vec<UserAction>) -> Histogram<u64> {
+    let rt = Runtime::new().unwrap();
+
+    rt.block_on(async move {
+        let books: Arc<Mutex<HashMap<Symbol, OrderBook>>> = Arc::new(Mutex::new(HashMap::new()));
+        let (action_tx, mut action_rx) = mpsc::channel::<UserAction>(1024);
+        let (response_tx, mut response_rx) = mpsc::channel::<Responses>(1024);
+
+        let engine_books = Arc::clone(&books);
+        tokio::spawn(async move {
+            while let Some(action) = action_rx.recv().await {
+                let responses = engine_books
+                    .lock()
+                    .unwrap()
+                    .entry(Symbol::new("IBM"))
+                    .or_insert_with(|| OrderBook::new("IBM", false))
+                    .new_user_action(action);
+                if response_tx.send(responses).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut histogram = histogram();
+        for (i, action) in actions.into_iter().enumerate() {
+            let start = Instant::now();
+            action_tx.send(action).await.unwrap();
+            response_rx.recv().await.unwrap();
+            if i >= WARM_UP {
+                histogram.record(start.elapsed().as_nanos() as u64).unwrap();
+            }
+        }
+        histogram
+    })
+}
+
+/// The single-writer engine thread fed by ring buffers
+fn engine_thread(actions: Vec<UserAction>, core: Option<usize>) -> Histogram<u64> {
+    let (mut action_tx, mut action_rx) = spsc::channel::<UserAction>(1024);
+    let (mut response_tx, mut response_rx) = spsc::channel::<Responses>(1024);
+
+    let engine = spsc::spawn_pinned("engine", core, move || {
+        let mut engine = Engine::new(false);
+        while let Some(action) = action_rx.recv() {
+            let (_, responses) = engine.new_user_action(action);
+            if response_tx.send(responses).is_err() {
+                break;
+            }
+        }
+    })
+    .unwrap();
+
+    let mut histogram = histogram();
+    for (i, action) in actions.into_iter().enumerate() {
+        let start = Instant::now();
+        action_tx.send(action).unwrap();
+        response_rx.recv().unwrap();
+        if i >= WARM_UP {
+            histogram.record(start.elapsed().as_nanos() as u64).unwrap();
+        }
+    }
+
+    drop(action_tx);
+    engine.join().unwrap();
+    histogram
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let arg = |name: &str| {
+        args.iter()
+            .position(|a| a == name)
+            .and_then(|i| args.get(i + 1))
+            .map(|v| v.parse::<usize>().unwrap())
+    };
+    let n = arg("--actions").unwrap_or(DEFAULT_ACTIONS);
+    let core = arg("--pin");
+
+    let actions = workload(WARM_UP + n);
+    println!("{} actions, engine thread core {:?}", n, core);
+    report("tokio/Mutex", &tokio_mutex(actions.clone()));
+    report("engine thread", &engine_thread(actions, core));
+}
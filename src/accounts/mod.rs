@@ -0,0 +1,326 @@
+//! This mod implements per-user accounts fed by the responses of the books.
+//!
+//! Every [Response::Trade] moves the position and the cash of both users of
+//! the trade. [Accounts] keep, per user and symbol, the net position, its
+//! cost, the PnL realized by reducing it and the cash spent or received,
+//! and value open positions at a mark price. The accounts can be written
+//! as an end-of-day CSV report:
+//!
+//! ```text
+//! # user_id, symbol, position, avg_cost, realized_pnl, unrealized_pnl, cash, mark
+//! 1, IBM, 100, 10, 0, 100, -1000, 11
+//! ```
+//!
+//! Positions are in lots, prices in ticks and amounts in ticks times lots
+//! of the symbol, so amounts of symbols with different scales or
+//! currencies can not be added up.
+
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::io::{self, Write};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::fixed::{Price, Scales};
+use crate::orderbook::{Response, Side};
+use crate::symbol::Symbol;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+/// This enum describes the price open positions are valued at
+pub enum MarkPrice {
+    /// Price of the last trade of the symbol
+    #[default]
+    LastTrade,
+    /// Middle of the best bid and ask, rounded down to a tick
+    ///
+    /// While one side of the book is empty the last trade is used.
+    Mid,
+}
+
+impl FromStr for MarkPrice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "last" => Ok(MarkPrice::LastTrade),
+            "mid" => Ok(MarkPrice::Mid),
+            _ => Err(format!("invalid mark price: {:?}", s)),
+        }
+    }
+}
+
+impl Display for MarkPrice {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            MarkPrice::LastTrade => f.write_str("last"),
+            MarkPrice::Mid => f.write_str("mid"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+/// This struct is the account of one user in one symbol
+pub struct Position {
+    /// Net position in lots, negative when short
+    pub position: i64,
+    /// Cost of the open position, negative when short
+    pub cost: i128,
+    /// PnL realized by reducing the position
+    pub realized: i128,
+    /// Cash received by selling minus cash spent buying
+    pub cash: i128,
+}
+
+impl Position {
+    /// Returns the average price of the open position, rounded towards
+    /// zero, `None` when flat
+    pub fn avg_cost(&self) -> Option<Price> {
+        match self.position {
+            0 => None,
+            position => Some(Price((self.cost / position as i128) as u64)),
+        }
+    }
+
+    /// Returns the PnL of the open position valued at `mark`
+    pub fn unrealized(&self, mark: Price) -> i128 {
+        self.position as i128 * mark.ticks() as i128 - self.cost
+    }
+
+    /// Applies a fill of `qty` lots at `price`, negative when selling
+    fn fill(&mut self, qty: i64, price: Price) {
+        let price = price.ticks() as i128;
+        self.cash -= qty as i128 * price;
+
+        // The fill opens or adds to the position
+        if self.position == 0 || self.position.signum() == qty.signum() {
+            self.position += qty;
+            self.cost += qty as i128 * price;
+            return;
+        }
+
+        // The fill reduces the position, the rest of it opens the other way
+        let closed = qty.unsigned_abs().min(self.position.unsigned_abs()) as i128;
+        let closed_cost = self.cost * closed / self.position.unsigned_abs() as i128;
+        self.realized += self.position.signum() as i128 * closed * price - closed_cost;
+        self.cost -= closed_cost;
+        self.position += qty.signum() * closed as i64;
+
+        let opened = qty - qty.signum() * closed as i64;
+        if opened != 0 {
+            self.position = opened;
+            self.cost = opened as i128 * price;
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+/// This struct keeps the [Position]s of every user
+pub struct Accounts {
+    /// Positions by `(user_id, symbol)`
+    positions: HashMap<(u32, Symbol), Position>,
+    /// Price of the last trade of every symbol
+    last_trades: HashMap<Symbol, Price>,
+    /// Best bid and ask of every symbol, zero for an empty side
+    quotes: HashMap<Symbol, (Price, Price)>,
+    /// Price open positions are valued at
+    mark_price: MarkPrice,
+}
+
+impl Accounts {
+    /// Creates empty [Accounts] valuing positions at `mark_price`
+    pub fn new(mark_price: MarkPrice) -> Self {
+        Accounts {
+            mark_price,
+            ..Default::default()
+        }
+    }
+
+    /// Applies a response of the book of `symbol`
+    ///
+    /// Trades move the positions, best prices move the mid. Other
+    /// responses are ignored.
+    pub fn apply(&mut self, symbol: Symbol, response: &Response) {
+        match response {
+            Response::Trade {
+                buyer_id,
+                seller_id,
+                price,
+                qty,
+                ..
+            } => {
+                let qty = i64::try_from(qty.lots()).unwrap();
+                self.positions
+                    .entry((*buyer_id, symbol))
+                    .or_default()
+                    .fill(qty, *price);
+                self.positions
+                    .entry((*seller_id, symbol))
+                    .or_default()
+                    .fill(-qty, *price);
+                self.last_trades.insert(symbol, *price);
+            }
+            Response::Best { side, price, .. } => {
+                let quote = self.quotes.entry(symbol).or_default();
+                match side {
+                    Side::Buy => quote.0 = *price,
+                    Side::Sell => quote.1 = *price,
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Returns the account of a user in `symbol`, if they ever traded it
+    pub fn position(&self, user_id: u32, symbol: impl Into<Symbol>) -> Option<&Position> {
+        self.positions.get(&(user_id, symbol.into()))
+    }
+
+    /// Returns every account, by user and then symbol name
+    pub fn positions(&self) -> Vec<(u32, Symbol, &Position)> {
+        let mut positions: Vec<(u32, Symbol, &Position)> = self
+            .positions
+            .iter()
+            .map(|((user_id, symbol), position)| (*user_id, *symbol, position))
+            .collect();
+        positions.sort_unstable_by_key(|(user_id, symbol, _)| (*user_id, symbol.as_str()));
+        positions
+    }
+
+    /// Returns the price positions in `symbol` are valued at, if it ever
+    /// traded
+    pub fn mark(&self, symbol: impl Into<Symbol>) -> Option<Price> {
+        let symbol = symbol.into();
+        let last_trade = self.last_trades.get(&symbol).copied();
+        match (self.mark_price, self.quotes.get(&symbol)) {
+            (MarkPrice::Mid, Some(&(bid, ask))) if bid != Price::ZERO && ask != Price::ZERO => {
+                last_trade.map(|_| Price((bid.ticks() + ask.ticks()) / 2))
+            }
+            (_, _) => last_trade,
+        }
+    }
+
+    /// Returns the unrealized PnL of a user in `symbol`
+    pub fn unrealized(&self, user_id: u32, symbol: impl Into<Symbol>) -> Option<i128> {
+        let symbol = symbol.into();
+        Some(
+            self.position(user_id, symbol)?
+                .unrealized(self.mark(symbol)?),
+        )
+    }
+
+    /// Writes the end-of-day report, one line per user and symbol
+    ///
+    /// Numbers are written with the decimals of the symbol in `scales`.
+    pub fn write_eod(&self, mut w: impl Write, scales: &Scales) -> io::Result<()> {
+        writeln!(
+            w,
+            "# user_id, symbol, position, avg_cost, realized_pnl, unrealized_pnl, cash, mark"
+        )?;
+        for (user_id, symbol, position) in self.positions() {
+            let scale = scales.get(symbol);
+            // Positions only exist for symbols that traded
+            let mark = self.mark(symbol).unwrap();
+            writeln!(
+                w,
+                "{}, {}, {}, {}, {}, {}, {}, {}",
+                user_id,
+                symbol,
+                scale.signed_qty(position.position),
+                scale.price(position.avg_cost().unwrap_or(Price::ZERO)),
+                scale.amount(position.realized),
+                scale.amount(position.unrealized(mark)),
+                scale.amount(position.cash),
+                scale.price(mark),
+            )?;
+        }
+        w.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixed::Quantity;
+
+    fn trade(buyer_id: u32, seller_id: u32, price: u64, qty: u64) -> Response {
+        Response::Trade {
+            buyer_id,
+            buyer_order_id: 1,
+            seller_id,
+            seller_order_id: 1,
+            price: Price(price),
+            qty: Quantity(qty),
+        }
+    }
+
+    #[test]
+    fn test_positions_and_pnl() {
+        let ibm = Symbol::new("IBM");
+        let mut accounts = Accounts::new(MarkPrice::LastTrade);
+
+        // 1 buys 100 at 10 and 100 at 12, then sells 150 at 13
+        accounts.apply(ibm, &trade(1, 2, 10, 100));
+        accounts.apply(ibm, &trade(1, 2, 12, 100));
+        assert_eq!(
+            accounts.position(1, "IBM").unwrap().avg_cost(),
+            Some(Price(11))
+        );
+        accounts.apply(ibm, &trade(3, 1, 13, 150));
+
+        let long = accounts.position(1, "IBM").unwrap();
+        assert_eq!(long.position, 50);
+        assert_eq!(long.avg_cost(), Some(Price(11)));
+        assert_eq!(long.realized, 300);
+        assert_eq!(long.cash, -1000 - 1200 + 1950);
+        assert_eq!(accounts.unrealized(1, "IBM"), Some(100));
+
+        // 2 is short 200 at 11 on average, the last trade is at 13
+        let short = accounts.position(2, "IBM").unwrap();
+        assert_eq!(short.position, -200);
+        assert_eq!(short.avg_cost(), Some(Price(11)));
+        assert_eq!(accounts.unrealized(2, "IBM"), Some(-400));
+
+        // Selling through a position opens a short at the trade price
+        accounts.apply(ibm, &trade(3, 1, 9, 80));
+        let flipped = accounts.position(1, "IBM").unwrap();
+        assert_eq!(flipped.position, -30);
+        assert_eq!(flipped.avg_cost(), Some(Price(9)));
+        assert_eq!(flipped.realized, 300 - 100);
+        assert_eq!(accounts.position(1, "AAPL"), None);
+    }
+
+    #[test]
+    fn test_mid_mark_and_eod() {
+        let ibm = Symbol::new("IBM");
+        let mut accounts = Accounts::new(MarkPrice::Mid);
+        accounts.apply(ibm, &trade(1, 2, 1000, 100));
+        assert_eq!(accounts.mark("IBM"), Some(Price(1000)));
+
+        let best = |side, price| Response::Best {
+            side,
+            price: Price(price),
+            qty: Quantity(100),
+        };
+        accounts.apply(ibm, &best(Side::Buy, 1005));
+        accounts.apply(ibm, &best(Side::Sell, 1010));
+        assert_eq!(accounts.mark("IBM"), Some(Price(1007)));
+        assert_eq!(accounts.mark("AAPL"), None);
+
+        let mut eod = vec![];
+        accounts
+            .write_eod(&mut eod, &"IBM:2".parse().unwrap())
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(eod)
+                .unwrap()
+                .lines()
+                .skip(1)
+                .collect::<Vec<_>>(),
+            vec![
+                "1, IBM, 100, 10.00, 0.00, 7.00, -1000.00, 10.07",
+                "2, IBM, -100, 10.00, 0.00, -7.00, 1000.00, 10.07",
+            ]
+        );
+    }
+}
@@ -0,0 +1,142 @@
+//! Replays a recorded action log and diffs the responses against golden output.
+//!
+//! With a golden directory, the log is split in the scenarios named by
+//! `#name:` comments and each one is checked against its own golden file.
+//! `--bless` writes the golden output instead of checking it, rejects
+//! with their reason.
+
+use order_book::codec::{Format, Reader, Writer};
+use order_book::orderbook::{Response, UserAction};
+use order_book::replay::{self, Scenario, DEFAULT_CONTEXT};
+
+use std::env;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::Path;
+use std::process;
+
+fn usage() -> ! {
+    eprintln!(
+        "Usage: replay <ACTIONS> <GOLDEN_FILE|GOLDEN_DIR> [--input-format F] [--trade] [--context N] [--bless]"
+    );
+    process::exit(2);
+}
+
+struct Options {
+    trade_active: bool,
+    context: usize,
+    bless: bool,
+}
+
+/// Checks or writes the golden output of one action log, returns whether
+/// it matches
+fn check(name: &str, actions: &[UserAction], golden: &Path, options: &Options) -> bool {
+    let emitted = replay::run(actions, options.trade_active);
+
+    if options.bless {
+        let mut writer =
+            Writer::new(Format::Csv, File::create(golden).unwrap()).with_trailing_fields(true);
+        for e in &emitted {
+            writer.write(&e.response).unwrap();
+        }
+        println!(
+            "{}: wrote {} responses to {}",
+            name,
+            emitted.len(),
+            golden.display()
+        );
+        return true;
+    }
+
+    let expected: Vec<Response> = match File::open(golden) {
+        Ok(file) => Reader::new(Format::Csv, BufReader::new(file))
+            .collect::<Result<_, _>>()
+            .unwrap_or_else(|e| {
+                eprintln!("{}: {}", golden.display(), e);
+                process::exit(2);
+            }),
+        Err(e) => {
+            eprintln!("{}: {}", golden.display(), e);
+            return false;
+        }
+    };
+
+    match replay::diff(actions, &emitted, &expected, options.context) {
+        Some(divergence) => {
+            println!("{}: FAILED\n{}", name, divergence);
+            false
+        }
+        None => {
+            println!("{}: ok ({} responses)", name, emitted.len());
+            true
+        }
+    }
+}
+
+fn main() {
+    let mut positional = vec![];
+    let mut format = Format::Csv;
+    let mut options = Options {
+        trade_active: false,
+        context: DEFAULT_CONTEXT,
+        bless: false,
+    };
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--trade" => options.trade_active = true,
+            "--bless" => options.bless = true,
+            "--input-format" => {
+                format = args
+                    .next()
+                    .and_then(|a| a.parse().ok())
+                    .unwrap_or_else(|| usage())
+            }
+            "--context" => {
+                options.context = args
+                    .next()
+                    .and_then(|a| a.parse().ok())
+                    .unwrap_or_else(|| usage())
+            }
+            _ => positional.push(arg),
+        }
+    }
+
+    let (actions, golden) = match (positional.first(), positional.get(1)) {
+        (Some(actions), Some(golden)) => (actions, Path::new(golden)),
+        _ => usage(),
+    };
+    let file = File::open(actions).unwrap_or_else(|e| {
+        eprintln!("{}: {}", actions, e);
+        process::exit(2);
+    });
+
+    let ok = if golden.is_dir() || (options.bless && golden.extension().is_none()) {
+        if format != Format::Csv {
+            eprintln!("Scenarios are only named in CSV logs");
+            process::exit(2);
+        }
+        fs::create_dir_all(golden).unwrap();
+
+        let scenarios: Vec<Scenario> = replay::scenarios(BufReader::new(file)).unwrap();
+        let mut ok = true;
+        for scenario in &scenarios {
+            let path = golden.join(scenario.golden_file_name());
+            ok &= check(&scenario.name, &scenario.actions, &path, &options);
+        }
+        ok
+    } else {
+        let actions: Vec<UserAction> = Reader::new(format, BufReader::new(file))
+            .collect::<Result<_, _>>()
+            .unwrap_or_else(|e| {
+                eprintln!("{}: {}", actions, e);
+                process::exit(2);
+            });
+        check(&positional[0], &actions, golden, &options)
+    };
+
+    if !ok {
+        process::exit(1);
+    }
+}
@@ -0,0 +1,145 @@
+//! Reference subscriber of the multicast market-data feed.
+//!
+//! Rebuilds the books of every symbol from the feed, filling gaps through
+//! the recovery service. Whenever the feed goes idle, and at the end of the
+//! session, it checks the books against a snapshot of the engine's books.
+
+use order_book::feed::{FeedError, RecoveryClient, Subscriber};
+use order_book::itch::{Event, Message, SystemEvent};
+use order_book::{Engine, UserAction};
+
+use std::env;
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::process;
+use std::time::Duration;
+
+/// Time without packets after which the book is checked against the engine
+const IDLE: Duration = Duration::from_secs(1);
+
+fn usage() -> ! {
+    eprintln!("Usage: subscriber <GROUP:PORT> <RECOVERY_ADDR:PORT> [--trade] [--interface ADDR]");
+    process::exit(2);
+}
+
+/// Returns the resting orders of the books that have any, by symbol name
+fn resting_orders(engine: &Engine) -> Vec<(String, Vec<UserAction>)> {
+    let mut books: Vec<(String, Vec<UserAction>)> = engine
+        .books()
+        .map(|(symbol, book)| (String::from(symbol.as_str()), book.resting_orders()))
+        .filter(|(_, orders)| !orders.is_empty())
+        .collect();
+    books.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    books
+}
+
+/// Compares the rebuilt books with a snapshot of the engine's books
+///
+/// The recovery service takes the snapshot from the books of the engine,
+/// not from the feed. Returns `None` when the engine has moved past the
+/// subscriber, in which case the two can not be compared yet.
+fn verify(subscriber: &Subscriber, recovery: SocketAddr) -> Result<Option<bool>, FeedError> {
+    let (seq, snapshot) = RecoveryClient::connect(recovery)?.snapshot()?;
+    if seq + 1 != subscriber.next_seq() {
+        return Ok(None);
+    }
+
+    // The resting orders of the snapshot never cross
+    let mut expected = Engine::new(false);
+    for message in snapshot {
+        if let Some(action) = message.event.to_user_action()? {
+            expected.new_user_action(action);
+        }
+    }
+
+    Ok(Some(
+        resting_orders(&expected) == resting_orders(subscriber.engine()),
+    ))
+}
+
+fn run(
+    group: SocketAddrV4,
+    interface: Ipv4Addr,
+    recovery: SocketAddr,
+    engine: Engine,
+) -> Result<bool, FeedError> {
+    let mut subscriber = Subscriber::join(group, interface, recovery, engine)?;
+    subscriber.set_timeout(Some(IDLE))?;
+    println!("Joined at sequence number {}", subscriber.next_seq());
+
+    let mut verified = false;
+    loop {
+        match subscriber.recv() {
+            Ok(messages) => {
+                verified = false;
+                for Message { seq, event } in messages {
+                    println!("{} {:?}", seq, event);
+
+                    if let Event::SystemEvent {
+                        event: SystemEvent::EndOfMessages,
+                    } = event
+                    {
+                        println!("Recovered {} messages", subscriber.recovered());
+                        return Ok(verify(&subscriber, recovery)? != Some(false));
+                    }
+                }
+            }
+            // The feed is idle - check the books while the engine is quiet
+            Err(FeedError::Io(e))
+                if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut =>
+            {
+                if !verified {
+                    match verify(&subscriber, recovery)? {
+                        Some(true) => {
+                            println!("Books match the engine at {}", subscriber.next_seq() - 1);
+                            verified = true;
+                        }
+                        Some(false) => return Ok(false),
+                        None => (),
+                    }
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn main() {
+    let mut positional = vec![];
+    let mut trade_active = false;
+    let mut interface = Ipv4Addr::LOCALHOST;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--trade" => trade_active = true,
+            "--interface" => {
+                interface = args
+                    .next()
+                    .and_then(|a| a.parse().ok())
+                    .unwrap_or_else(|| usage())
+            }
+            _ => positional.push(arg),
+        }
+    }
+
+    let (group, recovery) = match (positional.first(), positional.get(1)) {
+        (Some(group), Some(recovery)) => match (group.parse(), recovery.parse()) {
+            (Ok(group), Ok(recovery)) => (group, recovery),
+            _ => usage(),
+        },
+        _ => usage(),
+    };
+
+    match run(group, interface, recovery, Engine::new(trade_active)) {
+        Ok(true) => println!("Books match the engine"),
+        Ok(false) => {
+            eprintln!("Books do not match the engine");
+            process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("Feed error: {}", e);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,80 @@
+//! WebSocket gateway in front of a multi-symbol matching engine.
+
+use order_book::clock::parse_time_of_day;
+use order_book::engine::Engine;
+use order_book::ws::{serve, serve_with_admin, WsConfig};
+
+use std::env;
+use std::process;
+use std::time::Duration;
+
+use tokio::net::TcpListener;
+use tokio::runtime::Runtime;
+
+fn usage() -> ! {
+    eprintln!("Usage: ws_gateway <ADDR:PORT> [--trade] [--rate REQUESTS_PER_SEC] [--depth LEVELS] [--heartbeat SECS] [--day-close HH:MM] [--admin ADDR:PORT]");
+    process::exit(2);
+}
+
+fn main() {
+    let mut addr = None;
+    let mut trade_active = false;
+    let mut config = WsConfig::default();
+    let mut day_close = Duration::ZERO;
+    let mut admin = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--trade" => trade_active = true,
+            // Admin requests are only served on their own listener
+            "--admin" => admin = Some(args.next().unwrap_or_else(|| usage())),
+            "--rate" => {
+                config.max_requests_per_sec = args
+                    .next()
+                    .and_then(|a| a.parse().ok())
+                    .unwrap_or_else(|| usage())
+            }
+            // 0 never closes silent connections
+            "--heartbeat" => {
+                let secs: u64 = args
+                    .next()
+                    .and_then(|a| a.parse().ok())
+                    .unwrap_or_else(|| usage());
+                config.heartbeat = Some(Duration::from_secs(secs)).filter(|t| !t.is_zero());
+            }
+            "--day-close" => {
+                day_close = args
+                    .next()
+                    .and_then(|a| parse_time_of_day(&a))
+                    .unwrap_or_else(|| usage())
+            }
+            "--depth" => {
+                config.depth = args
+                    .next()
+                    .and_then(|a| a.parse().ok())
+                    .unwrap_or_else(|| usage())
+            }
+            _ => addr = Some(arg),
+        }
+    }
+    let addr = addr.unwrap_or_else(|| usage());
+
+    let rt = Runtime::new().unwrap();
+    if let Err(e) = rt.block_on(async move {
+        let listener = TcpListener::bind(&addr).await?;
+        println!("Listening on ws://{}", listener.local_addr()?);
+        let engine = Engine::new(trade_active).with_day_close(day_close);
+        match admin {
+            Some(admin) => {
+                let admin = TcpListener::bind(&admin).await?;
+                println!("Admin requests on ws://{}", admin.local_addr()?);
+                serve_with_admin(listener, admin, engine, config).await
+            }
+            None => serve(listener, engine, config).await,
+        }
+    }) {
+        eprintln!("WebSocket gateway error: {}", e);
+        process::exit(1);
+    }
+}
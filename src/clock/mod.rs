@@ -0,0 +1,135 @@
+//! This mod implements the clocks the engine reads time from.
+//!
+//! Timestamps are nanoseconds since the UNIX epoch. Production code reads
+//! the [WallClock], while tests drive a [ManualClock] so that behaviour
+//! depending on time, such as the expiry of orders, is deterministic.
+
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Nanoseconds since the UNIX epoch
+pub type Timestamp = u64;
+
+/// Nanoseconds in a day
+pub const NANOS_PER_DAY: u64 = 86_400_000_000_000;
+
+/// Parses a `HH:MM` time of day
+pub fn parse_time_of_day(s: &str) -> Option<Duration> {
+    let (hours, minutes) = s.split_once(':')?;
+    let (hours, minutes): (u64, u64) = (hours.parse().ok()?, minutes.parse().ok()?);
+    if hours >= 24 || minutes >= 60 {
+        return None;
+    }
+    Some(Duration::from_secs(hours * 3600 + minutes * 60))
+}
+
+/// This trait describes a source of time
+pub trait Clock: Debug + Send + Sync {
+    /// Returns the current time
+    fn now(&self) -> Timestamp;
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+/// This struct reads the time of the system
+pub struct WallClock;
+
+impl Clock for WallClock {
+    fn now(&self) -> Timestamp {
+        // The system time is after the epoch and before the year 2554
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as Timestamp
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+/// This struct is a clock that only moves when told to
+///
+/// Clones share the same time, so a test can keep one to move the time of
+/// the clock it handed over.
+pub struct ManualClock {
+    now: Arc<AtomicU64>,
+}
+
+impl ManualClock {
+    /// Creates a [ManualClock] showing `now`
+    pub fn new(now: Timestamp) -> Self {
+        ManualClock {
+            now: Arc::new(AtomicU64::new(now)),
+        }
+    }
+
+    /// Sets the time of the clock
+    pub fn set(&self, now: Timestamp) {
+        self.now.store(now, Ordering::SeqCst);
+    }
+
+    /// Moves the time of the clock forward by `by`
+    pub fn advance(&self, by: Duration) {
+        self.now.fetch_add(by.as_nanos() as u64, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Timestamp {
+        self.now.load(Ordering::SeqCst)
+    }
+}
+
+#[derive(Clone, Debug)]
+/// This struct shares a [Clock] between its owners
+///
+/// It defaults to the [WallClock]. Clocks are not part of the state of
+/// their owners, so any two [SharedClock]s compare equal.
+pub struct SharedClock(Arc<dyn Clock>);
+
+impl SharedClock {
+    /// Shares `clock`
+    pub fn new(clock: impl Clock + 'static) -> Self {
+        SharedClock(Arc::new(clock))
+    }
+}
+
+impl Default for SharedClock {
+    fn default() -> Self {
+        SharedClock::new(WallClock)
+    }
+}
+
+impl PartialEq for SharedClock {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Clock for SharedClock {
+    fn now(&self) -> Timestamp {
+        self.0.now()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clocks() {
+        let manual = ManualClock::new(1_000);
+        let shared = SharedClock::new(manual.clone());
+        assert_eq!(shared.now(), 1_000);
+
+        manual.advance(Duration::from_micros(2));
+        assert_eq!(shared.now(), 3_000);
+        manual.set(NANOS_PER_DAY);
+        assert_eq!(shared.now(), NANOS_PER_DAY);
+
+        // Some time after 2020
+        let now = WallClock.now();
+        assert!(now > 1_600_000_000 * 1_000_000_000);
+        assert!(WallClock.now() >= now);
+        assert_eq!(SharedClock::default(), shared);
+    }
+}
@@ -0,0 +1,885 @@
+//! This mod implements the encodings of [UserAction]s and [Response]s.
+//!
+//! Three [Format]s are supported:
+//! - CSV - the format of `input/input.csv` and of the [Response] [Display]
+//! - JSON lines - one JSON document per line
+//! - MessagePack - a stream of MessagePack values
+//!
+//! CSV prices and quantities are decimals in the [Scale] of their
+//! instrument, JSON and MessagePack carry them as ticks and lots. CSV
+//! records may end in optional fields, such as the reason of a reject or
+//! the time in force of a new order, which a [Writer] only writes when
+//! asked to:
+//!
+//! ```text
+//! N, 1, IBM, 10, 100, B, 1, gtd:1700000000000000000
+//! ```
+//!
+//! [Stamped] responses are written as the record of their response
+//! followed by the sequence number and timestamp, after any optional
+//! field:
+//!
+//! ```text
+//! R, 2, 103, trading_disabled, 7, 1700000000000000000
+//! ```
+//!
+//! Reading such a record as a plain [Response] ignores the stamp.
+//!
+//! [Display]: std::fmt::Display
+
+use std::fmt::{Display, Formatter};
+use std::io::{self, BufRead, Write};
+use std::marker::PhantomData;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use regex::Regex;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::fixed::{Price, Quantity, Scale, Scales};
+use crate::orderbook::{RejectReason, Response, TimeInForce, UserAction};
+use crate::sequence::Stamped;
+use crate::symbol::Symbol;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// This enum describes the supported encodings
+pub enum Format {
+    /// Comma separated values
+    Csv,
+    /// One JSON document per line
+    JsonLines,
+    /// Stream of MessagePack values
+    MessagePack,
+}
+
+impl FromStr for Format {
+    type Err = CodecError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "json" | "jsonl" => Ok(Format::JsonLines),
+            "msgpack" => Ok(Format::MessagePack),
+            _ => Err(CodecError::UnknownFormat(String::from(s))),
+        }
+    }
+}
+
+#[derive(Debug)]
+/// Errors produced while encoding or decoding
+pub enum CodecError {
+    /// Reading or writing failed
+    Io(io::Error),
+    /// The format name is not known
+    UnknownFormat(String),
+    /// A CSV line could not be parsed
+    Csv(String),
+    /// JSON encoding or decoding failed
+    Json(serde_json::Error),
+    /// MessagePack encoding failed
+    MessagePackEncode(rmp_serde::encode::Error),
+    /// MessagePack decoding failed
+    MessagePackDecode(rmp_serde::decode::Error),
+}
+
+impl Display for CodecError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            CodecError::Io(e) => write!(f, "{}", e),
+            CodecError::UnknownFormat(name) => write!(f, "unknown format: {}", name),
+            CodecError::Csv(line) => write!(f, "invalid CSV line: {:?}", line),
+            CodecError::Json(e) => write!(f, "{}", e),
+            CodecError::MessagePackEncode(e) => write!(f, "{}", e),
+            CodecError::MessagePackDecode(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+impl From<io::Error> for CodecError {
+    fn from(e: io::Error) -> Self {
+        CodecError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for CodecError {
+    fn from(e: serde_json::Error) -> Self {
+        CodecError::Json(e)
+    }
+}
+
+impl From<rmp_serde::encode::Error> for CodecError {
+    fn from(e: rmp_serde::encode::Error) -> Self {
+        CodecError::MessagePackEncode(e)
+    }
+}
+
+impl From<rmp_serde::decode::Error> for CodecError {
+    fn from(e: rmp_serde::decode::Error) -> Self {
+        CodecError::MessagePackDecode(e)
+    }
+}
+
+/// This trait describes a value with a CSV line representation
+pub trait CsvRecord: Sized {
+    /// Parses a CSV line with prices and quantities in the [Scale] of
+    /// their instrument, returns `None` for blank lines and comments
+    ///
+    /// Lines without a symbol use the default scale.
+    fn from_csv_scaled(line: &str, scales: &Scales) -> Result<Option<Self>, CodecError>;
+
+    /// Returns the CSV line of the value with prices and quantities in
+    /// `scale`, without line terminator
+    fn to_csv_scaled(&self, scale: Scale) -> String;
+
+    /// Returns the CSV line of the value followed by its optional
+    /// trailing fields
+    fn to_csv_extended(&self, scale: Scale) -> String {
+        self.to_csv_scaled(scale)
+    }
+
+    /// Parses a CSV line with whole prices and quantities
+    fn from_csv(line: &str) -> Result<Option<Self>, CodecError> {
+        Self::from_csv_scaled(line, &Scales::default())
+    }
+
+    /// Returns the CSV line of the value with whole prices and quantities
+    fn to_csv(&self) -> String {
+        self.to_csv_scaled(Scale::default())
+    }
+}
+
+/// Splits a CSV line in trimmed fields, `None` for blank lines and comments
+fn csv_fields(line: &str) -> Option<Vec<&str>> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        None
+    } else {
+        Some(line.split(',').map(str::trim).collect())
+    }
+}
+
+/// Splits the fields of a response record from its trailing stamp, if any
+///
+/// The stamp - sequence number and timestamp - follows every other field,
+/// including the optional reason of a reject.
+fn split_stamp<'a, 'b>(fields: &'b [&'a str]) -> (&'b [&'a str], Option<&'b [&'a str]>) {
+    let len = match fields.first() {
+        Some(&"A") => 3,
+        Some(&"R") if fields.len() >= 5 => fields.len() - 2,
+        Some(&"B") => 4,
+        Some(&"T") => 7,
+        _ => fields.len(),
+    };
+    if fields.len() == len + 2 {
+        let (fields, stamp) = fields.split_at(len);
+        (fields, Some(stamp))
+    } else {
+        (fields, None)
+    }
+}
+
+impl CsvRecord for UserAction {
+    fn from_csv_scaled(line: &str, scales: &Scales) -> Result<Option<Self>, CodecError> {
+        static NEW_ORDER_RE: OnceLock<Regex> = OnceLock::new();
+        static CANCEL_ORDER_RE: OnceLock<Regex> = OnceLock::new();
+
+        let invalid = || CodecError::Csv(String::from(line));
+        let parse = |s: &str| s.parse::<u32>().map_err(|_| invalid());
+
+        if csv_fields(line).is_none() {
+            return Ok(None);
+        }
+
+        if line.starts_with('N') {
+            let captures = NEW_ORDER_RE
+                .get_or_init(|| {
+                    Regex::new(
+                        r"^N, ([0-9]+), ([[:alpha:]]+), ([0-9.]+), ([0-9.]+), ([BS]), ([0-9]+)(?:, ([a-z0-9:]+))?",
+                    )
+                    .unwrap()
+                })
+                .captures(line)
+                .ok_or_else(invalid)?;
+
+            let symbol = Symbol::parse(&captures[2]).map_err(|_| invalid())?;
+            let scale = scales.get(symbol);
+
+            Ok(Some(UserAction::NewOrder {
+                user_id: parse(&captures[1])?,
+                symbol,
+                price: scale.parse_price(&captures[3]).map_err(|_| invalid())?,
+                qty: scale.parse_qty(&captures[4]).map_err(|_| invalid())?,
+                side: captures[5].parse().map_err(|_| invalid())?,
+                order_id: parse(&captures[6])?,
+                time_in_force: match captures.get(7) {
+                    Some(tif) => tif.as_str().parse().map_err(|_| invalid())?,
+                    None => TimeInForce::Gtc,
+                },
+            }))
+        } else if line.starts_with('C') {
+            let captures = CANCEL_ORDER_RE
+                .get_or_init(|| Regex::new(r"^C, ([0-9]+), ([0-9]+)").unwrap())
+                .captures(line)
+                .ok_or_else(invalid)?;
+
+            Ok(Some(UserAction::CancelOrder {
+                user_id: parse(&captures[1])?,
+                order_id: parse(&captures[2])?,
+            }))
+        } else if line.starts_with('F') {
+            Ok(Some(UserAction::Flush))
+        } else {
+            Err(invalid())
+        }
+    }
+
+    fn to_csv_scaled(&self, scale: Scale) -> String {
+        match self {
+            UserAction::NewOrder {
+                user_id,
+                symbol,
+                price,
+                qty,
+                side,
+                order_id,
+                ..
+            } => format!(
+                "N, {}, {}, {}, {}, {}, {}",
+                user_id,
+                symbol,
+                scale.price(*price),
+                scale.qty(*qty),
+                side,
+                order_id
+            ),
+            UserAction::CancelOrder { user_id, order_id } => {
+                format!("C, {}, {}", user_id, order_id)
+            }
+            UserAction::Flush => String::from("F"),
+        }
+    }
+
+    fn to_csv_extended(&self, scale: Scale) -> String {
+        match self {
+            UserAction::NewOrder { time_in_force, .. } if !time_in_force.is_gtc() => {
+                format!("{}, {}", self.to_csv_scaled(scale), time_in_force)
+            }
+            _ => self.to_csv_scaled(scale),
+        }
+    }
+}
+
+impl CsvRecord for Response {
+    fn from_csv_scaled(line: &str, scales: &Scales) -> Result<Option<Self>, CodecError> {
+        let fields = match csv_fields(line) {
+            Some(fields) => fields,
+            None => return Ok(None),
+        };
+
+        let invalid = || CodecError::Csv(String::from(line));
+        let parse = |s: &str| s.parse::<u32>().map_err(|_| invalid());
+        let scale = scales.default_scale();
+        let price = |s: &str| scale.parse_price(s).map_err(|_| invalid());
+        let qty = |s: &str| scale.parse_qty(s).map_err(|_| invalid());
+        // Empty side of the book is printed as "-"
+        let level = |s: &str| s == "-";
+
+        let response = match split_stamp(&fields).0 {
+            ["A", user_id, order_id] => Response::Acknowledge {
+                user_id: parse(user_id)?,
+                order_id: parse(order_id)?,
+            },
+            ["R", user_id, order_id] => Response::Reject {
+                user_id: parse(user_id)?,
+                order_id: parse(order_id)?,
+                reason: RejectReason::Unspecified,
+            },
+            ["R", user_id, order_id, reason] => Response::Reject {
+                user_id: parse(user_id)?,
+                order_id: parse(order_id)?,
+                reason: reason.parse().map_err(|_| invalid())?,
+            },
+            ["B", side, p, q] => Response::Best {
+                side: side.parse().map_err(|_| invalid())?,
+                price: if level(p) { Price::ZERO } else { price(p)? },
+                qty: if level(q) { Quantity::ZERO } else { qty(q)? },
+            },
+            ["T", buyer_id, buyer_order_id, seller_id, seller_order_id, p, q] => Response::Trade {
+                buyer_id: parse(buyer_id)?,
+                buyer_order_id: parse(buyer_order_id)?,
+                seller_id: parse(seller_id)?,
+                seller_order_id: parse(seller_order_id)?,
+                price: price(p)?,
+                qty: qty(q)?,
+            },
+            _ => return Err(invalid()),
+        };
+
+        Ok(Some(response))
+    }
+
+    fn to_csv_scaled(&self, scale: Scale) -> String {
+        match self {
+            Response::Best { side, price, qty } => {
+                let level = |empty: bool, value: String| {
+                    if empty {
+                        String::from("-")
+                    } else {
+                        value
+                    }
+                };
+                format!(
+                    "B, {}, {}, {}",
+                    side,
+                    level(*price == Price::ZERO, scale.price(*price).to_string()),
+                    level(*qty == Quantity::ZERO, scale.qty(*qty).to_string())
+                )
+            }
+            Response::Trade {
+                buyer_id,
+                buyer_order_id,
+                seller_id,
+                seller_order_id,
+                price,
+                qty,
+            } => format!(
+                "T, {}, {}, {}, {}, {}, {}",
+                buyer_id,
+                buyer_order_id,
+                seller_id,
+                seller_order_id,
+                scale.price(*price),
+                scale.qty(*qty)
+            ),
+            _ => self.to_string(),
+        }
+    }
+
+    /// Appends the reason of a reject, when there is one
+    fn to_csv_extended(&self, scale: Scale) -> String {
+        match self {
+            Response::Reject { reason, .. } if *reason != RejectReason::Unspecified => {
+                format!("{}, {}", self.to_csv_scaled(scale), reason)
+            }
+            _ => self.to_csv_scaled(scale),
+        }
+    }
+}
+
+impl CsvRecord for Stamped {
+    fn from_csv_scaled(line: &str, scales: &Scales) -> Result<Option<Self>, CodecError> {
+        let fields = match csv_fields(line) {
+            Some(fields) => fields,
+            None => return Ok(None),
+        };
+
+        let invalid = || CodecError::Csv(String::from(line));
+        let parse = |s: &str| s.parse::<u64>().map_err(|_| invalid());
+
+        let (seq, timestamp) = match split_stamp(&fields).1 {
+            Some([seq, timestamp]) => (parse(seq)?, parse(timestamp)?),
+            _ => return Err(invalid()),
+        };
+        // The line is not blank, so it holds a response
+        let response = Response::from_csv_scaled(line, scales)?.unwrap();
+
+        Ok(Some(Stamped {
+            seq,
+            timestamp,
+            response,
+        }))
+    }
+
+    fn to_csv_scaled(&self, scale: Scale) -> String {
+        format!(
+            "{}, {}, {}",
+            self.response.to_csv_scaled(scale),
+            self.seq,
+            self.timestamp
+        )
+    }
+
+    /// Writes the optional fields of the response before the stamp
+    fn to_csv_extended(&self, scale: Scale) -> String {
+        format!(
+            "{}, {}, {}",
+            self.response.to_csv_extended(scale),
+            self.seq,
+            self.timestamp
+        )
+    }
+}
+
+/// This struct decodes a stream of values of one [Format]
+pub struct Reader<R, T> {
+    format: Format,
+    reader: R,
+    line: String,
+    scales: Scales,
+    _marker: PhantomData<T>,
+}
+
+impl<R: BufRead, T: CsvRecord + DeserializeOwned> Reader<R, T> {
+    /// Creates a [Reader] decoding `reader` as `format`
+    pub fn new(format: Format, reader: R) -> Self {
+        Reader {
+            format,
+            reader,
+            line: String::new(),
+            scales: Scales::default(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Reads CSV prices and quantities in the scale of their instrument
+    pub fn with_scales(mut self, scales: Scales) -> Self {
+        self.scales = scales;
+        self
+    }
+
+    /// Decodes the next value, `None` at the end of the stream
+    fn read(&mut self) -> Result<Option<T>, CodecError> {
+        if self.format == Format::MessagePack {
+            return if self.reader.fill_buf()?.is_empty() {
+                Ok(None)
+            } else {
+                Ok(Some(rmp_serde::from_read(&mut self.reader)?))
+            };
+        }
+
+        loop {
+            self.line.clear();
+            if self.reader.read_line(&mut self.line)? == 0 {
+                return Ok(None);
+            }
+
+            let value = match self.format {
+                Format::Csv => T::from_csv_scaled(&self.line, &self.scales)?,
+                _ if self.line.trim().is_empty() => None,
+                _ => Some(serde_json::from_str(&self.line)?),
+            };
+            if value.is_some() {
+                return Ok(value);
+            }
+        }
+    }
+}
+
+impl<R: BufRead, T: CsvRecord + DeserializeOwned> Iterator for Reader<R, T> {
+    type Item = Result<T, CodecError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read().transpose()
+    }
+}
+
+/// This struct encodes values as one [Format]
+pub struct Writer<W> {
+    format: Format,
+    writer: W,
+    scales: Scales,
+    trailing_fields: bool,
+}
+
+impl<W: Write> Writer<W> {
+    /// Creates a [Writer] encoding to `writer` as `format`
+    pub fn new(format: Format, writer: W) -> Self {
+        Writer {
+            format,
+            writer,
+            scales: Scales::default(),
+            trailing_fields: false,
+        }
+    }
+
+    /// Writes CSV prices and quantities in the scale of their instrument
+    pub fn with_scales(mut self, scales: Scales) -> Self {
+        self.scales = scales;
+        self
+    }
+
+    /// Writes the optional trailing fields of CSV records when `enabled`
+    pub fn with_trailing_fields(mut self, enabled: bool) -> Self {
+        self.trailing_fields = enabled;
+        self
+    }
+
+    /// Encodes one value, CSV numbers in the default scale
+    pub fn write<T: CsvRecord + Serialize>(&mut self, value: &T) -> Result<(), CodecError> {
+        self.write_for(None, value)
+    }
+
+    /// Encodes one value of an instrument, CSV numbers in its scale
+    pub fn write_for<T: CsvRecord + Serialize>(
+        &mut self,
+        symbol: Option<Symbol>,
+        value: &T,
+    ) -> Result<(), CodecError> {
+        let scale = match symbol {
+            Some(symbol) => self.scales.get(symbol),
+            None => self.scales.default_scale(),
+        };
+
+        match self.format {
+            Format::Csv if self.trailing_fields => {
+                writeln!(self.writer, "{}", value.to_csv_extended(scale))?
+            }
+            Format::Csv => writeln!(self.writer, "{}", value.to_csv_scaled(scale))?,
+            Format::JsonLines => {
+                serde_json::to_writer(&mut self.writer, value)?;
+                writeln!(self.writer)?;
+            }
+            Format::MessagePack => rmp_serde::encode::write_named(&mut self.writer, value)?,
+        }
+
+        Ok(())
+    }
+
+    /// Flushes the underlying writer
+    pub fn flush(&mut self) -> Result<(), CodecError> {
+        Ok(self.writer.flush()?)
+    }
+
+    /// Returns the underlying writer
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instrument::InvalidOrder;
+    use crate::orderbook::{Order, Side, Trade};
+    use crate::risk::RiskBreach;
+    use proptest::prelude::*;
+
+    fn side() -> impl Strategy<Value = Side> {
+        prop_oneof![Just(Side::Buy), Just(Side::Sell)]
+    }
+
+    fn time_in_force() -> impl Strategy<Value = TimeInForce> {
+        prop_oneof![
+            Just(TimeInForce::Gtc),
+            Just(TimeInForce::Day),
+            any::<u64>().prop_map(TimeInForce::Gtd),
+        ]
+    }
+
+    fn user_action() -> impl Strategy<Value = UserAction> {
+        prop_oneof![
+            (
+                any::<u32>(),
+                "[A-Z]{1,8}",
+                any::<u64>(),
+                any::<u64>(),
+                side(),
+                any::<u32>(),
+                time_in_force()
+            )
+                .prop_map(
+                    |(user_id, symbol, price, qty, side, order_id, time_in_force)| {
+                        UserAction::NewOrder {
+                            user_id,
+                            symbol: Symbol::new(&symbol),
+                            price: Price(price),
+                            qty: Quantity(qty),
+                            side,
+                            order_id,
+                            time_in_force,
+                        }
+                    }
+                ),
+            (any::<u32>(), any::<u32>())
+                .prop_map(|(user_id, order_id)| UserAction::CancelOrder { user_id, order_id }),
+            Just(UserAction::Flush),
+        ]
+    }
+
+    fn reject_reason() -> impl Strategy<Value = RejectReason> {
+        prop_oneof![
+            Just(RejectReason::Unspecified),
+            Just(RejectReason::TradingDisabled),
+            Just(RejectReason::UnknownOrder),
+            Just(RejectReason::Invalid(InvalidOrder::OddLot)),
+            Just(RejectReason::Risk(RiskBreach::FatFinger)),
+            Just(RejectReason::UserBlocked),
+            Just(RejectReason::Expired),
+            Just(RejectReason::InvalidSymbol),
+        ]
+    }
+
+    fn response() -> impl Strategy<Value = Response> {
+        prop_oneof![
+            (any::<u32>(), any::<u32>())
+                .prop_map(|(user_id, order_id)| Response::Acknowledge { user_id, order_id }),
+            (any::<u32>(), any::<u32>(), reject_reason()).prop_map(
+                |(user_id, order_id, reason)| {
+                    Response::Reject {
+                        user_id,
+                        order_id,
+                        reason,
+                    }
+                }
+            ),
+            (side(), any::<u64>(), any::<u64>()).prop_map(|(side, price, qty)| Response::Best {
+                side,
+                price: Price(price),
+                qty: Quantity(qty)
+            }),
+            (any::<[u32; 4]>(), any::<u64>(), any::<u64>()).prop_map(|(v, price, qty)| {
+                Response::Trade {
+                    buyer_id: v[0],
+                    buyer_order_id: v[1],
+                    seller_id: v[2],
+                    seller_order_id: v[3],
+                    price: Price(price),
+                    qty: Quantity(qty),
+                }
+            }),
+        ]
+    }
+
+    fn stamped() -> impl Strategy<Value = Stamped> {
+        (any::<u64>(), any::<u64>(), response()).prop_map(|(seq, timestamp, response)| Stamped {
+            seq,
+            timestamp,
+            response,
+        })
+    }
+
+    fn roundtrip<T>(format: Format, values: &[T]) -> Vec<T>
+    where
+        T: CsvRecord + Serialize + DeserializeOwned,
+    {
+        let mut writer = Writer::new(format, vec![]).with_trailing_fields(true);
+        for value in values {
+            writer.write(value).unwrap();
+        }
+        let buf = writer.into_inner();
+
+        Reader::new(format, buf.as_slice())
+            .map(|v| v.unwrap())
+            .collect()
+    }
+
+    proptest! {
+        #[test]
+        fn test_actions_roundtrip(actions in prop::collection::vec(user_action(), 0..32)) {
+            for format in [Format::Csv, Format::JsonLines, Format::MessagePack] {
+                prop_assert_eq!(&roundtrip(format, &actions), &actions);
+            }
+        }
+
+        #[test]
+        fn test_responses_roundtrip(responses in prop::collection::vec(response(), 0..32)) {
+            for format in [Format::JsonLines, Format::MessagePack] {
+                prop_assert_eq!(&roundtrip(format, &responses), &responses);
+            }
+        }
+
+        #[test]
+        fn test_reject_reasons_roundtrip(user_id: u32, order_id: u32, reason in reject_reason()) {
+            let reject = Response::Reject { user_id, order_id, reason };
+            let mut writer = Writer::new(Format::Csv, vec![]).with_trailing_fields(true);
+            writer.write(&reject).unwrap();
+            let buf = writer.into_inner();
+
+            let read: Vec<Response> = Reader::new(Format::Csv, buf.as_slice())
+                .map(|r| r.unwrap())
+                .collect();
+            prop_assert_eq!(read, vec![reject]);
+        }
+
+        #[test]
+        fn test_stamped_roundtrip(stamped in prop::collection::vec(stamped(), 0..32)) {
+            for format in [Format::JsonLines, Format::MessagePack] {
+                prop_assert_eq!(&roundtrip(format, &stamped), &stamped);
+            }
+
+            let mut writer = Writer::new(Format::Csv, vec![]).with_trailing_fields(true);
+            for value in &stamped {
+                writer.write(value).unwrap();
+            }
+            let buf = writer.into_inner();
+            let read: Vec<Stamped> = Reader::new(Format::Csv, buf.as_slice())
+                .map(|s| s.unwrap())
+                .collect();
+            prop_assert_eq!(&read, &stamped);
+
+            // Plain responses are read without their stamp
+            let responses: Vec<Response> = Reader::new(Format::Csv, buf.as_slice())
+                .map(|r| r.unwrap())
+                .collect();
+            prop_assert_eq!(responses, stamped.into_iter().map(|s| s.response).collect::<Vec<_>>());
+        }
+
+        #[test]
+        fn test_orders_and_trades_roundtrip(v in any::<[u32; 4]>(), n in any::<[u64; 4]>()) {
+            let buyer = Order::new(v[0], Price(n[0]), Quantity(n[1]), v[1]);
+            let seller = Order::new(v[2], Price(n[2]), Quantity(n[3]), v[3]);
+
+            let json = serde_json::to_string(&buyer).unwrap();
+            prop_assert_eq!(&serde_json::from_str::<Order>(&json).unwrap(), &buyer);
+            let msgpack = rmp_serde::to_vec_named(&seller).unwrap();
+            prop_assert_eq!(&rmp_serde::from_slice::<Order>(&msgpack).unwrap(), &seller);
+
+            let trade = Trade::new(buyer, seller);
+            let json = serde_json::to_string(&trade).unwrap();
+            prop_assert_eq!(&serde_json::from_str::<Trade>(&json).unwrap(), &trade);
+            let msgpack = rmp_serde::to_vec_named(&trade).unwrap();
+            prop_assert_eq!(&rmp_serde::from_slice::<Trade>(&msgpack).unwrap(), &trade);
+        }
+    }
+
+    #[test]
+    fn test_csv_responses() {
+        let lines = "A, 1, 1\nB, B, 10, 100\nB, S, -, -\n\nR, 2, 103\nT, 1, 2, 2, 102, 11, 100\n";
+        let responses: Vec<Response> = Reader::new(Format::Csv, lines.as_bytes())
+            .map(|r| r.unwrap())
+            .collect();
+
+        assert_eq!(responses.len(), 5);
+        assert_eq!(
+            responses[2],
+            Response::Best {
+                side: Side::Sell,
+                price: Price::ZERO,
+                qty: Quantity::ZERO
+            }
+        );
+
+        let mut writer = Writer::new(Format::Csv, vec![]);
+        for response in &responses {
+            writer.write(response).unwrap();
+        }
+        assert_eq!(
+            String::from_utf8(writer.into_inner()).unwrap(),
+            lines.replace("\n\n", "\n")
+        );
+
+        // The reason is an optional trailing field
+        let reject = Response::from_csv("R, 2, 103, trading_disabled").unwrap();
+        assert_eq!(
+            reject,
+            Some(Response::Reject {
+                user_id: 2,
+                order_id: 103,
+                reason: RejectReason::TradingDisabled
+            })
+        );
+        assert_eq!(reject.unwrap().to_csv(), "R, 2, 103");
+        assert!(Response::from_csv("R, 2, 103, bogus").is_err());
+
+        // The stamp follows every other field
+        let line = "R, 2, 103, trading_disabled, 7, 1000";
+        let stamped = Stamped::from_csv(line).unwrap().unwrap();
+        assert_eq!((stamped.seq, stamped.timestamp), (7, 1000));
+        assert_eq!(
+            Some(stamped.response.clone()),
+            Response::from_csv(line).unwrap()
+        );
+        assert_eq!(stamped.to_csv(), "R, 2, 103, 7, 1000");
+        assert_eq!(stamped.to_csv_extended(Scale::default()), line);
+        assert!(Stamped::from_csv("A, 1, 1").is_err());
+        assert!(Stamped::from_csv("A, 1, 1, 7, x").is_err());
+    }
+
+    #[test]
+    fn test_csv_input_file() {
+        let file = std::fs::File::open("input/input.csv").unwrap();
+        let actions: Vec<UserAction> = Reader::new(Format::Csv, io::BufReader::new(file))
+            .map(|a| a.unwrap())
+            .collect();
+
+        assert_eq!(actions.len(), 82);
+        assert_eq!(
+            actions[0],
+            UserAction::NewOrder {
+                user_id: 1,
+                symbol: Symbol::new("IBM"),
+                price: Price(10),
+                qty: Quantity(100),
+                side: Side::Buy,
+                order_id: 1,
+                time_in_force: TimeInForce::Gtc,
+            }
+        );
+        assert_eq!(actions.last(), Some(&UserAction::Flush));
+        assert!(matches!(
+            UserAction::from_csv("X, 1"),
+            Err(CodecError::Csv(_))
+        ));
+
+        // The time in force is an optional trailing field
+        let line = "N, 1, IBM, 10, 100, B, 1, gtd:2000";
+        let action = UserAction::from_csv(line).unwrap().unwrap();
+        assert!(matches!(
+            action,
+            UserAction::NewOrder {
+                time_in_force: TimeInForce::Gtd(2000),
+                ..
+            }
+        ));
+        assert_eq!(action.to_csv(), "N, 1, IBM, 10, 100, B, 1");
+        assert_eq!(action.to_csv_extended(Scale::default()), line);
+        assert!(UserAction::from_csv("N, 1, IBM, 10, 100, B, 1, gtd").is_err());
+    }
+
+    #[test]
+    fn test_csv_decimals() {
+        let scales: Scales = "IBM:2,BTC:2:8".parse().unwrap();
+        let lines =
+            "N, 1, IBM, 10.25, 100, B, 1\nN, 2, BTC, 30000, 0.5, S, 1\nN, 3, VAL, 7, 1, B, 1\n";
+        let actions: Vec<UserAction> = Reader::new(Format::Csv, lines.as_bytes())
+            .with_scales(scales.clone())
+            .map(|a| a.unwrap())
+            .collect();
+
+        let prices: Vec<_> = actions
+            .iter()
+            .map(|a| match a {
+                UserAction::NewOrder { price, qty, .. } => (price.ticks(), qty.lots()),
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(prices, vec![(1025, 100), (3_000_000, 50_000_000), (7, 1)]);
+
+        // Written back in the scale of each instrument
+        let mut writer = Writer::new(Format::Csv, vec![]).with_scales(scales.clone());
+        for action in &actions {
+            if let UserAction::NewOrder { symbol, .. } = action {
+                writer.write_for(Some(*symbol), action).unwrap();
+            }
+        }
+        writer
+            .write_for(
+                Some(Symbol::new("IBM")),
+                &Response::Trade {
+                    buyer_id: 1,
+                    buyer_order_id: 1,
+                    seller_id: 2,
+                    seller_order_id: 2,
+                    price: Price(1025),
+                    qty: Quantity(100),
+                },
+            )
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(writer.into_inner()).unwrap(),
+            "N, 1, IBM, 10.25, 100, B, 1\nN, 2, BTC, 30000.00, 0.50000000, S, 1\n\
+             N, 3, VAL, 7, 1, B, 1\nT, 1, 1, 2, 2, 10.25, 100\n"
+        );
+
+        // More decimals than the instrument has, or not a number
+        for line in ["N, 1, IBM, 10.255, 100, B, 1", "N, 1, VAL, 10.5, 100, B, 1"] {
+            assert!(matches!(
+                UserAction::from_csv_scaled(line, &scales),
+                Err(CodecError::Csv(_))
+            ));
+        }
+    }
+}
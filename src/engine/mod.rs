@@ -0,0 +1,1384 @@
+//! This mod implements a multi-symbol matching engine.
+//!
+//! [Engine] keeps one [OrderBook] per symbol and remembers the symbol of
+//! every live order, so that cancels - which carry no symbol - reach the
+//! book that holds the order. It also keeps the order ids of every user
+//! unique across its books.
+//!
+//! Operations can cancel every live order matching a [CancelFilter] and
+//! block users from entering new orders.
+//!
+//! Live orders carry the time they were entered at, read from the
+//! [Clock] of the engine, and an expiry given by their [TimeInForce].
+//! [Engine::expire] cancels the orders whose expiry has passed.
+//!
+//! [Engine::stamp] numbers the responses the engine emits and stamps them
+//! with the time of the same clock.
+
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::clock::{Clock, SharedClock, Timestamp, NANOS_PER_DAY};
+use crate::fixed::{Price, Quantity};
+use crate::instrument::{Instrument, Instruments};
+use crate::orderbook::{
+    L2Event, L3Event, OrderBook, RejectReason, Response, RestingOrder, Side, TimeInForce,
+    UserAction,
+};
+use crate::risk::RiskGate;
+use crate::sequence::{Sequencer, StampedOutput};
+use crate::symbol::Symbol;
+
+/// Symbol of the targeted book and the responses to an action
+pub type Output = (Option<Symbol>, (Option<Response>, Option<Response>));
+
+#[derive(Clone, Debug, PartialEq)]
+/// This struct describes a live order known to the [Engine]
+pub struct LiveOrder {
+    /// Symbol of the book holding the order
+    pub symbol: Symbol,
+    /// Side of the order
+    pub side: Side,
+    /// Limit price
+    pub price: Price,
+    /// Open quantity
+    pub qty: Quantity,
+    /// Time the order was entered at
+    pub entry_time: Timestamp,
+    /// Time the order expires at, if it ever does
+    pub expiry: Option<Timestamp>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// This struct describes the times of a live order, which its book does
+/// not hold
+pub struct OrderTimes {
+    /// Owner of the order
+    pub user_id: u32,
+    /// Id of the order
+    pub order_id: u32,
+    /// Time the order was entered at
+    pub entry_time: Timestamp,
+    /// Time the order expires at, if it ever does
+    pub expiry: Option<Timestamp>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+/// This struct selects the live orders of a mass cancel
+///
+/// An order is selected when it matches every given field, so the empty
+/// filter selects every live order.
+pub struct CancelFilter {
+    /// Owner of the orders
+    pub user_id: Option<u32>,
+    /// Symbol of the orders
+    pub symbol: Option<Symbol>,
+    /// Side of the orders
+    pub side: Option<Side>,
+}
+
+impl CancelFilter {
+    /// Returns whether the filter selects an order of `symbol`
+    fn matches(&self, symbol: Symbol, order: &RestingOrder) -> bool {
+        self.user_id.is_none_or(|user_id| user_id == order.user_id)
+            && self.symbol.is_none_or(|s| s == symbol)
+            && self.side.is_none_or(|side| side == order.side)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+/// This enum describes how long an order id stays taken
+pub enum OrderIdScope {
+    /// A user can not reuse the id of one of their live orders
+    #[default]
+    Live,
+    /// A user can not reuse any id accepted since the last
+    /// [UserAction::Flush], which ends the session
+    Session,
+}
+
+impl FromStr for OrderIdScope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "live" => Ok(OrderIdScope::Live),
+            "session" => Ok(OrderIdScope::Session),
+            _ => Err(format!("invalid order id scope: {:?}", s)),
+        }
+    }
+}
+
+impl Display for OrderIdScope {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            OrderIdScope::Live => f.write_str("live"),
+            OrderIdScope::Session => f.write_str("session"),
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+/// This struct routes [UserAction]s to per-symbol [OrderBook]s
+pub struct Engine {
+    /// One [OrderBook] per symbol
+    books: HashMap<Symbol, OrderBook>,
+    /// Live orders by `(user_id, order_id)`
+    orders: HashMap<(u32, u32), LiveOrder>,
+    /// Enables trading functionality on new books
+    trade_active: bool,
+    /// Reference data of the instruments
+    instruments: Instruments,
+    /// How long an order id stays taken
+    order_id_scope: OrderIdScope,
+    /// Ids accepted in the session by `(user_id, order_id)`, only kept
+    /// with [OrderIdScope::Session]
+    session_ids: HashSet<(u32, u32)>,
+    /// [L2Event]s of every book in the order they happened, only kept
+    /// once enabled
+    l2_events: Option<Vec<(Symbol, L2Event)>>,
+    /// [L3Event]s of every book in the order they happened, only kept
+    /// once enabled
+    l3_events: Option<Vec<(Symbol, L3Event)>>,
+    /// Pre-trade checks of new orders, if any
+    risk: Option<RiskGate>,
+    /// Users whose new orders are rejected
+    blocked: HashSet<u32>,
+    /// Time the orders are entered and expire at
+    clock: SharedClock,
+    /// Time of day [TimeInForce::Day] orders expire at, since midnight UTC
+    day_close: Duration,
+    /// Live orders that expire by `(expiry, (user_id, order_id))`
+    expiries: BTreeSet<(Timestamp, (u32, u32))>,
+    /// Sequence numbers of the emitted responses
+    sequencer: Sequencer,
+}
+
+impl Engine {
+    /// Creates an [Engine] without books
+    pub fn new(trade_active: bool) -> Self {
+        Engine {
+            books: HashMap::new(),
+            orders: HashMap::new(),
+            trade_active,
+            instruments: Instruments::new(),
+            order_id_scope: OrderIdScope::Live,
+            session_ids: HashSet::new(),
+            l2_events: None,
+            l3_events: None,
+            risk: None,
+            blocked: HashSet::new(),
+            clock: SharedClock::default(),
+            day_close: Duration::ZERO,
+            expiries: BTreeSet::new(),
+            sequencer: Sequencer::new(),
+        }
+    }
+
+    /// Reads the time from `clock` instead of the [WallClock](crate::clock::WallClock)
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Returns the clock the time is read from
+    pub fn clock(&self) -> &SharedClock {
+        &self.clock
+    }
+
+    /// Expires [TimeInForce::Day] orders at `close` after midnight UTC,
+    /// midnight by default
+    pub fn with_day_close(mut self, close: Duration) -> Self {
+        self.day_close = Duration::from_nanos((close.as_nanos() % NANOS_PER_DAY as u128) as u64);
+        self
+    }
+
+    /// Returns the time of day [TimeInForce::Day] orders expire at
+    pub fn day_close(&self) -> Duration {
+        self.day_close
+    }
+
+    /// Returns when an order entered at `now` expires
+    fn expiry(&self, time_in_force: TimeInForce, now: Timestamp) -> Option<Timestamp> {
+        match time_in_force {
+            TimeInForce::Gtc => None,
+            TimeInForce::Day => {
+                let close = now - now % NANOS_PER_DAY + self.day_close.as_nanos() as Timestamp;
+                Some(if close > now {
+                    close
+                } else {
+                    close + NANOS_PER_DAY
+                })
+            }
+            TimeInForce::Gtd(expiry) => Some(expiry),
+        }
+    }
+
+    /// Forgets a live order that left its book
+    fn forget(&mut self, user_id: u32, order_id: u32) {
+        if let Some(LiveOrder {
+            expiry: Some(expiry),
+            ..
+        }) = self.orders.remove(&(user_id, order_id))
+        {
+            self.expiries.remove(&(expiry, (user_id, order_id)));
+        }
+    }
+
+    /// Records the [L2Event]s of every book, see [Engine::take_l2_events]
+    pub fn with_l2_events(mut self, enabled: bool) -> Self {
+        for book in self.books.values_mut() {
+            book.record_l2_events(enabled);
+        }
+        self.l2_events = if enabled { Some(vec![]) } else { None };
+        self
+    }
+
+    /// Returns the [L2Event]s recorded since the last call with the symbol
+    /// of their book, oldest first
+    pub fn take_l2_events(&mut self) -> Vec<(Symbol, L2Event)> {
+        self.l2_events
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    /// Records the [L3Event]s of every book, see [Engine::take_l3_events]
+    pub fn with_l3_events(mut self, enabled: bool) -> Self {
+        for book in self.books.values_mut() {
+            book.record_l3_events(enabled);
+        }
+        self.l3_events = if enabled { Some(vec![]) } else { None };
+        self
+    }
+
+    /// Returns the [L3Event]s recorded since the last call with the symbol
+    /// of their book, oldest first
+    pub fn take_l3_events(&mut self) -> Vec<(Symbol, L3Event)> {
+        self.l3_events
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    /// Moves the [L2Event]s and [L3Event]s of the books into the engine's
+    ///
+    /// Only the targeted book can have changed, every book when there is no
+    /// target.
+    fn collect_book_events(&mut self, target: Option<Symbol>) {
+        if self.l2_events.is_none() && self.l3_events.is_none() {
+            return;
+        }
+        let mut symbols: Vec<Symbol> = match target {
+            Some(symbol) => vec![symbol],
+            None => self.books.keys().copied().collect(),
+        };
+        symbols.sort_unstable_by_key(|symbol| symbol.as_str());
+        for symbol in symbols {
+            if let Some(book) = self.books.get_mut(&symbol) {
+                if let Some(events) = self.l2_events.as_mut() {
+                    events.extend(book.take_l2_events().into_iter().map(|e| (symbol, e)));
+                }
+                if let Some(events) = self.l3_events.as_mut() {
+                    events.extend(book.take_l3_events().into_iter().map(|e| (symbol, e)));
+                }
+            }
+        }
+    }
+
+    /// Rejects reused order ids within `scope`
+    ///
+    /// The session of a restored [Engine] starts with its live orders and
+    /// the ids restored by [Engine::with_session_ids].
+    pub fn with_order_id_scope(mut self, scope: OrderIdScope) -> Self {
+        self.order_id_scope = scope;
+        match scope {
+            OrderIdScope::Live => self.session_ids.clear(),
+            OrderIdScope::Session => self.session_ids.extend(self.orders.keys().copied()),
+        }
+        self
+    }
+
+    /// Returns the ids accepted in the session, by user and order id
+    ///
+    /// Only kept with [OrderIdScope::Session].
+    pub fn session_ids(&self) -> Vec<(u32, u32)> {
+        let mut ids: Vec<(u32, u32)> = self.session_ids.iter().copied().collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Restores the ids accepted in the session, see [Engine::session_ids]
+    ///
+    /// They are dropped again unless the scope is [OrderIdScope::Session].
+    pub fn with_session_ids(mut self, ids: impl IntoIterator<Item = (u32, u32)>) -> Self {
+        self.session_ids.extend(ids);
+        self
+    }
+
+    /// Returns how long an order id stays taken
+    pub fn order_id_scope(&self) -> OrderIdScope {
+        self.order_id_scope
+    }
+
+    /// Returns whether a new order may not use `(user_id, order_id)`
+    fn is_duplicate(&self, user_id: u32, order_id: u32) -> bool {
+        self.orders.contains_key(&(user_id, order_id))
+            || self.session_ids.contains(&(user_id, order_id))
+    }
+
+    /// Checks every new order against `risk` before it reaches its book
+    ///
+    /// The gate learns the live orders of the engine.
+    pub fn with_risk(mut self, mut risk: RiskGate) -> Self {
+        for (user_id, order_id) in self.orders.keys() {
+            risk.open(*user_id, *order_id);
+        }
+        self.risk = Some(risk);
+        self
+    }
+
+    /// Removes the risk gate, new orders are no longer checked
+    pub fn take_risk(&mut self) -> Option<RiskGate> {
+        self.risk.take()
+    }
+
+    /// Rejects the new orders of a user until [Engine::unblock_user]
+    ///
+    /// Their live orders stay in the books and can still be cancelled, see
+    /// [Engine::mass_cancel].
+    pub fn block_user(&mut self, user_id: u32) {
+        self.blocked.insert(user_id);
+    }
+
+    /// Accepts the new orders of a blocked user again
+    pub fn unblock_user(&mut self, user_id: u32) {
+        self.blocked.remove(&user_id);
+    }
+
+    /// Returns whether the new orders of a user are rejected
+    pub fn is_blocked(&self, user_id: u32) -> bool {
+        self.blocked.contains(&user_id)
+    }
+
+    /// Validates orders against `instruments`, including on existing books
+    ///
+    /// Once instruments are given, orders for unlisted symbols are rejected.
+    pub fn with_instruments(mut self, instruments: Instruments) -> Self {
+        for (symbol, book) in self.books.iter_mut() {
+            if let Some(instrument) = instruments.get(*symbol) {
+                book.set_instrument(instrument.clone());
+            }
+        }
+        self.instruments = instruments;
+        self
+    }
+
+    /// Returns the reference data of the instruments
+    pub fn instruments(&self) -> &Instruments {
+        &self.instruments
+    }
+
+    /// Returns whether trading is enabled on the books
+    pub fn trade_active(&self) -> bool {
+        self.trade_active
+    }
+
+    /// Creates an [Engine] holding restored books
+    ///
+    /// The live orders are rebuilt from the resting orders of the books.
+    /// Books carry no times, so they are entered at time 0 and never
+    /// expire until given their times, see [Engine::with_order_times].
+    pub fn from_books(trade_active: bool, books: Vec<(Symbol, OrderBook)>) -> Self {
+        let mut engine = Engine::new(trade_active);
+        for (symbol, mut book) in books {
+            for order in book.resting_orders() {
+                if let UserAction::NewOrder {
+                    user_id,
+                    price,
+                    qty,
+                    side,
+                    order_id,
+                    ..
+                } = order
+                {
+                    engine.orders.insert(
+                        (user_id, order_id),
+                        LiveOrder {
+                            symbol,
+                            side,
+                            price,
+                            qty,
+                            entry_time: 0,
+                            expiry: None,
+                        },
+                    );
+                }
+            }
+            // Snapshots carry no reference data
+            book.set_instrument(Instrument::new(symbol));
+            engine.books.insert(symbol, book);
+        }
+        engine
+    }
+
+    /// Returns the times of every live order, by user and order id
+    pub fn order_times(&self) -> Vec<OrderTimes> {
+        let mut times: Vec<OrderTimes> = self
+            .orders
+            .iter()
+            .map(|(&(user_id, order_id), order)| OrderTimes {
+                user_id,
+                order_id,
+                entry_time: order.entry_time,
+                expiry: order.expiry,
+            })
+            .collect();
+        times.sort_unstable_by_key(|t| (t.user_id, t.order_id));
+        times
+    }
+
+    /// Restores the times of live orders, see [Engine::order_times]
+    ///
+    /// Times of orders that are not live are ignored.
+    pub fn with_order_times(mut self, times: impl IntoIterator<Item = OrderTimes>) -> Self {
+        for times in times {
+            let key = (times.user_id, times.order_id);
+            if let Some(order) = self.orders.get_mut(&key) {
+                if let Some(expiry) = order.expiry {
+                    self.expiries.remove(&(expiry, key));
+                }
+                order.entry_time = times.entry_time;
+                order.expiry = times.expiry;
+                if let Some(expiry) = times.expiry {
+                    self.expiries.insert((expiry, key));
+                }
+            }
+        }
+        self
+    }
+
+    /// Returns every book with its symbol, in no particular order
+    pub fn books(&self) -> impl Iterator<Item = (&Symbol, &OrderBook)> {
+        self.books.iter()
+    }
+
+    /// Returns the book of `symbol`, if any order was ever sent for it
+    pub fn book(&self, symbol: impl Into<Symbol>) -> Option<&OrderBook> {
+        self.books.get(&symbol.into())
+    }
+
+    /// Returns the live order of a user
+    pub fn order(&self, user_id: u32, order_id: u32) -> Option<&LiveOrder> {
+        self.orders.get(&(user_id, order_id))
+    }
+
+    /// Applies a [UserAction] to the book it targets
+    ///
+    /// Returns the symbol of that book together with the responses of the
+    /// [OrderBook]. [UserAction::Flush] flushes every book and returns no
+    /// symbol, as does a cancel of an unknown order. A new order resting in
+    /// its book stays live until its time in force expires.
+    pub fn new_user_action(&mut self, action: UserAction) -> Output {
+        let action = self.pin_expiry(action);
+        match self.check_risk(&action) {
+            Some(reject) => reject,
+            None => self.apply_checked(action),
+        }
+    }
+
+    /// Returns the action with a [TimeInForce::Day] turned into the
+    /// [TimeInForce::Gtd] of the close it expires at by the time of the
+    /// clock
+    ///
+    /// Journaled engines pin actions before journaling them, so that a
+    /// replay gives their orders the same expiry.
+    pub fn pin_expiry(&self, mut action: UserAction) -> UserAction {
+        if let UserAction::NewOrder { time_in_force, .. } = &mut action {
+            if let (TimeInForce::Day, Some(expiry)) = (
+                *time_in_force,
+                self.expiry(*time_in_force, self.clock.now()),
+            ) {
+                *time_in_force = TimeInForce::Gtd(expiry);
+            }
+        }
+        action
+    }
+
+    /// Checks a [UserAction] against the blocked users, its expiry and the
+    /// risk gate, if any
+    ///
+    /// Returns the reject of an action of a blocked user, expiring before
+    /// it could rest or breaking a limit, which must then not be applied.
+    pub fn check_risk(&mut self, action: &UserAction) -> Option<Output> {
+        if let UserAction::NewOrder {
+            user_id,
+            symbol,
+            order_id,
+            time_in_force,
+            ..
+        } = action
+        {
+            let reject = |reason| {
+                let reject = Response::Reject {
+                    user_id: *user_id,
+                    order_id: *order_id,
+                    reason,
+                };
+                Some((Some(*symbol), (Some(reject), None)))
+            };
+
+            // Reject Order - its user is blocked
+            if self.blocked.contains(user_id) {
+                return reject(RejectReason::UserBlocked);
+            }
+
+            // Reject Order - it would expire before it could rest
+            let now = self.clock.now();
+            if self
+                .expiry(*time_in_force, now)
+                .is_some_and(|expiry| expiry <= now)
+            {
+                return reject(RejectReason::Expired);
+            }
+        }
+
+        // Reject Order - it breaks a risk limit of its user
+        match (self.risk.as_mut()?.check(action), action) {
+            (
+                Err(breach),
+                UserAction::NewOrder {
+                    user_id,
+                    symbol,
+                    order_id,
+                    ..
+                },
+            ) => {
+                let reject = Response::Reject {
+                    user_id: *user_id,
+                    order_id: *order_id,
+                    reason: breach.into(),
+                };
+                Some((Some(*symbol), (Some(reject), None)))
+            }
+            (_, _) => None,
+        }
+    }
+
+    /// Applies a [UserAction] that passed [Engine::check_risk]
+    ///
+    /// The risk gate still learns from its responses, see
+    /// [Engine::new_user_action]. Replayed orders rest even when they
+    /// expired since, they are cancelled by the next [Engine::expired].
+    pub fn apply_checked(&mut self, action: UserAction) -> Output {
+        let output = self.route(action.clone());
+        if let Some(risk) = self.risk.as_mut() {
+            risk.update(&action, &output.1);
+        }
+        self.collect_book_events(output.0);
+        output
+    }
+
+    /// Replaces the price and quantity of a live order
+    ///
+    /// Cancels the order and, once the cancel is acknowledged, enters one
+    /// with the same symbol, side, order id and expiry, so the order loses
+    /// its time priority. The replacement may reuse the order id under
+    /// [OrderIdScope::Session]. Returns every applied action with its
+    /// [Output]; a rejected replacement leaves the order cancelled.
+    pub fn amend(
+        &mut self,
+        user_id: u32,
+        order_id: u32,
+        price: Price,
+        qty: Quantity,
+    ) -> Vec<(UserAction, Output)> {
+        let order = self.orders.get(&(user_id, order_id)).cloned();
+        let cancel = UserAction::CancelOrder { user_id, order_id };
+        let output = self.new_user_action(cancel.clone());
+        let cancelled = matches!(output.1, (Some(Response::Acknowledge { .. }), _));
+        let mut outputs = vec![(cancel, output)];
+
+        if let (true, Some(order)) = (cancelled, order) {
+            let replacement = UserAction::NewOrder {
+                user_id,
+                symbol: order.symbol,
+                price,
+                qty,
+                side: order.side,
+                order_id,
+                time_in_force: order.expiry.map_or(TimeInForce::Gtc, TimeInForce::Gtd),
+            };
+            // The id stays taken for the session, even if the replacement
+            // is rejected
+            let in_session = self.session_ids.remove(&(user_id, order_id));
+            let output = self.new_user_action(replacement.clone());
+            if in_session {
+                self.session_ids.insert((user_id, order_id));
+            }
+            outputs.push((replacement, output));
+        }
+        outputs
+    }
+
+    /// Returns the cancels of the live orders selected by `filter`
+    ///
+    /// Books come by symbol name, orders in the order of
+    /// [OrderBook::orders]. Journaled engines apply them one by one like
+    /// any other action.
+    pub fn cancels(&self, filter: &CancelFilter) -> Vec<UserAction> {
+        let mut books: Vec<(&Symbol, &OrderBook)> = self
+            .books
+            .iter()
+            .filter(|(symbol, _)| filter.symbol.is_none_or(|s| s == **symbol))
+            .collect();
+        books.sort_unstable_by_key(|(symbol, _)| symbol.as_str());
+
+        books
+            .into_iter()
+            .flat_map(|(symbol, book)| {
+                book.orders()
+                    .filter(|order| filter.matches(*symbol, order))
+                    .map(|order| UserAction::CancelOrder {
+                        user_id: order.user_id,
+                        order_id: order.order_id,
+                    })
+            })
+            .collect()
+    }
+
+    /// Cancels every live order selected by `filter`
+    ///
+    /// Unlike [UserAction::Flush], every order is cancelled on its own and
+    /// gets its acknowledgement and the [Response::Best] update of its
+    /// book, in the order of [Engine::cancels].
+    pub fn mass_cancel(&mut self, filter: &CancelFilter) -> Vec<Output> {
+        self.cancels(filter)
+            .into_iter()
+            .map(|cancel| self.new_user_action(cancel))
+            .collect()
+    }
+
+    /// Returns the cancels of the live orders that expired by the time of
+    /// the clock, soonest expiry first
+    ///
+    /// Journaled engines apply them one by one like any other action.
+    pub fn expired(&self) -> Vec<UserAction> {
+        let now = self.clock.now();
+        self.expiries
+            .iter()
+            .take_while(|(expiry, _)| *expiry <= now)
+            .map(|(_, (user_id, order_id))| UserAction::CancelOrder {
+                user_id: *user_id,
+                order_id: *order_id,
+            })
+            .collect()
+    }
+
+    /// Cancels every live order that expired by the time of the clock
+    ///
+    /// Every order gets the acknowledgement of its cancel and the
+    /// [Response::Best] update of its book, in the order of
+    /// [Engine::expired].
+    pub fn expire(&mut self) -> Vec<Output> {
+        self.expired()
+            .into_iter()
+            .map(|cancel| self.new_user_action(cancel))
+            .collect()
+    }
+
+    /// Stamps the responses of an [Output] with the next sequence numbers
+    /// of the engine and the time of its clock
+    ///
+    /// Only emitted responses are meant to be stamped, so the numbers of
+    /// an engine have no gaps. Replayed actions are not stamped.
+    pub fn stamp(&mut self, output: Output) -> StampedOutput {
+        let now = self.clock.now();
+        self.sequencer.stamp_output(output, now)
+    }
+
+    /// Returns the sequence number of the last stamped response
+    pub fn last_seq(&self) -> u64 {
+        self.sequencer.last()
+    }
+
+    /// Numbers the next stamped response `last + 1`, so that a restored
+    /// engine carries on with the numbers of the responses it emitted
+    pub fn with_last_seq(mut self, last: u64) -> Self {
+        self.sequencer = Sequencer::resume(last);
+        self
+    }
+
+    /// Applies a [UserAction] to its book, see [Engine::new_user_action]
+    fn route(&mut self, action: UserAction) -> Output {
+        match action {
+            UserAction::NewOrder {
+                user_id,
+                symbol,
+                price,
+                qty,
+                side,
+                order_id,
+                time_in_force,
+            } => {
+                // Reject Order - the user already used its id
+                if self.is_duplicate(user_id, order_id) {
+                    let reject = Response::Reject {
+                        user_id,
+                        order_id,
+                        reason: RejectReason::DuplicateOrderId,
+                    };
+                    return (Some(symbol), (Some(reject), None));
+                }
+
+                let now = self.clock.now();
+                let expiry = self.expiry(time_in_force, now);
+
+                let book = match self.books.entry(symbol) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => match self.instruments.resolve(symbol) {
+                        Ok(instrument) => {
+                            let mut book =
+                                OrderBook::with_instrument(instrument, self.trade_active);
+                            book.record_l2_events(self.l2_events.is_some());
+                            book.record_l3_events(self.l3_events.is_some());
+                            entry.insert(book)
+                        }
+                        // Reject Order - the symbol is not in the reference data
+                        Err(invalid) => {
+                            let reject = Response::Reject {
+                                user_id,
+                                order_id,
+                                reason: invalid.into(),
+                            };
+                            return (Some(symbol), (Some(reject), None));
+                        }
+                    },
+                };
+                let responses = book.new_user_action(action);
+
+                if self.order_id_scope == OrderIdScope::Session {
+                    if let (Some(Response::Acknowledge { .. }), _) = responses {
+                        self.session_ids.insert((user_id, order_id));
+                    }
+                }
+                match &responses {
+                    // The resting order of the trade is gone
+                    (
+                        Some(Response::Acknowledge { .. }),
+                        Some(Response::Trade {
+                            buyer_id,
+                            buyer_order_id,
+                            seller_id,
+                            seller_order_id,
+                            ..
+                        }),
+                    ) => {
+                        self.forget(*buyer_id, *buyer_order_id);
+                        self.forget(*seller_id, *seller_order_id);
+                    }
+                    (Some(Response::Acknowledge { .. }), _) => {
+                        self.orders.insert(
+                            (user_id, order_id),
+                            LiveOrder {
+                                symbol,
+                                side,
+                                price,
+                                qty,
+                                entry_time: now,
+                                expiry,
+                            },
+                        );
+                        if let Some(expiry) = expiry {
+                            self.expiries.insert((expiry, (user_id, order_id)));
+                        }
+                    }
+                    (_, _) => (),
+                }
+
+                (Some(symbol), responses)
+            }
+            UserAction::CancelOrder { user_id, order_id } => {
+                let unknown = Response::Reject {
+                    user_id,
+                    order_id,
+                    reason: RejectReason::UnknownOrder,
+                };
+                match self.orders.get(&(user_id, order_id)) {
+                    Some(order) => {
+                        let symbol = order.symbol;
+                        let responses = self
+                            .books
+                            .get_mut(&symbol)
+                            .map(|book| book.new_user_action(action))
+                            .unwrap_or((Some(unknown), None));
+                        if let (Some(Response::Acknowledge { .. }), _) = responses {
+                            self.forget(user_id, order_id);
+                        }
+
+                        (Some(symbol), responses)
+                    }
+                    None => (None, (Some(unknown), None)),
+                }
+            }
+            UserAction::Flush => {
+                for book in self.books.values_mut() {
+                    book.new_user_action(UserAction::Flush);
+                }
+                self.orders.clear();
+                self.expiries.clear();
+                self.session_ids.clear();
+
+                (None, (None, None))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instrument::InvalidOrder;
+    use crate::sequence::Stamped;
+
+    fn new_order(user_id: u32, symbol: &str, price: u64, side: &str, order_id: u32) -> UserAction {
+        UserAction::NewOrder {
+            user_id,
+            symbol: Symbol::new(symbol),
+            price: Price(price),
+            qty: Quantity(100),
+            side: side.parse().unwrap(),
+            order_id,
+            time_in_force: TimeInForce::Gtc,
+        }
+    }
+
+    /// Returns a new order that stays live until `until` expires
+    fn good_till(mut action: UserAction, until: TimeInForce) -> UserAction {
+        if let UserAction::NewOrder { time_in_force, .. } = &mut action {
+            *time_in_force = until;
+        }
+        action
+    }
+
+    #[test]
+    fn test_routes_by_symbol() {
+        let mut engine = Engine::new(true);
+
+        engine.new_user_action(new_order(1, "IBM", 10, "B", 1));
+        engine.new_user_action(new_order(1, "AAPL", 20, "S", 2));
+        assert_eq!(engine.book("IBM").unwrap().bids(), 1);
+        assert_eq!(engine.book("AAPL").unwrap().asks(), 1);
+        assert_eq!(engine.order(1, 2).unwrap().symbol, "AAPL");
+
+        // Cancel reaches the AAPL book
+        let (symbol, responses) = engine.new_user_action(UserAction::CancelOrder {
+            user_id: 1,
+            order_id: 2,
+        });
+        assert_eq!(symbol, Some(Symbol::new("AAPL")));
+        assert_eq!(
+            responses.0,
+            Some(Response::Acknowledge {
+                user_id: 1,
+                order_id: 2
+            })
+        );
+        assert_eq!(engine.book("AAPL").unwrap().asks(), 0);
+        assert_eq!(engine.order(1, 2), None);
+
+        // Unknown order
+        let (symbol, responses) = engine.new_user_action(UserAction::CancelOrder {
+            user_id: 1,
+            order_id: 2,
+        });
+        assert_eq!(symbol, None);
+        assert_eq!(
+            responses,
+            (
+                Some(Response::Reject {
+                    user_id: 1,
+                    order_id: 2,
+                    reason: RejectReason::UnknownOrder
+                }),
+                None
+            )
+        );
+    }
+
+    #[test]
+    fn test_trade_and_flush_forget_orders() {
+        let mut engine = Engine::new(true);
+
+        engine.new_user_action(new_order(1, "IBM", 10, "B", 1));
+        engine.new_user_action(new_order(1, "IBM", 11, "B", 2));
+        engine.new_user_action(new_order(2, "IBM", 10, "S", 1));
+        assert_eq!(engine.order(1, 1), None);
+        assert_eq!(engine.order(2, 1), None);
+        assert!(engine.order(1, 2).is_some());
+
+        engine.new_user_action(UserAction::Flush);
+        assert_eq!(engine.order(1, 2), None);
+        assert_eq!(engine.book("IBM").unwrap().bids(), 0);
+    }
+
+    #[test]
+    fn test_validates_against_instruments() {
+        let instruments = Instruments::from_json(
+            r#"[{"symbol": "IBM", "tick_size": 5, "lot_size": 100, "max_qty": 1000}]"#,
+        )
+        .unwrap();
+        let mut engine = Engine::new(true).with_instruments(instruments);
+        let reject = |user_id, order_id, reason: InvalidOrder| {
+            let reason = reason.into();
+            (
+                Some(Response::Reject {
+                    user_id,
+                    order_id,
+                    reason,
+                }),
+                None,
+            )
+        };
+
+        // Off tick, then on tick
+        assert_eq!(
+            engine.new_user_action(new_order(1, "IBM", 12, "B", 1)).1,
+            reject(1, 1, InvalidOrder::OffTick)
+        );
+        assert!(matches!(
+            engine.new_user_action(new_order(1, "IBM", 10, "B", 2)).1,
+            (Some(Response::Acknowledge { .. }), _)
+        ));
+
+        // Odd lot and size limit
+        let mut order = new_order(1, "IBM", 10, "B", 3);
+        if let UserAction::NewOrder { qty, .. } = &mut order {
+            *qty = Quantity(150);
+        }
+        assert_eq!(
+            engine.new_user_action(order.clone()).1,
+            reject(1, 3, InvalidOrder::OddLot)
+        );
+        if let UserAction::NewOrder { qty, .. } = &mut order {
+            *qty = Quantity(1100);
+        }
+        assert_eq!(
+            engine.new_user_action(order).1,
+            reject(1, 3, InvalidOrder::AboveMaxQty)
+        );
+
+        // Unlisted symbols get no book
+        assert_eq!(
+            engine.new_user_action(new_order(1, "AAPL", 10, "B", 4)).1,
+            reject(1, 4, InvalidOrder::UnknownInstrument)
+        );
+        assert!(engine.book("AAPL").is_none());
+    }
+
+    #[test]
+    fn test_rejects_duplicate_order_ids() {
+        let duplicate = |user_id, order_id| {
+            (
+                Some(Response::Reject {
+                    user_id,
+                    order_id,
+                    reason: RejectReason::DuplicateOrderId,
+                }),
+                None,
+            )
+        };
+        let mut engine = Engine::new(true);
+
+        // Live ids are taken across books, other users may use them
+        engine.new_user_action(new_order(1, "IBM", 10, "B", 1));
+        assert_eq!(
+            engine.new_user_action(new_order(1, "IBM", 9, "B", 1)).1,
+            duplicate(1, 1)
+        );
+        assert_eq!(
+            engine.new_user_action(new_order(1, "AAPL", 9, "B", 1)).1,
+            duplicate(1, 1)
+        );
+        assert!(matches!(
+            engine.new_user_action(new_order(2, "IBM", 12, "S", 1)).1,
+            (Some(Response::Acknowledge { .. }), _)
+        ));
+        assert_eq!(engine.order(1, 1).unwrap().price, Price(10));
+
+        // Freed by a fill or a cancel
+        engine.new_user_action(new_order(3, "IBM", 10, "S", 1));
+        assert_eq!(engine.order(1, 1), None);
+        assert!(matches!(
+            engine.new_user_action(new_order(1, "IBM", 8, "B", 1)).1,
+            (Some(Response::Acknowledge { .. }), _)
+        ));
+        engine.new_user_action(UserAction::CancelOrder {
+            user_id: 1,
+            order_id: 1,
+        });
+        assert!(matches!(
+            engine.new_user_action(new_order(1, "IBM", 8, "B", 1)).1,
+            (Some(Response::Acknowledge { .. }), _)
+        ));
+
+        // Within a session ids are never reused, until the flush
+        let mut engine = Engine::new(true).with_order_id_scope(OrderIdScope::Session);
+        engine.new_user_action(new_order(1, "IBM", 10, "B", 1));
+        engine.new_user_action(UserAction::CancelOrder {
+            user_id: 1,
+            order_id: 1,
+        });
+        assert_eq!(
+            engine.new_user_action(new_order(1, "IBM", 10, "B", 1)).1,
+            duplicate(1, 1)
+        );
+        engine.new_user_action(UserAction::Flush);
+        assert!(matches!(
+            engine.new_user_action(new_order(1, "IBM", 10, "B", 1)).1,
+            (Some(Response::Acknowledge { .. }), _)
+        ));
+    }
+
+    #[test]
+    fn test_amend_reuses_session_id() {
+        let mut engine = Engine::new(true).with_order_id_scope(OrderIdScope::Session);
+        engine.new_user_action(new_order(1, "IBM", 10, "B", 1));
+
+        let outputs = engine.amend(1, 1, Price(11), Quantity(50));
+        assert_eq!(
+            outputs
+                .into_iter()
+                .map(|(_, (_, (res, _)))| res)
+                .collect::<Vec<_>>(),
+            vec![
+                Some(Response::Acknowledge {
+                    user_id: 1,
+                    order_id: 1
+                });
+                2
+            ]
+        );
+        assert_eq!(engine.order(1, 1).map(|o| o.price), Some(Price(11)));
+
+        // The id stays taken for the session
+        engine.new_user_action(UserAction::CancelOrder {
+            user_id: 1,
+            order_id: 1,
+        });
+        assert!(matches!(
+            engine.new_user_action(new_order(1, "IBM", 10, "B", 1)).1,
+            (
+                Some(Response::Reject {
+                    reason: RejectReason::DuplicateOrderId,
+                    ..
+                }),
+                None
+            )
+        ));
+        assert_eq!(
+            engine.amend(1, 1, Price(11), Quantity(50)).len(),
+            1,
+            "an unknown order is not replaced"
+        );
+    }
+
+    #[test]
+    fn test_collects_book_events() {
+        use crate::orderbook::{LevelChange, OrderChange};
+
+        let mut engine = Engine::new(true).with_l2_events(true).with_l3_events(true);
+        engine.new_user_action(new_order(1, "IBM", 10, "B", 1));
+        engine.new_user_action(new_order(1, "AAPL", 20, "S", 2));
+        engine.new_user_action(UserAction::CancelOrder {
+            user_id: 1,
+            order_id: 1,
+        });
+        let events: Vec<(Symbol, LevelChange)> = engine
+            .take_l2_events()
+            .into_iter()
+            .map(|(symbol, event)| (symbol, event.change))
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                (Symbol::new("IBM"), LevelChange::Add),
+                (Symbol::new("AAPL"), LevelChange::Add),
+                (Symbol::new("IBM"), LevelChange::Delete),
+            ]
+        );
+
+        let changes: Vec<OrderChange> = engine
+            .take_l3_events()
+            .into_iter()
+            .map(|(_, event)| event.change)
+            .collect();
+        assert_eq!(
+            changes,
+            vec![OrderChange::Add, OrderChange::Add, OrderChange::Delete]
+        );
+
+        engine.new_user_action(UserAction::Flush);
+        assert_eq!(engine.take_l2_events().len(), 1);
+        assert_eq!(engine.take_l3_events().len(), 1);
+        assert!(Engine::new(true).take_l2_events().is_empty());
+    }
+
+    #[test]
+    fn test_checks_risk() {
+        use crate::risk::{RiskBreach, RiskConfig};
+
+        let config = RiskConfig::from_json(r#"{"default": {"max_open_orders": 1}}"#).unwrap();
+        let mut engine = Engine::new(true);
+        engine.new_user_action(new_order(1, "IBM", 10, "B", 1));
+        let mut engine = engine.with_risk(RiskGate::new(config));
+
+        // The gate knows the order resting before it was added
+        assert_eq!(
+            engine.new_user_action(new_order(1, "AAPL", 10, "B", 2)),
+            (
+                Some(Symbol::new("AAPL")),
+                (
+                    Some(Response::Reject {
+                        user_id: 1,
+                        order_id: 2,
+                        reason: RiskBreach::MaxOpenOrders.into(),
+                    }),
+                    None,
+                )
+            )
+        );
+        assert!(engine.book("AAPL").is_none());
+
+        // The order leaves the book by a trade
+        engine.new_user_action(new_order(2, "IBM", 10, "S", 1));
+        assert!(matches!(
+            engine.new_user_action(new_order(1, "AAPL", 10, "B", 2)).1,
+            (Some(Response::Acknowledge { .. }), _)
+        ));
+    }
+
+    #[test]
+    fn test_mass_cancel_and_block() {
+        let mut engine = Engine::new(true);
+        engine.new_user_action(new_order(1, "IBM", 10, "B", 1));
+        engine.new_user_action(new_order(1, "IBM", 11, "B", 2));
+        engine.new_user_action(new_order(1, "IBM", 12, "S", 3));
+        engine.new_user_action(new_order(1, "AAPL", 20, "B", 4));
+        engine.new_user_action(new_order(2, "IBM", 9, "B", 1));
+
+        // Each cancel gets its ack and the best price it leaves behind
+        let buys = CancelFilter {
+            user_id: Some(1),
+            symbol: Some(Symbol::new("IBM")),
+            side: Some(Side::Buy),
+        };
+        let ibm = Some(Symbol::new("IBM"));
+        let ack = |order_id| {
+            Some(Response::Acknowledge {
+                user_id: 1,
+                order_id,
+            })
+        };
+        let best = |price| {
+            Some(Response::Best {
+                side: Side::Buy,
+                price: Price(price),
+                qty: Quantity(100),
+            })
+        };
+        assert_eq!(
+            engine.mass_cancel(&buys),
+            vec![(ibm, (ack(2), best(10))), (ibm, (ack(1), best(9)))]
+        );
+        assert!(engine.order(1, 3).is_some());
+        assert!(engine.order(2, 1).is_some());
+
+        // Blocked users can not enter orders but can still cancel
+        engine.block_user(1);
+        assert_eq!(
+            engine.new_user_action(new_order(1, "IBM", 10, "B", 5)),
+            (
+                ibm,
+                (
+                    Some(Response::Reject {
+                        user_id: 1,
+                        order_id: 5,
+                        reason: RejectReason::UserBlocked,
+                    }),
+                    None,
+                )
+            )
+        );
+        let user = CancelFilter {
+            user_id: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(
+            engine.cancels(&user),
+            vec![
+                UserAction::CancelOrder {
+                    user_id: 1,
+                    order_id: 4
+                },
+                UserAction::CancelOrder {
+                    user_id: 1,
+                    order_id: 3
+                },
+            ]
+        );
+        assert_eq!(engine.mass_cancel(&user).len(), 2);
+        assert!(engine.mass_cancel(&user).is_empty());
+        assert!(engine.order(2, 1).is_some());
+
+        engine.unblock_user(1);
+        assert!(!engine.is_blocked(1));
+        assert!(matches!(
+            engine.new_user_action(new_order(1, "IBM", 10, "B", 5)).1,
+            (Some(Response::Acknowledge { .. }), _)
+        ));
+    }
+
+    #[test]
+    fn test_expires_orders() {
+        use crate::clock::ManualClock;
+        use crate::orderbook::snapshot::BookV2;
+
+        // 10:00 UTC, the day closes at 16:00
+        let hour = Duration::from_secs(3600);
+        let clock = ManualClock::new(NANOS_PER_DAY + 10 * hour.as_nanos() as u64);
+        let mut engine = Engine::new(true)
+            .with_clock(SharedClock::new(clock.clone()))
+            .with_day_close(16 * hour);
+        let at = |hours: u64| NANOS_PER_DAY + hours * hour.as_nanos() as u64;
+
+        engine.new_user_action(new_order(1, "IBM", 10, "B", 1));
+        engine.new_user_action(good_till(new_order(1, "IBM", 11, "B", 2), TimeInForce::Day));
+        engine.new_user_action(good_till(
+            new_order(2, "IBM", 9, "B", 1),
+            TimeInForce::Gtd(at(12)),
+        ));
+        let order = engine.order(1, 2).unwrap();
+        assert_eq!((order.entry_time, order.expiry), (at(10), Some(at(16))));
+        assert_eq!(engine.order(1, 1).unwrap().expiry, None);
+
+        // DAY orders are pinned to the close they expire at
+        assert_eq!(
+            engine.pin_expiry(good_till(new_order(1, "IBM", 11, "B", 4), TimeInForce::Day)),
+            good_till(new_order(1, "IBM", 11, "B", 4), TimeInForce::Gtd(at(16)))
+        );
+
+        // Restored books learn the times of their orders
+        let restored = Engine::from_books(
+            true,
+            engine
+                .books()
+                .map(|(symbol, book)| (*symbol, OrderBook::from(BookV2::from(book))))
+                .collect(),
+        )
+        .with_clock(engine.clock().clone())
+        .with_day_close(engine.day_close())
+        .with_order_times(engine.order_times());
+        assert_eq!(restored, engine);
+
+        // Expired orders are rejected
+        assert_eq!(
+            engine
+                .new_user_action(good_till(
+                    new_order(3, "IBM", 9, "B", 1),
+                    TimeInForce::Gtd(at(10)),
+                ))
+                .1,
+            (
+                Some(Response::Reject {
+                    user_id: 3,
+                    order_id: 1,
+                    reason: RejectReason::Expired,
+                }),
+                None
+            )
+        );
+
+        clock.advance(hour);
+        assert!(engine.expire().is_empty());
+
+        // The GTD order expires first, then the DAY order moves the best bid
+        clock.set(at(16));
+        let ibm = Some(Symbol::new("IBM"));
+        assert_eq!(
+            engine.expire(),
+            vec![
+                (
+                    ibm,
+                    (
+                        Some(Response::Acknowledge {
+                            user_id: 2,
+                            order_id: 1
+                        }),
+                        None
+                    )
+                ),
+                (
+                    ibm,
+                    (
+                        Some(Response::Acknowledge {
+                            user_id: 1,
+                            order_id: 2
+                        }),
+                        Some(Response::Best {
+                            side: Side::Buy,
+                            price: Price(10),
+                            qty: Quantity(100)
+                        })
+                    )
+                ),
+            ]
+        );
+        assert!(engine.order(1, 1).is_some());
+
+        // DAY orders entered after the close expire the next day
+        engine.new_user_action(good_till(new_order(1, "IBM", 11, "B", 3), TimeInForce::Day));
+        assert_eq!(engine.order(1, 3).unwrap().expiry, Some(at(40)));
+
+        // Orders that traded or were cancelled never expire
+        engine.new_user_action(new_order(2, "IBM", 11, "S", 2));
+        clock.set(at(48));
+        assert!(engine.expired().is_empty());
+    }
+
+    #[test]
+    fn test_stamps_responses() {
+        use crate::clock::ManualClock;
+
+        let clock = ManualClock::new(1_000);
+        let mut engine = Engine::new(true).with_clock(SharedClock::new(clock.clone()));
+
+        // Responses of books of different symbols share one sequence
+        let output = engine.new_user_action(new_order(1, "IBM", 10, "B", 1));
+        let (_, (ack, best)) = engine.stamp(output);
+        assert_eq!(ack.map(|s| (s.seq, s.timestamp)), Some((1, 1_000)));
+        assert_eq!(best.map(|s| s.seq), Some(2));
+
+        clock.advance(Duration::from_micros(1));
+        let output = engine.new_user_action(new_order(1, "AAPL", 10, "S", 2));
+        let (symbol, (ack, _)) = engine.stamp(output);
+        assert_eq!(symbol, Some(Symbol::new("AAPL")));
+        assert_eq!(
+            ack,
+            Some(Stamped {
+                seq: 3,
+                timestamp: 2_000,
+                response: Response::Acknowledge {
+                    user_id: 1,
+                    order_id: 2
+                }
+            })
+        );
+        assert_eq!(engine.last_seq(), 4);
+    }
+}
@@ -0,0 +1,1037 @@
+//! This mod implements the UDP multicast market-data feed.
+//!
+//! [Publisher] sends the ITCH [Message]s of a session in sequenced
+//! packets over UDP multicast. Every published message is also kept in a
+//! [FeedStore], which the TCP recovery service ([serve_recovery]) uses to
+//! retransmit lost messages and to hand out snapshots of the books of the
+//! [Engine] to late joiners. [Subscriber] puts the two together and
+//! rebuilds the books of every symbol from the feed.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, TcpStream, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::clock::{Clock, SharedClock};
+use crate::engine::{Engine, Output};
+use crate::itch::{DecodeError, EncodeError, Encoder, Event, Message, ReplayError, SystemEvent};
+use crate::orderbook::{OrderBook, UserAction};
+use crate::symbol::Symbol;
+
+/// Size of the packet header: sequence number (8) + timestamp (8) + count (2)
+pub const PACKET_HEADER_LEN: usize = 18;
+
+/// Largest packet the [Publisher] sends - fits an Ethernet frame
+pub const MAX_PACKET_LEN: usize = 1400;
+
+/// Recovery request for a range of messages
+const RETRANSMIT_REQUEST: u8 = b'R';
+
+/// Recovery request for a book snapshot
+const SNAPSHOT_REQUEST: u8 = b'S';
+
+/// Size of a recovery response header: kind (1) + sequence number (8) + count (4)
+const RECOVERY_HEADER_LEN: usize = 13;
+
+/// Most messages returned by a single retransmission response
+const MAX_RETRANSMIT: u32 = 10_000;
+
+#[derive(Clone, Debug, PartialEq)]
+/// This struct is a multicast packet
+///
+/// `seq` is the sequence number of the first message. A packet without
+/// messages is a heartbeat and `seq` is the sequence number of the next
+/// message to be published, which lets idle subscribers detect gaps.
+pub struct Packet {
+    /// Sequence number of the first message
+    pub seq: u64,
+    /// Nanoseconds since the UNIX epoch at which the packet was sent
+    pub timestamp: u64,
+    /// Messages carried by the packet
+    pub messages: Vec<Message>,
+}
+
+impl Packet {
+    /// Returns the binary representation of the packet
+    pub fn encode(&self) -> Result<Vec<u8>, EncodeError> {
+        let mut buf = Vec::with_capacity(MAX_PACKET_LEN);
+        buf.extend_from_slice(&self.seq.to_le_bytes());
+        buf.extend_from_slice(&self.timestamp.to_le_bytes());
+        buf.extend_from_slice(&(self.messages.len() as u16).to_le_bytes());
+        for message in &self.messages {
+            message.encode(&mut buf)?;
+        }
+
+        Ok(buf)
+    }
+
+    /// Decodes a packet received from the network
+    pub fn decode(buf: &[u8]) -> Result<Packet, DecodeError> {
+        if buf.len() < PACKET_HEADER_LEN {
+            return Err(DecodeError::Truncated);
+        }
+
+        let seq = u64::from_le_bytes(buf[0..8].try_into().unwrap());
+        let timestamp = u64::from_le_bytes(buf[8..16].try_into().unwrap());
+        let count = u16::from_le_bytes(buf[16..18].try_into().unwrap());
+
+        let mut messages = Vec::with_capacity(count as usize);
+        let mut pos = PACKET_HEADER_LEN;
+        for _ in 0..count {
+            let (message, len) = Message::decode(&buf[pos..])?;
+            pos += len;
+            messages.push(message);
+        }
+
+        Ok(Packet {
+            seq,
+            timestamp,
+            messages,
+        })
+    }
+}
+
+#[derive(Debug)]
+/// Errors produced by the feed
+pub enum FeedError {
+    /// Network error
+    Io(io::Error),
+    /// A message could not be encoded
+    Encode(EncodeError),
+    /// A packet or recovery response could not be decoded
+    Decode(DecodeError),
+    /// A message could not be applied to the book
+    Replay(ReplayError),
+    /// The recovery service could not fill a gap
+    Unrecoverable { expected: u64, got: u64 },
+}
+
+impl Display for FeedError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            FeedError::Io(e) => write!(f, "{}", e),
+            FeedError::Encode(e) => write!(f, "{}", e),
+            FeedError::Decode(e) => write!(f, "{}", e),
+            FeedError::Replay(e) => write!(f, "{}", e),
+            FeedError::Unrecoverable { expected, got } => write!(
+                f,
+                "could not recover messages {} to {}",
+                expected,
+                got.saturating_sub(1)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for FeedError {}
+
+impl From<io::Error> for FeedError {
+    fn from(e: io::Error) -> Self {
+        FeedError::Io(e)
+    }
+}
+
+impl From<EncodeError> for FeedError {
+    fn from(e: EncodeError) -> Self {
+        FeedError::Encode(e)
+    }
+}
+
+impl From<DecodeError> for FeedError {
+    fn from(e: DecodeError) -> Self {
+        FeedError::Decode(e)
+    }
+}
+
+impl From<ReplayError> for FeedError {
+    fn from(e: ReplayError) -> Self {
+        FeedError::Replay(e)
+    }
+}
+
+/// Applies a [Message] to the books of an [Engine]
+fn apply(engine: &mut Engine, message: &Message) -> Result<(), ReplayError> {
+    if let Some(action) = message.event.to_user_action()? {
+        engine.new_user_action(action);
+    }
+
+    Ok(())
+}
+
+/// Returns the [Event::AddOrder]s that rebuild the resting orders of one
+/// book, in time priority
+fn add_orders(orders: &[UserAction]) -> impl Iterator<Item = Event> + '_ {
+    orders.iter().filter_map(|action| match action {
+        UserAction::NewOrder {
+            user_id,
+            symbol,
+            price,
+            qty,
+            side,
+            order_id,
+            ..
+        } => Some(Event::AddOrder {
+            symbol: *symbol,
+            user_id: *user_id,
+            order_id: *order_id,
+            side: *side,
+            price: *price,
+            qty: *qty,
+        }),
+        _ => None,
+    })
+}
+
+/// Most messages a [FeedStore] keeps for retransmission by default
+pub const DEFAULT_RETAINED: usize = 1 << 20;
+
+#[derive(Debug)]
+/// This struct keeps the latest published [Message]s of a session together
+/// with the books they rebuild
+///
+/// Only the last [DEFAULT_RETAINED] messages can be retransmitted, a
+/// subscriber that falls further behind has to join again from a
+/// snapshot. The books are kept up to date by applying every stored
+/// message, like a [Subscriber] does, so storing a message costs the same
+/// whatever the size of the books.
+pub struct FeedStore {
+    /// Latest published messages, the last one has sequence number `last_seq`
+    messages: VecDeque<Message>,
+    /// Most messages kept in `messages`
+    retained: usize,
+    /// Sequence number of the last stored message
+    last_seq: u64,
+    /// Books rebuilt from every stored message
+    books: Engine,
+}
+
+/// [FeedStore] shared between the [Publisher] and the recovery service
+pub type SharedStore = Arc<Mutex<FeedStore>>;
+
+impl Default for FeedStore {
+    fn default() -> Self {
+        FeedStore {
+            messages: VecDeque::new(),
+            retained: DEFAULT_RETAINED,
+            last_seq: 0,
+            books: Engine::new(true),
+        }
+    }
+}
+
+impl FeedStore {
+    /// Creates an empty [FeedStore]
+    pub fn new() -> Self {
+        FeedStore::default()
+    }
+
+    /// Keeps at most the last `retained` messages for retransmission
+    pub fn with_retained(mut self, retained: usize) -> Self {
+        self.retained = retained;
+        self
+    }
+
+    /// Creates an empty [SharedStore]
+    pub fn shared() -> SharedStore {
+        Arc::new(Mutex::new(Self::new()))
+    }
+
+    /// Sequence number of the last stored message, 0 if there is none
+    pub fn last_seq(&self) -> u64 {
+        self.last_seq
+    }
+
+    /// Stores published messages and applies them to the books
+    pub fn append(&mut self, messages: &[Message]) -> Result<(), ReplayError> {
+        for message in messages {
+            debug_assert_eq!(message.seq, self.last_seq + 1);
+            apply(&mut self.books, message)?;
+            if self.messages.len() == self.retained {
+                self.messages.pop_front();
+            }
+            self.messages.push_back(message.clone());
+            self.last_seq = message.seq;
+        }
+
+        Ok(())
+    }
+
+    /// Returns up to `count` messages starting from sequence number `seq`
+    ///
+    /// Nothing is returned once the message with sequence number `seq` is
+    /// no longer kept.
+    pub fn range(&self, seq: u64, count: u32) -> Vec<Message> {
+        let first = self.last_seq + 1 - self.messages.len() as u64;
+        if seq < first {
+            return vec![];
+        }
+
+        self.messages
+            .iter()
+            .skip((seq - first) as usize)
+            .take(count.min(MAX_RETRANSMIT) as usize)
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the sequence number the snapshot is taken at and the
+    /// messages that rebuild the books as of that sequence number, by
+    /// symbol name
+    pub fn snapshot(&self) -> (u64, Vec<Message>) {
+        let seq = self.last_seq;
+        let mut books: Vec<(&Symbol, &OrderBook)> = self.books.books().collect();
+        books.sort_unstable_by_key(|(symbol, _)| symbol.as_str());
+        let messages = books
+            .into_iter()
+            .flat_map(|(_, book)| add_orders(&book.resting_orders()).collect::<Vec<_>>())
+            .map(|event| Message { seq, event })
+            .collect();
+
+        (seq, messages)
+    }
+}
+
+/// This struct publishes book events over UDP multicast
+///
+/// Sending never blocks on subscribers, so the [Publisher] can be driven
+/// straight from the matching loop. Packets are stamped with the time of
+/// the clock of the engine.
+pub struct Publisher {
+    socket: UdpSocket,
+    group: SocketAddrV4,
+    encoder: Encoder,
+    store: SharedStore,
+    clock: SharedClock,
+}
+
+impl Publisher {
+    /// Creates a [Publisher] that sends to `group` through the network
+    /// interface with address `interface` and starts the session
+    ///
+    /// `clock` is the clock of the engine whose books are published.
+    pub fn bind(
+        group: SocketAddrV4,
+        interface: Ipv4Addr,
+        store: SharedStore,
+        clock: SharedClock,
+    ) -> Result<Self, FeedError> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_multicast_if_v4(&interface)?;
+        socket.set_multicast_loop_v4(true)?;
+        socket.bind(&SocketAddr::from((interface, 0)).into())?;
+
+        let mut publisher = Publisher {
+            socket: socket.into(),
+            group,
+            encoder: Encoder::new(),
+            store,
+            clock,
+        };
+        let start = publisher.encoder.system_event(SystemEvent::StartOfMessages);
+        publisher.send(vec![start])?;
+
+        Ok(publisher)
+    }
+
+    /// Publishes the book events of a [UserAction] given the [Output] the
+    /// engine gave for it
+    pub fn publish(&mut self, action: &UserAction, output: &Output) -> Result<(), FeedError> {
+        let messages = self.record(action, output)?;
+        self.transmit(&messages)
+    }
+
+    /// Publishes the end of the session
+    pub fn end_session(&mut self) -> Result<(), FeedError> {
+        let end = self.encoder.system_event(SystemEvent::EndOfMessages);
+        self.send(vec![end])
+    }
+
+    /// Sends a heartbeat packet
+    pub fn heartbeat(&self) -> Result<(), FeedError> {
+        self.transmit_packet(Packet {
+            seq: self.encoder.next_seq(),
+            timestamp: self.clock.now(),
+            messages: vec![],
+        })
+    }
+
+    /// Sequences and stores the book events of a [UserAction] without
+    /// sending them
+    ///
+    /// The messages are available to the recovery service, so this is
+    /// how a lost packet looks to subscribers.
+    pub fn record(
+        &mut self,
+        action: &UserAction,
+        output: &Output,
+    ) -> Result<Vec<Message>, FeedError> {
+        let messages = self.encoder.book_events(action, output);
+        self.store.lock().unwrap().append(&messages)?;
+
+        Ok(messages)
+    }
+
+    /// Records the resting orders of every book of a restored `engine` as
+    /// new orders, without sending them
+    pub fn restore(&mut self, engine: &Engine) -> Result<(), FeedError> {
+        let mut books: Vec<(&Symbol, &OrderBook)> = engine.books().collect();
+        books.sort_unstable_by_key(|(symbol, _)| symbol.as_str());
+        let messages: Vec<Message> = books
+            .into_iter()
+            .flat_map(|(_, book)| add_orders(&book.resting_orders()).collect::<Vec<_>>())
+            .map(|event| self.encoder.sequence(event))
+            .collect();
+
+        self.store.lock().unwrap().append(&messages)?;
+        Ok(())
+    }
+
+    /// Stores and sends messages
+    fn send(&mut self, messages: Vec<Message>) -> Result<(), FeedError> {
+        self.store.lock().unwrap().append(&messages)?;
+        self.transmit(&messages)
+    }
+
+    /// Sends already stored messages, splitting them in packets of at
+    /// most [MAX_PACKET_LEN] bytes
+    pub fn transmit(&self, messages: &[Message]) -> Result<(), FeedError> {
+        let mut start = 0;
+        while start < messages.len() {
+            let mut len = PACKET_HEADER_LEN;
+            let mut end = start;
+            while end < messages.len()
+                && end - start < u16::MAX as usize
+                && len + messages[end].encoded_len() <= MAX_PACKET_LEN
+            {
+                len += messages[end].encoded_len();
+                end += 1;
+            }
+
+            self.transmit_packet(Packet {
+                seq: messages[start].seq,
+                timestamp: self.clock.now(),
+                messages: messages[start..end].to_vec(),
+            })?;
+            start = end;
+        }
+
+        Ok(())
+    }
+
+    fn transmit_packet(&self, packet: Packet) -> Result<(), FeedError> {
+        self.socket.send_to(&packet.encode()?, self.group)?;
+
+        Ok(())
+    }
+}
+
+/// Runs the TCP recovery service on `listener`
+///
+/// Clients send either a retransmission request - `b'R'`, first sequence
+/// number (u64) and message count (u32) - or a snapshot request - `b'S'`.
+/// Both are answered with the request kind, a sequence number (u64), a
+/// message count (u32) and the messages. A retransmission response starts
+/// at the requested sequence number and may hold fewer messages than
+/// requested. A snapshot response carries the sequence number of the last
+/// message included in the snapshot.
+///
+/// Every client is served on its own thread.
+pub fn serve_recovery(listener: TcpListener, store: SharedStore) -> io::Result<()> {
+    loop {
+        let (stream, _) = listener.accept()?;
+        let store = Arc::clone(&store);
+        thread::spawn(move || handle_recovery(stream, store));
+    }
+}
+
+/// Reads a fixed size little-endian field
+fn read_array<const N: usize>(stream: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut buf = [0; N];
+    stream.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn handle_recovery(mut stream: TcpStream, store: SharedStore) -> Result<(), FeedError> {
+    loop {
+        let kind = match read_array::<1>(&mut stream) {
+            Ok([kind]) => kind,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let (seq, messages) = match kind {
+            RETRANSMIT_REQUEST => {
+                let seq = u64::from_le_bytes(read_array(&mut stream)?);
+                let count = u32::from_le_bytes(read_array(&mut stream)?);
+                let store = store.lock().unwrap();
+                (seq, store.range(seq, count))
+            }
+            SNAPSHOT_REQUEST => store.lock().unwrap().snapshot(),
+            _ => return Err(DecodeError::UnknownType(kind).into()),
+        };
+
+        let mut buf = vec![kind];
+        buf.extend_from_slice(&seq.to_le_bytes());
+        buf.extend_from_slice(&(messages.len() as u32).to_le_bytes());
+        for message in &messages {
+            message.encode(&mut buf)?;
+        }
+        stream.write_all(&buf)?;
+    }
+}
+
+/// This struct is a client of the TCP recovery service
+pub struct RecoveryClient {
+    stream: TcpStream,
+}
+
+impl RecoveryClient {
+    /// Connects to the recovery service
+    pub fn connect(addr: SocketAddr) -> Result<Self, FeedError> {
+        Ok(RecoveryClient {
+            stream: TcpStream::connect(addr)?,
+        })
+    }
+
+    /// Requests up to `count` messages starting from sequence number `seq`
+    pub fn retransmit(&mut self, seq: u64, count: u32) -> Result<Vec<Message>, FeedError> {
+        let mut request = vec![RETRANSMIT_REQUEST];
+        request.extend_from_slice(&seq.to_le_bytes());
+        request.extend_from_slice(&count.to_le_bytes());
+        self.stream.write_all(&request)?;
+
+        Ok(self.read_response()?.1)
+    }
+
+    /// Requests a book snapshot
+    pub fn snapshot(&mut self) -> Result<(u64, Vec<Message>), FeedError> {
+        self.stream.write_all(&[SNAPSHOT_REQUEST])?;
+        self.read_response()
+    }
+
+    fn read_response(&mut self) -> Result<(u64, Vec<Message>), FeedError> {
+        let header: [u8; RECOVERY_HEADER_LEN] = read_array(&mut self.stream)?;
+        let seq = u64::from_le_bytes(header[1..9].try_into().unwrap());
+        let count = u32::from_le_bytes(header[9..13].try_into().unwrap());
+
+        let mut messages = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let [message_type] = read_array(&mut self.stream)?;
+            let len =
+                Event::message_len(message_type).ok_or(DecodeError::UnknownType(message_type))?;
+            let mut buf = vec![0; len];
+            buf[0] = message_type;
+            self.stream.read_exact(&mut buf[1..])?;
+            messages.push(Message::decode(&buf)?.0);
+        }
+
+        Ok((seq, messages))
+    }
+}
+
+/// This struct receives the multicast feed and rebuilds the books of
+/// every symbol
+///
+/// On join it loads a snapshot from the recovery service, then applies
+/// the live messages that follow it. Gaps in the sequence numbers are
+/// filled from the recovery service before the packet that revealed them
+/// is applied, so the books always reflect a contiguous message stream.
+pub struct Subscriber {
+    socket: UdpSocket,
+    recovery: RecoveryClient,
+    engine: Engine,
+    next_seq: u64,
+    /// Number of messages recovered through retransmission
+    recovered: u64,
+}
+
+impl Subscriber {
+    /// Joins the multicast `group` on the network interface with address
+    /// `interface` and rebuilds the books of `engine` from a snapshot
+    ///
+    /// A `group` port of 0 binds a random port, see [Subscriber::port].
+    pub fn join(
+        group: SocketAddrV4,
+        interface: Ipv4Addr,
+        recovery: SocketAddr,
+        mut engine: Engine,
+    ) -> Result<Self, FeedError> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, group.port())).into())?;
+        socket.join_multicast_v4(group.ip(), &interface)?;
+
+        // Join the group before taking the snapshot so no message falls
+        // between the two
+        let mut recovery = RecoveryClient::connect(recovery)?;
+        let (seq, messages) = recovery.snapshot()?;
+        for message in &messages {
+            apply(&mut engine, message)?;
+        }
+
+        Ok(Subscriber {
+            socket: socket.into(),
+            recovery,
+            engine,
+            next_seq: seq + 1,
+            recovered: 0,
+        })
+    }
+
+    /// Sets how long [Subscriber::recv] waits for a packet, `None` waits
+    /// forever
+    pub fn set_timeout(&self, timeout: Option<Duration>) -> Result<(), FeedError> {
+        Ok(self.socket.set_read_timeout(timeout)?)
+    }
+
+    /// Local port the subscriber listens on
+    pub fn port(&self) -> Result<u16, FeedError> {
+        Ok(self.socket.local_addr()?.port())
+    }
+
+    /// Books rebuilt from the feed
+    pub fn engine(&self) -> &Engine {
+        &self.engine
+    }
+
+    /// Sequence number of the next expected message
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    /// Number of messages recovered through retransmission
+    pub fn recovered(&self) -> u64 {
+        self.recovered
+    }
+
+    /// Receives one packet and applies it to the book
+    ///
+    /// Returns the newly applied messages, including the ones recovered to
+    /// fill a gap.
+    pub fn recv(&mut self) -> Result<Vec<Message>, FeedError> {
+        let mut buf = vec![0; MAX_PACKET_LEN];
+        let len = self.socket.recv(&mut buf)?;
+        let packet = Packet::decode(&buf[..len])?;
+
+        let mut applied = vec![];
+        if packet.seq > self.next_seq {
+            let (expected, got) = (self.next_seq, packet.seq);
+            let mut missing = BTreeMap::new();
+            while self.next_seq + (missing.len() as u64) < got {
+                let seq = self.next_seq + missing.len() as u64;
+                let messages = self.recovery.retransmit(seq, (got - seq) as u32)?;
+                if messages.is_empty() {
+                    return Err(FeedError::Unrecoverable { expected, got });
+                }
+                missing.extend(messages.into_iter().map(|m| (m.seq, m)));
+            }
+            self.recovered += missing.len() as u64;
+            applied.extend(missing.into_values());
+        }
+
+        applied.extend(packet.messages);
+        applied.retain(|m| m.seq >= self.next_seq);
+        for message in &applied {
+            if message.seq != self.next_seq {
+                return Err(FeedError::Unrecoverable {
+                    expected: self.next_seq,
+                    got: message.seq,
+                });
+            }
+            apply(&mut self.engine, message)?;
+            self.next_seq += 1;
+        }
+
+        Ok(applied)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixed::{Price, Quantity};
+    use crate::orderbook::{Side, TimeInForce};
+
+    const GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 42, 42);
+
+    fn new_order(
+        user_id: u32,
+        symbol: &str,
+        price: u64,
+        qty: u64,
+        side: &str,
+        order_id: u32,
+    ) -> UserAction {
+        UserAction::NewOrder {
+            user_id,
+            symbol: Symbol::new(symbol),
+            price: Price(price),
+            qty: Quantity(qty),
+            side: side.parse().unwrap(),
+            order_id,
+            time_in_force: TimeInForce::Gtc,
+        }
+    }
+
+    fn actions() -> Vec<UserAction> {
+        vec![
+            new_order(1, "IBM", 10, 100, "B", 1),
+            new_order(1, "IBM", 12, 100, "S", 2),
+            new_order(2, "AAPL", 9, 100, "B", 101),
+            new_order(2, "IBM", 11, 100, "S", 102),
+            new_order(1, "IBM", 11, 100, "B", 3),
+            new_order(2, "AAPL", 10, 20, "S", 103),
+            UserAction::CancelOrder {
+                user_id: 2,
+                order_id: 101,
+            },
+            new_order(2, "IBM", 11, 100, "S", 104),
+        ]
+    }
+
+    /// Asserts that the books of the subscriber are the ones of `engine`
+    fn assert_same_books(subscriber: &Subscriber, engine: &Engine) {
+        for symbol in ["IBM", "AAPL"] {
+            assert_eq!(
+                subscriber
+                    .engine()
+                    .book(symbol)
+                    .map(OrderBook::resting_orders),
+                engine.book(symbol).map(OrderBook::resting_orders),
+                "{}",
+                symbol
+            );
+        }
+    }
+
+    /// Starts a recovery service and returns its address with the store
+    fn start_recovery() -> (SocketAddr, SharedStore) {
+        let store = FeedStore::shared();
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server_store = Arc::clone(&store);
+        thread::spawn(move || serve_recovery(listener, server_store));
+
+        (addr, store)
+    }
+
+    fn join(group: SocketAddrV4, recovery: SocketAddr) -> Subscriber {
+        let subscriber =
+            Subscriber::join(group, Ipv4Addr::LOCALHOST, recovery, Engine::new(true)).unwrap();
+        subscriber
+            .set_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        subscriber
+    }
+
+    fn recv_until(subscriber: &mut Subscriber, seq: u64) {
+        while subscriber.next_seq() <= seq {
+            subscriber.recv().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_packet_roundtrip() {
+        let mut encoder = Encoder::new();
+        let packet = Packet {
+            seq: 1,
+            timestamp: 42,
+            messages: vec![
+                encoder.system_event(SystemEvent::StartOfMessages),
+                encoder.sequence(Event::Best {
+                    symbol: Symbol::new("IBM"),
+                    side: Side::Sell,
+                    price: Price(11),
+                    qty: Quantity(100),
+                }),
+            ],
+        };
+
+        let buf = packet.encode().unwrap();
+        assert_eq!(&buf[16..18], &[2, 0]);
+        assert_eq!(Packet::decode(&buf), Ok(packet));
+        assert_eq!(Packet::decode(&buf[..10]), Err(DecodeError::Truncated));
+    }
+
+    #[test]
+    fn test_subscriber_rebuilds_engine_book() {
+        let (recovery, store) = start_recovery();
+        let mut subscriber = join(SocketAddrV4::new(GROUP, 0), recovery);
+        let group = SocketAddrV4::new(GROUP, subscriber.port().unwrap());
+        let mut publisher = Publisher::bind(
+            group,
+            Ipv4Addr::LOCALHOST,
+            Arc::clone(&store),
+            SharedClock::default(),
+        )
+        .unwrap();
+
+        let mut engine = Engine::new(true);
+        for (i, action) in actions().into_iter().enumerate() {
+            let output = engine.new_user_action(action.clone());
+            if i == 3 {
+                // Lose the packet - the subscriber recovers it over TCP
+                publisher.record(&action, &output).unwrap();
+            } else {
+                publisher.publish(&action, &output).unwrap();
+            }
+        }
+        publisher.end_session().unwrap();
+
+        let last_seq = store.lock().unwrap().last_seq();
+        recv_until(&mut subscriber, last_seq);
+
+        assert_eq!(subscriber.recovered(), 2);
+        assert_same_books(&subscriber, &engine);
+        assert_eq!(
+            subscriber.engine().book("IBM"),
+            engine.book("IBM"),
+            "the trade is replayed too"
+        );
+    }
+
+    #[test]
+    fn test_late_joiner_uses_snapshot() {
+        let (recovery, store) = start_recovery();
+        let port = {
+            let probe = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).unwrap();
+            probe.local_addr().unwrap().port()
+        };
+        let group = SocketAddrV4::new(GROUP, port);
+        let mut publisher = Publisher::bind(
+            group,
+            Ipv4Addr::LOCALHOST,
+            Arc::clone(&store),
+            SharedClock::default(),
+        )
+        .unwrap();
+
+        let mut engine = Engine::new(true);
+        let actions = actions();
+        let (before, after) = actions.split_at(5);
+        for action in before {
+            let output = engine.new_user_action(action.clone());
+            publisher.publish(action, &output).unwrap();
+        }
+
+        // The snapshot holds the books of every symbol
+        let mut subscriber = join(group, recovery);
+        assert_eq!(subscriber.next_seq(), store.lock().unwrap().last_seq() + 1);
+        assert_same_books(&subscriber, &engine);
+
+        for action in after {
+            let output = engine.new_user_action(action.clone());
+            publisher.publish(action, &output).unwrap();
+        }
+        publisher.heartbeat().unwrap();
+
+        let last_seq = store.lock().unwrap().last_seq();
+        recv_until(&mut subscriber, last_seq);
+
+        assert_same_books(&subscriber, &engine);
+    }
+
+    #[test]
+    fn test_store_keeps_the_books_of_the_engine() {
+        let store = FeedStore::shared();
+        let port = {
+            let probe = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).unwrap();
+            probe.local_addr().unwrap().port()
+        };
+        let mut publisher = Publisher::bind(
+            SocketAddrV4::new(GROUP, port),
+            Ipv4Addr::LOCALHOST,
+            Arc::clone(&store),
+            SharedClock::default(),
+        )
+        .unwrap();
+
+        let mut engine = Engine::new(true);
+        for action in actions() {
+            let output = engine.new_user_action(action.clone());
+            publisher.record(&action, &output).unwrap();
+        }
+
+        // AAPL comes first, every message of the snapshot is an AddOrder
+        let (seq, messages) = store.lock().unwrap().snapshot();
+        assert_eq!(seq, store.lock().unwrap().last_seq());
+        let mut expected = engine.book("AAPL").unwrap().resting_orders();
+        expected.extend(engine.book("IBM").unwrap().resting_orders());
+        let actions: Vec<UserAction> = messages
+            .iter()
+            .map(|m| m.event.to_user_action().unwrap().unwrap())
+            .collect();
+        assert_eq!(actions, expected);
+
+        // Best prices carry the symbol of their book
+        let bests: Vec<Symbol> = store
+            .lock()
+            .unwrap()
+            .range(2, u32::MAX)
+            .iter()
+            .filter_map(|m| match &m.event {
+                Event::Best { symbol, .. } => Some(*symbol),
+                _ => None,
+            })
+            .collect();
+        assert!(bests.contains(&Symbol::new("AAPL")));
+
+        let action = UserAction::Flush;
+        let output = engine.new_user_action(action.clone());
+        publisher.record(&action, &output).unwrap();
+        assert!(store.lock().unwrap().snapshot().1.is_empty());
+    }
+
+    #[test]
+    fn test_store_keeps_the_latest_messages() {
+        let mut store = FeedStore::new().with_retained(3);
+        let mut encoder = Encoder::new();
+        let mut engine = Engine::new(true);
+        for action in actions() {
+            let output = engine.new_user_action(action.clone());
+            store
+                .append(&encoder.book_events(&action, &output))
+                .unwrap();
+        }
+
+        // Older messages are gone, the books still hold every order
+        let last_seq = store.last_seq();
+        assert_eq!(last_seq, encoder.next_seq() - 1);
+        assert!(store.range(last_seq - 3, 10).is_empty());
+        let seqs: Vec<u64> = store
+            .range(last_seq - 2, 10)
+            .iter()
+            .map(|m| m.seq)
+            .collect();
+        assert_eq!(seqs, [last_seq - 2, last_seq - 1, last_seq]);
+        assert_eq!(store.range(last_seq, 10).len(), 1);
+
+        let mut subscriber = Engine::new(true);
+        for message in &store.snapshot().1 {
+            apply(&mut subscriber, message).unwrap();
+        }
+        for symbol in ["IBM", "AAPL"] {
+            assert_eq!(
+                subscriber.book(symbol).map(OrderBook::resting_orders),
+                engine.book(symbol).map(OrderBook::resting_orders),
+                "{}",
+                symbol
+            );
+        }
+    }
+
+    #[test]
+    fn test_packets_carry_the_engine_time() {
+        use crate::clock::ManualClock;
+
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).unwrap();
+        socket.set_reuse_address(true).unwrap();
+        socket
+            .bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)).into())
+            .unwrap();
+        socket
+            .join_multicast_v4(&GROUP, &Ipv4Addr::LOCALHOST)
+            .unwrap();
+        let socket: UdpSocket = socket.into();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let group = SocketAddrV4::new(GROUP, socket.local_addr().unwrap().port());
+        let recv = || {
+            let mut buf = vec![0; MAX_PACKET_LEN];
+            let len = socket.recv(&mut buf).unwrap();
+            Packet::decode(&buf[..len]).unwrap()
+        };
+
+        let clock = ManualClock::new(42);
+        let mut publisher = Publisher::bind(
+            group,
+            Ipv4Addr::LOCALHOST,
+            FeedStore::shared(),
+            SharedClock::new(clock.clone()),
+        )
+        .unwrap();
+        assert_eq!(recv().timestamp, 42);
+
+        clock.set(1_000);
+        let action = new_order(1, "IBM", 10, 100, "B", 1);
+        let output = Engine::new(true).new_user_action(action.clone());
+        publisher.publish(&action, &output).unwrap();
+        assert_eq!(recv().timestamp, 1_000);
+
+        clock.set(2_000);
+        publisher.heartbeat().unwrap();
+        let heartbeat = recv();
+        assert_eq!((heartbeat.seq, heartbeat.timestamp), (4, 2_000));
+    }
+
+    #[test]
+    fn test_snapshot_and_restore_after_flush() {
+        let port = {
+            let probe = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).unwrap();
+            probe.local_addr().unwrap().port()
+        };
+        let group = SocketAddrV4::new(GROUP, port);
+        let store = FeedStore::shared();
+        let mut publisher = Publisher::bind(
+            group,
+            Ipv4Addr::LOCALHOST,
+            Arc::clone(&store),
+            SharedClock::default(),
+        )
+        .unwrap();
+
+        // The books outlive the flush, the orders after it keep their symbol
+        let mut engine = Engine::new(true);
+        let actions = actions();
+        for action in actions.iter().chain([&UserAction::Flush]).chain(&actions) {
+            let output = engine.new_user_action(action.clone());
+            publisher.record(action, &output).unwrap();
+        }
+
+        let symbols = |messages: &[Message]| -> Vec<Symbol> {
+            messages
+                .iter()
+                .filter_map(|m| match &m.event {
+                    Event::AddOrder { symbol, .. } => Some(*symbol),
+                    _ => None,
+                })
+                .collect()
+        };
+        let expected = ["AAPL", "IBM", "IBM", "IBM"];
+        let (_, snapshot) = store.lock().unwrap().snapshot();
+        assert_eq!(symbols(&snapshot), expected);
+
+        // A publisher of the restored engine records the same orders
+        let restored = FeedStore::shared();
+        let mut publisher = Publisher::bind(
+            group,
+            Ipv4Addr::LOCALHOST,
+            Arc::clone(&restored),
+            SharedClock::default(),
+        )
+        .unwrap();
+        publisher.restore(&engine).unwrap();
+        let restored = restored.lock().unwrap();
+        assert_eq!(symbols(&restored.range(1, u32::MAX)), expected);
+
+        let mut subscriber = Engine::new(true);
+        for message in &restored.snapshot().1 {
+            apply(&mut subscriber, message).unwrap();
+        }
+        for symbol in ["IBM", "AAPL"] {
+            assert_eq!(
+                subscriber.book(symbol).map(OrderBook::resting_orders),
+                engine.book(symbol).map(OrderBook::resting_orders),
+                "{}",
+                symbol
+            );
+        }
+    }
+}
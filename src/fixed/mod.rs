@@ -0,0 +1,379 @@
+//! This mod implements fixed-point prices and quantities.
+//!
+//! [Price]s and [Quantity]s are whole numbers of ticks and lots. The
+//! [Scale] of an instrument tells how many of its decimals a tick and a lot
+//! stand for, so `10.25` is 1025 ticks of an instrument quoted with two
+//! decimals. Scales only matter where numbers are written as text; the
+//! books and the binary encodings work on ticks and lots.
+
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::symbol::Symbol;
+
+/// Most decimals a [Scale] can have, `10^19` does not fit in a `u64`
+pub const MAX_DECIMALS: u8 = 18;
+
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+/// This struct is a price in ticks
+///
+/// A price of 0 stands for no price, e.g. the best price of an empty side.
+pub struct Price(pub u64);
+
+impl Price {
+    /// No price
+    pub const ZERO: Price = Price(0);
+
+    /// Returns the number of ticks
+    pub fn ticks(&self) -> u64 {
+        self.0
+    }
+
+    /// Adds two prices, `None` on overflow
+    pub fn checked_add(self, other: Price) -> Option<Price> {
+        self.0.checked_add(other.0).map(Price)
+    }
+
+    /// Subtracts two prices, `None` below zero
+    pub fn checked_sub(self, other: Price) -> Option<Price> {
+        self.0.checked_sub(other.0).map(Price)
+    }
+}
+
+impl Display for Price {
+    /// Writes the number of ticks
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        Display::fmt(&self.0, f)
+    }
+}
+
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+/// This struct is a quantity in lots
+pub struct Quantity(pub u64);
+
+impl Quantity {
+    /// Nothing
+    pub const ZERO: Quantity = Quantity(0);
+
+    /// Returns the number of lots
+    pub fn lots(&self) -> u64 {
+        self.0
+    }
+
+    /// Adds two quantities, `None` on overflow
+    pub fn checked_add(self, other: Quantity) -> Option<Quantity> {
+        self.0.checked_add(other.0).map(Quantity)
+    }
+
+    /// Subtracts two quantities, `None` below zero
+    pub fn checked_sub(self, other: Quantity) -> Option<Quantity> {
+        self.0.checked_sub(other.0).map(Quantity)
+    }
+
+    /// Adds up quantities, `None` on overflow
+    pub fn checked_sum(quantities: impl IntoIterator<Item = Quantity>) -> Option<Quantity> {
+        quantities
+            .into_iter()
+            .try_fold(Quantity::ZERO, |sum, qty| sum.checked_add(qty))
+    }
+}
+
+impl Display for Quantity {
+    /// Writes the number of lots
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        Display::fmt(&self.0, f)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+/// This enum describes why a decimal string could not be parsed
+pub enum ParseFixedError {
+    /// Not digits with an optional decimal point
+    Invalid(String),
+    /// More decimals than the [Scale] allows
+    TooPrecise(String),
+    /// Too large for 64 bits
+    Overflow(String),
+    /// A [Scale] or [Scales] entry is malformed
+    InvalidScale(String),
+}
+
+impl Display for ParseFixedError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            ParseFixedError::Invalid(s) => write!(f, "invalid number: {:?}", s),
+            ParseFixedError::TooPrecise(s) => write!(f, "too many decimals: {:?}", s),
+            ParseFixedError::Overflow(s) => write!(f, "number too large: {:?}", s),
+            ParseFixedError::InvalidScale(s) => write!(f, "invalid scale: {:?}", s),
+        }
+    }
+}
+
+impl std::error::Error for ParseFixedError {}
+
+/// Parses a non-negative decimal string in units of `10^-decimals`
+///
+/// Trailing zero decimals past the scale are accepted, other extra
+/// decimals are an error rather than being rounded.
+pub fn parse_decimal(s: &str, decimals: u8) -> Result<u64, ParseFixedError> {
+    let (int, frac) = s.split_once('.').unwrap_or((s, ""));
+    let digits = |d: &str| d.bytes().all(|b| b.is_ascii_digit());
+    if int.is_empty() || !digits(int) || !digits(frac) || (s.contains('.') && frac.is_empty()) {
+        return Err(ParseFixedError::Invalid(String::from(s)));
+    }
+
+    let frac = frac.trim_end_matches('0');
+    if frac.len() > decimals as usize {
+        return Err(ParseFixedError::TooPrecise(String::from(s)));
+    }
+
+    let overflow = || ParseFixedError::Overflow(String::from(s));
+    let mut value: u64 = 0;
+    let padding = decimals as usize - frac.len();
+    for b in int.bytes().chain(frac.bytes()) {
+        value = value
+            .checked_mul(10)
+            .and_then(|v| v.checked_add((b - b'0') as u64))
+            .ok_or_else(overflow)?;
+    }
+    value
+        .checked_mul(10u64.pow(padding as u32))
+        .ok_or_else(overflow)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// This struct is a value shown with a fixed number of decimals
+pub struct Decimal {
+    value: u128,
+    negative: bool,
+    decimals: u8,
+}
+
+impl Decimal {
+    /// Creates a [Decimal] of a signed value
+    fn signed(value: i128, decimals: u8) -> Self {
+        Decimal {
+            value: value.unsigned_abs(),
+            negative: value < 0,
+            decimals,
+        }
+    }
+}
+
+impl Display for Decimal {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        if self.negative {
+            f.write_str("-")?;
+        }
+        if self.decimals == 0 {
+            return write!(f, "{}", self.value);
+        }
+
+        let unit = 10u128.pow(self.decimals as u32);
+        write!(
+            f,
+            "{}.{:0width$}",
+            self.value / unit,
+            self.value % unit,
+            width = self.decimals as usize
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+/// This struct describes the decimals of the prices and quantities of an
+/// instrument
+///
+/// The default scale has no decimals: one tick is one unit of price and
+/// one lot is one unit of quantity.
+pub struct Scale {
+    /// Decimals of a price
+    pub price: u8,
+    /// Decimals of a quantity
+    pub qty: u8,
+}
+
+impl Scale {
+    /// Creates a [Scale], fails above [MAX_DECIMALS]
+    pub fn new(price: u8, qty: u8) -> Result<Self, ParseFixedError> {
+        if price > MAX_DECIMALS || qty > MAX_DECIMALS {
+            return Err(ParseFixedError::InvalidScale(format!("{}:{}", price, qty)));
+        }
+        Ok(Scale { price, qty })
+    }
+
+    /// Parses a decimal price such as `10.25`
+    pub fn parse_price(&self, s: &str) -> Result<Price, ParseFixedError> {
+        parse_decimal(s, self.price).map(Price)
+    }
+
+    /// Parses a decimal quantity
+    pub fn parse_qty(&self, s: &str) -> Result<Quantity, ParseFixedError> {
+        parse_decimal(s, self.qty).map(Quantity)
+    }
+
+    /// Returns the decimal representation of a price
+    pub fn price(&self, price: Price) -> Decimal {
+        Decimal::signed(price.0 as i128, self.price)
+    }
+
+    /// Returns the decimal representation of a quantity
+    pub fn qty(&self, qty: Quantity) -> Decimal {
+        Decimal::signed(qty.0 as i128, self.qty)
+    }
+
+    /// Returns the decimal representation of a signed quantity in lots,
+    /// such as a net position
+    pub fn signed_qty(&self, lots: i64) -> Decimal {
+        Decimal::signed(lots as i128, self.qty)
+    }
+
+    /// Returns the decimal representation of an amount in ticks times
+    /// lots, such as the value of a trade
+    pub fn amount(&self, amount: i128) -> Decimal {
+        Decimal::signed(amount, self.price + self.qty)
+    }
+}
+
+impl FromStr for Scale {
+    type Err = ParseFixedError;
+
+    /// Parses `PRICE_DECIMALS[:QTY_DECIMALS]`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseFixedError::InvalidScale(String::from(s));
+        let (price, qty) = s.split_once(':').unwrap_or((s, "0"));
+        Scale::new(
+            price.parse().map_err(|_| invalid())?,
+            qty.parse().map_err(|_| invalid())?,
+        )
+        .map_err(|_| invalid())
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+/// This struct holds the [Scale] of every instrument
+///
+/// Instruments without their own scale use the default one.
+pub struct Scales {
+    default: Scale,
+    symbols: HashMap<Symbol, Scale>,
+}
+
+impl Scales {
+    /// Creates [Scales] giving every instrument `default`
+    pub fn new(default: Scale) -> Self {
+        Scales {
+            default,
+            symbols: HashMap::new(),
+        }
+    }
+
+    /// Sets the scale of one instrument
+    pub fn insert(&mut self, symbol: Symbol, scale: Scale) {
+        self.symbols.insert(symbol, scale);
+    }
+
+    /// Returns the scale of an instrument
+    pub fn get(&self, symbol: Symbol) -> Scale {
+        self.symbols.get(&symbol).copied().unwrap_or(self.default)
+    }
+
+    /// Returns the scale of instruments without their own
+    pub fn default_scale(&self) -> Scale {
+        self.default
+    }
+}
+
+impl FromStr for Scales {
+    type Err = ParseFixedError;
+
+    /// Parses comma separated `SYMBOL:PRICE_DECIMALS[:QTY_DECIMALS]`
+    /// entries, e.g. `IBM:2,BTC:2:8`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut scales = Scales::default();
+        for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (symbol, scale) = entry
+                .split_once(':')
+                .ok_or_else(|| ParseFixedError::InvalidScale(String::from(entry)))?;
+            let symbol = Symbol::parse(symbol)
+                .map_err(|_| ParseFixedError::InvalidScale(String::from(entry)))?;
+            scales.insert(symbol, scale.parse()?);
+        }
+        Ok(scales)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_decimal() {
+        assert_eq!(parse_decimal("10.25", 2), Ok(1025));
+        assert_eq!(parse_decimal("10", 2), Ok(1000));
+        assert_eq!(parse_decimal("10.5", 2), Ok(1050));
+        assert_eq!(parse_decimal("10.250", 2), Ok(1025));
+        assert_eq!(parse_decimal("007", 0), Ok(7));
+        assert_eq!(parse_decimal("18446744073709551615", 0), Ok(u64::MAX));
+
+        assert!(matches!(
+            parse_decimal("10.255", 2),
+            Err(ParseFixedError::TooPrecise(_))
+        ));
+        assert!(matches!(
+            parse_decimal("18446744073709551616", 0),
+            Err(ParseFixedError::Overflow(_))
+        ));
+        assert!(matches!(
+            parse_decimal("184467440737095516.16", 2),
+            Err(ParseFixedError::Overflow(_))
+        ));
+        for invalid in ["", ".5", "10.", "-1", "1e3", "1.2.3", " 1"] {
+            assert!(
+                matches!(parse_decimal(invalid, 2), Err(ParseFixedError::Invalid(_))),
+                "{:?}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_scale_roundtrip() {
+        let scale: Scale = "2:8".parse().unwrap();
+        assert_eq!(scale.parse_price("10.25"), Ok(Price(1025)));
+        assert_eq!(scale.price(Price(1025)).to_string(), "10.25");
+        assert_eq!(scale.price(Price(5)).to_string(), "0.05");
+        assert_eq!(scale.qty(Quantity(150_000_000)).to_string(), "1.50000000");
+        assert_eq!(Scale::default().price(Price(1025)).to_string(), "1025");
+        assert_eq!(scale.signed_qty(-150_000_000).to_string(), "-1.50000000");
+        assert_eq!(scale.amount(-5).to_string(), "-0.0000000005");
+        assert!("19".parse::<Scale>().is_err());
+
+        let scales: Scales = "IBM:2, BTC:2:8".parse().unwrap();
+        assert_eq!(scales.get(Symbol::new("IBM")), Scale::new(2, 0).unwrap());
+        assert_eq!(scales.get(Symbol::new("BTC")), scale);
+        assert_eq!(scales.get(Symbol::new("VAL")), Scale::default());
+    }
+
+    #[test]
+    fn test_checked_aggregation() {
+        assert_eq!(
+            Quantity::checked_sum([Quantity(1), Quantity(2)]),
+            Some(Quantity(3))
+        );
+        assert_eq!(
+            Quantity::checked_sum([Quantity(u64::MAX), Quantity(1)]),
+            None
+        );
+        assert_eq!(Price(1).checked_sub(Price(2)), None);
+    }
+}
@@ -0,0 +1,349 @@
+//! This mod implements instrument reference data.
+//!
+//! An [Instrument] describes what orders its book accepts: prices on its
+//! tick grid and within its price band, quantities in whole lots and within
+//! its size limits. [Instruments] are loaded from a JSON file:
+//!
+//! ```text
+//! [
+//!   {"symbol": "IBM", "tick_size": 5, "lot_size": 100, "min_qty": 100,
+//!    "max_qty": 100000, "min_price": 5, "max_price": 100000,
+//!    "currency": "USD", "scale": {"price": 2, "qty": 0}}
+//! ]
+//! ```
+//!
+//! Sizes, limits and prices are in ticks and lots. Every field but the
+//! symbol is optional, a missing limit is no limit.
+
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+
+use serde::{Deserialize, Serialize};
+
+use crate::fixed::{Price, Quantity, Scale, Scales};
+use crate::symbol::Symbol;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+/// This struct is the reference data of one instrument
+///
+/// The default instrument accepts any order.
+pub struct Instrument {
+    /// Symbol of the instrument
+    pub symbol: Symbol,
+    /// Prices must be a multiple of the tick size
+    pub tick_size: Price,
+    /// Quantities must be a multiple of the lot size
+    pub lot_size: Quantity,
+    /// Smallest order quantity
+    pub min_qty: Option<Quantity>,
+    /// Largest order quantity
+    pub max_qty: Option<Quantity>,
+    /// Lowest order price
+    pub min_price: Option<Price>,
+    /// Highest order price
+    pub max_price: Option<Price>,
+    /// Currency of the prices
+    pub currency: String,
+    /// Decimals of the prices and quantities in CSV
+    pub scale: Scale,
+}
+
+impl Default for Instrument {
+    fn default() -> Self {
+        Instrument {
+            symbol: Symbol::default(),
+            tick_size: Price(1),
+            lot_size: Quantity(1),
+            min_qty: None,
+            max_qty: None,
+            min_price: None,
+            max_price: None,
+            currency: String::new(),
+            scale: Scale::default(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+/// This enum describes why an order breaks its instrument's rules
+pub enum InvalidOrder {
+    /// The symbol is not in the reference data
+    UnknownInstrument,
+    /// The price is not a multiple of the tick size
+    OffTick,
+    /// The quantity is not a multiple of the lot size
+    OddLot,
+    /// The quantity is below the minimum
+    BelowMinQty,
+    /// The quantity is above the maximum
+    AboveMaxQty,
+    /// The price is outside the price limits
+    PriceOutOfRange,
+}
+
+impl Display for InvalidOrder {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        let reason = match self {
+            InvalidOrder::UnknownInstrument => "unknown instrument",
+            InvalidOrder::OffTick => "price off tick",
+            InvalidOrder::OddLot => "odd lot",
+            InvalidOrder::BelowMinQty => "quantity below minimum",
+            InvalidOrder::AboveMaxQty => "quantity above maximum",
+            InvalidOrder::PriceOutOfRange => "price out of range",
+        };
+        f.write_str(reason)
+    }
+}
+
+impl Instrument {
+    /// Creates an [Instrument] without limits
+    pub fn new(symbol: Symbol) -> Self {
+        Instrument {
+            symbol,
+            ..Default::default()
+        }
+    }
+
+    /// Checks an order against the instrument
+    pub fn validate(&self, price: Price, qty: Quantity) -> Result<(), InvalidOrder> {
+        if !price.ticks().is_multiple_of(self.tick_size.ticks()) {
+            return Err(InvalidOrder::OffTick);
+        }
+        if self.min_price.is_some_and(|min| price < min)
+            || self.max_price.is_some_and(|max| price > max)
+        {
+            return Err(InvalidOrder::PriceOutOfRange);
+        }
+        if !qty.lots().is_multiple_of(self.lot_size.lots()) {
+            return Err(InvalidOrder::OddLot);
+        }
+        if self.min_qty.is_some_and(|min| qty < min) {
+            return Err(InvalidOrder::BelowMinQty);
+        }
+        if self.max_qty.is_some_and(|max| qty > max) {
+            return Err(InvalidOrder::AboveMaxQty);
+        }
+        Ok(())
+    }
+
+    /// Checks the definition itself
+    fn check(&self) -> Result<(), InstrumentError> {
+        let invalid = |what: &str| {
+            Err(InstrumentError::Invalid(format!(
+                "{}: {}",
+                self.symbol, what
+            )))
+        };
+        if self.symbol == "" {
+            return invalid("symbol is missing");
+        }
+        if self.tick_size == Price::ZERO {
+            return invalid("tick_size must be positive");
+        }
+        if self.lot_size == Quantity::ZERO {
+            return invalid("lot_size must be positive");
+        }
+        if let (Some(min), Some(max)) = (self.min_qty, self.max_qty) {
+            if min > max {
+                return invalid("min_qty above max_qty");
+            }
+        }
+        if let (Some(min), Some(max)) = (self.min_price, self.max_price) {
+            if min > max {
+                return invalid("min_price above max_price");
+            }
+        }
+        Scale::new(self.scale.price, self.scale.qty)
+            .map_err(|e| InstrumentError::Invalid(format!("{}: {}", self.symbol, e)))?;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+/// This enum describes why reference data could not be loaded
+pub enum InstrumentError {
+    /// Reading the file failed
+    Io(io::Error),
+    /// The file is not a JSON list of instruments
+    Json(serde_json::Error),
+    /// An instrument is inconsistent or listed twice
+    Invalid(String),
+}
+
+impl Display for InstrumentError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            InstrumentError::Io(e) => write!(f, "{}", e),
+            InstrumentError::Json(e) => write!(f, "{}", e),
+            InstrumentError::Invalid(s) => write!(f, "invalid instrument {}", s),
+        }
+    }
+}
+
+impl std::error::Error for InstrumentError {}
+
+impl From<io::Error> for InstrumentError {
+    fn from(e: io::Error) -> Self {
+        InstrumentError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for InstrumentError {
+    fn from(e: serde_json::Error) -> Self {
+        InstrumentError::Json(e)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+/// This struct holds the reference data of every instrument
+///
+/// Without any instrument every symbol is accepted with no limits. Once
+/// instruments are defined, orders for other symbols are rejected.
+pub struct Instruments {
+    instruments: HashMap<Symbol, Instrument>,
+}
+
+impl Instruments {
+    /// Creates [Instruments] accepting every symbol
+    pub fn new() -> Self {
+        Instruments::default()
+    }
+
+    /// Parses a JSON list of instruments
+    pub fn from_json(json: &str) -> Result<Self, InstrumentError> {
+        let list: Vec<Instrument> = serde_json::from_str(json)?;
+        let mut instruments = Instruments::new();
+        for instrument in list {
+            instrument.check()?;
+            if instruments.get(instrument.symbol).is_some() {
+                return Err(InstrumentError::Invalid(format!(
+                    "{}: listed twice",
+                    instrument.symbol
+                )));
+            }
+            instruments.insert(instrument);
+        }
+        Ok(instruments)
+    }
+
+    /// Loads a JSON list of instruments from `path`
+    pub fn load(path: &str) -> Result<Self, InstrumentError> {
+        Instruments::from_json(&fs::read_to_string(path)?)
+    }
+
+    /// Adds or replaces an instrument
+    pub fn insert(&mut self, instrument: Instrument) {
+        self.instruments.insert(instrument.symbol, instrument);
+    }
+
+    /// Returns the definition of `symbol`, if any
+    pub fn get(&self, symbol: Symbol) -> Option<&Instrument> {
+        self.instruments.get(&symbol)
+    }
+
+    /// Returns the instrument of a new book of `symbol`
+    ///
+    /// Unlisted symbols get an [Instrument] without limits when there is no
+    /// reference data at all, otherwise they are unknown.
+    pub fn resolve(&self, symbol: Symbol) -> Result<Instrument, InvalidOrder> {
+        match self.instruments.get(&symbol) {
+            Some(instrument) => Ok(instrument.clone()),
+            None if self.instruments.is_empty() => Ok(Instrument::new(symbol)),
+            None => Err(InvalidOrder::UnknownInstrument),
+        }
+    }
+
+    /// Returns the CSV scales of the instruments
+    pub fn scales(&self) -> Scales {
+        let mut scales = Scales::default();
+        for instrument in self.instruments.values() {
+            scales.insert(instrument.symbol, instrument.scale);
+        }
+        scales
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JSON: &str = r#"[
+        {"symbol": "IBM", "tick_size": 5, "lot_size": 100, "min_qty": 100,
+         "max_qty": 1000, "min_price": 5, "max_price": 500, "currency": "USD",
+         "scale": {"price": 2, "qty": 0}},
+        {"symbol": "AAPL"}
+    ]"#;
+
+    #[test]
+    fn test_validate() {
+        let instruments = Instruments::from_json(JSON).unwrap();
+        let ibm = instruments.resolve(Symbol::new("IBM")).unwrap();
+        assert_eq!(ibm.currency, "USD");
+
+        assert_eq!(ibm.validate(Price(10), Quantity(200)), Ok(()));
+        assert_eq!(
+            ibm.validate(Price(12), Quantity(200)),
+            Err(InvalidOrder::OffTick)
+        );
+        assert_eq!(
+            ibm.validate(Price(10), Quantity(150)),
+            Err(InvalidOrder::OddLot)
+        );
+        assert_eq!(
+            ibm.validate(Price(10), Quantity(0)),
+            Err(InvalidOrder::BelowMinQty)
+        );
+        assert_eq!(
+            ibm.validate(Price(10), Quantity(1100)),
+            Err(InvalidOrder::AboveMaxQty)
+        );
+        assert_eq!(
+            ibm.validate(Price(505), Quantity(100)),
+            Err(InvalidOrder::PriceOutOfRange)
+        );
+        assert_eq!(
+            ibm.validate(Price(0), Quantity(100)),
+            Err(InvalidOrder::PriceOutOfRange)
+        );
+
+        // Defaults accept anything
+        let aapl = instruments.resolve(Symbol::new("AAPL")).unwrap();
+        assert_eq!(aapl.validate(Price(7), Quantity(3)), Ok(()));
+        assert_eq!(
+            instruments.resolve(Symbol::new("VAL")),
+            Err(InvalidOrder::UnknownInstrument)
+        );
+        assert!(Instruments::new().resolve(Symbol::new("VAL")).is_ok());
+
+        assert_eq!(
+            instruments.scales().get(Symbol::new("IBM")),
+            Scale::new(2, 0).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_rejects_bad_definitions() {
+        for json in [
+            r#"[{"symbol": "IBM", "tick_size": 0}]"#,
+            r#"[{"symbol": "IBM", "min_qty": 10, "max_qty": 1}]"#,
+            r#"[{"symbol": "IBM", "scale": {"price": 19, "qty": 0}}]"#,
+            r#"[{"symbol": "IBM"}, {"symbol": "IBM"}]"#,
+        ] {
+            assert!(
+                matches!(
+                    Instruments::from_json(json),
+                    Err(InstrumentError::Invalid(_))
+                ),
+                "{}",
+                json
+            );
+        }
+        assert!(matches!(
+            Instruments::from_json("{}"),
+            Err(InstrumentError::Json(_))
+        ));
+    }
+}
@@ -0,0 +1,959 @@
+//! This mod implements an ITCH-style binary market-data feed.
+//!
+//! Book events are encoded as fixed-width, little-endian messages. Every
+//! message starts with a one byte message type followed by the session
+//! sequence number, so a consumer can detect gaps and replay a feed back
+//! into an [OrderBook].
+//!
+//! ```
+//! use order_book::itch::{encode_all, Decoder, Encoder, SystemEvent};
+//!
+//! let mut encoder = Encoder::new();
+//! let messages = vec![
+//!     encoder.system_event(SystemEvent::StartOfMessages),
+//!     encoder.system_event(SystemEvent::EndOfMessages),
+//! ];
+//! let feed = encode_all(&messages).unwrap();
+//!
+//! let decoded: Vec<_> = Decoder::new(&feed).map(|m| m.unwrap()).collect();
+//! assert_eq!(decoded, messages);
+//! assert_eq!(decoded[1].seq, 2);
+//! ```
+
+use std::fmt::{Display, Formatter};
+
+use crate::engine::Output;
+use crate::fixed::{Price, Quantity};
+use crate::orderbook::{OrderBook, Response, Side, TimeInForce, UserAction};
+use crate::symbol::Symbol;
+
+/// Size of the header shared by all messages: type (1) + sequence number (8)
+pub const HEADER_LEN: usize = 9;
+
+/// Size of the space padded symbol field
+pub const SYMBOL_LEN: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+/// This enum describes the system events that can appear on the feed
+pub enum SystemEvent {
+    /// First message of a session
+    StartOfMessages,
+    /// The [OrderBook] was flushed - every resting order is gone
+    BookFlush,
+    /// Last message of a session
+    EndOfMessages,
+}
+
+impl SystemEvent {
+    /// Returns the one byte code used on the wire
+    fn code(&self) -> u8 {
+        match self {
+            Self::StartOfMessages => b'O',
+            Self::BookFlush => b'F',
+            Self::EndOfMessages => b'C',
+        }
+    }
+
+    /// Provides a way to get a [SystemEvent] from its wire code
+    fn from_code(code: u8) -> Option<Self> {
+        match code {
+            b'O' => Some(Self::StartOfMessages),
+            b'F' => Some(Self::BookFlush),
+            b'C' => Some(Self::EndOfMessages),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+/// This enum describes a book event carried by a [Message]
+///
+/// Sides are written on the wire as their one letter code (`B` or `S`),
+/// the same letters used by the CSV input and output.
+pub enum Event {
+    /// Session level event
+    SystemEvent { event: SystemEvent },
+    /// A new order was added to the book
+    AddOrder {
+        symbol: Symbol,
+        user_id: u32,
+        order_id: u32,
+        side: Side,
+        price: Price,
+        qty: Quantity,
+    },
+    /// A resting order was executed against an incoming order
+    OrderExecuted {
+        user_id: u32,
+        order_id: u32,
+        executed_qty: Quantity,
+        match_number: u64,
+    },
+    /// A resting order was partially cancelled
+    OrderCancel {
+        user_id: u32,
+        order_id: u32,
+        cancelled_qty: Quantity,
+    },
+    /// A resting order was removed from the book
+    OrderDelete { user_id: u32, order_id: u32 },
+    /// An incoming order traded without resting in the book
+    ///
+    /// `side` is the side of the incoming (aggressor) order. The resting
+    /// order of the same match is reported by an [Event::OrderExecuted]
+    /// with the same `match_number`, so volume should be counted from
+    /// [Event::Trade] messages only.
+    Trade {
+        symbol: Symbol,
+        side: Side,
+        buyer_id: u32,
+        buyer_order_id: u32,
+        seller_id: u32,
+        seller_order_id: u32,
+        price: Price,
+        qty: Quantity,
+        match_number: u64,
+    },
+    /// The top of the book of `symbol` changed on one side
+    ///
+    /// A price and quantity of 0 means that side of the book is empty.
+    Best {
+        symbol: Symbol,
+        side: Side,
+        price: Price,
+        qty: Quantity,
+    },
+}
+
+impl Event {
+    /// Returns the one byte message type used on the wire
+    pub fn message_type(&self) -> u8 {
+        match self {
+            Self::SystemEvent { .. } => b'S',
+            Self::AddOrder { .. } => b'A',
+            Self::OrderExecuted { .. } => b'E',
+            Self::OrderCancel { .. } => b'X',
+            Self::OrderDelete { .. } => b'D',
+            Self::Trade { .. } => b'P',
+            Self::Best { .. } => b'Q',
+        }
+    }
+
+    /// Returns the length on the wire of a message of the given type,
+    /// header included
+    pub fn message_len(message_type: u8) -> Option<usize> {
+        let body = match message_type {
+            b'S' => 1,
+            b'A' => SYMBOL_LEN + 4 + 4 + 1 + 8 + 8,
+            b'E' => 4 + 4 + 8 + 8,
+            b'X' => 4 + 4 + 8,
+            b'D' => 4 + 4,
+            b'P' => SYMBOL_LEN + 1 + 4 * 4 + 8 + 8 + 8,
+            b'Q' => SYMBOL_LEN + 1 + 8 + 8,
+            _ => return None,
+        };
+
+        Some(HEADER_LEN + body)
+    }
+
+    /// Translates a replayed [Event] to the [UserAction] that reproduces it
+    /// on an [OrderBook]
+    ///
+    /// [Event::OrderExecuted] maps to no action because the execution is
+    /// reproduced by the aggressor order of the matching [Event::Trade].
+    /// [Event::Best] maps to no action because it is derived from the book.
+    pub fn to_user_action(&self) -> Result<Option<UserAction>, ReplayError> {
+        match self {
+            Self::SystemEvent {
+                event: SystemEvent::BookFlush,
+            } => Ok(Some(UserAction::Flush)),
+            Self::SystemEvent { .. } => Ok(None),
+            Self::AddOrder {
+                symbol,
+                user_id,
+                order_id,
+                side,
+                price,
+                qty,
+            } => Ok(Some(UserAction::NewOrder {
+                user_id: *user_id,
+                symbol: *symbol,
+                price: *price,
+                qty: *qty,
+                side: *side,
+                order_id: *order_id,
+                time_in_force: TimeInForce::Gtc,
+            })),
+            Self::OrderExecuted { .. } | Self::Best { .. } => Ok(None),
+            // The engine only knows how to remove whole orders
+            Self::OrderCancel {
+                user_id, order_id, ..
+            } => Err(ReplayError::PartialCancel {
+                user_id: *user_id,
+                order_id: *order_id,
+            }),
+            Self::OrderDelete { user_id, order_id } => Ok(Some(UserAction::CancelOrder {
+                user_id: *user_id,
+                order_id: *order_id,
+            })),
+            Self::Trade {
+                symbol,
+                side,
+                buyer_id,
+                buyer_order_id,
+                seller_id,
+                seller_order_id,
+                price,
+                qty,
+                ..
+            } => {
+                let (user_id, order_id) = match side {
+                    Side::Buy => (*buyer_id, *buyer_order_id),
+                    Side::Sell => (*seller_id, *seller_order_id),
+                };
+
+                Ok(Some(UserAction::NewOrder {
+                    user_id,
+                    symbol: *symbol,
+                    price: *price,
+                    qty: *qty,
+                    side: *side,
+                    order_id,
+                    time_in_force: TimeInForce::Gtc,
+                }))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+/// This struct is a sequenced [Event] as it appears on the feed
+pub struct Message {
+    /// Session sequence number - starts from 1
+    pub seq: u64,
+    /// Book event
+    pub event: Event,
+}
+
+impl Message {
+    /// Length of this message on the wire
+    pub fn encoded_len(&self) -> usize {
+        Event::message_len(self.event.message_type()).unwrap()
+    }
+
+    /// Appends the binary representation of this message to `buf`
+    pub fn encode(&self, buf: &mut Vec<u8>) -> Result<(), EncodeError> {
+        buf.push(self.event.message_type());
+        buf.extend_from_slice(&self.seq.to_le_bytes());
+
+        match &self.event {
+            Event::SystemEvent { event } => buf.push(event.code()),
+            Event::AddOrder {
+                symbol,
+                user_id,
+                order_id,
+                side,
+                price,
+                qty,
+            } => {
+                put_symbol(buf, symbol.as_str())?;
+                buf.extend_from_slice(&user_id.to_le_bytes());
+                buf.extend_from_slice(&order_id.to_le_bytes());
+                buf.push(side.as_char() as u8);
+                buf.extend_from_slice(&price.ticks().to_le_bytes());
+                buf.extend_from_slice(&qty.lots().to_le_bytes());
+            }
+            Event::OrderExecuted {
+                user_id,
+                order_id,
+                executed_qty,
+                match_number,
+            } => {
+                buf.extend_from_slice(&user_id.to_le_bytes());
+                buf.extend_from_slice(&order_id.to_le_bytes());
+                buf.extend_from_slice(&executed_qty.lots().to_le_bytes());
+                buf.extend_from_slice(&match_number.to_le_bytes());
+            }
+            Event::OrderCancel {
+                user_id,
+                order_id,
+                cancelled_qty,
+            } => {
+                buf.extend_from_slice(&user_id.to_le_bytes());
+                buf.extend_from_slice(&order_id.to_le_bytes());
+                buf.extend_from_slice(&cancelled_qty.lots().to_le_bytes());
+            }
+            Event::OrderDelete { user_id, order_id } => {
+                buf.extend_from_slice(&user_id.to_le_bytes());
+                buf.extend_from_slice(&order_id.to_le_bytes());
+            }
+            Event::Trade {
+                symbol,
+                side,
+                buyer_id,
+                buyer_order_id,
+                seller_id,
+                seller_order_id,
+                price,
+                qty,
+                match_number,
+            } => {
+                put_symbol(buf, symbol.as_str())?;
+                buf.push(side.as_char() as u8);
+                buf.extend_from_slice(&buyer_id.to_le_bytes());
+                buf.extend_from_slice(&buyer_order_id.to_le_bytes());
+                buf.extend_from_slice(&seller_id.to_le_bytes());
+                buf.extend_from_slice(&seller_order_id.to_le_bytes());
+                buf.extend_from_slice(&price.ticks().to_le_bytes());
+                buf.extend_from_slice(&qty.lots().to_le_bytes());
+                buf.extend_from_slice(&match_number.to_le_bytes());
+            }
+            Event::Best {
+                symbol,
+                side,
+                price,
+                qty,
+            } => {
+                put_symbol(buf, symbol.as_str())?;
+                buf.push(side.as_char() as u8);
+                buf.extend_from_slice(&price.ticks().to_le_bytes());
+                buf.extend_from_slice(&qty.lots().to_le_bytes());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Decodes one message from the start of `buf`
+    ///
+    /// Returns the message and the number of bytes consumed.
+    pub fn decode(buf: &[u8]) -> Result<(Message, usize), DecodeError> {
+        let message_type = *buf.first().ok_or(DecodeError::Truncated)?;
+        let len = Event::message_len(message_type).ok_or(DecodeError::UnknownType(message_type))?;
+        if buf.len() < len {
+            return Err(DecodeError::Truncated);
+        }
+
+        let mut r = Reader {
+            buf: &buf[..len],
+            pos: 1,
+        };
+        let seq = r.u64();
+
+        let event = match message_type {
+            b'S' => {
+                let code = r.u8();
+                Event::SystemEvent {
+                    event: SystemEvent::from_code(code)
+                        .ok_or(DecodeError::InvalidField("system event code"))?,
+                }
+            }
+            b'A' => Event::AddOrder {
+                symbol: r.symbol()?,
+                user_id: r.u32(),
+                order_id: r.u32(),
+                side: r.side()?,
+                price: Price(r.u64()),
+                qty: Quantity(r.u64()),
+            },
+            b'E' => Event::OrderExecuted {
+                user_id: r.u32(),
+                order_id: r.u32(),
+                executed_qty: Quantity(r.u64()),
+                match_number: r.u64(),
+            },
+            b'X' => Event::OrderCancel {
+                user_id: r.u32(),
+                order_id: r.u32(),
+                cancelled_qty: Quantity(r.u64()),
+            },
+            b'D' => Event::OrderDelete {
+                user_id: r.u32(),
+                order_id: r.u32(),
+            },
+            b'P' => Event::Trade {
+                symbol: r.symbol()?,
+                side: r.side()?,
+                buyer_id: r.u32(),
+                buyer_order_id: r.u32(),
+                seller_id: r.u32(),
+                seller_order_id: r.u32(),
+                price: Price(r.u64()),
+                qty: Quantity(r.u64()),
+                match_number: r.u64(),
+            },
+            _ => Event::Best {
+                symbol: r.symbol()?,
+                side: r.side()?,
+                price: Price(r.u64()),
+                qty: Quantity(r.u64()),
+            },
+        };
+
+        Ok((Message { seq, event }, len))
+    }
+}
+
+/// Returns whether `symbol` fits the symbol field of the messages
+pub fn is_valid_symbol(symbol: &str) -> bool {
+    symbol.len() <= SYMBOL_LEN && symbol.is_ascii()
+}
+
+/// Writes `symbol` as a fixed width, right space padded field
+fn put_symbol(buf: &mut Vec<u8>, symbol: &str) -> Result<(), EncodeError> {
+    if !is_valid_symbol(symbol) {
+        return Err(EncodeError::InvalidSymbol(String::from(symbol)));
+    }
+
+    buf.extend_from_slice(symbol.as_bytes());
+    buf.resize(buf.len() + SYMBOL_LEN - symbol.len(), b' ');
+
+    Ok(())
+}
+
+/// Cursor over a message whose length was already checked
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take<const N: usize>(&mut self) -> [u8; N] {
+        let mut out = [0; N];
+        out.copy_from_slice(&self.buf[self.pos..self.pos + N]);
+        self.pos += N;
+        out
+    }
+
+    fn u8(&mut self) -> u8 {
+        self.take::<1>()[0]
+    }
+
+    fn u32(&mut self) -> u32 {
+        u32::from_le_bytes(self.take())
+    }
+
+    fn u64(&mut self) -> u64 {
+        u64::from_le_bytes(self.take())
+    }
+
+    fn side(&mut self) -> Result<Side, DecodeError> {
+        Side::try_from(self.u8() as char).map_err(|_| DecodeError::InvalidField("side"))
+    }
+
+    fn symbol(&mut self) -> Result<Symbol, DecodeError> {
+        let raw = self.take::<SYMBOL_LEN>();
+        std::str::from_utf8(&raw)
+            .ok()
+            .and_then(|s| Symbol::parse(s.trim_end_matches(' ')).ok())
+            .ok_or(DecodeError::InvalidField("symbol"))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+/// Errors produced while encoding a [Message]
+pub enum EncodeError {
+    /// Symbol is not ASCII or longer than [SYMBOL_LEN]
+    InvalidSymbol(String),
+}
+
+impl Display for EncodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            EncodeError::InvalidSymbol(symbol) => write!(f, "invalid symbol: {:?}", symbol),
+        }
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+#[derive(Debug, PartialEq)]
+/// Errors produced while decoding a feed
+pub enum DecodeError {
+    /// The buffer ends in the middle of a message
+    Truncated,
+    /// The message type is not known
+    UnknownType(u8),
+    /// A field holds a value that is not allowed
+    InvalidField(&'static str),
+    /// A message was lost between `expected` and `got`
+    SequenceGap { expected: u64, got: u64 },
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            DecodeError::Truncated => write!(f, "truncated message"),
+            DecodeError::UnknownType(t) => write!(f, "unknown message type: {:#04x}", t),
+            DecodeError::InvalidField(field) => write!(f, "invalid {}", field),
+            DecodeError::SequenceGap { expected, got } => {
+                write!(f, "sequence gap: expected {}, got {}", expected, got)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+#[derive(Debug, PartialEq)]
+/// Errors produced while replaying a feed into an [OrderBook]
+pub enum ReplayError {
+    /// The feed could not be decoded
+    Decode(DecodeError),
+    /// Partial cancels can not be reproduced by the engine
+    PartialCancel { user_id: u32, order_id: u32 },
+}
+
+impl Display for ReplayError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            ReplayError::Decode(e) => write!(f, "{}", e),
+            ReplayError::PartialCancel { user_id, order_id } => write!(
+                f,
+                "partial cancel of order {} of user {} can not be replayed",
+                order_id, user_id
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+impl From<DecodeError> for ReplayError {
+    fn from(e: DecodeError) -> Self {
+        ReplayError::Decode(e)
+    }
+}
+
+#[derive(Debug)]
+/// This struct turns [OrderBook] activity into sequenced [Message]s
+///
+/// One [Encoder] represents one feed session: sequence numbers start
+/// from 1 and increase by one for every message.
+pub struct Encoder {
+    /// Sequence number of the next message
+    next_seq: u64,
+    /// Last match number handed out
+    match_number: u64,
+}
+
+impl Default for Encoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Encoder {
+    /// Creates an [Encoder] for a new session
+    pub fn new() -> Self {
+        Encoder {
+            next_seq: 1,
+            match_number: 0,
+        }
+    }
+
+    /// Sequence number that the next message will carry
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    /// Wraps an [Event] in a [Message] with the next sequence number
+    pub fn sequence(&mut self, event: Event) -> Message {
+        let seq = self.next_seq();
+        self.next_seq = seq + 1;
+        Message { seq, event }
+    }
+
+    /// Creates a system event [Message]
+    pub fn system_event(&mut self, event: SystemEvent) -> Message {
+        self.sequence(Event::SystemEvent { event })
+    }
+
+    /// Translates a [UserAction] and the [Output] of the book it targeted
+    /// to book event [Message]s
+    ///
+    /// Rejected actions do not change the book, so they produce no message.
+    /// A change of the top of the book is reported by an [Event::Best]
+    /// following the book event that caused it.
+    pub fn book_events(&mut self, action: &UserAction, output: &Output) -> Vec<Message> {
+        let (symbol, responses) = output;
+        let mut events = vec![];
+
+        match (action, responses) {
+            (
+                UserAction::NewOrder {
+                    symbol, side, qty, ..
+                },
+                (
+                    Some(Response::Acknowledge { .. }),
+                    Some(Response::Trade {
+                        buyer_id,
+                        buyer_order_id,
+                        seller_id,
+                        seller_order_id,
+                        price,
+                        qty: _,
+                    }),
+                ),
+            ) => {
+                self.match_number += 1;
+                let (resting_id, resting_order_id) = match side {
+                    Side::Buy => (*seller_id, *seller_order_id),
+                    Side::Sell => (*buyer_id, *buyer_order_id),
+                };
+
+                events.push(Event::OrderExecuted {
+                    user_id: resting_id,
+                    order_id: resting_order_id,
+                    executed_qty: *qty,
+                    match_number: self.match_number,
+                });
+                events.push(Event::Trade {
+                    symbol: *symbol,
+                    side: *side,
+                    buyer_id: *buyer_id,
+                    buyer_order_id: *buyer_order_id,
+                    seller_id: *seller_id,
+                    seller_order_id: *seller_order_id,
+                    price: *price,
+                    qty: *qty,
+                    match_number: self.match_number,
+                });
+            }
+            (
+                UserAction::NewOrder {
+                    user_id,
+                    symbol,
+                    price,
+                    qty,
+                    side,
+                    order_id,
+                    ..
+                },
+                (Some(Response::Acknowledge { .. }), _),
+            ) => events.push(Event::AddOrder {
+                symbol: *symbol,
+                user_id: *user_id,
+                order_id: *order_id,
+                side: *side,
+                price: *price,
+                qty: *qty,
+            }),
+            (
+                UserAction::CancelOrder { user_id, order_id },
+                (Some(Response::Acknowledge { .. }), _),
+            ) => events.push(Event::OrderDelete {
+                user_id: *user_id,
+                order_id: *order_id,
+            }),
+            (UserAction::Flush, _) => events.push(Event::SystemEvent {
+                event: SystemEvent::BookFlush,
+            }),
+            (_, _) => (),
+        }
+
+        if let (
+            Some(symbol),
+            (Some(Response::Acknowledge { .. }), Some(Response::Best { side, price, qty })),
+        ) = (symbol, responses)
+        {
+            events.push(Event::Best {
+                symbol: *symbol,
+                side: *side,
+                price: *price,
+                qty: *qty,
+            });
+        }
+
+        events.into_iter().map(|e| self.sequence(e)).collect()
+    }
+}
+
+/// This struct iterates over the [Message]s of an encoded feed
+///
+/// It checks that sequence numbers are contiguous and stops after the
+/// first error.
+pub struct Decoder<'a> {
+    buf: &'a [u8],
+    expected_seq: Option<u64>,
+    failed: bool,
+}
+
+impl<'a> Decoder<'a> {
+    /// Creates a [Decoder] over an encoded feed
+    pub fn new(buf: &'a [u8]) -> Self {
+        Decoder {
+            buf,
+            expected_seq: None,
+            failed: false,
+        }
+    }
+}
+
+impl<'a> Iterator for Decoder<'a> {
+    type Item = Result<Message, DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buf.is_empty() || self.failed {
+            return None;
+        }
+
+        let res = Message::decode(self.buf).and_then(|(message, len)| match self.expected_seq {
+            Some(expected) if expected != message.seq => Err(DecodeError::SequenceGap {
+                expected,
+                got: message.seq,
+            }),
+            _ => {
+                self.buf = &self.buf[len..];
+                self.expected_seq = Some(message.seq + 1);
+                Ok(message)
+            }
+        });
+
+        self.failed = res.is_err();
+        Some(res)
+    }
+}
+
+/// Encodes a slice of [Message]s back to back
+pub fn encode_all(messages: &[Message]) -> Result<Vec<u8>, EncodeError> {
+    let mut buf = Vec::with_capacity(messages.iter().map(|m| m.encoded_len()).sum());
+    for message in messages {
+        message.encode(&mut buf)?;
+    }
+
+    Ok(buf)
+}
+
+/// Replays an encoded feed into an [OrderBook]
+///
+/// Returns the responses the [OrderBook] gave for every replayed action,
+/// which can be compared with the ones produced by the original session.
+#[allow(clippy::type_complexity)]
+pub fn replay(
+    book: &mut OrderBook,
+    feed: &[u8],
+) -> Result<Vec<(Option<Response>, Option<Response>)>, ReplayError> {
+    let mut responses = vec![];
+
+    for message in Decoder::new(feed) {
+        if let Some(action) = message?.event.to_user_action()? {
+            responses.push(book.new_user_action(action));
+        }
+    }
+
+    Ok(responses)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_order(user_id: u32, price: u64, qty: u64, side: &str, order_id: u32) -> UserAction {
+        UserAction::NewOrder {
+            user_id,
+            symbol: Symbol::new("VAL"),
+            price: Price(price),
+            qty: Quantity(qty),
+            side: side.parse().unwrap(),
+            order_id,
+            time_in_force: TimeInForce::Gtc,
+        }
+    }
+
+    #[test]
+    fn test_roundtrip_every_message_type() {
+        let mut encoder = Encoder::new();
+        let messages = vec![
+            encoder.system_event(SystemEvent::StartOfMessages),
+            encoder.sequence(Event::AddOrder {
+                symbol: Symbol::new("IBM"),
+                user_id: 1,
+                order_id: 2,
+                side: Side::Buy,
+                price: Price(10),
+                qty: Quantity(100),
+            }),
+            encoder.sequence(Event::OrderExecuted {
+                user_id: 1,
+                order_id: 2,
+                executed_qty: Quantity(100),
+                match_number: 7,
+            }),
+            encoder.sequence(Event::OrderCancel {
+                user_id: 1,
+                order_id: 2,
+                cancelled_qty: Quantity(50),
+            }),
+            encoder.sequence(Event::OrderDelete {
+                user_id: 1,
+                order_id: 2,
+            }),
+            encoder.sequence(Event::Trade {
+                symbol: Symbol::new("IBM"),
+                side: Side::Sell,
+                buyer_id: 1,
+                buyer_order_id: 2,
+                seller_id: 3,
+                seller_order_id: 4,
+                price: Price(10),
+                qty: Quantity(100),
+                match_number: 7,
+            }),
+            encoder.sequence(Event::Best {
+                symbol: Symbol::new("IBM"),
+                side: Side::Buy,
+                price: Price(0),
+                qty: Quantity(0),
+            }),
+            encoder.system_event(SystemEvent::EndOfMessages),
+        ];
+
+        let buf = encode_all(&messages).unwrap();
+        assert_eq!(
+            buf.len(),
+            messages.iter().map(|m| m.encoded_len()).sum::<usize>()
+        );
+
+        let decoded: Vec<Message> = Decoder::new(&buf).map(|m| m.unwrap()).collect();
+        assert_eq!(decoded, messages);
+        assert_eq!(decoded.last().unwrap().seq, 8);
+    }
+
+    #[test]
+    fn test_fixed_width_little_endian_layout() {
+        let message = Message {
+            seq: 0x0102,
+            event: Event::OrderDelete {
+                user_id: 3,
+                order_id: 0x0405,
+            },
+        };
+        let mut buf = vec![];
+        message.encode(&mut buf).unwrap();
+
+        assert_eq!(
+            buf,
+            vec![b'D', 0x02, 0x01, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0x05, 0x04, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let mut encoder = Encoder::new();
+        let mut buf = vec![];
+        encoder
+            .system_event(SystemEvent::StartOfMessages)
+            .encode(&mut buf)
+            .unwrap();
+
+        assert_eq!(
+            Message::decode(&buf[..buf.len() - 1]),
+            Err(DecodeError::Truncated)
+        );
+        assert_eq!(Message::decode(b"Z"), Err(DecodeError::UnknownType(b'Z')));
+
+        // Skip sequence number 2
+        encoder.system_event(SystemEvent::BookFlush);
+        encoder
+            .system_event(SystemEvent::EndOfMessages)
+            .encode(&mut buf)
+            .unwrap();
+        let decoded: Vec<_> = Decoder::new(&buf).collect();
+        assert_eq!(
+            decoded[1],
+            Err(DecodeError::SequenceGap {
+                expected: 2,
+                got: 3
+            })
+        );
+
+        let long_symbol = Message {
+            seq: 1,
+            event: Event::AddOrder {
+                symbol: Symbol::new("TOOLONGSYM"),
+                user_id: 1,
+                order_id: 1,
+                side: Side::Buy,
+                price: Price(1),
+                qty: Quantity(1),
+            },
+        };
+        assert!(long_symbol.encode(&mut vec![]).is_err());
+    }
+
+    #[test]
+    fn test_replay_rebuilds_book() {
+        let actions = vec![
+            new_order(1, 10, 100, "B", 1),
+            new_order(2, 9, 100, "B", 101),
+            new_order(2, 11, 100, "S", 102),
+            new_order(2, 12, 50, "S", 103),
+            // Rejected - no order of this size at 11
+            new_order(1, 11, 20, "B", 2),
+            // Trades against 102
+            new_order(1, 11, 100, "B", 3),
+            UserAction::CancelOrder {
+                user_id: 2,
+                order_id: 101,
+            },
+            // Unknown order
+            UserAction::CancelOrder {
+                user_id: 9,
+                order_id: 9,
+            },
+            new_order(3, 10, 100, "B", 1),
+        ];
+
+        let mut book = OrderBook::new("VAL", true);
+        let mut encoder = Encoder::new();
+        let mut messages = vec![encoder.system_event(SystemEvent::StartOfMessages)];
+        let mut expected = vec![];
+
+        for action in actions {
+            let responses = book.new_user_action(action.clone());
+            messages.extend(
+                encoder.book_events(&action, &(Some(Symbol::new("VAL")), responses.clone())),
+            );
+            if !matches!(responses.0, Some(Response::Reject { .. })) {
+                expected.push(responses);
+            }
+        }
+
+        let types: Vec<u8> = messages.iter().map(|m| m.event.message_type()).collect();
+        assert_eq!(types, b"SAQAAQAEPDAQ".to_vec());
+
+        let mut replayed = OrderBook::new("VAL", true);
+        let responses = replay(&mut replayed, &encode_all(&messages).unwrap()).unwrap();
+
+        assert_eq!(responses, expected);
+        assert_eq!(replayed, book);
+    }
+
+    #[test]
+    fn test_replay_flush_and_partial_cancel() {
+        let mut book = OrderBook::new("VAL", false);
+        let mut encoder = Encoder::new();
+        let action = new_order(1, 10, 100, "B", 1);
+        let responses = book.new_user_action(action.clone());
+        let mut messages = encoder.book_events(&action, &(Some(Symbol::new("VAL")), responses));
+        messages.extend(encoder.book_events(&UserAction::Flush, &(None, (None, None))));
+
+        let mut replayed = OrderBook::new("VAL", false);
+        replay(&mut replayed, &encode_all(&messages).unwrap()).unwrap();
+        assert_eq!(0, replayed.bids());
+
+        messages.push(encoder.sequence(Event::OrderCancel {
+            user_id: 1,
+            order_id: 1,
+            cancelled_qty: Quantity(10),
+        }));
+        assert_eq!(
+            replay(&mut replayed, &encode_all(&messages).unwrap()),
+            Err(ReplayError::PartialCancel {
+                user_id: 1,
+                order_id: 1
+            })
+        );
+    }
+}
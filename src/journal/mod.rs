@@ -0,0 +1,613 @@
+//! This mod implements the event-sourced journal of [UserAction]s.
+//!
+//! Every action is appended to the journal before it is applied to a book.
+//! On startup the journal is read back and replayed, which rebuilds every
+//! book deterministically.
+//!
+//! A journal file starts with a header (little endian):
+//!
+//! | Field   | Size | Description                  |
+//! |---------|------|------------------------------|
+//! | magic   | 4    | `OBJN`                       |
+//! | version | 2    | Layout version of the records |
+//!
+//! Each record is then laid out as:
+//!
+//! | Field    | Size | Description                               |
+//! |----------|------|-------------------------------------------|
+//! | length   | 4    | Length of the payload                     |
+//! | checksum | 4    | CRC-32 of the sequence number and payload |
+//! | seq      | 8    | Sequence number, starting at 1            |
+//! | header   | 4    | CRC-32 of the three fields above          |
+//! | payload  | *    | MessagePack encoded action                |
+//!
+//! The header checksum guards the length, so that a damaged length is not
+//! mistaken for a record cut short. Journals written before have neither
+//! the file header nor the header checksums; they are rewritten in this
+//! layout when opened.
+//!
+//! The payload holds the [UserAction] together with the number of the last
+//! response stamped before it, so that a restarted engine carries on with
+//! the numbers of the responses it emitted. Responses to actions rejected
+//! before the journal are recorded by a payload without an action. Journals
+//! written before hold the bare action.
+//!
+//! Appends are buffered and `fsync`ed in batches. A crash may lose the
+//! unsynced tail of the journal or leave its last record half written; a
+//! record cut short by the end of the file is dropped when the journal is
+//! opened again. A damaged record anywhere else is an error and leaves the
+//! journal untouched.
+
+use std::fmt::{Display, Formatter};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::orderbook::UserAction;
+
+/// Journal file magic
+pub const MAGIC: [u8; 4] = *b"OBJN";
+
+/// Record layout version written by this build
+pub const VERSION: u16 = 2;
+
+/// Length of the file header
+pub const FILE_HEADER_LEN: usize = 6;
+
+/// Length of the record header
+pub const RECORD_HEADER_LEN: usize = 20;
+
+/// Length of the record header of journals without a file header
+const LEGACY_HEADER_LEN: usize = 16;
+
+/// Default number of appends between two `fsync`s
+pub const DEFAULT_SYNC_EVERY: usize = 64;
+
+#[derive(Debug)]
+/// Errors produced by the journal
+pub enum JournalError {
+    /// Reading or writing the file failed
+    Io(io::Error),
+    /// The action could not be encoded
+    Encode(rmp_serde::encode::Error),
+    /// A record with a valid checksum could not be decoded
+    Decode(rmp_serde::decode::Error),
+    /// A record in the middle of the journal is damaged
+    Corrupt { offset: u64 },
+    /// The layout version is not known to this build
+    UnsupportedVersion(u16),
+    /// Sequence numbers are not contiguous
+    SequenceGap { expected: u64, got: u64 },
+}
+
+impl Display for JournalError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            JournalError::Io(e) => write!(f, "{}", e),
+            JournalError::Encode(e) => write!(f, "{}", e),
+            JournalError::Decode(e) => write!(f, "{}", e),
+            JournalError::Corrupt { offset } => {
+                write!(f, "corrupt journal record at offset {}", offset)
+            }
+            JournalError::UnsupportedVersion(version) => {
+                write!(f, "unsupported journal version {}", version)
+            }
+            JournalError::SequenceGap { expected, got } => write!(
+                f,
+                "journal sequence gap: expected {}, got {}",
+                expected, got
+            ),
+        }
+    }
+}
+
+impl std::error::Error for JournalError {}
+
+impl From<io::Error> for JournalError {
+    fn from(e: io::Error) -> Self {
+        JournalError::Io(e)
+    }
+}
+
+impl From<rmp_serde::encode::Error> for JournalError {
+    fn from(e: rmp_serde::encode::Error) -> Self {
+        JournalError::Encode(e)
+    }
+}
+
+impl From<rmp_serde::decode::Error> for JournalError {
+    fn from(e: rmp_serde::decode::Error) -> Self {
+        JournalError::Decode(e)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+/// This struct describes one journaled [UserAction]
+pub struct Entry {
+    /// Sequence number of the action
+    pub seq: u64,
+    /// The action itself, `None` for a record of responses only, see
+    /// [Journal::append_stamped]
+    pub action: Option<UserAction>,
+    /// Number of the last response stamped before the record was written,
+    /// unknown in older journals
+    pub stamped: Option<u64>,
+}
+
+#[derive(Serialize)]
+/// This struct is the payload of a record as it is written
+struct PayloadRef<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    action: Option<&'a UserAction>,
+    stamped: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+/// This enum is the payload of a record as it is read back
+enum Payload {
+    Stamped {
+        #[serde(default)]
+        action: Option<UserAction>,
+        stamped: u64,
+    },
+    /// Records of older journals
+    Action(UserAction),
+}
+
+/// Checksum of a record
+fn checksum(seq: u64, payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&seq.to_le_bytes());
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+/// Encodes one record in the current layout
+fn encode(out: &mut impl Write, seq: u64, payload: &[u8]) -> io::Result<()> {
+    let mut header = [0; RECORD_HEADER_LEN];
+    header[0..4].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+    header[4..8].copy_from_slice(&checksum(seq, payload).to_le_bytes());
+    header[8..16].copy_from_slice(&seq.to_le_bytes());
+    let crc = crc32fast::hash(&header[..16]);
+    header[16..20].copy_from_slice(&crc.to_le_bytes());
+    out.write_all(&header)?;
+    out.write_all(payload)
+}
+
+/// Records of a journal file, as `(seq, payload)`s
+struct Records<'a> {
+    records: Vec<(u64, &'a [u8])>,
+    /// Length of the valid part of the file
+    len: usize,
+    /// The file has no file header nor header checksums
+    legacy: bool,
+}
+
+/// Splits a journal file into its records
+///
+/// Only a record cut short by the end of `buf` ends the journal early. A
+/// damaged record is an error, except for the last record of the file.
+fn split(buf: &[u8]) -> Result<Records<'_>, JournalError> {
+    // A file shorter than its header holds no record yet
+    if buf.len() < FILE_HEADER_LEN {
+        return Ok(Records {
+            records: vec![],
+            len: 0,
+            legacy: false,
+        });
+    }
+
+    let legacy = buf[0..4] != MAGIC;
+    let (header_len, mut offset) = match legacy {
+        true => (LEGACY_HEADER_LEN, 0),
+        false => {
+            let version = u16::from_le_bytes(buf[4..6].try_into().unwrap());
+            if version != VERSION {
+                return Err(JournalError::UnsupportedVersion(version));
+            }
+            (RECORD_HEADER_LEN, FILE_HEADER_LEN)
+        }
+    };
+
+    let mut records = vec![];
+    while offset < buf.len() {
+        let rest = &buf[offset..];
+        if rest.len() < header_len {
+            break;
+        }
+
+        let len = u32::from_le_bytes(rest[0..4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(rest[4..8].try_into().unwrap());
+        let seq = u64::from_le_bytes(rest[8..16].try_into().unwrap());
+        if !legacy {
+            let header_crc = u32::from_le_bytes(rest[16..20].try_into().unwrap());
+            if crc32fast::hash(&rest[..16]) != header_crc {
+                return Err(JournalError::Corrupt {
+                    offset: offset as u64,
+                });
+            }
+        }
+        let end = header_len.saturating_add(len);
+        if rest.len() < end {
+            break;
+        }
+
+        let payload = &rest[header_len..end];
+        if checksum(seq, payload) != crc {
+            if rest.len() == end {
+                break;
+            }
+            return Err(JournalError::Corrupt {
+                offset: offset as u64,
+            });
+        }
+
+        let expected = records.len() as u64 + 1;
+        if seq != expected {
+            return Err(JournalError::SequenceGap { expected, got: seq });
+        }
+
+        records.push((seq, payload));
+        offset += end;
+    }
+
+    Ok(Records {
+        records,
+        len: offset,
+        legacy,
+    })
+}
+
+/// Decodes the payload of a record
+fn decode(seq: u64, payload: &[u8]) -> Result<Entry, JournalError> {
+    Ok(match rmp_serde::from_slice(payload)? {
+        Payload::Stamped { action, stamped } => Entry {
+            seq,
+            action,
+            stamped: Some(stamped),
+        },
+        Payload::Action(action) => Entry {
+            seq,
+            action: Some(action),
+            stamped: None,
+        },
+    })
+}
+
+/// Decodes every record of a journal file
+fn entries(records: &Records) -> Result<Vec<Entry>, JournalError> {
+    records
+        .records
+        .iter()
+        .map(|(seq, payload)| decode(*seq, payload))
+        .collect()
+}
+
+/// Reads every entry of a journal without modifying it
+pub fn read(path: impl AsRef<Path>) -> Result<Vec<Entry>, JournalError> {
+    let buf = fs::read(path)?;
+    entries(&split(&buf)?)
+}
+
+#[derive(Debug)]
+/// This struct appends sequenced [UserAction]s to a journal file
+pub struct Journal {
+    /// Buffered journal file
+    file: BufWriter<File>,
+    /// Sequence number of the next append
+    next_seq: u64,
+    /// Number of appends between two `fsync`s
+    sync_every: usize,
+    /// Appends since the last `fsync`
+    pending: usize,
+}
+
+impl Journal {
+    /// Opens or creates a journal
+    ///
+    /// Returns the journal, positioned after its last valid record, and the
+    /// entries to replay. A torn last record is truncated away, a journal
+    /// of the older layout is rewritten in the current one.
+    pub fn open(
+        path: impl AsRef<Path>,
+        sync_every: usize,
+    ) -> Result<(Journal, Vec<Entry>), JournalError> {
+        let path = path.as_ref();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        let mut buf = vec![];
+        file.read_to_end(&mut buf)?;
+        let records = split(&buf)?;
+        let entries = entries(&records)?;
+        if records.legacy {
+            // Written aside and renamed over, so that a crash leaves either
+            // journal whole
+            let tmp = path.with_extension("tmp");
+            let mut out = BufWriter::new(File::create(&tmp)?);
+            out.write_all(&MAGIC)?;
+            out.write_all(&VERSION.to_le_bytes())?;
+            for (seq, payload) in &records.records {
+                encode(&mut out, *seq, payload)?;
+            }
+            out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+            fs::rename(&tmp, path)?;
+            file = OpenOptions::new().read(true).write(true).open(path)?;
+        } else if records.len < FILE_HEADER_LEN {
+            file.set_len(0)?;
+            file.write_all(&MAGIC)?;
+            file.write_all(&VERSION.to_le_bytes())?;
+            file.sync_data()?;
+        } else if records.len < buf.len() {
+            file.set_len(records.len as u64)?;
+            file.sync_data()?;
+        }
+        file.seek(SeekFrom::End(0))?;
+
+        let journal = Journal {
+            file: BufWriter::new(file),
+            next_seq: entries.len() as u64 + 1,
+            sync_every: sync_every.max(1),
+            pending: 0,
+        };
+
+        Ok((journal, entries))
+    }
+
+    /// Returns the sequence number of the next append
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    /// Appends an action with the number of the last response stamped
+    /// before it, returns its sequence number
+    ///
+    /// The journal is synced every `sync_every` appends.
+    pub fn append(&mut self, action: &UserAction, stamped: u64) -> Result<u64, JournalError> {
+        self.write(PayloadRef {
+            action: Some(action),
+            stamped,
+        })
+    }
+
+    /// Appends the number of the last stamped response without an action,
+    /// returns its sequence number
+    ///
+    /// Records the responses to actions rejected before the journal, so
+    /// that a restarted engine does not number responses again.
+    pub fn append_stamped(&mut self, stamped: u64) -> Result<u64, JournalError> {
+        self.write(PayloadRef {
+            action: None,
+            stamped,
+        })
+    }
+
+    /// Appends one record, returns its sequence number
+    fn write(&mut self, payload: PayloadRef) -> Result<u64, JournalError> {
+        let seq = self.next_seq;
+        let payload = rmp_serde::to_vec_named(&payload)?;
+        encode(&mut self.file, seq, &payload)?;
+        self.next_seq += 1;
+
+        self.pending += 1;
+        if self.pending >= self.sync_every {
+            self.sync()?;
+        }
+
+        Ok(seq)
+    }
+
+    /// Writes the buffered appends and `fsync`s them
+    pub fn sync(&mut self) -> Result<(), JournalError> {
+        if self.pending > 0 {
+            self.file.flush()?;
+            self.file.get_ref().sync_data()?;
+            self.pending = 0;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Journal {
+    fn drop(&mut self) {
+        let _ = self.sync();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{ManualClock, SharedClock};
+    use crate::codec::{Format, Reader};
+    use crate::engine::Engine;
+
+    fn input_actions() -> Vec<UserAction> {
+        let file = File::open("input/input.csv").unwrap();
+        Reader::new(Format::Csv, io::BufReader::new(file))
+            .map(|a| a.unwrap())
+            .collect()
+    }
+
+    /// Returns an [Engine] whose clock stands still, so that replays enter
+    /// orders at the same times
+    fn new_engine() -> Engine {
+        Engine::new(true).with_clock(SharedClock::new(ManualClock::default()))
+    }
+
+    fn engine_after(actions: &[UserAction]) -> Engine {
+        let mut engine = new_engine();
+        for action in actions {
+            engine.new_user_action(action.clone());
+        }
+        engine
+    }
+
+    #[test]
+    fn test_append_and_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal");
+        let actions = input_actions();
+
+        let (mut journal, entries) = Journal::open(&path, 8).unwrap();
+        assert!(entries.is_empty());
+        for (i, action) in actions.iter().enumerate() {
+            assert_eq!(journal.append(action, 2 * i as u64).unwrap(), i as u64 + 1);
+        }
+        drop(journal);
+
+        let (journal, entries) = Journal::open(&path, 8).unwrap();
+        assert_eq!(journal.next_seq(), actions.len() as u64 + 1);
+        assert!(entries
+            .iter()
+            .zip(0..)
+            .all(|(e, i)| e.stamped == Some(2 * i)));
+        assert_eq!(
+            entries
+                .into_iter()
+                .filter_map(|e| e.action)
+                .collect::<Vec<_>>(),
+            actions
+        );
+    }
+
+    #[test]
+    fn test_reads_bare_actions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal");
+        let actions = input_actions();
+
+        // Records of older journals hold only the action
+        let mut buf = vec![];
+        for (seq, action) in (1..).zip(&actions[..2]) {
+            let payload = rmp_serde::to_vec_named(action).unwrap();
+            buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+            buf.extend_from_slice(&checksum(seq, &payload).to_le_bytes());
+            buf.extend_from_slice(&seq.to_le_bytes());
+            buf.extend_from_slice(&payload);
+        }
+        std::fs::write(&path, buf).unwrap();
+
+        // They are rewritten in the current layout
+        let (mut journal, _) = Journal::open(&path, 1).unwrap();
+        journal.append(&actions[2], 3).unwrap();
+        assert_eq!(journal.append_stamped(6).unwrap(), 4);
+        drop(journal);
+        assert_eq!(std::fs::read(&path).unwrap()[0..4], MAGIC);
+
+        let entries = read(&path).unwrap();
+        assert_eq!(
+            entries
+                .iter()
+                .map(|e| (e.action.clone(), e.stamped))
+                .collect::<Vec<_>>(),
+            vec![
+                (Some(actions[0].clone()), None),
+                (Some(actions[1].clone()), None),
+                (Some(actions[2].clone()), Some(3)),
+                (None, Some(6)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_kill_and_replay() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal");
+        let actions = input_actions();
+        let expected = engine_after(&actions);
+
+        // Kill the process mid-stream: the unsynced tail is lost and the
+        // last record is torn
+        let (mut journal, _) = Journal::open(&path, 16).unwrap();
+        for action in &actions[..40] {
+            journal.append(action, 0).unwrap();
+        }
+        std::mem::forget(journal);
+        let len = std::fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+
+        // Replay rebuilds the books up to the last intact record
+        let (mut journal, entries) = Journal::open(&path, 16).unwrap();
+        let replayed = entries.len();
+        assert_eq!(replayed, 31);
+        let mut engine = new_engine();
+        for action in entries.into_iter().filter_map(|e| e.action) {
+            engine.new_user_action(action);
+        }
+        assert_eq!(engine, engine_after(&actions[..replayed]));
+
+        // The input is resent from the first action missing in the journal
+        for action in &actions[replayed..] {
+            journal.append(action, 0).unwrap();
+            engine.new_user_action(action.clone());
+        }
+        assert_eq!(engine, expected);
+        drop(journal);
+
+        let mut replayed = new_engine();
+        for action in read(&path).unwrap().into_iter().filter_map(|e| e.action) {
+            replayed.new_user_action(action);
+        }
+        assert_eq!(replayed, expected);
+    }
+
+    #[test]
+    fn test_corrupt_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal");
+
+        let (mut journal, _) = Journal::open(&path, 1).unwrap();
+        for action in &input_actions()[..3] {
+            journal.append(action, 0).unwrap();
+        }
+        drop(journal);
+
+        let mut buf = std::fs::read(&path).unwrap();
+        buf[FILE_HEADER_LEN + RECORD_HEADER_LEN] ^= 0xff;
+        std::fs::write(&path, buf).unwrap();
+
+        assert!(matches!(
+            Journal::open(&path, 1),
+            Err(JournalError::Corrupt { offset: 6 })
+        ));
+    }
+
+    #[test]
+    fn test_corrupt_length() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal");
+
+        let (mut journal, _) = Journal::open(&path, 1).unwrap();
+        for action in &input_actions()[..3] {
+            journal.append(action, 0).unwrap();
+        }
+        drop(journal);
+
+        // A longer length of the second record runs past the end of the
+        // file, yet the records after it are not dropped
+        let mut buf = std::fs::read(&path).unwrap();
+        let first = u32::from_le_bytes(buf[6..10].try_into().unwrap()) as usize;
+        let offset = FILE_HEADER_LEN + RECORD_HEADER_LEN + first;
+        buf[offset + 3] ^= 0x01;
+        std::fs::write(&path, &buf).unwrap();
+
+        assert!(matches!(
+            Journal::open(&path, 1),
+            Err(JournalError::Corrupt { offset: o }) if o == offset as u64
+        ));
+        assert_eq!(std::fs::read(&path).unwrap(), buf);
+    }
+}
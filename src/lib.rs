@@ -0,0 +1,67 @@
+//! Order book matching engine and the market-data encodings built on top of it.
+//!
+//! The engine can be embedded directly: an [OrderBook] matches the orders of
+//! one symbol, an [Engine] routes [UserAction]s to one book per symbol and
+//! answers each of them with up to two [Response]s.
+//!
+//! ```
+//! use order_book::{Engine, Price, Quantity, Response, Side, Symbol, TimeInForce, UserAction};
+//!
+//! let mut engine = Engine::new(true);
+//! engine.new_user_action(UserAction::NewOrder {
+//!     user_id: 1,
+//!     symbol: Symbol::new("IBM"),
+//!     price: Price(10),
+//!     qty: Quantity(100),
+//!     side: Side::Sell,
+//!     order_id: 1,
+//!     time_in_force: TimeInForce::Gtc,
+//! });
+//! let (symbol, (ack, trade)) = engine.new_user_action(UserAction::NewOrder {
+//!     user_id: 2,
+//!     symbol: Symbol::new("IBM"),
+//!     price: Price(10),
+//!     qty: Quantity(100),
+//!     side: Side::Buy,
+//!     order_id: 1,
+//!     time_in_force: TimeInForce::Gtc,
+//! });
+//!
+//! assert_eq!(symbol, Some(Symbol::new("IBM")));
+//! assert_eq!(ack, Some(Response::Acknowledge { user_id: 2, order_id: 1 }));
+//! assert!(matches!(trade, Some(Response::Trade { qty: Quantity(100), .. })));
+//! ```
+
+pub mod accounts;
+pub mod clock;
+pub mod codec;
+pub mod engine;
+pub mod feed;
+pub mod fixed;
+pub mod instrument;
+pub mod itch;
+pub mod journal;
+pub mod orderbook;
+pub mod pipeline;
+pub mod replay;
+pub mod risk;
+pub mod sequence;
+pub mod shard;
+pub mod snapshot;
+pub mod spsc;
+pub mod symbol;
+pub mod ws;
+
+pub use accounts::{Accounts, MarkPrice, Position};
+pub use clock::{Clock, ManualClock, SharedClock, Timestamp, WallClock};
+pub use codec::{CodecError, CsvRecord, Format, Reader, Writer};
+pub use engine::{CancelFilter, Engine, LiveOrder, OrderIdScope, OrderTimes};
+pub use fixed::{Price, Quantity, Scale, Scales};
+pub use instrument::{Instrument, Instruments, InvalidOrder};
+pub use orderbook::{
+    OrderBook, ParseSideError, ParseTimeInForceError, RejectReason, Response, Side, TimeInForce,
+    UserAction,
+};
+pub use risk::{RiskBreach, RiskConfig, RiskGate, RiskLimits};
+pub use sequence::{Sequencer, Stamped, StampedOutput};
+pub use symbol::{ParseSymbolError, Symbol};
@@ -1,109 +1,309 @@
-mod orderbook;
-use orderbook::{OrderBook, Response, UserAction};
+use order_book::clock::parse_time_of_day;
+use order_book::codec::{Format, Reader, Writer};
+use order_book::feed::{serve_recovery, FeedStore, Publisher};
+use order_book::pipeline::{self, Output, StageError, State};
+use order_book::shard;
+use order_book::snapshot::DEFAULT_SNAPSHOT_EVERY;
+use order_book::{
+    Accounts, Clock, Engine, Instruments, MarkPrice, OrderIdScope, RiskGate, Scales, Sequencer,
+    SharedClock, UserAction,
+};
 
-use std::collections::HashMap;
+use std::env;
 use std::fs::File;
-use std::io::{BufRead, BufReader};
-use std::sync::{Arc, Mutex};
-
-use regex::Regex;
-use tokio::runtime::Runtime;
-
-async fn produce_input(filename: &str, prod: &mut Arc<Mutex<Vec<UserAction>>>) {
-    let file = File::open(filename).unwrap();
-    let lines = BufReader::new(file).lines();
-
-    println!("Produce");
-
-    let new_order_re =
-        Regex::new(r"^N, ([0-9]+), ([[:alpha:]]+), ([0-9]+), ([0-9]+), ([BS]), ([0-9]+)").unwrap();
-    let cancel_order_re = Regex::new(r"^C, ([0-9]+), ([0-9]+)").unwrap();
-
-    for line in lines.flatten() {
-        println!("{}", line);
-        if line.starts_with('N') {
-            let captures = new_order_re.captures(&line).unwrap();
-
-            prod.lock().ok().unwrap().push(UserAction::NewOrder {
-                user_id: captures.get(1).unwrap().as_str().parse::<u32>().unwrap(),
-                symbol: String::from(captures.get(2).unwrap().as_str()),
-                price: captures.get(3).unwrap().as_str().parse::<u32>().unwrap(),
-                qty: captures.get(4).unwrap().as_str().parse::<u32>().unwrap(),
-                side: String::from(captures.get(5).unwrap().as_str()),
-                order_id: captures.get(6).unwrap().as_str().parse::<u32>().unwrap(),
-            });
-        } else if line.starts_with('C') {
-            let captures = cancel_order_re.captures(&line).unwrap();
-            prod.lock().ok().unwrap().push(UserAction::CancelOrder {
-                user_id: captures.get(1).unwrap().as_str().parse::<u32>().unwrap(),
-                order_id: captures.get(2).unwrap().as_str().parse::<u32>().unwrap(),
-            })
-        } else if line.starts_with('F') {
-            prod.lock().ok().unwrap().push(UserAction::Flush);
+use std::io::{self, BufReader};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener};
+use std::process;
+use std::sync::Arc;
+use std::thread;
+
+/// Reads the input and hands the actions to `submit`
+///
+/// Returns once the input ends or `submit` reports the engine is gone.
+fn produce_input(
+    filename: &str,
+    format: Format,
+    scales: Scales,
+    mut submit: impl FnMut(UserAction) -> bool,
+) -> Result<(), StageError> {
+    let file = File::open(filename).map_err(|e| format!("{}: {}", filename, e))?;
+
+    for action in Reader::new(format, BufReader::new(file)).with_scales(scales) {
+        if !submit(action?) {
+            break;
         }
     }
+
+    Ok(())
 }
 
-async fn process(
-    prod: &mut Arc<Mutex<Vec<UserAction>>>,
-    cons: &mut Arc<Mutex<HashMap<String, OrderBook>>>,
-    resp: &mut Arc<Mutex<Vec<(Option<Response>, Option<Response>)>>>,
-) {
-    println!("Process");
-    while let Ok(v) = prod.lock().as_mut() {
-        if v.len() > 0 {
-            println!("Process actions: {}", v.len());
-            let action = v.remove(0);
-
-            if let Ok(val) = cons.lock().as_mut() {
-                let entry = val
-                    .entry(String::from("IBM"))
-                    .or_insert_with(|| OrderBook::new("IBM", false));
-                let response = entry.new_user_action(action);
-                if let Ok(rsp) = resp.lock().as_mut() {
-                    rsp.push(response);
-                }
+/// Encoding of the responses written to stdout
+#[derive(Clone)]
+struct OutputOptions {
+    format: Format,
+    scales: Scales,
+    /// Appends the reason of a reject to its CSV record
+    reject_reasons: bool,
+    /// Writes the sequence number and timestamp of every response
+    stamps: bool,
+    /// End-of-day account report written once the responses are done
+    eod: Option<(String, MarkPrice)>,
+}
+
+/// Writes the responses to stdout until the engine is done
+fn show_results(
+    outputs: impl Iterator<Item = Output>,
+    options: OutputOptions,
+) -> Result<(), StageError> {
+    let mut writer = Writer::new(options.format, io::stdout().lock())
+        .with_scales(options.scales.clone())
+        .with_trailing_fields(options.reject_reasons);
+    let mut accounts = options.eod.as_ref().map(|(_, mark)| Accounts::new(*mark));
+
+    for (symbol, (res1, res2)) in outputs {
+        for stamped in [res1, res2].iter().flatten() {
+            if options.stamps {
+                writer.write_for(symbol, stamped)?;
+            } else {
+                writer.write_for(symbol, &stamped.response)?;
+            }
+            if let (Some(accounts), Some(symbol)) = (accounts.as_mut(), symbol) {
+                accounts.apply(symbol, &stamped.response);
             }
         }
     }
+    writer.flush()?;
+
+    if let (Some(accounts), Some((path, _))) = (accounts, options.eod) {
+        let file = File::create(&path).map_err(|e| format!("{}: {}", path, e))?;
+        accounts.write_eod(io::BufWriter::new(file), &options.scales)?;
+    }
+    Ok(())
 }
 
-async fn show_results(resp: &mut Arc<Mutex<Vec<(Option<Response>, Option<Response>)>>>) {
-    println!("Results");
-    while let Ok(v) = resp.lock().as_mut() {
-        if v.len() > 0 {
-            let (res1, res2) = v.remove(0);
-            match (res1, res2) {
-                (Some(res1), Some(res2)) => println!("{}\n{}", res1, res2),
-                (Some(res1), None) => println!("{}", res1),
-                (_, _) => (),
+/// Runs the input through `shards` engine threads partitioned by symbol
+///
+/// The merged responses are in the order of a single engine, so they are
+/// numbered as that engine would number them.
+fn run_sharded(
+    shards: usize,
+    cores: &[usize],
+    input: &str,
+    input_format: Format,
+    output: OutputOptions,
+    new_engine: impl Fn() -> Engine,
+) -> Vec<Result<(), StageError>> {
+    let (mut router, merger) = shard::spawn(shards, cores, new_engine).unwrap();
+    let mut refused = Ok(());
+
+    let scales = output.scales.clone();
+    let clock = SharedClock::default();
+    let mut sequencer = Sequencer::new();
+    let stamped = merger.map(move |(_, o)| sequencer.stamp_output(o, clock.now()));
+    let output_handle = thread::spawn(move || show_results(stamped, output));
+    let produced = produce_input(input, input_format, scales, |action| {
+        match router.submit(action) {
+            Ok(_) => true,
+            // Orders that may expire need a single engine
+            Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+                refused = Err(e);
+                false
             }
+            Err(_) => false,
         }
+    });
+    drop(router);
+
+    vec![
+        produced.and(refused.map_err(Into::into)),
+        output_handle.join().unwrap(),
+    ]
+}
+
+/// Starts the multicast feed publisher and its recovery service
+fn start_feed(group: SocketAddrV4, recovery: SocketAddr, clock: SharedClock) -> Publisher {
+    let store = FeedStore::shared();
+    let listener = TcpListener::bind(recovery).unwrap();
+    let server_store = Arc::clone(&store);
+    thread::spawn(move || serve_recovery(listener, server_store));
+
+    Publisher::bind(group, Ipv4Addr::LOCALHOST, store, clock).unwrap()
+}
+
+/// Prints the errors of the stages, exits with status 1 if there is any
+fn exit_on_errors(results: Vec<Result<(), StageError>>) {
+    let mut failed = false;
+    for e in results.into_iter().filter_map(Result::err) {
+        eprintln!("Error: {}", e);
+        failed = true;
+    }
+    if failed {
+        process::exit(1);
     }
 }
 
 fn main() {
-    let rt = Runtime::new().unwrap();
+    let args: Vec<String> = env::args().collect();
+    let arg = |name: &str| {
+        args.iter()
+            .position(|a| a == name)
+            .and_then(|i| args.get(i + 1))
+    };
+    // Optional core of the engine thread: --pin CORE
+    let core: Option<usize> = arg("--pin").map(|c| c.parse().unwrap());
 
-    let mut prod = Arc::new(Mutex::new(vec![]));
-    let mut cons = Arc::new(Mutex::new(HashMap::new()));
-    let mut resp: Arc<Mutex<Vec<(Option<Response>, Option<Response>)>>> =
-        Arc::new(Mutex::new(vec![]));
+    // Input file and encodings: --input PATH --input-format F --output-format F
+    let input = arg("--input")
+        .cloned()
+        .unwrap_or_else(|| String::from("./input/input.csv"));
+    let input_format: Format = arg("--input-format")
+        .map(|f| f.parse().unwrap())
+        .unwrap_or(Format::Csv);
+    let output_format: Format = arg("--output-format")
+        .map(|f| f.parse().unwrap())
+        .unwrap_or(Format::Csv);
+    // CSV rejects end in their reason: --reject-reasons
+    let reject_reasons = args.iter().any(|a| a == "--reject-reasons");
+    // Responses end in their sequence number and timestamp: --stamps
+    let stamps = args.iter().any(|a| a == "--stamps");
+    // Crossing orders trade instead of being rejected: --trade
+    let trade_active = args.iter().any(|a| a == "--trade");
+    // Optional end-of-day accounts: --eod PATH, positions marked to
+    // --mark last|mid
+    let eod = arg("--eod").map(|path| {
+        let mark: MarkPrice = arg("--mark")
+            .map(|m| m.parse().unwrap())
+            .unwrap_or_default();
+        (path.clone(), mark)
+    });
+    // Optional reference data: --instruments PATH, a JSON list of instruments
+    let instruments = match arg("--instruments") {
+        Some(path) => Instruments::load(path).unwrap_or_else(|e| {
+            eprintln!("{}: {}", path, e);
+            process::exit(2);
+        }),
+        None => Instruments::new(),
+    };
+    // Decimals of the CSV prices and quantities: --scales IBM:2,BTC:2:8,
+    // by default those of the instruments
+    let scales: Scales = arg("--scales")
+        .map(|s| s.parse().unwrap())
+        .unwrap_or_else(|| instruments.scales());
+    // Reuse of order ids: --order-id-scope live|session
+    let order_id_scope: OrderIdScope = arg("--order-id-scope")
+        .map(|s| s.parse().unwrap())
+        .unwrap_or_default();
+    // Optional pre-trade risk limits: --risk PATH, reloaded when it changes
+    let risk = arg("--risk").map(|path| {
+        RiskGate::watch(path).unwrap_or_else(|e| {
+            eprintln!("{}: {}", path, e);
+            process::exit(2);
+        })
+    });
+    // Time of day DAY orders expire at: --day-close HH:MM, UTC
+    let day_close = arg("--day-close")
+        .map(|t| {
+            parse_time_of_day(t).unwrap_or_else(|| {
+                eprintln!("invalid --day-close: {:?}", t);
+                process::exit(2);
+            })
+        })
+        .unwrap_or_default();
+    let new_engine = || {
+        Engine::new(trade_active)
+            .with_instruments(instruments.clone())
+            .with_order_id_scope(order_id_scope)
+            .with_day_close(day_close)
+    };
+    let output = OutputOptions {
+        format: output_format,
+        scales: scales.clone(),
+        reject_reasons,
+        stamps,
+        eod,
+    };
 
-    rt.block_on(async move {
-        let mut prod_ref = Arc::clone(&prod);
-        let mut resp_ref = Arc::clone(&resp);
+    // Optional sharding by symbol: --shards N, pinned to cores from --pin on
+    if let Some(shards) = arg("--shards") {
+        let shards: usize = shards.parse().unwrap();
+        // Risk limits are per user, so they can not be split by symbol,
+        // and shards never expire orders
+        if [
+            "--journal",
+            "--snapshots",
+            "--multicast",
+            "--risk",
+            "--day-close",
+        ]
+        .iter()
+        .any(|a| arg(a).is_some())
+        {
+            eprintln!(
+                "--shards can not be combined with --journal, --snapshots, --multicast, --risk or --day-close"
+            );
+            process::exit(2);
+        }
 
-        println!("hello from the async block");
+        let cores: Vec<usize> = core.map(|c| (c..c + shards).collect()).unwrap_or_default();
+        exit_on_errors(run_sharded(
+            shards,
+            &cores,
+            &input,
+            input_format,
+            output,
+            new_engine,
+        ));
+        return;
+    }
 
-        //bonus, you could spawn tasks too
-        let produce_handle = tokio::spawn(async move {
-            produce_input("./input/input.csv", &mut prod).await
-        });
-        let process_handle =
-            tokio::spawn(async move { process(&mut prod_ref, &mut cons, &mut resp).await });
-        let res_handle = tokio::spawn(async move { show_results(&mut resp_ref).await });
+    let mut state = State::new(false);
+    state.engine = match risk {
+        Some(risk) => new_engine().with_risk(risk),
+        None => new_engine(),
+    };
 
-        let (_r1, _r2, _r3) = tokio::join!(produce_handle, process_handle, res_handle);
+    // Optional market-data feed: --multicast GROUP:PORT --recovery ADDR:PORT
+    if let (Some(group), Some(recovery)) = (arg("--multicast"), arg("--recovery")) {
+        state.publisher = Some(start_feed(
+            group.parse().unwrap(),
+            recovery.parse().unwrap(),
+            state.engine.clock().clone(),
+        ));
+    }
+
+    // Optional journal: --journal PATH, replayed before the input is read.
+    // With --snapshots DIR the books are restored from the latest snapshot
+    // and only the journal tail is replayed.
+    state.snapshots = arg("--snapshots").map(|dir| {
+        let every = arg("--snapshot-every")
+            .map(|n| {
+                n.parse().ok().filter(|n| *n > 0).unwrap_or_else(|| {
+                    eprintln!("--snapshot-every must be a positive number: {:?}", n);
+                    process::exit(2);
+                })
+            })
+            .unwrap_or(DEFAULT_SNAPSHOT_EVERY);
+        (dir.clone(), every)
     });
+    if let Some(path) = arg("--journal") {
+        eprintln!("Replay journal: {}", state.recover(path).unwrap());
+    }
+
+    // Input -> engine thread -> output, connected by lock-free ring
+    // buffers. Each stage ends when the one before it is done, so the
+    // program exits once the input is exhausted and every response is
+    // written.
+    let (mut action_tx, mut response_rx, engine_handle) = pipeline::spawn(state, core).unwrap();
+    let output_handle =
+        thread::spawn(move || show_results(std::iter::from_fn(|| response_rx.recv()), output));
+    let produced = produce_input(&input, input_format, scales, |action| {
+        action_tx.send(action).is_ok()
+    });
+    drop(action_tx);
+
+    exit_on_errors(vec![
+        produced,
+        engine_handle.join().unwrap(),
+        output_handle.join().unwrap(),
+    ]);
 }
@@ -0,0 +1,276 @@
+//! This mod implements the aggregated (level 2) view of an [OrderBook].
+//!
+//! [OrderBook::depth] returns the best price levels of both sides. When
+//! enabled, the book also records an [L2Event] for every level an action
+//! adds, changes or removes, so a consumer can keep a ladder up to date
+//! without reading the whole book.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use super::{Order, OrderBook, Side, UserAction};
+use crate::fixed::{Price, Quantity};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// This struct is one aggregated price level
+pub struct Level {
+    /// Price of the level
+    pub price: Price,
+    /// Total quantity of the orders at that price
+    pub qty: Quantity,
+    /// Number of orders at that price
+    pub orders: usize,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+/// This struct holds the best price levels of both sides, best first
+pub struct Depth {
+    /// Bid levels from the highest price down
+    pub bids: Vec<Level>,
+    /// Ask levels from the lowest price up
+    pub asks: Vec<Level>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// This enum describes what happened to a price level
+pub enum LevelChange {
+    /// The first order arrived at the price
+    Add,
+    /// The quantity of the level changed
+    Update,
+    /// The last order at the price is gone
+    Delete,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// This struct describes one change of a price level
+pub struct L2Event {
+    /// What happened to the level
+    pub change: LevelChange,
+    /// Side of the level
+    pub side: Side,
+    /// Price of the level
+    pub price: Price,
+    /// New total quantity of the level, zero once deleted
+    pub qty: Quantity,
+}
+
+/// Returns the total quantity of a level
+///
+/// The book rejects orders that would overflow their level.
+fn level_qty(orders: &[Order]) -> Quantity {
+    Quantity::checked_sum(orders.iter().map(Order::qty)).unwrap()
+}
+
+/// Returns the best `n` levels of one side
+fn best_levels(col: &HashMap<Price, Vec<Order>>, side: Side, n: usize) -> Vec<Level> {
+    let mut prices: Vec<&Price> = col.keys().collect();
+    match side {
+        Side::Buy => prices.sort_unstable_by(|a, b| b.cmp(a)),
+        Side::Sell => prices.sort_unstable(),
+    }
+
+    prices
+        .into_iter()
+        .take(n)
+        .map(|price| Level {
+            price: *price,
+            qty: level_qty(&col[price]),
+            orders: col[price].len(),
+        })
+        .collect()
+}
+
+impl OrderBook {
+    /// Returns the best `n` price levels of each side
+    pub fn depth(&self, n: usize) -> Depth {
+        Depth {
+            bids: best_levels(&self.bids, Side::Buy, n),
+            asks: best_levels(&self.asks, Side::Sell, n),
+        }
+    }
+
+    /// Starts or stops recording [L2Event]s
+    ///
+    /// Stopping drops the events not taken yet.
+    pub fn record_l2_events(&mut self, enabled: bool) {
+        self.l2_events = if enabled { Some(vec![]) } else { None };
+    }
+
+    /// Returns the [L2Event]s recorded since the last call, oldest first
+    pub fn take_l2_events(&mut self) -> Vec<L2Event> {
+        self.l2_events
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    /// Returns the orders of one side
+    pub(super) fn side(&self, side: Side) -> &HashMap<Price, Vec<Order>> {
+        match side {
+            Side::Buy => &self.bids,
+            Side::Sell => &self.asks,
+        }
+    }
+
+    /// Returns the levels `action` may change
+    pub(super) fn touched_levels(&self, action: &UserAction) -> Vec<(Side, Price)> {
+        match action {
+            // The order rests on its side or trades on the other one
+            UserAction::NewOrder { price, side, .. } => {
+                vec![(*side, *price), (side.opposite(), *price)]
+            }
+            // Cancels look in the asks first
+            UserAction::CancelOrder { user_id, order_id } => [Side::Sell, Side::Buy]
+                .into_iter()
+                .find_map(|side| {
+                    Self::get_order_index(self.side(side), *user_id, *order_id)
+                        .map(|(price, _)| (side, price))
+                })
+                .into_iter()
+                .collect(),
+            UserAction::Flush => {
+                let mut levels: Vec<(Side, Price)> = [Side::Buy, Side::Sell]
+                    .into_iter()
+                    .flat_map(|side| self.side(side).keys().map(move |price| (side, *price)))
+                    .collect();
+                levels.sort_unstable();
+                levels
+            }
+        }
+    }
+
+    /// Returns the total quantity of each level, `None` for empty ones
+    pub(super) fn level_quantities(&self, levels: &[(Side, Price)]) -> Vec<Option<Quantity>> {
+        levels
+            .iter()
+            .map(|(side, price)| self.side(*side).get(price).map(|o| level_qty(o)))
+            .collect()
+    }
+
+    /// Records the [L2Event]s of the levels that changed
+    pub(super) fn record_level_changes(
+        &mut self,
+        levels: &[(Side, Price)],
+        before: &[Option<Quantity>],
+    ) {
+        let after = self.level_quantities(levels);
+        let events = match self.l2_events.as_mut() {
+            Some(events) => events,
+            None => return,
+        };
+
+        for ((&(side, price), before), after) in levels.iter().zip(before).zip(after) {
+            let (change, qty) = match (before, after) {
+                (None, Some(qty)) => (LevelChange::Add, qty),
+                (Some(old), Some(qty)) if *old != qty => (LevelChange::Update, qty),
+                (Some(_), None) => (LevelChange::Delete, Quantity::ZERO),
+                _ => continue,
+            };
+            events.push(L2Event {
+                change,
+                side,
+                price,
+                qty,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orderbook::TimeInForce;
+    use crate::symbol::Symbol;
+
+    fn new_order(user_id: u32, price: u64, qty: u64, side: Side, order_id: u32) -> UserAction {
+        UserAction::NewOrder {
+            user_id,
+            symbol: Symbol::new("IBM"),
+            price: Price(price),
+            qty: Quantity(qty),
+            side,
+            order_id,
+            time_in_force: TimeInForce::Gtc,
+        }
+    }
+
+    fn event(change: LevelChange, side: Side, price: u64, qty: u64) -> L2Event {
+        L2Event {
+            change,
+            side,
+            price: Price(price),
+            qty: Quantity(qty),
+        }
+    }
+
+    #[test]
+    fn test_depth() {
+        let mut ob = OrderBook::new("IBM", false);
+        for (i, (price, side)) in [
+            (10, Side::Buy),
+            (10, Side::Buy),
+            (9, Side::Buy),
+            (8, Side::Buy),
+        ]
+        .into_iter()
+        .chain([(12, Side::Sell), (11, Side::Sell)])
+        .enumerate()
+        {
+            ob.new_user_action(new_order(1, price, 100, side, i as u32));
+        }
+
+        let depth = ob.depth(2);
+        let level = |price, qty, orders| Level {
+            price: Price(price),
+            qty: Quantity(qty),
+            orders,
+        };
+        assert_eq!(depth.bids, vec![level(10, 200, 2), level(9, 100, 1)]);
+        assert_eq!(depth.asks, vec![level(11, 100, 1), level(12, 100, 1)]);
+        assert_eq!(ob.depth(0), Depth::default());
+    }
+
+    #[test]
+    fn test_l2_events() {
+        let mut ob = OrderBook::new("IBM", true);
+        ob.new_user_action(new_order(1, 10, 100, Side::Buy, 1));
+        assert_eq!(ob.take_l2_events(), vec![]);
+
+        ob.record_l2_events(true);
+        ob.new_user_action(new_order(1, 10, 50, Side::Buy, 2));
+        ob.new_user_action(new_order(2, 11, 100, Side::Sell, 1));
+        assert_eq!(
+            ob.take_l2_events(),
+            vec![
+                event(LevelChange::Update, Side::Buy, 10, 150),
+                event(LevelChange::Add, Side::Sell, 11, 100),
+            ]
+        );
+
+        // A trade takes the resting order out of its level
+        ob.new_user_action(new_order(3, 10, 100, Side::Sell, 1));
+        assert_eq!(
+            ob.take_l2_events(),
+            vec![event(LevelChange::Update, Side::Buy, 10, 50)]
+        );
+
+        // Rejects change nothing
+        ob.new_user_action(new_order(3, 10, 70, Side::Sell, 2));
+        assert_eq!(ob.take_l2_events(), vec![]);
+
+        ob.new_user_action(UserAction::CancelOrder {
+            user_id: 2,
+            order_id: 1,
+        });
+        ob.new_user_action(UserAction::Flush);
+        assert_eq!(
+            ob.take_l2_events(),
+            vec![
+                event(LevelChange::Delete, Side::Sell, 11, 0),
+                event(LevelChange::Delete, Side::Buy, 10, 0),
+            ]
+        );
+    }
+}
@@ -0,0 +1,224 @@
+//! This mod implements the order by order (level 3) view of an [OrderBook].
+//!
+//! [OrderBook::orders] walks every resting order with its place in the
+//! queue of its price level. When enabled, the book also records an
+//! [L3Event] for every order it adds, executes or removes, so a consumer
+//! can rebuild the whole book from the events alone.
+
+use serde::{Deserialize, Serialize};
+
+use super::{Order, OrderBook, Side};
+use crate::fixed::{Price, Quantity};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// This struct describes one resting order
+pub struct RestingOrder {
+    /// Side of the order
+    pub side: Side,
+    /// Limit price
+    pub price: Price,
+    /// Open quantity
+    pub qty: Quantity,
+    /// Owner of the order
+    pub user_id: u32,
+    /// Id of the order, unique per user
+    pub order_id: u32,
+    /// Number of orders ahead of it at its price, 0 for the first one
+    pub position: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// This enum describes what happened to an order
+pub enum OrderChange {
+    /// The order started resting in the book
+    Add,
+    /// The quantity of the order changed without losing its place
+    ///
+    /// The book has no action doing that yet, amends cancel the order and
+    /// add a new one.
+    Modify,
+    /// The order traded `qty` against an incoming order and left the book
+    Execute,
+    /// The order was cancelled or flushed
+    Delete,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// This struct describes one change of a resting order
+pub struct L3Event {
+    /// What happened to the order
+    pub change: OrderChange,
+    /// Side of the order
+    pub side: Side,
+    /// Limit price
+    pub price: Price,
+    /// Quantity added, left, traded or removed, depending on the change
+    pub qty: Quantity,
+    /// Owner of the order
+    pub user_id: u32,
+    /// Id of the order, unique per user
+    pub order_id: u32,
+}
+
+impl L3Event {
+    /// Creates the [L3Event] of an order
+    pub(super) fn new(change: OrderChange, side: Side, order: &Order) -> Self {
+        L3Event {
+            change,
+            side,
+            price: order.price,
+            qty: order.qty,
+            user_id: order.user_id,
+            order_id: order.order_id,
+        }
+    }
+}
+
+/// Records an [L3Event] when events are recorded
+pub(super) fn record(events: &mut Option<&mut Vec<L3Event>>, event: impl FnOnce() -> L3Event) {
+    if let Some(events) = events {
+        events.push(event());
+    }
+}
+
+impl OrderBook {
+    /// Returns every resting order
+    ///
+    /// Bids come first from the best price down, then asks from the best
+    /// price up. Orders of the same price come in time priority, which is
+    /// the order they fill in.
+    pub fn orders(&self) -> impl Iterator<Item = RestingOrder> + '_ {
+        let mut bid_prices: Vec<&Price> = self.bids.keys().collect();
+        let mut ask_prices: Vec<&Price> = self.asks.keys().collect();
+        bid_prices.sort_unstable_by(|a, b| b.cmp(a));
+        ask_prices.sort_unstable();
+
+        let bids = bid_prices.into_iter().map(|p| (Side::Buy, &self.bids[p]));
+        let asks = ask_prices.into_iter().map(|p| (Side::Sell, &self.asks[p]));
+
+        bids.chain(asks).flat_map(|(side, level)| {
+            level
+                .iter()
+                .enumerate()
+                .map(move |(position, o)| RestingOrder {
+                    side,
+                    price: o.price,
+                    qty: o.qty,
+                    user_id: o.user_id,
+                    order_id: o.order_id,
+                    position,
+                })
+        })
+    }
+
+    /// Starts or stops recording [L3Event]s
+    ///
+    /// Stopping drops the events not taken yet.
+    pub fn record_l3_events(&mut self, enabled: bool) {
+        self.l3_events = if enabled { Some(vec![]) } else { None };
+    }
+
+    /// Returns the [L3Event]s recorded since the last call, oldest first
+    pub fn take_l3_events(&mut self) -> Vec<L3Event> {
+        self.l3_events
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orderbook::{TimeInForce, UserAction};
+    use crate::symbol::Symbol;
+
+    fn new_order(user_id: u32, price: u64, qty: u64, side: Side, order_id: u32) -> UserAction {
+        UserAction::NewOrder {
+            user_id,
+            symbol: Symbol::new("IBM"),
+            price: Price(price),
+            qty: Quantity(qty),
+            side,
+            order_id,
+            time_in_force: TimeInForce::Gtc,
+        }
+    }
+
+    fn event(change: OrderChange, side: Side, price: u64, qty: u64, user_id: u32) -> L3Event {
+        L3Event {
+            change,
+            side,
+            price: Price(price),
+            qty: Quantity(qty),
+            user_id,
+            order_id: 1,
+        }
+    }
+
+    #[test]
+    fn test_orders() {
+        let mut ob = OrderBook::new("IBM", false);
+        ob.new_user_action(new_order(1, 9, 100, Side::Buy, 1));
+        ob.new_user_action(new_order(2, 10, 50, Side::Buy, 1));
+        ob.new_user_action(new_order(3, 10, 70, Side::Buy, 1));
+        ob.new_user_action(new_order(4, 11, 100, Side::Sell, 1));
+
+        let orders: Vec<(u32, Side, Price, usize)> = ob
+            .orders()
+            .map(|o| (o.user_id, o.side, o.price, o.position))
+            .collect();
+        assert_eq!(
+            orders,
+            vec![
+                (2, Side::Buy, Price(10), 0),
+                (3, Side::Buy, Price(10), 1),
+                (1, Side::Buy, Price(9), 0),
+                (4, Side::Sell, Price(11), 0),
+            ]
+        );
+        assert_eq!(OrderBook::new("IBM", false).orders().count(), 0);
+    }
+
+    #[test]
+    fn test_l3_events() {
+        let mut ob = OrderBook::new("IBM", true);
+        ob.new_user_action(new_order(1, 10, 100, Side::Buy, 1));
+        assert_eq!(ob.take_l3_events(), vec![]);
+
+        ob.record_l3_events(true);
+        ob.new_user_action(new_order(2, 10, 50, Side::Buy, 1));
+        ob.new_user_action(new_order(3, 11, 100, Side::Sell, 1));
+        assert_eq!(
+            ob.take_l3_events(),
+            vec![
+                event(OrderChange::Add, Side::Buy, 10, 50, 2),
+                event(OrderChange::Add, Side::Sell, 11, 100, 3),
+            ]
+        );
+
+        // The resting order executes, the incoming one never rests
+        ob.new_user_action(new_order(4, 10, 100, Side::Sell, 1));
+        assert_eq!(
+            ob.take_l3_events(),
+            vec![event(OrderChange::Execute, Side::Buy, 10, 100, 1)]
+        );
+
+        // Rejects change nothing
+        ob.new_user_action(new_order(4, 10, 70, Side::Sell, 2));
+        assert_eq!(ob.take_l3_events(), vec![]);
+
+        ob.new_user_action(UserAction::CancelOrder {
+            user_id: 3,
+            order_id: 1,
+        });
+        ob.new_user_action(UserAction::Flush);
+        assert_eq!(
+            ob.take_l3_events(),
+            vec![
+                event(OrderChange::Delete, Side::Sell, 11, 100, 3),
+                event(OrderChange::Delete, Side::Buy, 10, 50, 2),
+            ]
+        );
+    }
+}
@@ -1,46 +1,306 @@
+//! This mod implements an orders book inner functionaity.
+//!
+//! Provides an abstraction over two [HashMap]s that hold the orders
+//! per each price.
+
 use std::{
     collections::HashMap,
     fmt::{Display, Formatter},
+    str::FromStr,
 };
 
-/// This mod implements an orders book inner functionaity.
-///
-/// Provides an abstraction over two [HashMap]s that hold the orders
-/// per each price.
-///
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::clock::Timestamp;
+use crate::fixed::{Price, Quantity};
+use crate::instrument::{Instrument, InvalidOrder};
+use crate::risk::RiskBreach;
+use crate::symbol::Symbol;
+
+pub mod depth;
+pub mod l3;
+pub mod queue;
+pub mod snapshot;
 
-#[allow(dead_code)]
-#[derive(Clone, Copy, Debug)]
-/// This enum is an internal mod enum that describes the direction
+pub use depth::{Depth, L2Event, Level, LevelChange};
+pub use l3::{L3Event, OrderChange, RestingOrder};
+pub use queue::QueuePosition;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+/// This enum is a public enum that describes the direction
 /// of an order.
 ///
-/// This enum is used internally to select between logic applied to
-/// sell and buy orders.
-pub(super) enum Side {
+/// It is written as its one letter code, `B` or `S`, in every encoding.
+pub enum Side {
+    #[serde(rename = "B")]
     Buy,
+    #[serde(rename = "S")]
     Sell,
 }
 
 impl Side {
-    /// Provides a way to get a [Side] from a [String]
-    pub(super) fn new(s: String) -> Self {
-        if s.starts_with('B') {
-            Self::Buy
-        } else {
-            Self::Sell
+    /// Returns the one letter code of the side
+    pub fn as_char(&self) -> char {
+        match self {
+            Self::Buy => 'B',
+            Self::Sell => 'S',
         }
     }
 
-    /// Returns one letter string used at output
-    pub(super) fn get_one_letter_string(&self) -> String {
+    /// Returns the side an order of this side trades against
+    pub fn opposite(&self) -> Self {
         match self {
-            Self::Buy => "B".to_string(),
-            Self::Sell => "S".to_string(),
+            Self::Buy => Self::Sell,
+            Self::Sell => Self::Buy,
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
+impl TryFrom<char> for Side {
+    type Error = ParseSideError;
+
+    fn try_from(c: char) -> Result<Self, Self::Error> {
+        match c {
+            'B' => Ok(Self::Buy),
+            'S' => Ok(Self::Sell),
+            _ => Err(ParseSideError(c.to_string())),
+        }
+    }
+}
+
+impl FromStr for Side {
+    type Err = ParseSideError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "B" => Ok(Self::Buy),
+            "S" => Ok(Self::Sell),
+            _ => Err(ParseSideError(String::from(s))),
+        }
+    }
+}
+
+impl Display for Side {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "{}", self.as_char())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+/// This struct is the error of parsing a [Side] from anything but `B` or `S`
+pub struct ParseSideError(pub String);
+
+impl Display for ParseSideError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "invalid side: {:?}", self.0)
+    }
+}
+
+impl std::error::Error for ParseSideError {}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+/// This enum describes how long a new order stays live
+///
+/// It is written as `gtc`, `day` or `gtd:<nanos>` in CSV.
+pub enum TimeInForce {
+    /// Good till cancelled, the order never expires
+    #[default]
+    Gtc,
+    /// The order expires at the close of the day it was entered on, see
+    /// [Engine::with_day_close](crate::engine::Engine::with_day_close)
+    Day,
+    /// Good till date, the order expires at the given time
+    Gtd(Timestamp),
+}
+
+impl TimeInForce {
+    /// Returns whether the order never expires
+    pub fn is_gtc(&self) -> bool {
+        *self == TimeInForce::Gtc
+    }
+}
+
+impl FromStr for TimeInForce {
+    type Err = ParseTimeInForceError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gtc" => Ok(TimeInForce::Gtc),
+            "day" => Ok(TimeInForce::Day),
+            _ => s
+                .strip_prefix("gtd:")
+                .and_then(|expiry| expiry.parse().ok())
+                .map(TimeInForce::Gtd)
+                .ok_or_else(|| ParseTimeInForceError(String::from(s))),
+        }
+    }
+}
+
+impl Display for TimeInForce {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            TimeInForce::Gtc => f.write_str("gtc"),
+            TimeInForce::Day => f.write_str("day"),
+            TimeInForce::Gtd(expiry) => write!(f, "gtd:{}", expiry),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+/// This struct is the error of parsing an unknown [TimeInForce]
+pub struct ParseTimeInForceError(pub String);
+
+impl Display for ParseTimeInForceError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "invalid time in force: {:?}", self.0)
+    }
+}
+
+impl std::error::Error for ParseTimeInForceError {}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+/// This enum describes why a [UserAction] was rejected
+///
+/// Reasons are written as short codes such as `trading_disabled` in every
+/// encoding.
+pub enum RejectReason {
+    /// No reason was recorded, e.g. CSV output without the reason field
+    #[default]
+    Unspecified,
+    /// The order crosses the book while trading is disabled
+    TradingDisabled,
+    /// The order crosses the book but no resting order at its price has the
+    /// same quantity
+    NoMatchingQty,
+    /// The order crosses the book but no order rests at its price
+    NoMatchingPrice,
+    /// The quantity of the order's price level would overflow
+    LevelOverflow,
+    /// The order to cancel is not live
+    UnknownOrder,
+    /// The user already has a live order with this order id
+    DuplicateOrderId,
+    /// The order breaks the rules of its instrument
+    Invalid(InvalidOrder),
+    /// The order breaks a risk limit of its user
+    Risk(RiskBreach),
+    /// The user is blocked from entering new orders
+    UserBlocked,
+    /// The order expires before it could rest
+    Expired,
+    /// The symbol can not be carried by the market-data feed
+    InvalidSymbol,
+}
+
+impl RejectReason {
+    /// Every reason with its code
+    const CODES: [(RejectReason, &'static str); 22] = [
+        (RejectReason::Unspecified, "unspecified"),
+        (RejectReason::TradingDisabled, "trading_disabled"),
+        (RejectReason::NoMatchingQty, "no_matching_qty"),
+        (RejectReason::NoMatchingPrice, "no_matching_price"),
+        (RejectReason::LevelOverflow, "level_overflow"),
+        (RejectReason::UnknownOrder, "unknown_order"),
+        (RejectReason::DuplicateOrderId, "duplicate_order_id"),
+        (
+            RejectReason::Invalid(InvalidOrder::UnknownInstrument),
+            "unknown_instrument",
+        ),
+        (RejectReason::Invalid(InvalidOrder::OffTick), "off_tick"),
+        (RejectReason::Invalid(InvalidOrder::OddLot), "odd_lot"),
+        (
+            RejectReason::Invalid(InvalidOrder::BelowMinQty),
+            "below_min_qty",
+        ),
+        (
+            RejectReason::Invalid(InvalidOrder::AboveMaxQty),
+            "above_max_qty",
+        ),
+        (
+            RejectReason::Invalid(InvalidOrder::PriceOutOfRange),
+            "price_out_of_range",
+        ),
+        (RejectReason::Risk(RiskBreach::MaxOrderQty), "max_order_qty"),
+        (RejectReason::Risk(RiskBreach::MaxNotional), "max_notional"),
+        (
+            RejectReason::Risk(RiskBreach::MaxOpenOrders),
+            "max_open_orders",
+        ),
+        (RejectReason::Risk(RiskBreach::MaxPosition), "max_position"),
+        (RejectReason::Risk(RiskBreach::FatFinger), "fat_finger"),
+        (RejectReason::Risk(RiskBreach::RateLimit), "rate_limit"),
+        (RejectReason::UserBlocked, "user_blocked"),
+        (RejectReason::Expired, "expired"),
+        (RejectReason::InvalidSymbol, "invalid_symbol"),
+    ];
+
+    /// Returns the code of the reason
+    pub fn code(&self) -> &'static str {
+        RejectReason::CODES
+            .iter()
+            .find(|(reason, _)| reason == self)
+            .map(|(_, code)| *code)
+            .unwrap()
+    }
+}
+
+impl From<InvalidOrder> for RejectReason {
+    fn from(invalid: InvalidOrder) -> Self {
+        RejectReason::Invalid(invalid)
+    }
+}
+
+impl From<RiskBreach> for RejectReason {
+    fn from(breach: RiskBreach) -> Self {
+        RejectReason::Risk(breach)
+    }
+}
+
+impl FromStr for RejectReason {
+    type Err = ParseRejectReasonError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        RejectReason::CODES
+            .iter()
+            .find(|(_, code)| *code == s)
+            .map(|(reason, _)| *reason)
+            .ok_or_else(|| ParseRejectReasonError(String::from(s)))
+    }
+}
+
+impl Display for RejectReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        f.write_str(self.code())
+    }
+}
+
+impl Serialize for RejectReason {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.code())
+    }
+}
+
+impl<'de> Deserialize<'de> for RejectReason {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let code = <std::borrow::Cow<'de, str>>::deserialize(deserializer)?;
+        code.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+/// This struct is the error of parsing an unknown [RejectReason] code
+pub struct ParseRejectReasonError(pub String);
+
+impl Display for ParseRejectReasonError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "invalid reject reason: {:?}", self.0)
+    }
+}
+
+impl std::error::Error for ParseRejectReasonError {}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 /// This enum is a public enum that describes result of a [UserAction]
 /// on the [OrderBook]
 ///
@@ -51,9 +311,18 @@ pub enum Response {
     Acknowledge { user_id: u32, order_id: u32 },
     /// This variant of [Response] enum is used show the Top of Book has modified and
     /// there is a new Best
-    Best { side: String, price: u32, qty: u32 },
+    Best {
+        side: Side,
+        price: Price,
+        qty: Quantity,
+    },
     /// This variant of [Response] enum is used to reject a bad [UserAction]
-    Reject { user_id: u32, order_id: u32 },
+    Reject {
+        user_id: u32,
+        order_id: u32,
+        #[serde(default)]
+        reason: RejectReason,
+    },
     /// This variant of [Response] enum signals there is a match of prices that produced
     /// a trade
     Trade {
@@ -61,8 +330,8 @@ pub enum Response {
         buyer_order_id: u32,
         seller_id: u32,
         seller_order_id: u32,
-        price: u32,
-        qty: u32,
+        price: Price,
+        qty: Quantity,
     },
 }
 
@@ -78,19 +347,21 @@ impl Display for Response {
                     f,
                     "B, {}, {}, {}",
                     side,
-                    if *price == 0 {
+                    if *price == Price::ZERO {
                         String::from("-")
                     } else {
                         price.to_string()
                     },
-                    if *qty == 0 {
+                    if *qty == Quantity::ZERO {
                         String::from("-")
                     } else {
                         qty.to_string()
                     }
                 )
             }
-            Response::Reject { user_id, order_id } => {
+            Response::Reject {
+                user_id, order_id, ..
+            } => {
                 write!(f, "R, {}, {}", user_id, order_id)
             }
             Response::Trade {
@@ -111,6 +382,7 @@ impl Display for Response {
     }
 }
 
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 /// This enum is a public enum that describes the possible [UserAction]s
 /// on the [OrderBook]
 ///
@@ -120,11 +392,15 @@ pub enum UserAction {
     /// This enum variant describes a new order that comes from an user
     NewOrder {
         user_id: u32,
-        symbol: String,
-        price: u32,
-        qty: u32,
-        side: String,
+        symbol: Symbol,
+        price: Price,
+        qty: Quantity,
+        side: Side,
         order_id: u32,
+        /// How long the order stays live, left out of every encoding when
+        /// it is [TimeInForce::Gtc]
+        #[serde(default, skip_serializing_if = "TimeInForce::is_gtc")]
+        time_in_force: TimeInForce,
     },
     /// This enum variant describes a cancel order from an user
     CancelOrder { user_id: u32, order_id: u32 },
@@ -133,7 +409,7 @@ pub enum UserAction {
     Flush,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 /// This struct is a private struct used to represent an open [Order]
 /// in the [OrderBook] asks/bids.
 ///
@@ -141,14 +417,14 @@ pub enum UserAction {
 /// such as [Side] is derived from the implementation
 pub(super) struct Order {
     user_id: u32,
-    price: u32,
-    qty: u32,
+    price: Price,
+    qty: Quantity,
     order_id: u32,
 }
 
 impl Order {
     /// Function to create an [Order] from raw data
-    pub(super) fn new(user_id: u32, price: u32, qty: u32, order_id: u32) -> Self {
+    pub(super) fn new(user_id: u32, price: Price, qty: Quantity, order_id: u32) -> Self {
         Order {
             user_id,
             price,
@@ -158,12 +434,12 @@ impl Order {
     }
 
     /// Price getter
-    pub(super) fn price(&self) -> u32 {
+    pub(super) fn price(&self) -> Price {
         self.price
     }
 
     /// Quantity geter
-    pub(super) fn qty(&self) -> u32 {
+    pub(super) fn qty(&self) -> Quantity {
         self.qty
     }
 
@@ -172,10 +448,7 @@ impl Order {
     /// is Best of the Book
     pub(super) fn best(&self, side: Side) -> Response {
         Response::Best {
-            side: match side {
-                Side::Buy => String::from("B"),
-                Side::Sell => String::from("S"),
-            },
+            side,
             price: self.price,
             qty: self.qty,
         }
@@ -191,18 +464,19 @@ impl Order {
         }
     }
 
-    /// Gets a [Response::Acknowledge] from the order which
+    /// Gets a [Response::Reject] from the order which
     /// is returned to the user to signal that this order
     /// was rejected
-    pub(super) fn reject(&self) -> Response {
+    pub(super) fn reject(&self, reason: RejectReason) -> Response {
         Response::Reject {
             user_id: self.user_id,
             order_id: self.order_id,
+            reason,
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 /// Struct to keep records of trades
 /// This struct is used only when a trade is made
 pub(super) struct Trade {
@@ -210,8 +484,8 @@ pub(super) struct Trade {
     seller_id: u32,
     buyer_order_id: u32,
     seller_order_id: u32,
-    price: u32,
-    qty: u32,
+    price: Price,
+    qty: Quantity,
 }
 
 impl Trade {
@@ -241,27 +515,28 @@ impl Trade {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 /// This struct provides the needed functionality to create,
 /// interact with an [OrderBook]
 ///
-/// It hold information suchh as ask/bid [Order]s, trades, ticker.
+/// It hold information suchh as ask/bid orders, trades, ticker.
 ///
 /// # Examples
 ///
 /// ```
-/// use orderbook::{OrderBook, UserAction};
+/// use order_book::{OrderBook, Price, Quantity, Response, Side, Symbol, TimeInForce, UserAction};
 /// // Creates OrderBook - with Trading disabled
 /// let mut ob = OrderBook::new("IBM", false);
 ///
 /// // Add new Order
 /// let response = ob.new_user_action(UserAction::NewOrder{
 ///     user_id: 1,
-///     symbol: "IBM",
-///     price: 10,
-///     qty: 100,
-///     side: "B",
+///     symbol: Symbol::new("IBM"),
+///     price: Price(10),
+///     qty: Quantity(100),
+///     side: Side::Buy,
 ///     order_id: 1,
+///     time_in_force: TimeInForce::Gtc,
 /// });
 /// assert_eq!(
 ///     response.0,
@@ -269,41 +544,67 @@ impl Trade {
 /// ));
 /// assert_eq!(
 ///     response.1,
-///     Some(Response::Best{side: "B".to_string(), user_id: 1, order_id: 1}
+///     Some(Response::Best{side: Side::Buy, price: Price(10), qty: Quantity(100)}
 /// ));
 /// ```
-///
 pub struct OrderBook {
     /// Maximum bid
-    max_bid: u32,
+    max_bid: Price,
     /// Minimum ask
-    min_ask: u32,
+    min_ask: Price,
     /// OrderBook's ticker for which holds orders
-    ticker: String,
+    ticker: Symbol,
     /// [HashMap] with ask orders
-    asks: HashMap<u32, Vec<Order>>,
+    asks: HashMap<Price, Vec<Order>>,
     /// [HashMap] with bid orders
-    bids: HashMap<u32, Vec<Order>>,
+    bids: HashMap<Price, Vec<Order>>,
     /// [Vec] of [Trades] - this is empty if `trade_active` is `false`
     trades: Vec<Trade>,
     /// Enables trading functionality
     trade_active: bool,
+    /// Reference data every new order is validated against
+    instrument: Instrument,
+    /// Level changes not taken yet, `None` when they are not recorded
+    l2_events: Option<Vec<L2Event>>,
+    /// Order changes not taken yet, `None` when they are not recorded
+    l3_events: Option<Vec<L3Event>>,
+    /// Level of every resting order by `(user_id, order_id)`
+    locations: HashMap<(u32, u32), (Side, Price)>,
 }
 
 impl OrderBook {
-    /// Creates a new empty [OrderBook]
+    /// Creates a new empty [OrderBook] accepting any order
     pub fn new(ticker: &str, trade_active: bool) -> Self {
+        OrderBook::with_instrument(Instrument::new(Symbol::new(ticker)), trade_active)
+    }
+
+    /// Creates a new empty [OrderBook] for an instrument
+    pub fn with_instrument(instrument: Instrument, trade_active: bool) -> Self {
         OrderBook {
-            max_bid: 0,
-            min_ask: 0,
-            ticker: String::from(ticker),
+            max_bid: Price::ZERO,
+            min_ask: Price::ZERO,
+            ticker: instrument.symbol,
             asks: HashMap::new(),
             bids: HashMap::new(),
             trades: vec![],
             trade_active,
+            instrument,
+            l2_events: None,
+            l3_events: None,
+            locations: HashMap::new(),
         }
     }
 
+    /// Returns the reference data of the book
+    pub fn instrument(&self) -> &Instrument {
+        &self.instrument
+    }
+
+    /// Replaces the reference data, resting orders are kept as they are
+    pub fn set_instrument(&mut self, instrument: Instrument) {
+        self.instrument = instrument;
+    }
+
     #[allow(dead_code)]
     /// Ticker getter
     pub fn ticker(&self) -> &str {
@@ -322,33 +623,54 @@ impl OrderBook {
         self.asks.len()
     }
 
+    /// Returns the resting orders as the [UserAction]s that would recreate
+    /// them on an empty [OrderBook]
+    ///
+    /// Bids come first from the best price down, then asks from the best
+    /// price up. Orders of the same price keep their time priority. They
+    /// carry the symbol of the instrument, which outlives a flush of the
+    /// ticker.
+    pub fn resting_orders(&self) -> Vec<UserAction> {
+        self.orders()
+            .map(|o| UserAction::NewOrder {
+                user_id: o.user_id,
+                symbol: self.instrument.symbol,
+                price: o.price,
+                qty: o.qty,
+                side: o.side,
+                order_id: o.order_id,
+                time_in_force: TimeInForce::Gtc,
+            })
+            .collect()
+    }
+
     /// Private method that tries to insert a new order for given collection
     /// TODO: In case an order which matches offer, implement a way to print
     /// Ack, Trade, Best
     /// As of now it only prins: Ack, Trade
+    #[allow(clippy::too_many_arguments)]
     fn new_order_logic(
         // Collection in which to insert
-        col_insert: &mut HashMap<u32, Vec<Order>>,
+        col_insert: &mut HashMap<Price, Vec<Order>>,
         // Collection in which to search equivalent offer
-        col_search: &mut HashMap<u32, Vec<Order>>,
+        col_search: &mut HashMap<Price, Vec<Order>>,
         // Vec of trades in case of need
         trades: &mut Vec<Trade>,
         // Best price of same time - competitors
-        best: &mut u32,
+        best: &mut Price,
         // Best opposite price - the offer
-        best_opposite: &mut u32,
+        best_opposite: &mut Price,
         order: Order,
         side: Side,
         trade_active: bool,
         // function to check whether price crosses book
-        f: impl Fn(u32, u32) -> bool,
+        f: impl Fn(Price, Price) -> bool,
+        // Order changes in case they are recorded
+        mut l3_events: Option<&mut Vec<L3Event>>,
     ) -> (Option<Response>, Option<Response>) {
-        let mut res = (None, None);
+        let res;
         let price = order.price();
 
-        // Get Vec of orders corresponding with price in target insert collection
-        let entry = col_insert.entry(order.price()).or_insert(vec![]);
-
         // if price crosses book and there are opposing offers
         if f(price, *best_opposite) && !col_search.is_empty() {
             // Check if corresponding order can trade
@@ -367,10 +689,16 @@ impl OrderBook {
                             // Get ack response before consuming
                             let ack = order.ack();
 
+                            // The resting order leaves the book
+                            let resting = val.remove(k);
+                            l3::record(&mut l3_events, || {
+                                L3Event::new(OrderChange::Execute, side.opposite(), &resting)
+                            });
+
                             // Get trade by consuming the 2 orders
                             let trade = match side {
-                                Side::Buy => Trade::new(order, val.remove(k)),
-                                Side::Sell => Trade::new(val.remove(k), order),
+                                Side::Buy => Trade::new(order, resting),
+                                Side::Sell => Trade::new(resting, order),
                             };
 
                             // Get trade response to return from this method
@@ -384,63 +712,73 @@ impl OrderBook {
 
                                 // Recalculate best opposite offer
                                 *best_opposite = match side {
-                                    Side::Buy => *col_search.keys().min().unwrap_or(&0),
-                                    Side::Sell => *col_search.keys().max().unwrap_or(&0),
+                                    Side::Buy => *col_search.keys().min().unwrap_or(&Price::ZERO),
+                                    Side::Sell => *col_search.keys().max().unwrap_or(&Price::ZERO),
                                 };
-
                             }
                             res = (Some(ack), Some(trade_resp));
                         }
                         // If corresponding price does not exist in offers list -> reject book crossing
                         None => {
-                            res = (Some(order.reject()), None);
+                            res = (Some(order.reject(RejectReason::NoMatchingQty)), None);
                         }
                     }
                 }
+                // If no offer rests at the order's price -> reject book crossing
+                else {
+                    res = (Some(order.reject(RejectReason::NoMatchingPrice)), None);
+                }
             }
             // Reject Order - Trading not allowed
             else {
-                res = (Some(order.reject()), None);
+                res = (Some(order.reject(RejectReason::TradingDisabled)), None);
             }
         }
+        // Reject Order - the quantity of its price level would overflow
+        else if Quantity::checked_sum(
+            col_insert
+                .get(&price)
+                .into_iter()
+                .flatten()
+                .map(Order::qty)
+                .chain([order.qty()]),
+        )
+        .is_none()
+        {
+            res = (Some(order.reject(RejectReason::LevelOverflow)), None);
+        }
         // Check if new order exceeds current best
-        else if f(price, *best) || *best == 0 {
+        else if f(price, *best) || *best == Price::ZERO {
             // Get ack response before consuming order
             let ack = order.ack();
 
             // Change best price
             *best = price;
 
-            // Push order to OrderBook
+            // Push order to OrderBook in the Vec of orders corresponding with price
+            l3::record(&mut l3_events, || {
+                L3Event::new(OrderChange::Add, side, &order)
+            });
+            let entry = col_insert.entry(price).or_default();
             entry.push(order);
 
-            // Get Response with Best being Sum of quantities
+            // Get Response with Best being Sum of quantities, which was
+            // checked not to overflow
             res = (
                 Some(ack),
-                Some(entry.iter().fold(
-                    Response::Best {
-                        side: side.get_one_letter_string(),
-                        price: 0,
-                        qty: 0,
-                    },
-                    |acc, o| match acc {
-                        Response::Best {
-                            side,
-                            price: _,
-                            qty,
-                        } => Response::Best {
-                            side,
-                            price: o.price(),
-                            qty: qty + o.qty(),
-                        },
-                        _ => acc,
-                    },
-                )),
+                Some(Response::Best {
+                    side,
+                    price,
+                    qty: Quantity::checked_sum(entry.iter().map(Order::qty)).unwrap(),
+                }),
             );
         } else {
             // if none of the above matches, ack order
             res = (Some(order.ack()), None);
-            entry.push(order);
+            l3::record(&mut l3_events, || {
+                L3Event::new(OrderChange::Add, side, &order)
+            });
+            col_insert.entry(price).or_default().push(order);
         }
 
         res
@@ -448,6 +786,11 @@ impl OrderBook {
 
     /// Private method that tries to insert a new order
     fn new_order(&mut self, side: Side, order: Order) -> (Option<Response>, Option<Response>) {
+        // Reject Order - it breaks the rules of the instrument
+        if let Err(invalid) = self.instrument.validate(order.price(), order.qty()) {
+            return (Some(order.reject(invalid.into())), None);
+        }
+
         match side {
             // Side::Buy => self.new_buy_order(order),
             Side::Buy => Self::new_order_logic(
@@ -460,6 +803,7 @@ impl OrderBook {
                 Side::Buy,
                 self.trade_active,
                 |a, b| a >= b,
+                self.l3_events.as_mut(),
             ),
             Side::Sell => Self::new_order_logic(
                 &mut self.asks,
@@ -471,16 +815,17 @@ impl OrderBook {
                 Side::Sell,
                 self.trade_active,
                 |a, b| a <= b,
+                self.l3_events.as_mut(),
             ),
         }
     }
 
     /// Search for Order in HashMap
     fn get_order_index(
-        col: &HashMap<u32, Vec<Order>>,
+        col: &HashMap<Price, Vec<Order>>,
         user_id: u32,
         order_id: u32,
-    ) -> Option<(u32, usize)> {
+    ) -> Option<(Price, usize)> {
         let mut res = None;
 
         // Search for the order in bids HashMap
@@ -506,11 +851,12 @@ impl OrderBook {
 
     /// This private method runs the logic to cancel orders
     fn cancel_order_logic(
-        col: &mut HashMap<u32, Vec<Order>>,
+        col: &mut HashMap<Price, Vec<Order>>,
         side: Side,
         user_id: u32,
         order_id: u32,
-        best: &mut u32,
+        best: &mut Price,
+        mut l3_events: Option<&mut Vec<L3Event>>,
     ) -> (Option<Response>, Option<Response>) {
         let res;
         let found;
@@ -521,7 +867,7 @@ impl OrderBook {
             }
             None => {
                 found = false;
-                (0, 0)
+                (Price::ZERO, 0)
             }
         };
 
@@ -529,6 +875,9 @@ impl OrderBook {
         if found {
             // Get mutable reference to containing vec
             let v = col.get_mut(&price).unwrap();
+            l3::record(&mut l3_events, || {
+                L3Event::new(OrderChange::Delete, side, &v[order_idx])
+            });
 
             // Check if it is the only order -> eliminate the whole entry
             if v.len() == 1 {
@@ -554,13 +903,13 @@ impl OrderBook {
                         }
                         // if new best -> return (Ack, Best(Side, 0, 0))
                         None => {
-                            *best = 0;
+                            *best = Price::ZERO;
                             res = (
                                 Some(Response::Acknowledge { user_id, order_id }),
                                 Some(Response::Best {
-                                    side: side.get_one_letter_string(),
-                                    price: 0,
-                                    qty: 0,
+                                    side,
+                                    price: Price::ZERO,
+                                    qty: Quantity::ZERO,
                                 }),
                             );
                         }
@@ -584,7 +933,15 @@ impl OrderBook {
             }
             res
         } else {
-            (Some(Response::Reject { user_id, order_id }), None)
+            let reason = RejectReason::UnknownOrder;
+            (
+                Some(Response::Reject {
+                    user_id,
+                    order_id,
+                    reason,
+                }),
+                None,
+            )
         }
     }
 
@@ -601,6 +958,7 @@ impl OrderBook {
             user_id,
             order_id,
             &mut self.min_ask,
+            self.l3_events.as_mut(),
         );
         match res {
             // if we get two responses (Ack, Best) -> means it is canceled and new best
@@ -616,15 +974,30 @@ impl OrderBook {
                 user_id,
                 order_id,
                 &mut self.max_bid,
+                self.l3_events.as_mut(),
             ),
         }
     }
 
     /// Private method that flushes the [OrderBook]
     fn flush(&mut self) {
-        self.max_bid = 0;
-        self.min_ask = 0;
-        self.ticker = String::from("");
+        // Every resting order is deleted
+        let mut l3_events = self.l3_events.take();
+        if let Some(events) = l3_events.as_mut() {
+            events.extend(self.orders().map(|o| L3Event {
+                change: OrderChange::Delete,
+                side: o.side,
+                price: o.price,
+                qty: o.qty,
+                user_id: o.user_id,
+                order_id: o.order_id,
+            }));
+        }
+        self.l3_events = l3_events;
+
+        self.max_bid = Price::ZERO;
+        self.min_ask = Price::ZERO;
+        self.ticker = Symbol::default();
         self.asks.clear();
         self.bids.clear();
     }
@@ -634,7 +1007,17 @@ impl OrderBook {
     /// This method translates the [UserAction] received as parameter
     /// to a suitable input dependng on the type of [UserAction]
     pub fn new_user_action(&mut self, action: UserAction) -> (Option<Response>, Option<Response>) {
-        match action {
+        // Levels are only compared when their changes are recorded
+        let (levels, before) = match self.l2_events {
+            Some(_) => {
+                let levels = self.touched_levels(&action);
+                let before = self.level_quantities(&levels);
+                (levels, before)
+            }
+            None => (vec![], vec![]),
+        };
+
+        let responses = match action {
             UserAction::NewOrder {
                 user_id,
                 symbol: _,
@@ -642,13 +1025,18 @@ impl OrderBook {
                 qty,
                 side,
                 order_id,
-            } => self.new_order(Side::new(side), Order::new(user_id, price, qty, order_id)),
+                ..
+            } => self.new_order(side, Order::new(user_id, price, qty, order_id)),
             UserAction::CancelOrder { user_id, order_id } => self.cancel_order(user_id, order_id),
             UserAction::Flush => {
                 self.flush();
                 (None, None)
             }
-        }
+        };
+
+        self.update_locations(&action, &responses);
+        self.record_level_changes(&levels, &before);
+        responses
     }
 }
 
@@ -660,11 +1048,12 @@ mod tests {
         ($ob:expr, $user_id:expr, $symbol:expr, $price:expr, $qty:expr, $side:expr, $order_id:expr) => {
             $ob.new_user_action(UserAction::NewOrder {
                 user_id: $user_id,
-                symbol: String::from($symbol),
-                price: $price,
-                qty: $qty,
-                side: String::from($side),
+                symbol: Symbol::new($symbol),
+                price: Price($price),
+                qty: Quantity($qty),
+                side: $side.parse().unwrap(),
                 order_id: $order_id,
+                time_in_force: TimeInForce::Gtc,
             })
         };
         ($ob:expr, $user_id:expr, $order_id:expr) => {
@@ -678,6 +1067,63 @@ mod tests {
         };
     }
 
+    #[test]
+    fn test_side() {
+        assert_eq!("B".parse(), Ok(Side::Buy));
+        assert_eq!(Side::try_from('S'), Ok(Side::Sell));
+        assert!("X".parse::<Side>().is_err());
+        assert!("Buy".parse::<Side>().is_err());
+        assert_eq!(Side::Sell.to_string(), "S");
+        assert_eq!(Side::Buy.opposite(), Side::Sell);
+
+        // Encoded as its one letter code
+        assert_eq!(serde_json::to_string(&Side::Buy).unwrap(), "\"B\"");
+        assert!(serde_json::from_str::<Side>("\"X\"").is_err());
+    }
+
+    #[test]
+    fn test_reject_reasons() {
+        let reason = |res: (Option<Response>, Option<Response>)| match res.0 {
+            Some(Response::Reject { reason, .. }) => reason,
+            other => panic!("not a reject: {:?}", other),
+        };
+
+        let mut ob = OrderBook::new("IBM", true);
+        add_new_order!(ob, 1, "IBM", 10, 100, "S", 1);
+        assert_eq!(
+            reason(add_new_order!(ob, 2, "IBM", 10, 50, "B", 1)),
+            RejectReason::NoMatchingQty
+        );
+        assert_eq!(
+            reason(add_new_order!(ob, 2, "IBM", 11, 100, "B", 2)),
+            RejectReason::NoMatchingPrice
+        );
+        assert_eq!(ob.bids(), 0);
+        assert_eq!(reason(add_new_order!(ob, 2, 9)), RejectReason::UnknownOrder);
+        add_new_order!(ob, 1, "IBM", 11, u64::MAX, "S", 2);
+        assert_eq!(
+            reason(add_new_order!(ob, 1, "IBM", 11, 1, "S", 3)),
+            RejectReason::LevelOverflow
+        );
+
+        let mut instrument = Instrument::new(Symbol::new("IBM"));
+        instrument.tick_size = Price(5);
+        let mut ob = OrderBook::with_instrument(instrument, false);
+        assert_eq!(
+            reason(add_new_order!(ob, 1, "IBM", 12, 100, "S", 1)),
+            RejectReason::Invalid(InvalidOrder::OffTick)
+        );
+        add_new_order!(ob, 1, "IBM", 10, 100, "S", 1);
+        assert_eq!(
+            reason(add_new_order!(ob, 2, "IBM", 10, 100, "B", 1)),
+            RejectReason::TradingDisabled
+        );
+        assert_eq!(
+            "off_tick".parse(),
+            Ok(RejectReason::Invalid(InvalidOrder::OffTick))
+        );
+    }
+
     #[test]
     #[ignore]
     fn test_empty_orderbook() {
@@ -692,11 +1138,12 @@ mod tests {
 
         ob.new_user_action(UserAction::NewOrder {
             user_id: 1,
-            symbol: String::from("TSLA"),
-            price: 10,
-            qty: 100,
-            side: String::from("B"),
+            symbol: Symbol::new("TSLA"),
+            price: Price(10),
+            qty: Quantity(100),
+            side: Side::Buy,
             order_id: 1,
+            time_in_force: TimeInForce::Gtc,
         });
 
         assert_eq!("TSLA", ob.ticker());
@@ -709,20 +1156,22 @@ mod tests {
 
         let res1 = ob.new_user_action(UserAction::NewOrder {
             user_id: 1,
-            symbol: String::from("TSLA"),
-            price: 10,
-            qty: 100,
-            side: String::from("B"),
+            symbol: Symbol::new("TSLA"),
+            price: Price(10),
+            qty: Quantity(100),
+            side: Side::Buy,
             order_id: 1,
+            time_in_force: TimeInForce::Gtc,
         });
 
         let res2 = ob.new_user_action(UserAction::NewOrder {
             user_id: 2,
-            symbol: String::from("TSLA"),
-            price: 10,
-            qty: 100,
-            side: String::from("S"),
+            symbol: Symbol::new("TSLA"),
+            price: Price(10),
+            qty: Quantity(100),
+            side: Side::Sell,
             order_id: 1,
+            time_in_force: TimeInForce::Gtc,
         });
 
         assert_eq!("TSLA", ob.ticker());
@@ -736,16 +1185,17 @@ mod tests {
         assert_eq!(
             res1.1,
             Some(Response::Best {
-                side: String::from("B"),
-                price: 10,
-                qty: 100
+                side: Side::Buy,
+                price: Price(10),
+                qty: Quantity(100)
             })
         );
         assert_eq!(
             res2.0,
             Some(Response::Reject {
                 user_id: 2,
-                order_id: 1
+                order_id: 1,
+                reason: RejectReason::TradingDisabled
             })
         );
         assert_eq!(res2.0, None);
@@ -758,29 +1208,32 @@ mod tests {
 
         let res1 = ob.new_user_action(UserAction::NewOrder {
             user_id: 1,
-            symbol: String::from("TSLA"),
-            price: 10,
-            qty: 100,
-            side: String::from("B"),
+            symbol: Symbol::new("TSLA"),
+            price: Price(10),
+            qty: Quantity(100),
+            side: Side::Buy,
             order_id: 1,
+            time_in_force: TimeInForce::Gtc,
         });
 
         let res2 = ob.new_user_action(UserAction::NewOrder {
             user_id: 2,
-            symbol: String::from("TSLA"),
-            price: 12,
-            qty: 100,
-            side: String::from("S"),
+            symbol: Symbol::new("TSLA"),
+            price: Price(12),
+            qty: Quantity(100),
+            side: Side::Sell,
             order_id: 1,
+            time_in_force: TimeInForce::Gtc,
         });
 
         let res3 = ob.new_user_action(UserAction::NewOrder {
             user_id: 3,
-            symbol: String::from("TSLA"),
-            price: 11,
-            qty: 100,
-            side: String::from("S"),
+            symbol: Symbol::new("TSLA"),
+            price: Price(11),
+            qty: Quantity(100),
+            side: Side::Sell,
             order_id: 1,
+            time_in_force: TimeInForce::Gtc,
         });
 
         assert_eq!("TSLA", ob.ticker());
@@ -794,9 +1247,9 @@ mod tests {
         assert_eq!(
             res1.1,
             Some(Response::Best {
-                side: String::from("B"),
-                price: 10,
-                qty: 100
+                side: Side::Buy,
+                price: Price(10),
+                qty: Quantity(100)
             })
         );
         assert_eq!(
@@ -809,9 +1262,9 @@ mod tests {
         assert_eq!(
             res2.1,
             Some(Response::Best {
-                side: String::from("B"),
-                price: 12,
-                qty: 100
+                side: Side::Buy,
+                price: Price(12),
+                qty: Quantity(100)
             })
         );
         assert_eq!(
@@ -845,9 +1298,9 @@ mod tests {
         assert_eq!(
             res1.1,
             Some(Response::Best {
-                side: String::from("B"),
-                price: 10,
-                qty: 100
+                side: Side::Buy,
+                price: Price(10),
+                qty: Quantity(100)
             })
         );
 
@@ -865,9 +1318,9 @@ mod tests {
         assert_eq!(
             res2.1,
             Some(Response::Best {
-                side: String::from("S"),
-                price: 12,
-                qty: 100
+                side: Side::Sell,
+                price: Price(12),
+                qty: Quantity(100)
             })
         );
 
@@ -897,9 +1350,9 @@ mod tests {
         assert_eq!(
             res4.1,
             Some(Response::Best {
-                side: String::from("S"),
-                price: 11,
-                qty: 100
+                side: Side::Sell,
+                price: Price(11),
+                qty: Quantity(100)
             })
         );
 
@@ -911,7 +1364,8 @@ mod tests {
             res5.0,
             Some(Response::Reject {
                 user_id: 1,
-                order_id: 3
+                order_id: 3,
+                reason: RejectReason::TradingDisabled
             })
         );
         assert_eq!(res5.1, None);
@@ -923,7 +1377,8 @@ mod tests {
             res6.0,
             Some(Response::Reject {
                 user_id: 2,
-                order_id: 103
+                order_id: 103,
+                reason: RejectReason::TradingDisabled
             })
         );
         assert_eq!(res6.1, None);
@@ -943,9 +1398,9 @@ mod tests {
         assert_eq!(
             res7.1,
             Some(Response::Best {
-                side: String::from("B"),
-                price: 10,
-                qty: 200
+                side: Side::Buy,
+                price: Price(10),
+                qty: Quantity(200)
             })
         );
 
@@ -963,9 +1418,9 @@ mod tests {
         assert_eq!(
             res8.1,
             Some(Response::Best {
-                side: String::from("S"),
-                price: 11,
-                qty: 200
+                side: Side::Sell,
+                price: Price(11),
+                qty: Quantity(200)
             })
         );
 
@@ -998,9 +1453,9 @@ mod tests {
         assert_eq!(
             res1.1,
             Some(Response::Best {
-                side: String::from("B"),
-                price: 10,
-                qty: 100
+                side: Side::Buy,
+                price: Price(10),
+                qty: Quantity(100)
             })
         );
 
@@ -1018,9 +1473,9 @@ mod tests {
         assert_eq!(
             res2.1,
             Some(Response::Best {
-                side: String::from("S"),
-                price: 12,
-                qty: 100
+                side: Side::Sell,
+                price: Price(12),
+                qty: Quantity(100)
             })
         );
 
@@ -1038,9 +1493,9 @@ mod tests {
         assert_eq!(
             res3.1,
             Some(Response::Best {
-                side: String::from("S"),
-                price: 11,
-                qty: 100
+                side: Side::Sell,
+                price: Price(11),
+                qty: Quantity(100)
             })
         );
 
@@ -1052,7 +1507,8 @@ mod tests {
             res4.0,
             Some(Response::Reject {
                 user_id: 2,
-                order_id: 103
+                order_id: 103,
+                reason: RejectReason::TradingDisabled
             })
         );
         assert_eq!(res4.1, None);
@@ -1072,9 +1528,9 @@ mod tests {
         assert_eq!(
             res5.1,
             Some(Response::Best {
-                side: String::from("B"),
-                price: 10,
-                qty: 200
+                side: Side::Buy,
+                price: Price(10),
+                qty: Quantity(200)
             })
         );
 
@@ -1107,9 +1563,9 @@ mod tests {
         assert_eq!(
             res1.1,
             Some(Response::Best {
-                side: String::from("B"),
-                price: 10,
-                qty: 100
+                side: Side::Buy,
+                price: Price(10),
+                qty: Quantity(100)
             })
         );
 
@@ -1139,9 +1595,9 @@ mod tests {
         assert_eq!(
             res3.1,
             Some(Response::Best {
-                side: String::from("S"),
-                price: 11,
-                qty: 100
+                side: Side::Sell,
+                price: Price(11),
+                qty: Quantity(100)
             })
         );
 
@@ -1153,7 +1609,8 @@ mod tests {
             res4.0,
             Some(Response::Reject {
                 user_id: 1,
-                order_id: 2
+                order_id: 2,
+                reason: RejectReason::TradingDisabled
             })
         );
         assert_eq!(res4.1, None);
@@ -1173,9 +1630,9 @@ mod tests {
         assert_eq!(
             res5.1,
             Some(Response::Best {
-                side: String::from("S"),
-                price: 11,
-                qty: 200
+                side: Side::Sell,
+                price: Price(11),
+                qty: Quantity(200)
             })
         );
 
@@ -1208,9 +1665,9 @@ mod tests {
         assert_eq!(
             res1.1,
             Some(Response::Best {
-                side: String::from("B"),
-                price: 10,
-                qty: 100
+                side: Side::Buy,
+                price: Price(10),
+                qty: Quantity(100)
             })
         );
 
@@ -1228,9 +1685,9 @@ mod tests {
         assert_eq!(
             res2.1,
             Some(Response::Best {
-                side: String::from("S"),
-                price: 12,
-                qty: 100
+                side: Side::Sell,
+                price: Price(12),
+                qty: Quantity(100)
             })
         );
 
@@ -1260,9 +1717,9 @@ mod tests {
         assert_eq!(
             res4.1,
             Some(Response::Best {
-                side: String::from("S"),
-                price: 11,
-                qty: 100
+                side: Side::Sell,
+                price: Price(11),
+                qty: Quantity(100)
             })
         );
 
@@ -1274,7 +1731,8 @@ mod tests {
             res5.0,
             Some(Response::Reject {
                 user_id: 2,
-                order_id: 103
+                order_id: 103,
+                reason: RejectReason::TradingDisabled
             })
         );
         assert_eq!(res5.1, None);
@@ -1307,9 +1765,9 @@ mod tests {
         assert_eq!(
             res1.1,
             Some(Response::Best {
-                side: String::from("B"),
-                price: 10,
-                qty: 100
+                side: Side::Buy,
+                price: Price(10),
+                qty: Quantity(100)
             })
         );
 
@@ -1327,9 +1785,9 @@ mod tests {
         assert_eq!(
             res2.1,
             Some(Response::Best {
-                side: String::from("S"),
-                price: 12,
-                qty: 100
+                side: Side::Sell,
+                price: Price(12),
+                qty: Quantity(100)
             })
         );
 
@@ -1359,9 +1817,9 @@ mod tests {
         assert_eq!(
             res4.1,
             Some(Response::Best {
-                side: String::from("S"),
-                price: 11,
-                qty: 100
+                side: Side::Sell,
+                price: Price(11),
+                qty: Quantity(100)
             })
         );
 
@@ -1373,7 +1831,8 @@ mod tests {
             res5.0,
             Some(Response::Reject {
                 user_id: 1,
-                order_id: 103
+                order_id: 103,
+                reason: RejectReason::TradingDisabled
             })
         );
         assert_eq!(res5.1, None);
@@ -1407,9 +1866,9 @@ mod tests {
         assert_eq!(
             res1.1,
             Some(Response::Best {
-                side: String::from("B"),
-                price: 10,
-                qty: 100
+                side: Side::Buy,
+                price: Price(10),
+                qty: Quantity(100)
             })
         );
 
@@ -1427,9 +1886,9 @@ mod tests {
         assert_eq!(
             res2.1,
             Some(Response::Best {
-                side: String::from("S"),
-                price: 16,
-                qty: 100
+                side: Side::Sell,
+                price: Price(16),
+                qty: Quantity(100)
             })
         );
 
@@ -1459,9 +1918,9 @@ mod tests {
         assert_eq!(
             res4.1,
             Some(Response::Best {
-                side: String::from("S"),
-                price: 15,
-                qty: 100
+                side: Side::Sell,
+                price: Price(15),
+                qty: Quantity(100)
             })
         );
 
@@ -1480,9 +1939,9 @@ mod tests {
         assert_eq!(
             res5.1,
             Some(Response::Best {
-                side: String::from("B"),
-                price: 11,
-                qty: 100
+                side: Side::Buy,
+                price: Price(11),
+                qty: Quantity(100)
             })
         );
 
@@ -1500,9 +1959,9 @@ mod tests {
         assert_eq!(
             res6.1,
             Some(Response::Best {
-                side: String::from("S"),
-                price: 14,
-                qty: 100
+                side: Side::Sell,
+                price: Price(14),
+                qty: Quantity(100)
             })
         );
 
@@ -1535,9 +1994,9 @@ mod tests {
         assert_eq!(
             res1.1,
             Some(Response::Best {
-                side: String::from("B"),
-                price: 10,
-                qty: 100
+                side: Side::Buy,
+                price: Price(10),
+                qty: Quantity(100)
             })
         );
 
@@ -1555,9 +2014,9 @@ mod tests {
         assert_eq!(
             res2.1,
             Some(Response::Best {
-                side: String::from("S"),
-                price: 12,
-                qty: 100
+                side: Side::Sell,
+                price: Price(12),
+                qty: Quantity(100)
             })
         );
 
@@ -1587,9 +2046,9 @@ mod tests {
         assert_eq!(
             res4.1,
             Some(Response::Best {
-                side: String::from("S"),
-                price: 11,
-                qty: 100
+                side: Side::Sell,
+                price: Price(11),
+                qty: Quantity(100)
             })
         );
 
@@ -1601,7 +2060,8 @@ mod tests {
             res5.0,
             Some(Response::Reject {
                 user_id: 2,
-                order_id: 103
+                order_id: 103,
+                reason: RejectReason::TradingDisabled
             })
         );
         assert_eq!(res5.1, None);
@@ -1635,9 +2095,9 @@ mod tests {
         assert_eq!(
             res1.1,
             Some(Response::Best {
-                side: String::from("B"),
-                price: 10,
-                qty: 100
+                side: Side::Buy,
+                price: Price(10),
+                qty: Quantity(100)
             })
         );
 
@@ -1655,9 +2115,9 @@ mod tests {
         assert_eq!(
             res2.1,
             Some(Response::Best {
-                side: String::from("S"),
-                price: 12,
-                qty: 100
+                side: Side::Sell,
+                price: Price(12),
+                qty: Quantity(100)
             })
         );
 
@@ -1687,9 +2147,9 @@ mod tests {
         assert_eq!(
             res4.1,
             Some(Response::Best {
-                side: String::from("S"),
-                price: 11,
-                qty: 100
+                side: Side::Sell,
+                price: Price(11),
+                qty: Quantity(100)
             })
         );
 
@@ -1701,7 +2161,8 @@ mod tests {
             res5.0,
             Some(Response::Reject {
                 user_id: 1,
-                order_id: 3
+                order_id: 3,
+                reason: RejectReason::TradingDisabled
             })
         );
         assert_eq!(res5.1, None);
@@ -1735,9 +2196,9 @@ mod tests {
         assert_eq!(
             res1.1,
             Some(Response::Best {
-                side: String::from("B"),
-                price: 10,
-                qty: 100
+                side: Side::Buy,
+                price: Price(10),
+                qty: Quantity(100)
             })
         );
 
@@ -1755,9 +2216,9 @@ mod tests {
         assert_eq!(
             res2.1,
             Some(Response::Best {
-                side: String::from("S"),
-                price: 12,
-                qty: 100
+                side: Side::Sell,
+                price: Price(12),
+                qty: Quantity(100)
             })
         );
 
@@ -1787,9 +2248,9 @@ mod tests {
         assert_eq!(
             res4.1,
             Some(Response::Best {
-                side: String::from("S"),
-                price: 11,
-                qty: 100
+                side: Side::Sell,
+                price: Price(11),
+                qty: Quantity(100)
             })
         );
 
@@ -1808,9 +2269,9 @@ mod tests {
         assert_eq!(
             res5.1,
             Some(Response::Best {
-                side: String::from("B"),
-                price: 9,
-                qty: 100
+                side: Side::Buy,
+                price: Price(9),
+                qty: Quantity(100)
             })
         );
 
@@ -1828,9 +2289,9 @@ mod tests {
         assert_eq!(
             res6.1,
             Some(Response::Best {
-                side: String::from("S"),
-                price: 12,
-                qty: 100
+                side: Side::Sell,
+                price: Price(12),
+                qty: Quantity(100)
             })
         );
 
@@ -1863,9 +2324,9 @@ mod tests {
         assert_eq!(
             res1.1,
             Some(Response::Best {
-                side: String::from("B"),
-                price: 10,
-                qty: 100
+                side: Side::Buy,
+                price: Price(10),
+                qty: Quantity(100)
             })
         );
 
@@ -1883,9 +2344,9 @@ mod tests {
         assert_eq!(
             res2.1,
             Some(Response::Best {
-                side: String::from("S"),
-                price: 12,
-                qty: 100
+                side: Side::Sell,
+                price: Price(12),
+                qty: Quantity(100)
             })
         );
 
@@ -1915,9 +2376,9 @@ mod tests {
         assert_eq!(
             res4.1,
             Some(Response::Best {
-                side: String::from("S"),
-                price: 11,
-                qty: 100
+                side: Side::Sell,
+                price: Price(11),
+                qty: Quantity(100)
             })
         );
 
@@ -1975,9 +2436,9 @@ mod tests {
         assert_eq!(
             res1.1,
             Some(Response::Best {
-                side: String::from("B"),
-                price: 10,
-                qty: 100
+                side: Side::Buy,
+                price: Price(10),
+                qty: Quantity(100)
             })
         );
 
@@ -1995,9 +2456,9 @@ mod tests {
         assert_eq!(
             res2.1,
             Some(Response::Best {
-                side: String::from("S"),
-                price: 12,
-                qty: 100
+                side: Side::Sell,
+                price: Price(12),
+                qty: Quantity(100)
             })
         );
 
@@ -2027,9 +2488,9 @@ mod tests {
         assert_eq!(
             res4.1,
             Some(Response::Best {
-                side: String::from("S"),
-                price: 11,
-                qty: 100
+                side: Side::Sell,
+                price: Price(11),
+                qty: Quantity(100)
             })
         );
 
@@ -2048,9 +2509,9 @@ mod tests {
         assert_eq!(
             res5.1,
             Some(Response::Best {
-                side: String::from("B"),
-                price: 9,
-                qty: 100
+                side: Side::Buy,
+                price: Price(9),
+                qty: Quantity(100)
             })
         );
 
@@ -2068,9 +2529,9 @@ mod tests {
         assert_eq!(
             res6.1,
             Some(Response::Best {
-                side: String::from("B"),
-                price: 0,
-                qty: 0
+                side: Side::Buy,
+                price: Price(0),
+                qty: Quantity(0)
             })
         );
 
@@ -2103,9 +2564,9 @@ mod tests {
         assert_eq!(
             res1.1,
             Some(Response::Best {
-                side: String::from("B"),
-                price: 10,
-                qty: 100
+                side: Side::Buy,
+                price: Price(10),
+                qty: Quantity(100)
             })
         );
 
@@ -2123,9 +2584,9 @@ mod tests {
         assert_eq!(
             res2.1,
             Some(Response::Best {
-                side: String::from("S"),
-                price: 12,
-                qty: 100
+                side: Side::Sell,
+                price: Price(12),
+                qty: Quantity(100)
             })
         );
 
@@ -2155,9 +2616,9 @@ mod tests {
         assert_eq!(
             res4.1,
             Some(Response::Best {
-                side: String::from("S"),
-                price: 11,
-                qty: 100
+                side: Side::Sell,
+                price: Price(11),
+                qty: Quantity(100)
             })
         );
 
@@ -2176,9 +2637,9 @@ mod tests {
         assert_eq!(
             res5.1,
             Some(Response::Best {
-                side: String::from("S"),
-                price: 11,
-                qty: 200
+                side: Side::Sell,
+                price: Price(11),
+                qty: Quantity(200)
             })
         );
 
@@ -2196,9 +2657,9 @@ mod tests {
         assert_eq!(
             res6.1,
             Some(Response::Best {
-                side: String::from("S"),
-                price: 11,
-                qty: 100
+                side: Side::Sell,
+                price: Price(11),
+                qty: Quantity(100)
             })
         );
 
@@ -2217,9 +2678,9 @@ mod tests {
         assert_eq!(
             res7.1,
             Some(Response::Best {
-                side: String::from("S"),
-                price: 12,
-                qty: 100
+                side: Side::Sell,
+                price: Price(12),
+                qty: Quantity(100)
             })
         );
 
@@ -2237,9 +2698,9 @@ mod tests {
         assert_eq!(
             res8.1,
             Some(Response::Best {
-                side: String::from("S"),
-                price: 0,
-                qty: 0
+                side: Side::Sell,
+                price: Price(0),
+                qty: Quantity(0)
             })
         );
 
@@ -2271,9 +2732,9 @@ mod tests {
         assert_eq!(
             res1.1,
             Some(Response::Best {
-                side: String::from("B"),
-                price: 10,
-                qty: 100
+                side: Side::Buy,
+                price: Price(10),
+                qty: Quantity(100)
             })
         );
 
@@ -2291,9 +2752,9 @@ mod tests {
         assert_eq!(
             res2.1,
             Some(Response::Best {
-                side: String::from("S"),
-                price: 12,
-                qty: 100
+                side: Side::Sell,
+                price: Price(12),
+                qty: Quantity(100)
             })
         );
 
@@ -2323,9 +2784,9 @@ mod tests {
         assert_eq!(
             res4.1,
             Some(Response::Best {
-                side: String::from("S"),
-                price: 11,
-                qty: 100
+                side: Side::Sell,
+                price: Price(11),
+                qty: Quantity(100)
             })
         );
 
@@ -2349,8 +2810,8 @@ mod tests {
                 buyer_order_id: 103,
                 seller_id: 1,
                 seller_order_id: 2,
-                price: 12,
-                qty: 100
+                price: Price(12),
+                qty: Quantity(100)
             })
         );
 
@@ -2383,9 +2844,9 @@ mod tests {
         assert_eq!(
             res1.1,
             Some(Response::Best {
-                side: String::from("B"),
-                price: 10,
-                qty: 100
+                side: Side::Buy,
+                price: Price(10),
+                qty: Quantity(100)
             })
         );
 
@@ -2415,9 +2876,9 @@ mod tests {
         assert_eq!(
             res3.1,
             Some(Response::Best {
-                side: String::from("S"),
-                price: 11,
-                qty: 100
+                side: Side::Sell,
+                price: Price(11),
+                qty: Quantity(100)
             })
         );
 
@@ -2440,8 +2901,8 @@ mod tests {
                 buyer_order_id: 2,
                 seller_id: 2,
                 seller_order_id: 102,
-                price: 11,
-                qty: 100
+                price: Price(11),
+                qty: Quantity(100)
             })
         );
 
@@ -2460,9 +2921,9 @@ mod tests {
         assert_eq!(
             res5.1,
             Some(Response::Best {
-                side: String::from("S"),
-                price: 11,
-                qty: 100
+                side: Side::Sell,
+                price: Price(11),
+                qty: Quantity(100)
             })
         );
 
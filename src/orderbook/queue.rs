@@ -0,0 +1,183 @@
+//! This mod implements queue position queries of resting orders.
+//!
+//! The [OrderBook] keeps the price level of every resting order, so
+//! [OrderBook::queue_position] only walks the level of the order instead
+//! of the whole book. It assumes that a user does not reuse the id of one
+//! of their live orders, which the [Engine](crate::Engine) enforces.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use super::{Order, OrderBook, Response, Side, UserAction};
+use crate::fixed::{Price, Quantity};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// This struct describes where a resting order waits to be filled
+pub struct QueuePosition {
+    /// Side of the order
+    pub side: Side,
+    /// Price level of the order
+    pub price: Price,
+    /// Number of orders of the level that fill before it
+    pub orders_ahead: usize,
+    /// Total quantity of the orders that fill before it
+    pub qty_ahead: Quantity,
+}
+
+/// Returns the level of every order of one side by `(user_id, order_id)`
+pub(super) fn locate(
+    col: &HashMap<Price, Vec<Order>>,
+    side: Side,
+) -> impl Iterator<Item = ((u32, u32), (Side, Price))> + '_ {
+    col.iter().flat_map(move |(price, level)| {
+        level
+            .iter()
+            .map(move |o| ((o.user_id, o.order_id), (side, *price)))
+    })
+}
+
+impl OrderBook {
+    /// Returns the place of an order in the queue of its price level
+    ///
+    /// Orders of a level are matched in time priority, so the orders ahead
+    /// are the ones of the same level that arrived earlier. As an incoming
+    /// order only trades against one of the same quantity, an order can
+    /// still fill before some of those ahead. Returns `None` when the
+    /// order is not resting in the book.
+    pub fn queue_position(&self, user_id: u32, order_id: u32) -> Option<QueuePosition> {
+        let (side, price) = *self.locations.get(&(user_id, order_id))?;
+        let level = self.side(side).get(&price)?;
+        let index = level
+            .iter()
+            .position(|o| o.user_id == user_id && o.order_id == order_id)?;
+
+        // The book rejects orders that would overflow their level
+        let qty_ahead = Quantity::checked_sum(level[..index].iter().map(Order::qty)).unwrap();
+        Some(QueuePosition {
+            side,
+            price,
+            orders_ahead: index,
+            qty_ahead,
+        })
+    }
+
+    /// Keeps the level of the resting orders up to date after `action`
+    pub(super) fn update_locations(
+        &mut self,
+        action: &UserAction,
+        responses: &(Option<Response>, Option<Response>),
+    ) {
+        match (action, responses) {
+            // The resting order of the trade is gone, the incoming one
+            // never rested
+            (
+                UserAction::NewOrder { .. },
+                (
+                    Some(Response::Acknowledge { .. }),
+                    Some(Response::Trade {
+                        buyer_id,
+                        buyer_order_id,
+                        seller_id,
+                        seller_order_id,
+                        ..
+                    }),
+                ),
+            ) => {
+                self.locations.remove(&(*buyer_id, *buyer_order_id));
+                self.locations.remove(&(*seller_id, *seller_order_id));
+            }
+            (
+                UserAction::NewOrder {
+                    user_id,
+                    price,
+                    side,
+                    order_id,
+                    ..
+                },
+                (Some(Response::Acknowledge { .. }), _),
+            ) => {
+                self.locations
+                    .insert((*user_id, *order_id), (*side, *price));
+            }
+            (
+                UserAction::CancelOrder { user_id, order_id },
+                (Some(Response::Acknowledge { .. }), _),
+            ) => {
+                self.locations.remove(&(*user_id, *order_id));
+            }
+            (UserAction::Flush, _) => self.locations.clear(),
+            (_, _) => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orderbook::TimeInForce;
+    use crate::symbol::Symbol;
+
+    fn new_order(user_id: u32, price: u64, qty: u64, side: Side, order_id: u32) -> UserAction {
+        UserAction::NewOrder {
+            user_id,
+            symbol: Symbol::new("IBM"),
+            price: Price(price),
+            qty: Quantity(qty),
+            side,
+            order_id,
+            time_in_force: TimeInForce::Gtc,
+        }
+    }
+
+    fn position(side: Side, price: u64, orders_ahead: usize, qty_ahead: u64) -> QueuePosition {
+        QueuePosition {
+            side,
+            price: Price(price),
+            orders_ahead,
+            qty_ahead: Quantity(qty_ahead),
+        }
+    }
+
+    #[test]
+    fn test_queue_position() {
+        let mut ob = OrderBook::new("IBM", true);
+        ob.new_user_action(new_order(1, 10, 100, Side::Buy, 1));
+        ob.new_user_action(new_order(2, 10, 50, Side::Buy, 1));
+        ob.new_user_action(new_order(3, 10, 70, Side::Buy, 1));
+        ob.new_user_action(new_order(4, 11, 30, Side::Sell, 1));
+
+        assert_eq!(ob.queue_position(1, 1), Some(position(Side::Buy, 10, 0, 0)));
+        assert_eq!(
+            ob.queue_position(3, 1),
+            Some(position(Side::Buy, 10, 2, 150))
+        );
+        assert_eq!(
+            ob.queue_position(4, 1),
+            Some(position(Side::Sell, 11, 0, 0))
+        );
+        assert_eq!(ob.queue_position(5, 1), None);
+
+        // The first order of the incoming quantity fills, then a cancel
+        ob.new_user_action(new_order(5, 10, 50, Side::Sell, 1));
+        assert_eq!(ob.queue_position(2, 1), None);
+        assert_eq!(
+            ob.queue_position(3, 1),
+            Some(position(Side::Buy, 10, 1, 100))
+        );
+        ob.new_user_action(UserAction::CancelOrder {
+            user_id: 1,
+            order_id: 1,
+        });
+        assert_eq!(ob.queue_position(3, 1), Some(position(Side::Buy, 10, 0, 0)));
+
+        // The position agrees with the resting orders
+        for order in ob.orders() {
+            let queue = ob.queue_position(order.user_id, order.order_id).unwrap();
+            assert_eq!(queue.orders_ahead, order.position);
+        }
+
+        ob.new_user_action(UserAction::Flush);
+        assert_eq!(ob.queue_position(3, 1), None);
+    }
+}
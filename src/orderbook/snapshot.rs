@@ -0,0 +1,229 @@
+//! This mod describes the serialized state of an [OrderBook].
+//!
+//! Each layout is a separate, frozen set of structs. When the [OrderBook]
+//! changes, a new layout is added next to the old ones together with its
+//! conversion, so snapshots written by older builds stay readable.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use super::{queue, Order, OrderBook, Side, Trade};
+use crate::fixed::{Price, Quantity};
+use crate::instrument::Instrument;
+use crate::symbol::Symbol;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+/// This struct describes a resting order in layout version 1
+pub struct OrderV1 {
+    pub user_id: u32,
+    pub order_id: u32,
+    pub price: u32,
+    pub qty: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+/// This struct describes a recorded trade in layout version 1
+pub struct TradeV1 {
+    pub buyer_id: u32,
+    pub buyer_order_id: u32,
+    pub seller_id: u32,
+    pub seller_order_id: u32,
+    pub price: u32,
+    pub qty: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+/// This struct describes an [OrderBook] in layout version 1
+///
+/// Price levels are sorted by price, orders within a level keep their time
+/// priority.
+pub struct BookV1 {
+    pub ticker: String,
+    pub trade_active: bool,
+    pub max_bid: u32,
+    pub min_ask: u32,
+    pub bids: Vec<(u32, Vec<OrderV1>)>,
+    pub asks: Vec<(u32, Vec<OrderV1>)>,
+    pub trades: Vec<TradeV1>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+/// This struct describes a resting order in layout version 2
+///
+/// Prices are in ticks and quantities in lots, both 64 bits wide.
+pub struct OrderV2 {
+    pub user_id: u32,
+    pub order_id: u32,
+    pub price: u64,
+    pub qty: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+/// This struct describes a recorded trade in layout version 2
+pub struct TradeV2 {
+    pub buyer_id: u32,
+    pub buyer_order_id: u32,
+    pub seller_id: u32,
+    pub seller_order_id: u32,
+    pub price: u64,
+    pub qty: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+/// This struct describes an [OrderBook] in layout version 2
+///
+/// Same as [BookV1] with 64 bit prices and quantities.
+pub struct BookV2 {
+    pub ticker: String,
+    pub trade_active: bool,
+    pub max_bid: u64,
+    pub min_ask: u64,
+    pub bids: Vec<(u64, Vec<OrderV2>)>,
+    pub asks: Vec<(u64, Vec<OrderV2>)>,
+    pub trades: Vec<TradeV2>,
+}
+
+impl From<OrderV1> for OrderV2 {
+    fn from(o: OrderV1) -> Self {
+        OrderV2 {
+            user_id: o.user_id,
+            order_id: o.order_id,
+            price: o.price as u64,
+            qty: o.qty as u64,
+        }
+    }
+}
+
+impl From<TradeV1> for TradeV2 {
+    fn from(t: TradeV1) -> Self {
+        TradeV2 {
+            buyer_id: t.buyer_id,
+            buyer_order_id: t.buyer_order_id,
+            seller_id: t.seller_id,
+            seller_order_id: t.seller_order_id,
+            price: t.price as u64,
+            qty: t.qty as u64,
+        }
+    }
+}
+
+impl From<BookV1> for BookV2 {
+    fn from(book: BookV1) -> Self {
+        let levels = |levels: Vec<(u32, Vec<OrderV1>)>| {
+            levels
+                .into_iter()
+                .map(|(price, orders)| {
+                    (
+                        price as u64,
+                        orders.into_iter().map(OrderV2::from).collect(),
+                    )
+                })
+                .collect()
+        };
+
+        BookV2 {
+            ticker: book.ticker,
+            trade_active: book.trade_active,
+            max_bid: book.max_bid as u64,
+            min_ask: book.min_ask as u64,
+            bids: levels(book.bids),
+            asks: levels(book.asks),
+            trades: book.trades.into_iter().map(TradeV2::from).collect(),
+        }
+    }
+}
+
+/// Sorted price levels of one side of the book
+fn levels_v2(side: &HashMap<Price, Vec<Order>>) -> Vec<(u64, Vec<OrderV2>)> {
+    let mut levels: Vec<(u64, Vec<OrderV2>)> = side
+        .iter()
+        .map(|(price, orders)| {
+            let orders = orders
+                .iter()
+                .map(|o| OrderV2 {
+                    user_id: o.user_id,
+                    order_id: o.order_id,
+                    price: o.price.ticks(),
+                    qty: o.qty.lots(),
+                })
+                .collect();
+            (price.ticks(), orders)
+        })
+        .collect();
+    levels.sort_unstable_by_key(|(price, _)| *price);
+    levels
+}
+
+/// Restores one side of the book
+fn side_from_v2(levels: Vec<(u64, Vec<OrderV2>)>) -> HashMap<Price, Vec<Order>> {
+    levels
+        .into_iter()
+        .map(|(price, orders)| {
+            let orders = orders
+                .into_iter()
+                .map(|o| Order::new(o.user_id, Price(o.price), Quantity(o.qty), o.order_id))
+                .collect();
+            (Price(price), orders)
+        })
+        .collect()
+}
+
+impl From<&OrderBook> for BookV2 {
+    fn from(book: &OrderBook) -> Self {
+        BookV2 {
+            ticker: String::from(book.ticker.as_str()),
+            trade_active: book.trade_active,
+            max_bid: book.max_bid.ticks(),
+            min_ask: book.min_ask.ticks(),
+            bids: levels_v2(&book.bids),
+            asks: levels_v2(&book.asks),
+            trades: book
+                .trades
+                .iter()
+                .map(|t| TradeV2 {
+                    buyer_id: t.buyer_id,
+                    buyer_order_id: t.buyer_order_id,
+                    seller_id: t.seller_id,
+                    seller_order_id: t.seller_order_id,
+                    price: t.price.ticks(),
+                    qty: t.qty.lots(),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<BookV2> for OrderBook {
+    fn from(book: BookV2) -> Self {
+        let asks = side_from_v2(book.asks);
+        let bids = side_from_v2(book.bids);
+        let locations = queue::locate(&bids, Side::Buy)
+            .chain(queue::locate(&asks, Side::Sell))
+            .collect();
+        OrderBook {
+            max_bid: Price(book.max_bid),
+            min_ask: Price(book.min_ask),
+            ticker: Symbol::new(&book.ticker),
+            asks,
+            bids,
+            trades: book
+                .trades
+                .into_iter()
+                .map(|t| Trade {
+                    buyer_id: t.buyer_id,
+                    seller_id: t.seller_id,
+                    buyer_order_id: t.buyer_order_id,
+                    seller_order_id: t.seller_order_id,
+                    price: Price(t.price),
+                    qty: Quantity(t.qty),
+                })
+                .collect(),
+            trade_active: book.trade_active,
+            instrument: Instrument::new(Symbol::new(&book.ticker)),
+            l2_events: None,
+            l3_events: None,
+            locations,
+        }
+    }
+}
@@ -0,0 +1,630 @@
+//! This mod implements the single-writer engine thread.
+//!
+//! One thread owns the [Engine] together with the optional journal,
+//! snapshots and market-data publisher. It is fed actions through a
+//! lock-free ring buffer and hands the responses on through another one,
+//! stamped with the sequence numbers of the engine.
+//!
+//! Orders whose time in force has run out are cancelled before the next
+//! action and whenever no action came for [EXPIRY_SWEEP]. Their cancels
+//! are journaled, applied and published like any other action.
+
+use std::error::Error;
+use std::io;
+use std::sync::mpsc::RecvTimeoutError;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::engine::{self, Engine};
+use crate::feed::Publisher;
+use crate::itch;
+use crate::journal::{Journal, DEFAULT_SYNC_EVERY};
+use crate::orderbook::{RejectReason, Response, UserAction};
+use crate::sequence::Stamped;
+use crate::snapshot;
+use crate::spsc::{self, Consumer, Producer};
+use crate::symbol::Symbol;
+
+/// Capacity of the ring buffers in and out of the engine thread
+pub const RING_CAPACITY: usize = 1024;
+
+/// Longest time an expired order stays live while no action comes
+pub const EXPIRY_SWEEP: Duration = Duration::from_millis(100);
+
+/// Most actions applied before the journal is synced and their responses
+/// are sent on
+pub const MAX_BATCH: usize = 64;
+
+/// Error ending a pipeline stage
+pub type StageError = Box<dyn Error + Send + Sync>;
+
+/// Stamped responses of one action
+pub type Responses = (Option<Stamped>, Option<Stamped>);
+
+/// Symbol of one action and its responses
+pub type Output = (Option<Symbol>, Responses);
+
+/// Handle of the engine thread
+pub type EngineHandle = JoinHandle<Result<(), StageError>>;
+
+/// This struct holds the state owned by the engine thread
+pub struct State {
+    /// Books of every symbol
+    pub engine: Engine,
+    /// Market-data feed, if enabled
+    pub publisher: Option<Publisher>,
+    /// Journal every action is appended to before it is applied
+    pub journal: Option<Journal>,
+    /// Snapshot directory and the number of actions between snapshots,
+    /// 0 never takes one
+    pub snapshots: Option<(String, u64)>,
+}
+
+impl State {
+    /// Creates the state of an engine without journal nor feed
+    pub fn new(trade_active: bool) -> Self {
+        State {
+            engine: Engine::new(trade_active),
+            publisher: None,
+            journal: None,
+            snapshots: None,
+        }
+    }
+
+    /// Opens the journal at `path` and restores the books from it
+    ///
+    /// With snapshots the books are restored from the latest snapshot and
+    /// only the journal tail is replayed. The feed learns the restored
+    /// orders as new orders. Returns the number of replayed entries.
+    pub fn recover(&mut self, path: &str) -> Result<usize, StageError> {
+        let (journal, restored, entries) = match self.snapshots.as_ref() {
+            Some((dir, _)) => snapshot::recover(dir, path, DEFAULT_SYNC_EVERY)?,
+            None => {
+                let (journal, entries) = Journal::open(path, DEFAULT_SYNC_EVERY)?;
+                (journal, None, entries)
+            }
+        };
+
+        let instruments = self.engine.instruments().clone();
+        let risk = self.engine.take_risk();
+        let trade_active = self.engine.trade_active();
+        self.engine = restored
+            .map(|snapshot| snapshot.restore(trade_active))
+            .unwrap_or_else(|| Engine::new(trade_active))
+            .with_instruments(instruments)
+            .with_order_id_scope(self.engine.order_id_scope())
+            .with_clock(self.engine.clock().clone())
+            .with_day_close(self.engine.day_close());
+        if let Some(risk) = risk {
+            self.engine = std::mem::take(&mut self.engine).with_risk(risk);
+        }
+
+        if let Some(publisher) = self.publisher.as_mut() {
+            publisher.restore(&self.engine)?;
+        }
+
+        // Only actions that passed the risk checks are journaled, each
+        // with the number of the last response emitted before it. Rejects
+        // of the others leave a record of their number only
+        let replayed = entries.len();
+        let mut stamped = self.engine.last_seq();
+        for entry in entries {
+            let mut count = 0;
+            if let Some(action) = entry.action {
+                let output = self.engine.apply_checked(action.clone());
+                if let Some(publisher) = self.publisher.as_mut() {
+                    publisher.record(&action, &output)?;
+                }
+                let (res1, res2) = &output.1;
+                count = [res1, res2].iter().filter(|r| r.is_some()).count() as u64;
+            }
+            stamped = entry.stamped.unwrap_or(stamped) + count;
+        }
+        self.engine = std::mem::take(&mut self.engine).with_last_seq(stamped);
+        self.journal = Some(journal);
+
+        Ok(replayed)
+    }
+
+    /// Journals, applies and publishes one action, then stamps its
+    /// responses
+    pub fn process(&mut self, action: UserAction) -> Result<Output, StageError> {
+        // Day orders are journaled with the time they expire at
+        let action = self.engine.pin_expiry(action);
+
+        // Actions breaking a risk limit never reach the books, nor the
+        // journal
+        if let Some(reject) = self.engine.check_risk(&action) {
+            return self.reject(reject);
+        }
+
+        // Nor do symbols the feed can not carry, so that publishing never
+        // fails on an action that was already applied
+        if let (
+            Some(_),
+            UserAction::NewOrder {
+                user_id,
+                symbol,
+                order_id,
+                ..
+            },
+        ) = (self.publisher.as_ref(), &action)
+        {
+            if !itch::is_valid_symbol(symbol.as_str()) {
+                let reject = Response::Reject {
+                    user_id: *user_id,
+                    order_id: *order_id,
+                    reason: RejectReason::InvalidSymbol,
+                };
+                return self.reject((Some(*symbol), (Some(reject), None)));
+            }
+        }
+
+        self.commit(action)
+    }
+
+    /// Stamps the reject of an action that never reaches the journal
+    ///
+    /// The journal still records the number of the reject, so that it is
+    /// not handed out again after a restart.
+    fn reject(&mut self, reject: engine::Output) -> Result<Output, StageError> {
+        let output = self.engine.stamp(reject);
+        if let Some(journal) = self.journal.as_mut() {
+            journal.append_stamped(self.engine.last_seq())?;
+        }
+        Ok(output)
+    }
+
+    /// Cancels the orders that expired by the time of the engine clock,
+    /// see [Engine::expired], then stamps the responses of every cancel
+    pub fn expire(&mut self) -> Result<Vec<Output>, StageError> {
+        self.engine
+            .expired()
+            .into_iter()
+            .map(|cancel| self.commit(cancel))
+            .collect()
+    }
+
+    /// Journals, applies and publishes an action that passed the checks,
+    /// then stamps its responses
+    fn commit(&mut self, action: UserAction) -> Result<Output, StageError> {
+        // The action is journaled before it is applied
+        let seq = match self.journal.as_mut() {
+            Some(journal) => Some(journal.append(&action, self.engine.last_seq())?),
+            None => None,
+        };
+
+        let output = self.engine.apply_checked(action.clone());
+        if let Some(publisher) = self.publisher.as_mut() {
+            publisher.publish(&action, &output)?;
+        }
+        let output = self.engine.stamp(output);
+
+        // Snapshots never get ahead of the synced journal, and hold the
+        // number of the last response of their action
+        if let (Some(seq), Some((dir, every))) = (seq, self.snapshots.as_ref()) {
+            if seq.is_multiple_of(*every) {
+                if let Some(journal) = self.journal.as_mut() {
+                    journal.sync()?;
+                }
+                snapshot::save(dir, seq, &self.engine)?;
+            }
+        }
+
+        Ok(output)
+    }
+
+    /// Applies `action` and the actions waiting after it, up to
+    /// [MAX_BATCH], then syncs the journal
+    ///
+    /// Expired orders are cancelled before each action. Returns the
+    /// outputs of the group, which are durable once it returns.
+    pub fn process_batch(
+        &mut self,
+        mut action: Option<UserAction>,
+        waiting: &mut Consumer<UserAction>,
+    ) -> Result<Vec<Output>, StageError> {
+        let mut outputs = self.expire()?;
+        let mut batch = 0;
+        while let Some(next) = action {
+            outputs.push(self.process(next)?);
+            batch += 1;
+            action = match batch < MAX_BATCH {
+                true => waiting.pop(),
+                false => None,
+            };
+            if action.is_some() {
+                outputs.extend(self.expire()?);
+            }
+        }
+
+        self.sync()?;
+        Ok(outputs)
+    }
+
+    /// Syncs the journal
+    pub fn sync(&mut self) -> Result<(), StageError> {
+        if let Some(journal) = self.journal.as_mut() {
+            journal.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Syncs the journal and ends the feed session
+    pub fn shutdown(&mut self) -> Result<(), StageError> {
+        self.sync()?;
+        if let Some(publisher) = self.publisher.as_mut() {
+            publisher.end_session()?;
+        }
+        Ok(())
+    }
+}
+
+/// Applies the actions in arrival order and sends the responses on
+///
+/// Runs on the engine thread, which owns every book. Expired orders are
+/// cancelled before each action and at least every [EXPIRY_SWEEP].
+/// Actions are committed in groups: the waiting actions, up to
+/// [MAX_BATCH], are applied, the journal is synced once, and only then
+/// their responses are sent on, so no response outlives a crash.
+pub fn process(
+    mut actions: Consumer<UserAction>,
+    mut responses: Producer<Output>,
+    mut state: State,
+) -> Result<(), StageError> {
+    loop {
+        let action = match actions.recv_timeout(EXPIRY_SWEEP) {
+            Ok(action) => Some(action),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+
+        if state
+            .process_batch(action, &mut actions)?
+            .into_iter()
+            .any(|output| responses.send(output).is_err())
+        {
+            break;
+        }
+    }
+
+    state.shutdown()
+}
+
+/// Starts the engine thread, pinned to `core` when given
+///
+/// The thread ends once the action producer is dropped, after syncing the
+/// journal and ending the feed session.
+pub fn spawn(
+    state: State,
+    core: Option<usize>,
+) -> io::Result<(Producer<UserAction>, Consumer<Output>, EngineHandle)> {
+    let (action_tx, action_rx) = spsc::channel(RING_CAPACITY);
+    let (response_tx, response_rx) = spsc::channel(RING_CAPACITY);
+
+    let handle = spsc::spawn_pinned("engine", core, move || {
+        process(action_rx, response_tx, state)
+    })?;
+
+    Ok((action_tx, response_rx, handle))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::{Format, Reader};
+    use crate::journal;
+    use crate::replay;
+    use std::fs::File;
+    use std::io::BufReader;
+    use std::thread;
+
+    #[test]
+    fn test_engine_thread_keeps_order_and_shuts_down() {
+        let file = File::open("input/input.csv").unwrap();
+        let input: Vec<UserAction> = Reader::new(Format::Csv, BufReader::new(file))
+            .map(|a| a.unwrap())
+            .collect();
+
+        let (mut action_tx, action_rx) = spsc::channel(4);
+        let (response_tx, mut response_rx) = spsc::channel(4);
+        let state = State::new(false);
+        let handle = spsc::spawn_pinned("engine", None, move || {
+            process(action_rx, response_tx, state)
+        })
+        .unwrap();
+
+        let actions = input.clone();
+        thread::spawn(move || {
+            for action in actions {
+                action_tx.send(action).unwrap();
+            }
+        });
+
+        let mut emitted = vec![];
+        while let Some((_, (res1, res2))) = response_rx.recv() {
+            emitted.extend([res1, res2].into_iter().flatten());
+        }
+        handle.join().unwrap().unwrap();
+
+        // Every response is numbered in the order it was emitted
+        assert!(emitted.iter().zip(1..).all(|(s, seq)| s.seq == seq));
+        assert!(emitted.windows(2).all(|w| w[0].timestamp <= w[1].timestamp));
+        let emitted: Vec<Response> = emitted.into_iter().map(|s| s.response).collect();
+
+        let expected: Vec<Response> = replay::run(&input, false)
+            .into_iter()
+            .map(|e| e.response)
+            .collect();
+        assert_eq!(emitted, expected);
+    }
+
+    #[test]
+    fn test_batch_is_synced_before_its_responses() {
+        let file = File::open("input/input.csv").unwrap();
+        let input: Vec<UserAction> = Reader::new(Format::Csv, BufReader::new(file))
+            .map(|a| a.unwrap())
+            .collect();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal");
+
+        let mut state = State::new(true);
+        state.recover(path.to_str().unwrap()).unwrap();
+        let (mut action_tx, mut action_rx) = spsc::channel(RING_CAPACITY);
+        for action in input.iter().cloned() {
+            action_tx.send(action).unwrap();
+        }
+
+        // Each group is on disk by the time its outputs may be sent on
+        let mut outputs = 0;
+        while let Some(action) = action_rx.pop() {
+            let batch = state.process_batch(Some(action), &mut action_rx).unwrap();
+            assert!(batch.len() <= MAX_BATCH);
+            outputs += batch.len();
+            assert_eq!(journal::read(&path).unwrap().len(), outputs);
+        }
+        assert_eq!(outputs, input.len());
+    }
+
+    #[test]
+    fn test_feed_rejects_long_symbols_before_the_journal() {
+        use crate::feed::FeedStore;
+        use crate::fixed::{Price, Quantity};
+        use crate::journal::Journal;
+        use crate::orderbook::{Side, TimeInForce};
+        use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal");
+        let port = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let group = SocketAddrV4::new(Ipv4Addr::new(239, 255, 42, 43), port);
+
+        let mut state = State::new(false);
+        state.journal = Some(Journal::open(&path, DEFAULT_SYNC_EVERY).unwrap().0);
+        let clock = state.engine.clock().clone();
+        state.publisher =
+            Some(Publisher::bind(group, Ipv4Addr::LOCALHOST, FeedStore::shared(), clock).unwrap());
+
+        let new_order = |symbol: &str| UserAction::NewOrder {
+            user_id: 1,
+            symbol: Symbol::new(symbol),
+            price: Price(10),
+            qty: Quantity(100),
+            side: Side::Buy,
+            order_id: 1,
+            time_in_force: TimeInForce::Gtc,
+        };
+        let (_, (reject, _)) = state.process(new_order("LONGSYMBOL")).unwrap();
+        assert_eq!(
+            reject.map(|s| s.response),
+            Some(Response::Reject {
+                user_id: 1,
+                order_id: 1,
+                reason: RejectReason::InvalidSymbol
+            })
+        );
+        let (_, (ack, _)) = state.process(new_order("IBM")).unwrap();
+        assert_eq!(
+            ack.map(|s| s.response),
+            Some(Response::Acknowledge {
+                user_id: 1,
+                order_id: 1
+            })
+        );
+        state.shutdown().unwrap();
+
+        // Only the number of the reject is journaled
+        let (_, entries) = Journal::open(&path, DEFAULT_SYNC_EVERY).unwrap();
+        assert_eq!(
+            entries
+                .into_iter()
+                .map(|e| (e.action.is_some(), e.stamped))
+                .collect::<Vec<_>>(),
+            vec![(false, Some(1)), (true, Some(1))]
+        );
+    }
+
+    #[test]
+    fn test_sequence_carries_on_after_restart() {
+        use crate::fixed::{Price, Quantity};
+        use crate::orderbook::{Side, TimeInForce};
+
+        let new_order = |order_id, time_in_force| UserAction::NewOrder {
+            user_id: 1,
+            symbol: Symbol::new("IBM"),
+            price: Price(10 + order_id as u64),
+            qty: Quantity(100),
+            side: Side::Buy,
+            order_id,
+            time_in_force,
+        };
+        let last_seq =
+            |(_, (res1, res2)): Output| [res1, res2].into_iter().flatten().map(|s| s.seq).max();
+
+        for every in [None, Some(2)] {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("journal");
+            let new_state = || {
+                let mut state = State::new(true);
+                state.snapshots =
+                    every.map(|every| (dir.path().join("snapshots").display().to_string(), every));
+                state.recover(path.to_str().unwrap()).unwrap();
+                state
+            };
+
+            // The expired order is rejected before the journal
+            let mut state = new_state();
+            state.process(new_order(1, TimeInForce::Gtc)).unwrap();
+            state.process(new_order(2, TimeInForce::Gtc)).unwrap();
+            let reject = state.process(new_order(3, TimeInForce::Gtd(1))).unwrap();
+            assert_eq!(last_seq(reject), Some(5));
+            state.shutdown().unwrap();
+            drop(state);
+
+            let mut state = new_state();
+            assert_eq!(state.engine.last_seq(), 5);
+            let ack = state.process(new_order(4, TimeInForce::Gtc)).unwrap();
+            assert_eq!(last_seq(ack), Some(7));
+        }
+    }
+
+    #[test]
+    fn test_snapshot_keeps_session_ids() {
+        use crate::clock::{ManualClock, SharedClock};
+        use crate::engine::OrderIdScope;
+        use crate::fixed::{Price, Quantity};
+        use crate::orderbook::{Side, TimeInForce};
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal");
+        let snapshots = dir.path().join("snapshots");
+        let new_state = |snapshots: Option<&std::path::Path>| {
+            let mut state = State::new(true);
+            state.engine = Engine::new(true)
+                .with_clock(SharedClock::new(ManualClock::default()))
+                .with_order_id_scope(OrderIdScope::Session);
+            state.snapshots = snapshots.map(|dir| (dir.to_str().unwrap().to_owned(), 3));
+            state.recover(path.to_str().unwrap()).unwrap();
+            state
+        };
+        let new_order = |order_id| UserAction::NewOrder {
+            user_id: 1,
+            symbol: Symbol::new("IBM"),
+            price: Price(10),
+            qty: Quantity(100),
+            side: Side::Buy,
+            order_id,
+            time_in_force: TimeInForce::Gtc,
+        };
+
+        // The snapshot is taken after order 1 was cancelled
+        let mut state = new_state(Some(&snapshots));
+        state.process(new_order(1)).unwrap();
+        state
+            .process(UserAction::CancelOrder {
+                user_id: 1,
+                order_id: 1,
+            })
+            .unwrap();
+        state.process(new_order(2)).unwrap();
+        state.shutdown().unwrap();
+        drop(state);
+        assert_eq!(std::fs::read_dir(&snapshots).unwrap().count(), 1);
+
+        // Recovery from the snapshot matches the full replay
+        let from_snapshot = new_state(Some(&snapshots)).engine;
+        let replayed = new_state(None).engine;
+        assert_eq!(from_snapshot.session_ids(), vec![(1, 1), (1, 2)]);
+        assert_eq!(from_snapshot, replayed);
+
+        let mut state = new_state(Some(&snapshots));
+        let (_, (reject, _)) = state.process(new_order(1)).unwrap();
+        assert_eq!(
+            reject.map(|s| s.response),
+            Some(Response::Reject {
+                user_id: 1,
+                order_id: 1,
+                reason: RejectReason::DuplicateOrderId
+            })
+        );
+    }
+
+    #[test]
+    fn test_expiry_is_journaled() {
+        use crate::clock::{ManualClock, SharedClock, NANOS_PER_DAY};
+        use crate::fixed::{Price, Quantity};
+        use crate::orderbook::{Side, TimeInForce};
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal");
+        let snapshots = dir.path().join("snapshots");
+        let clock = ManualClock::new(1_000);
+        let new_state = || {
+            let mut state = State::new(true);
+            state.engine = Engine::new(true).with_clock(SharedClock::new(clock.clone()));
+            state.snapshots = Some((snapshots.to_str().unwrap().to_owned(), 2));
+            state.recover(path.to_str().unwrap()).unwrap();
+            state
+        };
+        let new_order = |order_id, time_in_force| UserAction::NewOrder {
+            user_id: 1,
+            symbol: Symbol::new("IBM"),
+            price: Price(10),
+            qty: Quantity(100),
+            side: Side::Buy,
+            order_id,
+            time_in_force,
+        };
+
+        // The snapshot after the second order keeps both expiries
+        let mut state = new_state();
+        state.process(new_order(1, TimeInForce::Day)).unwrap();
+        state
+            .process(new_order(2, TimeInForce::Gtd(5_000)))
+            .unwrap();
+        state.shutdown().unwrap();
+        drop(state);
+
+        let mut state = new_state();
+        assert_eq!(
+            state.engine.order(1, 1).unwrap().expiry,
+            Some(NANOS_PER_DAY)
+        );
+        clock.set(5_000);
+        let expired = state.expire().unwrap();
+        assert_eq!(
+            expired
+                .into_iter()
+                .map(|(_, (ack, _))| ack.map(|s| s.response))
+                .collect::<Vec<_>>(),
+            vec![Some(Response::Acknowledge {
+                user_id: 1,
+                order_id: 2
+            })]
+        );
+        state.shutdown().unwrap();
+        drop(state);
+
+        // The DAY order was journaled with its close, the expiry as a cancel
+        let (_, entries) = Journal::open(&path, DEFAULT_SYNC_EVERY).unwrap();
+        let actions: Vec<UserAction> = entries.into_iter().filter_map(|e| e.action).collect();
+        assert_eq!(
+            actions,
+            vec![
+                new_order(1, TimeInForce::Gtd(NANOS_PER_DAY)),
+                new_order(2, TimeInForce::Gtd(5_000)),
+                UserAction::CancelOrder {
+                    user_id: 1,
+                    order_id: 2
+                },
+            ]
+        );
+        let state = new_state();
+        assert!(state.engine.order(1, 2).is_none());
+        assert!(state.engine.order(1, 1).is_some());
+    }
+}
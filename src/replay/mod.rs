@@ -0,0 +1,235 @@
+//! This mod implements deterministic replay of recorded actions.
+//!
+//! A recorded action log is fed through a fresh [Engine] and the emitted
+//! [Response] stream is compared against a golden output, reporting the
+//! first divergence with the responses leading up to it.
+
+use std::fmt::{Display, Formatter};
+use std::io::BufRead;
+
+use crate::codec::{CodecError, CsvRecord};
+use crate::engine::Engine;
+use crate::orderbook::{Response, UserAction};
+
+/// Prefix of the comment line naming a scenario in the CSV input
+pub const SCENARIO_PREFIX: &str = "#name:";
+
+/// Default number of matching responses shown before a divergence
+pub const DEFAULT_CONTEXT: usize = 3;
+
+#[derive(Clone, Debug, PartialEq)]
+/// This struct describes a named sequence of actions
+pub struct Scenario {
+    /// Name of the scenario, e.g. `scenario 1`
+    pub name: String,
+    /// Actions of the scenario
+    pub actions: Vec<UserAction>,
+}
+
+impl Scenario {
+    /// Returns the file name of the golden output of the scenario
+    pub fn golden_file_name(&self) -> String {
+        format!("{}.csv", self.name.replace(' ', "_"))
+    }
+}
+
+/// Splits a CSV action log in the scenarios named by `#name:` comments
+///
+/// Actions before the first name are dropped.
+pub fn scenarios(reader: impl BufRead) -> Result<Vec<Scenario>, CodecError> {
+    let mut scenarios: Vec<Scenario> = vec![];
+
+    for line in reader.lines() {
+        let line = line?;
+        if let Some(name) = line.strip_prefix(SCENARIO_PREFIX) {
+            scenarios.push(Scenario {
+                name: String::from(name.trim()),
+                actions: vec![],
+            });
+        } else if let Some(action) = UserAction::from_csv(&line)? {
+            if let Some(scenario) = scenarios.last_mut() {
+                scenario.actions.push(action);
+            }
+        }
+    }
+
+    Ok(scenarios)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+/// This struct describes one emitted [Response]
+pub struct Emitted {
+    /// Index of the action that produced the response
+    pub action: usize,
+    /// The response itself
+    pub response: Response,
+}
+
+/// Feeds actions through a fresh [Engine] and collects the responses
+pub fn run(actions: &[UserAction], trade_active: bool) -> Vec<Emitted> {
+    let mut engine = Engine::new(trade_active);
+    let mut emitted = vec![];
+
+    for (i, action) in actions.iter().enumerate() {
+        let (_, (r1, r2)) = engine.new_user_action(action.clone());
+        for response in [r1, r2].into_iter().flatten() {
+            emitted.push(Emitted {
+                action: i,
+                response,
+            });
+        }
+    }
+
+    emitted
+}
+
+#[derive(Clone, Debug, PartialEq)]
+/// This struct describes the first difference between two response streams
+pub struct Divergence {
+    /// Index of the first differing response
+    pub index: usize,
+    /// The action that produced the differing response, if any
+    pub action: Option<(usize, UserAction)>,
+    /// The matching responses before the divergence
+    pub context: Vec<Response>,
+    /// The golden response, `None` past the end of the golden output
+    pub expected: Option<Response>,
+    /// The emitted response, `None` past the end of the emitted stream
+    pub actual: Option<Response>,
+}
+
+impl Display for Divergence {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        let line = |r: &Option<Response>| match r {
+            Some(r) => r.to_csv(),
+            None => String::from("<end of output>"),
+        };
+
+        writeln!(f, "first divergence at response {}", self.index + 1)?;
+        if let Some((i, action)) = &self.action {
+            writeln!(f, "  after action {}: {}", i + 1, action.to_csv())?;
+        }
+        let first = self.index - self.context.len();
+        for (i, response) in self.context.iter().enumerate() {
+            writeln!(f, "  {:>6}   {}", first + i + 1, response.to_csv())?;
+        }
+        writeln!(f, "  expected {}", line(&self.expected))?;
+        write!(f, "  actual   {}", line(&self.actual))
+    }
+}
+
+/// Compares emitted responses against the golden output
+///
+/// Returns the first divergence with up to `context` matching responses
+/// before it, or `None` when both streams are identical.
+pub fn diff(
+    actions: &[UserAction],
+    emitted: &[Emitted],
+    expected: &[Response],
+    context: usize,
+) -> Option<Divergence> {
+    let index = (0..emitted.len().max(expected.len())).find(|&i| {
+        match (emitted.get(i), expected.get(i)) {
+            (Some(e), Some(expected)) => e.response != *expected,
+            _ => true,
+        }
+    })?;
+
+    Some(Divergence {
+        index,
+        action: emitted
+            .get(index)
+            .map(|e| (e.action, actions[e.action].clone())),
+        context: expected[index.saturating_sub(context)..index].to_vec(),
+        expected: expected.get(index).cloned(),
+        actual: emitted.get(index).map(|e| e.response.clone()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::{Format, Reader};
+    use crate::orderbook::RejectReason;
+    use std::fs::File;
+    use std::io::BufReader;
+    use std::path::Path;
+
+    fn input_scenarios() -> Vec<Scenario> {
+        scenarios(BufReader::new(File::open("input/input.csv").unwrap())).unwrap()
+    }
+
+    fn golden(scenario: &Scenario) -> Vec<Response> {
+        let path = Path::new("input/golden").join(scenario.golden_file_name());
+        Reader::new(Format::Csv, BufReader::new(File::open(path).unwrap()))
+            .map(|r| r.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_scenarios_match_golden_output() {
+        let scenarios = input_scenarios();
+        assert_eq!(scenarios.len(), 12);
+
+        for scenario in scenarios {
+            let emitted = run(&scenario.actions, false);
+            if let Some(divergence) = diff(&scenario.actions, &emitted, &golden(&scenario), 3) {
+                panic!("{}: {}", scenario.name, divergence);
+            }
+        }
+    }
+
+    #[test]
+    fn test_reports_first_divergence() {
+        let scenario = &input_scenarios()[0];
+        let emitted = run(&scenario.actions, false);
+        let mut expected = golden(scenario);
+        expected[5] = Response::Reject {
+            user_id: 9,
+            order_id: 9,
+            reason: RejectReason::Unspecified,
+        };
+
+        let divergence = diff(&scenario.actions, &emitted, &expected, 2).unwrap();
+        assert_eq!(divergence.index, 5);
+        assert_eq!(divergence.context, expected[3..5].to_vec());
+        assert_eq!(divergence.actual, Some(emitted[5].response.clone()));
+        assert_eq!(divergence.action.map(|(i, _)| i), Some(emitted[5].action));
+
+        // A truncated golden output diverges at its end
+        let divergence = diff(&scenario.actions, &emitted, &expected[..2], 2).unwrap();
+        assert_eq!(divergence.index, 2);
+        assert_eq!(divergence.expected, None);
+    }
+
+    #[test]
+    fn test_golden_reject_reasons() {
+        let scenario = &input_scenarios()[0];
+        let emitted = run(&scenario.actions, false);
+        let mut expected = golden(scenario);
+        let i = expected
+            .iter()
+            .position(|r| matches!(r, Response::Reject { .. }))
+            .unwrap();
+
+        // The golden output records the reason, which has to match
+        let recorded = |reason| match emitted[i].response {
+            Response::Reject {
+                user_id, order_id, ..
+            } => Response::Reject {
+                user_id,
+                order_id,
+                reason,
+            },
+            _ => unreachable!(),
+        };
+        assert_eq!(expected[i], recorded(RejectReason::TradingDisabled));
+        for reason in [RejectReason::UnknownOrder, RejectReason::Unspecified] {
+            expected[i] = recorded(reason);
+            assert_eq!(
+                diff(&scenario.actions, &emitted, &expected, 0).map(|d| d.index),
+                Some(i)
+            );
+        }
+    }
+}
@@ -0,0 +1,627 @@
+//! This mod implements pre-trade risk checks.
+//!
+//! A [RiskGate] checks every new order of a user against their
+//! [RiskLimits] before it reaches a book, and learns the open orders,
+//! positions and last prices it needs from the responses of the books.
+//! Limits are loaded from a JSON file and reloaded when it changes. A
+//! background thread watches the file, so the matching thread never
+//! touches the file system:
+//!
+//! ```text
+//! {
+//!   "default": {"max_order_qty": 1000, "max_orders_per_sec": 100},
+//!   "users": {"7": {"max_order_qty": 100, "max_notional": 100000,
+//!                   "max_open_orders": 10, "max_position": 500,
+//!                   "max_price_deviation": 500}}
+//! }
+//! ```
+//!
+//! Quantities and positions are in lots, notionals in ticks times lots and
+//! price deviations in basis points of the last trade price. Every limit
+//! is optional, a missing limit is no limit. Users without their own
+//! limits get the default ones.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::fixed::{Price, Quantity};
+use crate::orderbook::{Response, Side, UserAction};
+use crate::symbol::Symbol;
+use crate::ws::Throttle;
+
+/// How often a watched limits file is checked for changes
+pub const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+/// This struct holds the limits of one user
+pub struct RiskLimits {
+    /// Largest quantity of one order
+    pub max_order_qty: Option<Quantity>,
+    /// Largest price times quantity of one order
+    pub max_notional: Option<u128>,
+    /// Most orders resting at once, over every symbol
+    pub max_open_orders: Option<usize>,
+    /// Largest net position, long or short, in one symbol once an order
+    /// fills
+    pub max_position: Option<u64>,
+    /// Largest distance of an order's price from the last trade price, in
+    /// basis points
+    pub max_price_deviation: Option<u64>,
+    /// Most new orders per second
+    pub max_orders_per_sec: Option<u32>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+/// This struct holds the limits of every user
+pub struct RiskConfig {
+    /// Limits of the users without their own
+    pub default: RiskLimits,
+    /// Limits by user id
+    pub users: HashMap<u32, RiskLimits>,
+}
+
+impl RiskConfig {
+    /// Parses a JSON risk configuration
+    pub fn from_json(json: &str) -> Result<Self, RiskError> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    /// Loads a JSON risk configuration from `path`
+    pub fn load(path: &str) -> Result<Self, RiskError> {
+        RiskConfig::from_json(&fs::read_to_string(path)?)
+    }
+
+    /// Returns the limits of a user
+    pub fn limits(&self, user_id: u32) -> &RiskLimits {
+        self.users.get(&user_id).unwrap_or(&self.default)
+    }
+}
+
+#[derive(Debug)]
+/// This enum describes why risk limits could not be loaded
+pub enum RiskError {
+    /// Reading the file failed
+    Io(io::Error),
+    /// The file is not a JSON risk configuration
+    Json(serde_json::Error),
+}
+
+impl Display for RiskError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            RiskError::Io(e) => write!(f, "{}", e),
+            RiskError::Json(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for RiskError {}
+
+impl From<io::Error> for RiskError {
+    fn from(e: io::Error) -> Self {
+        RiskError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for RiskError {
+    fn from(e: serde_json::Error) -> Self {
+        RiskError::Json(e)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+/// This enum describes which limit an order breaks
+pub enum RiskBreach {
+    /// The quantity is above the user's maximum
+    MaxOrderQty,
+    /// Price times quantity is above the user's maximum
+    MaxNotional,
+    /// The user already has their maximum of resting orders
+    MaxOpenOrders,
+    /// The position would be above the user's maximum once filled
+    MaxPosition,
+    /// The price is too far from the last trade price
+    FatFinger,
+    /// The user sent too many orders in the last second
+    RateLimit,
+}
+
+impl Display for RiskBreach {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        let breach = match self {
+            RiskBreach::MaxOrderQty => "order quantity above limit",
+            RiskBreach::MaxNotional => "order notional above limit",
+            RiskBreach::MaxOpenOrders => "too many open orders",
+            RiskBreach::MaxPosition => "position above limit",
+            RiskBreach::FatFinger => "price too far from last trade",
+            RiskBreach::RateLimit => "too many orders per second",
+        };
+        f.write_str(breach)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+/// A limits file watched for changes
+struct Watcher {
+    path: String,
+    /// Modification time of the loaded file
+    modified: Option<SystemTime>,
+}
+
+impl Watcher {
+    /// Loads the file if it changed since it was loaded
+    ///
+    /// On error the file is tried again on the next call.
+    fn poll(&mut self) -> Result<Option<RiskConfig>, RiskError> {
+        let modified = fs::metadata(&self.path)?.modified()?;
+        if self.modified == Some(modified) {
+            return Ok(None);
+        }
+
+        let config = RiskConfig::load(&self.path)?;
+        self.modified = Some(modified);
+        Ok(Some(config))
+    }
+}
+
+#[derive(Debug, Default)]
+/// Limits loaded by the watcher thread and not yet picked up by the
+/// [RiskGate]
+///
+/// The watcher stops once the gate is dropped. Like
+/// [SharedClock](crate::clock::SharedClock), any two compare equal.
+struct Reloaded(Arc<Mutex<Option<RiskConfig>>>);
+
+impl PartialEq for Reloaded {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+/// This struct checks new orders against the limits of their user
+///
+/// It only knows what it was told through [RiskGate::update], so it can be
+/// driven without any book.
+pub struct RiskGate {
+    config: RiskConfig,
+    /// Limits reloaded from the watched file
+    reloaded: Option<Reloaded>,
+    /// Resting order ids by user
+    open_orders: HashMap<u32, HashSet<u32>>,
+    /// Net positions by `(user_id, symbol)`
+    positions: HashMap<(u32, Symbol), i128>,
+    /// Price of the last trade of every symbol
+    last_prices: HashMap<Symbol, Price>,
+    /// Order rate of the users with a rate limit
+    throttles: HashMap<u32, Throttle>,
+}
+
+impl RiskGate {
+    /// Creates a [RiskGate] enforcing `config`
+    pub fn new(config: RiskConfig) -> Self {
+        RiskGate {
+            config,
+            ..Default::default()
+        }
+    }
+
+    /// Creates a [RiskGate] enforcing the limits of the file at `path`
+    ///
+    /// A background thread checks the file for changes every
+    /// [RELOAD_INTERVAL] and the new limits are enforced from the next
+    /// order on. A broken file keeps the previous limits until it is fixed.
+    pub fn watch(path: &str) -> Result<Self, RiskError> {
+        Self::watch_every(path, RELOAD_INTERVAL)
+    }
+
+    /// Creates a [RiskGate] enforcing the limits of the file at `path`,
+    /// checked for changes every `interval`
+    fn watch_every(path: &str, interval: Duration) -> Result<Self, RiskError> {
+        let mut watcher = Watcher {
+            path: String::from(path),
+            modified: None,
+        };
+        let mut gate = RiskGate::new(watcher.poll()?.unwrap_or_default());
+        let reloaded = Reloaded::default();
+        let pending = Arc::clone(&reloaded.0);
+        gate.reloaded = Some(reloaded);
+
+        thread::spawn(move || {
+            while Arc::strong_count(&pending) > 1 {
+                thread::sleep(interval);
+                if let Ok(Some(config)) = watcher.poll() {
+                    *pending.lock().unwrap() = Some(config);
+                }
+            }
+        });
+        Ok(gate)
+    }
+
+    /// Returns the limits being enforced
+    pub fn config(&self) -> &RiskConfig {
+        &self.config
+    }
+
+    /// Replaces the limits
+    ///
+    /// The rates measured so far restart for the users whose rate limit
+    /// changed only.
+    pub fn set_config(&mut self, config: RiskConfig) {
+        let previous = std::mem::replace(&mut self.config, config);
+        let config = &self.config;
+        self.throttles.retain(|user_id, _| {
+            previous.limits(*user_id).max_orders_per_sec
+                == config.limits(*user_id).max_orders_per_sec
+        });
+    }
+
+    /// Enforces the limits reloaded by the watcher thread, if any
+    ///
+    /// Never waits for the watcher: limits it is still storing are picked
+    /// up by a later call. Returns whether new limits were enforced.
+    pub fn reload(&mut self) -> bool {
+        let config = self
+            .reloaded
+            .as_ref()
+            .and_then(|reloaded| reloaded.0.try_lock().ok()?.take());
+        match config {
+            Some(config) => {
+                self.set_config(config);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Checks an action against the limits of its user
+    ///
+    /// Only new orders are checked, cancels and flushes always pass.
+    pub fn check(&mut self, action: &UserAction) -> Result<(), RiskBreach> {
+        self.check_at(action, Instant::now())
+    }
+
+    /// Checks an action as if it arrived at `now`
+    pub fn check_at(&mut self, action: &UserAction, now: Instant) -> Result<(), RiskBreach> {
+        let (user_id, symbol, price, qty, side) = match action {
+            UserAction::NewOrder {
+                user_id,
+                symbol,
+                price,
+                qty,
+                side,
+                ..
+            } => (*user_id, *symbol, *price, *qty, *side),
+            _ => return Ok(()),
+        };
+
+        self.reload();
+        let limits = self.config.limits(user_id);
+
+        // Every order counts towards the rate, even a rejected one
+        if let Some(rate) = limits.max_orders_per_sec {
+            let throttle = self
+                .throttles
+                .entry(user_id)
+                .or_insert_with(|| Throttle::new(rate));
+            if !throttle.allow_at(now) {
+                return Err(RiskBreach::RateLimit);
+            }
+        }
+        if limits.max_order_qty.is_some_and(|max| qty > max) {
+            return Err(RiskBreach::MaxOrderQty);
+        }
+        if limits
+            .max_notional
+            .is_some_and(|max| price.ticks() as u128 * qty.lots() as u128 > max)
+        {
+            return Err(RiskBreach::MaxNotional);
+        }
+        if let Some(max) = limits.max_open_orders {
+            if self.open_orders.get(&user_id).map_or(0, HashSet::len) >= max {
+                return Err(RiskBreach::MaxOpenOrders);
+            }
+        }
+        if let Some(max) = limits.max_position {
+            let position = self.positions.get(&(user_id, symbol)).copied().unwrap_or(0);
+            let filled = match side {
+                Side::Buy => position + qty.lots() as i128,
+                Side::Sell => position - qty.lots() as i128,
+            };
+            if filled.unsigned_abs() > max as u128 {
+                return Err(RiskBreach::MaxPosition);
+            }
+        }
+        if let (Some(max), Some(last)) = (
+            limits.max_price_deviation,
+            self.last_prices.get(&symbol).copied(),
+        ) {
+            let distance = price.ticks().abs_diff(last.ticks()) as u128;
+            if distance * 10_000 > last.ticks() as u128 * max as u128 {
+                return Err(RiskBreach::FatFinger);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Learns the open orders, positions and prices changed by an action
+    /// from the responses it got
+    pub fn update(
+        &mut self,
+        action: &UserAction,
+        responses: &(Option<Response>, Option<Response>),
+    ) {
+        match (action, responses) {
+            (
+                UserAction::NewOrder { symbol, .. },
+                (
+                    Some(Response::Acknowledge { .. }),
+                    Some(Response::Trade {
+                        buyer_id,
+                        buyer_order_id,
+                        seller_id,
+                        seller_order_id,
+                        price,
+                        qty,
+                    }),
+                ),
+            ) => {
+                // Only the resting order of the trade was open
+                for (user_id, order_id) in
+                    [(buyer_id, buyer_order_id), (seller_id, seller_order_id)]
+                {
+                    self.close(*user_id, *order_id);
+                }
+                *self.positions.entry((*buyer_id, *symbol)).or_default() += qty.lots() as i128;
+                *self.positions.entry((*seller_id, *symbol)).or_default() -= qty.lots() as i128;
+                self.last_prices.insert(*symbol, *price);
+            }
+            (
+                UserAction::NewOrder {
+                    user_id, order_id, ..
+                },
+                (Some(Response::Acknowledge { .. }), _),
+            ) => self.open(*user_id, *order_id),
+            (
+                UserAction::CancelOrder { user_id, order_id },
+                (Some(Response::Acknowledge { .. }), _),
+            ) => self.close(*user_id, *order_id),
+            (UserAction::Flush, _) => self.open_orders.clear(),
+            (_, _) => (),
+        }
+    }
+
+    /// Counts a resting order of a user
+    pub fn open(&mut self, user_id: u32, order_id: u32) {
+        self.open_orders
+            .entry(user_id)
+            .or_default()
+            .insert(order_id);
+    }
+
+    /// Forgets a resting order of a user
+    fn close(&mut self, user_id: u32, order_id: u32) {
+        if let Some(orders) = self.open_orders.get_mut(&user_id) {
+            orders.remove(&order_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orderbook::TimeInForce;
+
+    const JSON: &str = r#"{
+        "default": {"max_order_qty": 1000},
+        "users": {"7": {"max_notional": 10000, "max_open_orders": 2,
+                        "max_position": 150, "max_price_deviation": 1000,
+                        "max_orders_per_sec": 4}}
+    }"#;
+
+    fn new_order(user_id: u32, price: u64, qty: u64, side: Side, order_id: u32) -> UserAction {
+        UserAction::NewOrder {
+            user_id,
+            symbol: Symbol::new("IBM"),
+            price: Price(price),
+            qty: Quantity(qty),
+            side,
+            order_id,
+            time_in_force: TimeInForce::Gtc,
+        }
+    }
+
+    fn ack(action: &UserAction) -> (Option<Response>, Option<Response>) {
+        match action {
+            UserAction::NewOrder {
+                user_id, order_id, ..
+            } => (
+                Some(Response::Acknowledge {
+                    user_id: *user_id,
+                    order_id: *order_id,
+                }),
+                None,
+            ),
+            _ => (None, None),
+        }
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut gate = RiskGate::new(RiskConfig::from_json(JSON).unwrap());
+        let start = Instant::now();
+        let check = |gate: &mut RiskGate, order: &UserAction| gate.check_at(order, start);
+
+        // Default limits
+        assert_eq!(
+            check(&mut gate, &new_order(1, 10, 1000, Side::Buy, 1)),
+            Ok(())
+        );
+        assert_eq!(
+            check(&mut gate, &new_order(1, 10, 1001, Side::Buy, 1)),
+            Err(RiskBreach::MaxOrderQty)
+        );
+
+        // Own limits replace the default ones
+        assert_eq!(
+            check(&mut gate, &new_order(7, 100, 101, Side::Buy, 1)),
+            Err(RiskBreach::MaxNotional)
+        );
+        assert_eq!(
+            check(&mut gate, &new_order(7, 10, 200, Side::Sell, 1)),
+            Err(RiskBreach::MaxPosition)
+        );
+
+        // Two open orders at most
+        for order_id in 1..=2 {
+            let order = new_order(7, 10, 100, Side::Buy, order_id);
+            assert_eq!(check(&mut gate, &order), Ok(()));
+            gate.update(&order, &ack(&order));
+        }
+        assert_eq!(
+            check(&mut gate, &new_order(7, 10, 100, Side::Buy, 3)),
+            Err(RiskBreach::RateLimit)
+        );
+        assert_eq!(
+            gate.check_at(
+                &new_order(7, 10, 100, Side::Buy, 3),
+                start + RELOAD_INTERVAL
+            ),
+            Err(RiskBreach::MaxOpenOrders)
+        );
+    }
+
+    #[test]
+    fn test_positions_and_prices_from_responses() {
+        let mut gate = RiskGate::new(RiskConfig::from_json(JSON).unwrap());
+        let resting = new_order(9, 100, 100, Side::Sell, 1);
+        gate.update(&resting, &ack(&resting));
+
+        let trade = Response::Trade {
+            buyer_id: 7,
+            buyer_order_id: 1,
+            seller_id: 9,
+            seller_order_id: 1,
+            price: Price(100),
+            qty: Quantity(100),
+        };
+        let order = new_order(7, 100, 100, Side::Buy, 1);
+        gate.update(&order, &(ack(&order).0, Some(trade)));
+        assert!(gate.open_orders[&9].is_empty());
+
+        // 7 is long 100, the last price is 100
+        let start = Instant::now();
+        assert_eq!(
+            gate.check_at(&new_order(7, 100, 60, Side::Buy, 2), start),
+            Err(RiskBreach::MaxPosition)
+        );
+        assert_eq!(
+            gate.check_at(&new_order(7, 111, 10, Side::Buy, 2), start),
+            Err(RiskBreach::FatFinger)
+        );
+        assert_eq!(
+            gate.check_at(&new_order(7, 90, 10, Side::Sell, 2), start),
+            Ok(())
+        );
+
+        // Cancels are never checked
+        let cancel = UserAction::CancelOrder {
+            user_id: 7,
+            order_id: 2,
+        };
+        assert_eq!(gate.check_at(&cancel, start), Ok(()));
+    }
+
+    #[test]
+    fn test_set_config_keeps_unchanged_rates() {
+        let config = RiskConfig::from_json(JSON).unwrap();
+        let mut gate = RiskGate::new(config.clone());
+        let start = Instant::now();
+        for order_id in 1..=4 {
+            let order = new_order(7, 10, 10, Side::Buy, order_id);
+            assert_eq!(gate.check_at(&order, start), Ok(()));
+        }
+        let order = new_order(7, 10, 10, Side::Buy, 5);
+        assert_eq!(gate.check_at(&order, start), Err(RiskBreach::RateLimit));
+
+        // Other limits change, the rate of user 7 keeps counting
+        let mut changed = config.clone();
+        changed.default.max_order_qty = Some(Quantity(500));
+        gate.set_config(changed.clone());
+        assert_eq!(gate.check_at(&order, start), Err(RiskBreach::RateLimit));
+
+        changed.users.get_mut(&7).unwrap().max_orders_per_sec = Some(5);
+        gate.set_config(changed);
+        assert_eq!(gate.check_at(&order, start), Ok(()));
+    }
+
+    /// Writes `json` to `path` with a modification time of `modified`
+    fn write_limits(path: &str, json: &str, modified: SystemTime) {
+        fs::write(path, json).unwrap();
+        fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_reloads_changed_file() {
+        let path = std::env::temp_dir().join(format!("risk-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        fs::write(path, r#"{"default": {"max_order_qty": 10}}"#).unwrap();
+
+        let mut watcher = Watcher {
+            path: String::from(path),
+            modified: None,
+        };
+        let config = watcher.poll().unwrap().unwrap();
+        assert_eq!(config.default.max_order_qty, Some(Quantity(10)));
+        assert_eq!(watcher.poll().unwrap(), None);
+
+        // A broken file is tried again
+        let modified = SystemTime::now() + Duration::from_secs(1);
+        write_limits(path, "{", modified);
+        assert!(watcher.poll().is_err());
+        assert!(watcher.poll().is_err());
+
+        write_limits(path, r#"{"default": {"max_order_qty": 20}}"#, modified);
+        let config = watcher.poll().unwrap().unwrap();
+        assert_eq!(config.default.max_order_qty, Some(Quantity(20)));
+        assert_eq!(watcher.poll().unwrap(), None);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_watcher_hands_over_changed_limits() {
+        let path = std::env::temp_dir().join(format!("risk-watch-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        fs::write(path, r#"{"default": {"max_order_qty": 10}}"#).unwrap();
+
+        let mut gate = RiskGate::watch_every(path, Duration::from_millis(10)).unwrap();
+        assert_eq!(gate.config().default.max_order_qty, Some(Quantity(10)));
+
+        let modified = SystemTime::now() + Duration::from_secs(1);
+        write_limits(path, r#"{"default": {"max_order_qty": 20}}"#, modified);
+        let order = new_order(1, 10, 20, Side::Buy, 1);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while gate.check(&order).is_err() {
+            assert!(Instant::now() < deadline, "limits not reloaded");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(gate.config().default.max_order_qty, Some(Quantity(20)));
+        assert!(!gate.reload());
+        fs::remove_file(path).unwrap();
+    }
+}
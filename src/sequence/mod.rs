@@ -0,0 +1,103 @@
+//! This mod implements the stamping of emitted [Response]s.
+//!
+//! Every response leaving the engine gets the next number of one sequence
+//! and the time it was emitted at, so responses of different books can be
+//! ordered and correlated. The [Engine](crate::Engine) stamps its outputs
+//! with its own [Sequencer] and [Clock](crate::Clock), see
+//! [Engine::stamp](crate::Engine::stamp).
+
+use serde::{Deserialize, Serialize};
+
+use crate::clock::Timestamp;
+use crate::engine::Output;
+use crate::orderbook::Response;
+use crate::symbol::Symbol;
+
+/// Symbol of the targeted book and the stamped responses to an action
+pub type StampedOutput = (Option<Symbol>, (Option<Stamped>, Option<Stamped>));
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+/// This struct is a [Response] with its sequence number and time
+///
+/// JSON and MessagePack carry the stamp next to the fields of the
+/// response, CSV appends it to the record.
+pub struct Stamped {
+    /// Number of the response, starting at 1
+    pub seq: u64,
+    /// Time the response was emitted at
+    pub timestamp: Timestamp,
+    /// The response itself
+    #[serde(flatten)]
+    pub response: Response,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+/// This struct hands out consecutive sequence numbers to responses
+pub struct Sequencer {
+    /// Number of the last stamped response, 0 before the first one
+    last: u64,
+}
+
+impl Sequencer {
+    /// Creates a [Sequencer] whose first response is number 1
+    pub fn new() -> Self {
+        Sequencer::default()
+    }
+
+    /// Creates a [Sequencer] that carries on after response number `last`
+    pub fn resume(last: u64) -> Self {
+        Sequencer { last }
+    }
+
+    /// Returns the number of the last stamped response
+    pub fn last(&self) -> u64 {
+        self.last
+    }
+
+    /// Stamps one response with the next number and `timestamp`
+    pub fn stamp(&mut self, response: Response, timestamp: Timestamp) -> Stamped {
+        self.last += 1;
+        Stamped {
+            seq: self.last,
+            timestamp,
+            response,
+        }
+    }
+
+    /// Stamps the responses of one action, in order, with `timestamp`
+    pub fn stamp_output(&mut self, output: Output, timestamp: Timestamp) -> StampedOutput {
+        let (symbol, (res1, res2)) = output;
+        let res1 = res1.map(|r| self.stamp(r, timestamp));
+        let res2 = res2.map(|r| self.stamp(r, timestamp));
+        (symbol, (res1, res2))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sequencer() {
+        let ack = |order_id| Response::Acknowledge {
+            user_id: 1,
+            order_id,
+        };
+        let mut sequencer = Sequencer::new();
+        assert_eq!(sequencer.last(), 0);
+
+        let (symbol, (res1, res2)) =
+            sequencer.stamp_output((Some(Symbol::new("IBM")), (Some(ack(1)), Some(ack(2)))), 7);
+        assert_eq!(symbol, Some(Symbol::new("IBM")));
+        assert_eq!(res1.map(|s| (s.seq, s.timestamp)), Some((1, 7)));
+        assert_eq!(res2.map(|s| (s.seq, s.response)), Some((2, ack(2))));
+
+        // Actions without responses take no number
+        assert_eq!(
+            sequencer.stamp_output((None, (None, None)), 8),
+            (None, (None, None))
+        );
+        assert_eq!(sequencer.stamp(ack(3), 9).seq, 3);
+        assert_eq!(sequencer.last(), 3);
+    }
+}
@@ -0,0 +1,666 @@
+//! This mod implements a multi-threaded engine partitioned by symbol.
+//!
+//! Symbols are hash-partitioned across N shards, each an [Engine] on its
+//! own thread owning its books. The [Router] gives every action a global
+//! sequence number and sends it to the owning shard; cancels - which carry
+//! no symbol - go to the shard the order was sent to and flushes go to all
+//! of them. The [Merger] reads the shard outputs back in sequence order.
+//!
+//! The merged stream is the one a single [Engine] would emit. Order ids
+//! are unique across the shards: the [Merger] tells the [Router] which ids
+//! its outputs take and release, so an id is free again once its order is
+//! rejected, filled or cancelled. When the id of a new order may still be
+//! taken by another shard, the [Router] waits for the [Merger] to catch up
+//! before it decides.
+//!
+//! Shards never expire orders, so the [Router] refuses new orders with a
+//! time in force other than [TimeInForce::Gtc].
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::JoinHandle;
+
+use crate::engine::{Engine, OrderIdScope};
+use crate::orderbook::{RejectReason, Response, TimeInForce, UserAction};
+use crate::spsc::{self, Consumer, Producer};
+use crate::symbol::Symbol;
+
+/// Capacity of the rings between the router, the shards and the merger
+pub const RING_CAPACITY: usize = 1024;
+
+/// Symbol of the book and responses of one action, as returned by
+/// [Engine::new_user_action]
+pub type Output = (Option<Symbol>, (Option<Response>, Option<Response>));
+
+/// Returns the shard owning `symbol`
+///
+/// Uses FNV-1a, so the partitioning is stable across runs and builds.
+pub fn shard_of(symbol: &str, shards: usize) -> usize {
+    let hash = symbol.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    });
+    (hash % shards as u64) as usize
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+/// This enum describes where the router sent an action
+enum Target {
+    /// The shard of a new order
+    New(usize),
+    /// The shard of a cancelled order
+    Cancel(usize),
+    /// Every shard
+    All,
+    /// No shard, the router rejected the new order of a taken id
+    Duplicate {
+        user_id: u32,
+        symbol: Symbol,
+        order_id: u32,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+/// This enum describes what the [Merger] learnt from the output of an
+/// action about the order ids
+enum Feedback {
+    /// A shard took an id
+    Take((u32, u32), usize),
+    /// An id is free again
+    Release((u32, u32)),
+    /// Every id is free again
+    Clear,
+    /// The output of the action with this sequence number was merged
+    Merged(u64),
+}
+
+/// This struct sends actions to the shards
+pub struct Router {
+    inputs: Vec<Producer<UserAction>>,
+    targets: Producer<(u64, Target)>,
+    /// Ids taken as of the last merged action, by `(user_id, order_id)`
+    owners: HashMap<(u32, u32), usize>,
+    /// Shard and sequence number of the last new order sent for an id and
+    /// not merged yet
+    pending: HashMap<(u32, u32), (usize, u64)>,
+    /// Ids of `pending` in the order they were sent
+    sent: VecDeque<(u64, (u32, u32))>,
+    /// Ids taken and released by the merged outputs
+    feedback: Receiver<Feedback>,
+    /// Sequence number of the last merged action
+    merged: u64,
+    next_seq: u64,
+}
+
+impl Router {
+    /// Returns the number of shards
+    pub fn shards(&self) -> usize {
+        self.inputs.len()
+    }
+
+    /// Applies what the [Merger] learnt about the order ids
+    fn learn(&mut self, feedback: Feedback) {
+        match feedback {
+            Feedback::Take(id, shard) => {
+                self.owners.insert(id, shard);
+            }
+            Feedback::Release(id) => {
+                self.owners.remove(&id);
+            }
+            Feedback::Clear => self.owners.clear(),
+            Feedback::Merged(seq) => {
+                self.merged = seq;
+                while let Some((sent, id)) = self.sent.front().copied() {
+                    if sent > seq {
+                        break;
+                    }
+                    self.sent.pop_front();
+                    if self.pending.get(&id).is_some_and(|(_, s)| *s == sent) {
+                        self.pending.remove(&id);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Waits until the output of the action with sequence number `seq` is
+    /// merged
+    fn wait_merged(&mut self, seq: u64) -> io::Result<()> {
+        while self.merged < seq {
+            let feedback = self
+                .feedback
+                .recv()
+                .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "merger is gone"))?;
+            self.learn(feedback);
+        }
+
+        Ok(())
+    }
+
+    /// Returns where a new order of `shard` with sequence number `seq`
+    /// goes
+    fn new_order_target(
+        &mut self,
+        seq: u64,
+        shard: usize,
+        user_id: u32,
+        symbol: Symbol,
+        order_id: u32,
+    ) -> io::Result<Target> {
+        let id = (user_id, order_id);
+        let elsewhere = |router: &Router| {
+            router.owners.get(&id).is_some_and(|owner| *owner != shard)
+                || router
+                    .pending
+                    .get(&id)
+                    .is_some_and(|(owner, _)| *owner != shard)
+        };
+
+        // Only the merged outputs tell whether another shard still holds
+        // the id. The shard itself checks the ids it owns.
+        if elsewhere(self) {
+            self.wait_merged(seq - 1)?;
+            if elsewhere(self) {
+                return Ok(Target::Duplicate {
+                    user_id,
+                    symbol,
+                    order_id,
+                });
+            }
+        }
+
+        self.pending.insert(id, (shard, seq));
+        self.sent.push_back((seq, id));
+        Ok(Target::New(shard))
+    }
+
+    /// Sends an action to the shard owning it, returns its global sequence
+    /// number
+    ///
+    /// A new order whose id is taken by another shard is rejected without
+    /// reaching any shard, see the [module](self) documentation. Waits
+    /// while the shard is busy, or until the [Merger] read the outputs the
+    /// owner of an id depends on. Fails when the shards or the [Merger]
+    /// are gone, and on a new order that may expire.
+    pub fn submit(&mut self, action: UserAction) -> io::Result<u64> {
+        if let UserAction::NewOrder {
+            time_in_force: time_in_force @ (TimeInForce::Day | TimeInForce::Gtd(_)),
+            ..
+        } = action
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("sharded engines never expire orders: {}", time_in_force),
+            ));
+        }
+
+        while let Ok(feedback) = self.feedback.try_recv() {
+            self.learn(feedback);
+        }
+
+        let seq = self.next_seq;
+        let shards = self.shards();
+        let target = match &action {
+            UserAction::NewOrder {
+                user_id,
+                symbol,
+                order_id,
+                ..
+            } => {
+                let shard = shard_of(symbol.as_str(), shards);
+                self.new_order_target(seq, shard, *user_id, *symbol, *order_id)?
+            }
+            // A cancel of an unknown order is rejected by any shard
+            UserAction::CancelOrder { user_id, order_id } => {
+                let id = (*user_id, *order_id);
+                let shard = match self.pending.get(&id) {
+                    Some((shard, _)) => Some(*shard),
+                    None => self.owners.get(&id).copied(),
+                };
+                Target::Cancel(shard.unwrap_or(0))
+            }
+            UserAction::Flush => Target::All,
+        };
+
+        self.next_seq += 1;
+        let gone = || io::Error::new(io::ErrorKind::BrokenPipe, "shard is gone");
+
+        self.targets.send((seq, target)).map_err(|_| gone())?;
+        match target {
+            Target::New(shard) | Target::Cancel(shard) => {
+                self.inputs[shard].send(action).map_err(|_| gone())?
+            }
+            Target::All => {
+                for input in &mut self.inputs {
+                    input.send(action.clone()).map_err(|_| gone())?;
+                }
+            }
+            Target::Duplicate { .. } => (),
+        }
+
+        Ok(seq)
+    }
+}
+
+/// This struct merges the outputs of the shards in sequence order
+pub struct Merger {
+    outputs: Vec<Consumer<Output>>,
+    targets: Consumer<(u64, Target)>,
+    handles: Vec<JoinHandle<Engine>>,
+    /// How long the shards keep an order id taken
+    order_id_scope: OrderIdScope,
+    /// Ids taken and released, sent back to the [Router]
+    feedback: Sender<Feedback>,
+}
+
+impl Merger {
+    /// Tells the [Router] the ids the output of an action takes and
+    /// releases, as a single [Engine] would
+    fn learn(&self, seq: u64, target: Target, output: &Output) {
+        let session = self.order_id_scope == OrderIdScope::Session;
+        let mut changes = vec![];
+        match (target, &output.1) {
+            (
+                Target::New(shard),
+                (
+                    Some(Response::Acknowledge { user_id, order_id }),
+                    Some(Response::Trade {
+                        buyer_id,
+                        buyer_order_id,
+                        seller_id,
+                        seller_order_id,
+                        ..
+                    }),
+                ),
+            ) => {
+                if session {
+                    changes.push(Feedback::Take((*user_id, *order_id), shard));
+                } else {
+                    changes.push(Feedback::Release((*buyer_id, *buyer_order_id)));
+                    changes.push(Feedback::Release((*seller_id, *seller_order_id)));
+                }
+            }
+            (Target::New(shard), (Some(Response::Acknowledge { user_id, order_id }), _)) => {
+                changes.push(Feedback::Take((*user_id, *order_id), shard));
+            }
+            (Target::Cancel(_), (Some(Response::Acknowledge { user_id, order_id }), _))
+                if !session =>
+            {
+                changes.push(Feedback::Release((*user_id, *order_id)));
+            }
+            (Target::All, _) => changes.push(Feedback::Clear),
+            (_, _) => (),
+        }
+        changes.push(Feedback::Merged(seq));
+
+        // The router is gone once every action was submitted
+        for change in changes {
+            let _ = self.feedback.send(change);
+        }
+    }
+
+    /// Returns the output of the next action with its global sequence
+    /// number
+    ///
+    /// Returns `None` once the [Router] is dropped and every output was
+    /// read.
+    pub fn recv(&mut self) -> Option<(u64, Output)> {
+        let (seq, target) = self.targets.recv()?;
+
+        let output = match target {
+            Target::New(shard) | Target::Cancel(shard) => self.outputs[shard].recv()?,
+            Target::All => {
+                for output in &mut self.outputs {
+                    output.recv()?;
+                }
+                (None, (None, None))
+            }
+            Target::Duplicate {
+                user_id,
+                symbol,
+                order_id,
+            } => {
+                let reject = Response::Reject {
+                    user_id,
+                    order_id,
+                    reason: RejectReason::DuplicateOrderId,
+                };
+                (Some(symbol), (Some(reject), None))
+            }
+        };
+        self.learn(seq, target, &output);
+
+        Some((seq, output))
+    }
+
+    /// Waits for the shards to stop and returns their engines
+    ///
+    /// The [Router] must be dropped first. Outputs not read yet are
+    /// discarded.
+    pub fn join(mut self) -> Vec<Engine> {
+        while self.recv().is_some() {}
+        drop(self.outputs);
+
+        self.handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect()
+    }
+}
+
+impl Iterator for Merger {
+    type Item = (u64, Output);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.recv()
+    }
+}
+
+/// Starts `shards` engine threads
+///
+/// Every shard runs its own engine made by `new_engine`, the [Router]
+/// keeps order ids unique across the shards. Shard `i` is pinned to
+/// `cores[i]` when given. Sequence numbers start at 1.
+///
+/// The [Router] may wait for the [Merger], so they must not be driven
+/// from the same thread.
+pub fn spawn(
+    shards: usize,
+    cores: &[usize],
+    new_engine: impl Fn() -> Engine,
+) -> io::Result<(Router, Merger)> {
+    let shards = shards.max(1);
+    let mut inputs = vec![];
+    let mut outputs = vec![];
+    let mut handles = vec![];
+    let mut order_id_scope = OrderIdScope::default();
+
+    for i in 0..shards {
+        let (input, mut actions) = spsc::channel::<UserAction>(RING_CAPACITY);
+        let (mut responses, output) = spsc::channel::<Output>(RING_CAPACITY);
+
+        let name = format!("shard-{}", i);
+        let mut engine = new_engine();
+        order_id_scope = engine.order_id_scope();
+        handles.push(spsc::spawn_pinned(
+            &name,
+            cores.get(i).copied(),
+            move || {
+                while let Some(action) = actions.recv() {
+                    if responses.send(engine.new_user_action(action)).is_err() {
+                        break;
+                    }
+                }
+                engine
+            },
+        )?);
+        inputs.push(input);
+        outputs.push(output);
+    }
+
+    let (targets_tx, targets_rx) = spsc::channel(RING_CAPACITY * shards);
+    let (feedback_tx, feedback_rx) = mpsc::channel();
+    let router = Router {
+        inputs,
+        targets: targets_tx,
+        owners: HashMap::new(),
+        pending: HashMap::new(),
+        sent: VecDeque::new(),
+        feedback: feedback_rx,
+        merged: 0,
+        next_seq: 1,
+    };
+    let merger = Merger {
+        outputs,
+        targets: targets_rx,
+        handles,
+        order_id_scope,
+        feedback: feedback_tx,
+    };
+
+    Ok((router, merger))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::{Format, Reader};
+    use crate::fixed::{Price, Quantity};
+    use crate::orderbook::{OrderBook, Side, TimeInForce};
+    use std::collections::HashSet;
+    use std::fs::File;
+    use std::io::BufReader;
+    use std::thread;
+
+    /// Orders, cancels and flushes over several symbols
+    fn workload() -> Vec<UserAction> {
+        let file = File::open("input/input.csv").unwrap();
+        let mut actions: Vec<UserAction> = Reader::new(Format::Csv, BufReader::new(file))
+            .map(|a| a.unwrap())
+            .collect();
+
+        let symbols = ["IBM", "AAPL", "VAL", "MSFT", "NVDA", "AMZN", "GOOG"];
+        for i in 0..5_000u32 {
+            let user_id = i % 7;
+            if i % 4 == 3 {
+                actions.push(UserAction::CancelOrder {
+                    user_id,
+                    order_id: i / 2,
+                });
+            } else {
+                let buy = (i * 7) % 3 == 0;
+                actions.push(UserAction::NewOrder {
+                    user_id,
+                    symbol: Symbol::new(symbols[(i * 13 % 7) as usize]),
+                    price: Price(if buy { 95 + i % 6 } else { 100 + i % 6 } as u64),
+                    qty: Quantity(100),
+                    side: if buy { Side::Buy } else { Side::Sell },
+                    order_id: i,
+                    time_in_force: TimeInForce::Gtc,
+                });
+            }
+        }
+
+        // Few ids reused over every symbol once their orders are
+        // rejected, filled or cancelled
+        for i in 0..5_000u32 {
+            let user_id = i % 3;
+            let order_id = 10_000 + i % 40;
+            if i % 5 == 4 {
+                actions.push(UserAction::CancelOrder { user_id, order_id });
+            } else {
+                let buy = i % 2 == 0;
+                actions.push(UserAction::NewOrder {
+                    user_id,
+                    symbol: Symbol::new(symbols[(i * 3 % 7) as usize]),
+                    price: Price(98 + (i * 7 % 5) as u64),
+                    qty: Quantity(100),
+                    side: if buy { Side::Buy } else { Side::Sell },
+                    order_id,
+                    time_in_force: TimeInForce::Gtc,
+                });
+            }
+        }
+        actions
+    }
+
+    fn run(shards: usize, actions: &[UserAction]) -> (Vec<(u64, Output)>, Vec<Engine>) {
+        let (mut router, merger) = spawn(shards, &[], || Engine::new(true)).unwrap();
+
+        let actions = actions.to_vec();
+        let submitter = thread::spawn(move || {
+            for action in actions {
+                router.submit(action).unwrap();
+            }
+        });
+
+        let mut merger = merger;
+        let mut outputs = vec![];
+        while let Some(output) = merger.recv() {
+            outputs.push(output);
+        }
+        submitter.join().unwrap();
+
+        (outputs, merger.join())
+    }
+
+    /// Every book of the engines, by symbol
+    fn books(engines: &[Engine]) -> HashMap<Symbol, &OrderBook> {
+        engines
+            .iter()
+            .flat_map(|engine| engine.books())
+            .map(|(symbol, book)| (*symbol, book))
+            .collect()
+    }
+
+    #[test]
+    fn test_one_and_many_shards_agree() {
+        let actions = workload();
+        let (single, single_engines) = run(1, &actions);
+        let (sharded, sharded_engines) = run(4, &actions);
+
+        assert_eq!(single.len(), actions.len());
+        assert_eq!(
+            single.iter().map(|(seq, _)| *seq).collect::<Vec<_>>(),
+            (1..=actions.len() as u64).collect::<Vec<_>>()
+        );
+        assert_eq!(sharded, single);
+        assert_eq!(books(&sharded_engines), books(&single_engines));
+
+        // The symbols really are spread over the shards
+        assert!(sharded_engines.iter().all(|e| e.books().count() > 0));
+
+        // Ids come back to other shards after rejects, fills and cancels
+        let mut acked: HashMap<(u32, u32), HashSet<usize>> = HashMap::new();
+        for (_, (symbol, responses)) in &single {
+            if let (Some(symbol), (Some(Response::Acknowledge { user_id, order_id }), _)) =
+                (symbol, responses)
+            {
+                acked
+                    .entry((*user_id, *order_id))
+                    .or_default()
+                    .insert(shard_of(symbol.as_str(), 4));
+            }
+        }
+        assert!(acked.values().filter(|shards| shards.len() > 1).count() > 10);
+        let reasons: HashSet<RejectReason> = single
+            .iter()
+            .filter_map(|(_, (_, responses))| match responses.0 {
+                Some(Response::Reject { reason, .. }) => Some(reason),
+                _ => None,
+            })
+            .collect();
+        assert!(reasons.contains(&RejectReason::DuplicateOrderId));
+        assert!(reasons.contains(&RejectReason::NoMatchingQty));
+    }
+
+    #[test]
+    fn test_ids_stay_with_their_shard() {
+        let new_order = |symbol: &str| UserAction::NewOrder {
+            user_id: 1,
+            symbol: Symbol::new(symbol),
+            price: Price(10),
+            qty: Quantity(100),
+            side: Side::Buy,
+            order_id: 1,
+            time_in_force: TimeInForce::Gtc,
+        };
+        assert_ne!(shard_of("IBM", 4), shard_of("AAPL", 4));
+        let cancel = UserAction::CancelOrder {
+            user_id: 1,
+            order_id: 1,
+        };
+        let ask = |qty: u64| UserAction::NewOrder {
+            user_id: 2,
+            symbol: Symbol::new("IBM"),
+            price: Price(10),
+            qty: Quantity(qty),
+            side: Side::Sell,
+            order_id: qty as u32,
+            time_in_force: TimeInForce::Gtc,
+        };
+        let actions = [
+            new_order("IBM"),
+            new_order("AAPL"),
+            cancel.clone(),
+            new_order("AAPL"),
+            cancel.clone(),
+            ask(50),
+            new_order("IBM"),
+            new_order("AAPL"),
+            cancel.clone(),
+            ask(100),
+            new_order("IBM"),
+            new_order("AAPL"),
+        ];
+
+        let ack = |user_id, order_id| Some(Response::Acknowledge { user_id, order_id });
+        let reject = |reason| {
+            Some(Response::Reject {
+                user_id: 1,
+                order_id: 1,
+                reason,
+            })
+        };
+        let (outputs, engines) = run(4, &actions);
+        let responses: Vec<_> = outputs.into_iter().map(|(_, (_, r))| r.0).collect();
+        assert_eq!(
+            responses,
+            vec![
+                ack(1, 1),
+                reject(RejectReason::DuplicateOrderId),
+                // The IBM order is cancelled, then the id is free again
+                ack(1, 1),
+                ack(1, 1),
+                ack(1, 1),
+                ack(2, 50),
+                // The IBM order is rejected, so the id stays free
+                reject(RejectReason::NoMatchingQty),
+                ack(1, 1),
+                ack(1, 1),
+                ack(2, 100),
+                // The IBM order fills, so the id is free again
+                ack(1, 1),
+                ack(1, 1),
+            ]
+        );
+        let books = books(&engines);
+        assert_eq!(books[&Symbol::new("IBM")].orders().count(), 1);
+        assert_eq!(books[&Symbol::new("AAPL")].orders().count(), 1);
+    }
+
+    #[test]
+    fn test_refuses_expiring_orders() {
+        let (mut router, merger) = spawn(2, &[], || Engine::new(true)).unwrap();
+        let new_order = |order_id, time_in_force| UserAction::NewOrder {
+            user_id: 1,
+            symbol: Symbol::new("IBM"),
+            price: Price(10),
+            qty: Quantity(100),
+            side: Side::Buy,
+            order_id,
+            time_in_force,
+        };
+
+        for time_in_force in [TimeInForce::Day, TimeInForce::Gtd(1_000)] {
+            let e = router.submit(new_order(1, time_in_force)).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        }
+        assert_eq!(router.submit(new_order(1, TimeInForce::Gtc)).unwrap(), 1);
+        drop(router);
+        assert_eq!(merger.count(), 1);
+    }
+
+    #[test]
+    fn test_shard_of() {
+        assert_eq!(shard_of("IBM", 1), 0);
+        // FNV-1a of "IBM" is 0x412e_4819_c925_0b0d
+        assert_eq!(shard_of("IBM", 4), 1);
+
+        let mut used = [false; 4];
+        for symbol in ["IBM", "AAPL", "VAL", "NVDA"] {
+            used[shard_of(symbol, 4)] = true;
+        }
+        assert_eq!(used, [true; 4]);
+    }
+}
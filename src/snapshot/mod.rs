@@ -0,0 +1,533 @@
+//! This mod implements periodic snapshots of the books for fast restart.
+//!
+//! A snapshot holds every [OrderBook] as of a journal sequence number.
+//! Recovery loads the latest snapshot and replays only the journal entries
+//! after it.
+//!
+//! Each snapshot file is laid out as (little endian):
+//!
+//! | Field    | Size | Description                              |
+//! |----------|------|------------------------------------------|
+//! | magic    | 4    | `OBSN`                                   |
+//! | version  | 2    | Layout version of the books              |
+//! | seq      | 8    | Journal sequence number of the snapshot  |
+//! | checksum | 4    | CRC-32 of the payload                    |
+//! | payload  | *    | MessagePack encoded books and order times |
+//!
+//! The payload of version 5 is a [PayloadV5], which adds the order ids
+//! accepted in the session to the [PayloadV4] of version 4. Version 4 adds
+//! the number of the last stamped response to the [PayloadV3] of version 3. Version 3 holds
+//! the [BookV2]s together with the entry and expiry times of the live
+//! orders. Versions 1 and 2 hold only the `(symbol, book)`s, as [BookV1]s
+//! with 32 bit prices and quantities and as [BookV2]s; their orders never
+//! expire. Older versions keep being decoded through their own layout when
+//! the current one changes.
+
+use std::cmp::Reverse;
+use std::fmt::{Display, Formatter};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::engine::{Engine, OrderTimes};
+use crate::journal::{Entry, Journal, JournalError};
+use crate::orderbook::snapshot::{BookV1, BookV2};
+use crate::orderbook::OrderBook;
+use crate::symbol::Symbol;
+
+/// Snapshot file magic
+pub const MAGIC: [u8; 4] = *b"OBSN";
+
+/// Layout version written by this build
+pub const VERSION: u16 = 5;
+
+/// Length of the snapshot header
+pub const HEADER_LEN: usize = 18;
+
+/// Default number of journaled actions between two snapshots
+pub const DEFAULT_SNAPSHOT_EVERY: u64 = 1000;
+
+#[derive(Debug)]
+/// Errors produced while writing or loading snapshots
+pub enum SnapshotError {
+    /// Reading or writing the file failed
+    Io(io::Error),
+    /// The books could not be encoded
+    Encode(rmp_serde::encode::Error),
+    /// The payload could not be decoded
+    Decode(rmp_serde::decode::Error),
+    /// The file is not a snapshot
+    BadMagic,
+    /// The layout version is not known to this build
+    UnsupportedVersion(u16),
+    /// The payload does not match its checksum
+    Checksum,
+    /// The journal could not be opened
+    Journal(JournalError),
+}
+
+impl Display for SnapshotError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            SnapshotError::Io(e) => write!(f, "{}", e),
+            SnapshotError::Encode(e) => write!(f, "{}", e),
+            SnapshotError::Decode(e) => write!(f, "{}", e),
+            SnapshotError::BadMagic => write!(f, "not a snapshot file"),
+            SnapshotError::UnsupportedVersion(version) => {
+                write!(f, "unsupported snapshot version {}", version)
+            }
+            SnapshotError::Checksum => write!(f, "snapshot checksum mismatch"),
+            SnapshotError::Journal(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> Self {
+        SnapshotError::Io(e)
+    }
+}
+
+impl From<rmp_serde::encode::Error> for SnapshotError {
+    fn from(e: rmp_serde::encode::Error) -> Self {
+        SnapshotError::Encode(e)
+    }
+}
+
+impl From<rmp_serde::decode::Error> for SnapshotError {
+    fn from(e: rmp_serde::decode::Error) -> Self {
+        SnapshotError::Decode(e)
+    }
+}
+
+impl From<JournalError> for SnapshotError {
+    fn from(e: JournalError) -> Self {
+        SnapshotError::Journal(e)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// This struct describes the times of a live order in layout version 3
+pub struct OrderTimesV3 {
+    pub user_id: u32,
+    pub order_id: u32,
+    pub entry_time: u64,
+    pub expiry: Option<u64>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+/// This struct describes the payload of layout version 3
+///
+/// Books are sorted by symbol, order times by user and order id.
+pub struct PayloadV3 {
+    pub books: Vec<(String, BookV2)>,
+    pub times: Vec<OrderTimesV3>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+/// This struct describes the payload of layout version 4
+///
+/// Same as [PayloadV3] with the number of the last response stamped once
+/// the action at the sequence number of the snapshot was applied.
+pub struct PayloadV4 {
+    pub books: Vec<(String, BookV2)>,
+    pub times: Vec<OrderTimesV3>,
+    pub stamped: u64,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+/// This struct describes the payload of layout version 5
+///
+/// Same as [PayloadV4] with the `(user_id, order_id)`s accepted in the
+/// session, sorted, see [Engine::session_ids].
+pub struct PayloadV5 {
+    pub books: Vec<(String, BookV2)>,
+    pub times: Vec<OrderTimesV3>,
+    pub stamped: u64,
+    pub session_ids: Vec<(u32, u32)>,
+}
+
+impl From<OrderTimes> for OrderTimesV3 {
+    fn from(times: OrderTimes) -> Self {
+        OrderTimesV3 {
+            user_id: times.user_id,
+            order_id: times.order_id,
+            entry_time: times.entry_time,
+            expiry: times.expiry,
+        }
+    }
+}
+
+impl From<OrderTimesV3> for OrderTimes {
+    fn from(times: OrderTimesV3) -> Self {
+        OrderTimes {
+            user_id: times.user_id,
+            order_id: times.order_id,
+            entry_time: times.entry_time,
+            expiry: times.expiry,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+/// This struct describes the books as of a journal sequence number
+pub struct Snapshot {
+    /// Sequence number of the last journaled action applied to the books
+    pub seq: u64,
+    /// Books by symbol, sorted by symbol
+    pub books: Vec<(Symbol, OrderBook)>,
+    /// Times of the live orders, empty before layout version 3
+    pub times: Vec<OrderTimes>,
+    /// Number of the last stamped response, 0 before layout version 4
+    pub stamped: u64,
+    /// Order ids accepted in the session, empty before layout version 5
+    pub session_ids: Vec<(u32, u32)>,
+}
+
+impl Snapshot {
+    /// Encodes the books, order times, number of the last stamped response
+    /// and session order ids of an engine in the current layout
+    pub fn encode(seq: u64, engine: &Engine) -> Result<Vec<u8>, SnapshotError> {
+        let mut books: Vec<(String, BookV2)> = engine
+            .books()
+            .map(|(symbol, book)| (String::from(symbol.as_str()), BookV2::from(book)))
+            .collect();
+        books.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        let payload = rmp_serde::to_vec_named(&PayloadV5 {
+            books,
+            times: engine.order_times().into_iter().map(Into::into).collect(),
+            stamped: engine.last_seq(),
+            session_ids: engine.session_ids(),
+        })?;
+
+        let mut buf = Vec::with_capacity(HEADER_LEN + payload.len());
+        buf.extend_from_slice(&MAGIC);
+        buf.extend_from_slice(&VERSION.to_le_bytes());
+        buf.extend_from_slice(&seq.to_le_bytes());
+        buf.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        buf.extend_from_slice(&payload);
+
+        Ok(buf)
+    }
+
+    /// Decodes a snapshot of any known layout version
+    pub fn decode(buf: &[u8]) -> Result<Snapshot, SnapshotError> {
+        if buf.len() < HEADER_LEN || buf[0..4] != MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+
+        let version = u16::from_le_bytes(buf[4..6].try_into().unwrap());
+        let seq = u64::from_le_bytes(buf[6..14].try_into().unwrap());
+        let crc = u32::from_le_bytes(buf[14..18].try_into().unwrap());
+        let payload = &buf[HEADER_LEN..];
+        if crc32fast::hash(payload) != crc {
+            return Err(SnapshotError::Checksum);
+        }
+
+        let (books, times, stamped, session_ids) = match version {
+            1 => (
+                rmp_serde::from_slice::<Vec<(String, BookV1)>>(payload)?
+                    .into_iter()
+                    .map(|(symbol, book)| (symbol, BookV2::from(book)))
+                    .collect(),
+                vec![],
+                0,
+                vec![],
+            ),
+            2 => (
+                rmp_serde::from_slice::<Vec<(String, BookV2)>>(payload)?,
+                vec![],
+                0,
+                vec![],
+            ),
+            3 => {
+                let payload = rmp_serde::from_slice::<PayloadV3>(payload)?;
+                (payload.books, payload.times, 0, vec![])
+            }
+            4 => {
+                let payload = rmp_serde::from_slice::<PayloadV4>(payload)?;
+                (payload.books, payload.times, payload.stamped, vec![])
+            }
+            5 => {
+                let payload = rmp_serde::from_slice::<PayloadV5>(payload)?;
+                (
+                    payload.books,
+                    payload.times,
+                    payload.stamped,
+                    payload.session_ids,
+                )
+            }
+            _ => return Err(SnapshotError::UnsupportedVersion(version)),
+        };
+
+        Ok(Snapshot {
+            seq,
+            books: books
+                .into_iter()
+                .map(|(symbol, book)| (Symbol::new(&symbol), OrderBook::from(book)))
+                .collect(),
+            times: times.into_iter().map(Into::into).collect(),
+            stamped,
+            session_ids,
+        })
+    }
+
+    /// Creates an [Engine] holding the books, order times and session
+    /// order ids of the snapshot, see [Engine::from_books], that numbers
+    /// its responses on from the last stamped one
+    ///
+    /// The session order ids only count once the engine is given
+    /// [OrderIdScope::Session](crate::engine::OrderIdScope::Session).
+    pub fn restore(self, trade_active: bool) -> Engine {
+        Engine::from_books(trade_active, self.books)
+            .with_order_times(self.times)
+            .with_session_ids(self.session_ids)
+            .with_last_seq(self.stamped)
+    }
+}
+
+/// Returns the file name of the snapshot at `seq`
+fn file_name(seq: u64) -> String {
+    format!("snapshot-{:020}.bin", seq)
+}
+
+/// Parses the sequence number out of a snapshot file name
+fn file_seq(name: &str) -> Option<u64> {
+    name.strip_prefix("snapshot-")?
+        .strip_suffix(".bin")?
+        .parse()
+        .ok()
+}
+
+/// Writes a snapshot of the books of an engine to `dir`
+///
+/// The file is written under a temporary name and renamed once synced, so
+/// a crash never leaves a partial snapshot behind.
+pub fn save(dir: impl AsRef<Path>, seq: u64, engine: &Engine) -> Result<PathBuf, SnapshotError> {
+    let dir = dir.as_ref();
+    fs::create_dir_all(dir)?;
+
+    let buf = Snapshot::encode(seq, engine)?;
+    let path = dir.join(file_name(seq));
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(&buf)?;
+    file.sync_all()?;
+    fs::rename(&tmp, &path)?;
+    File::open(dir)?.sync_all()?;
+
+    Ok(path)
+}
+
+/// Loads the latest readable snapshot of `dir` taken at or before `max_seq`
+///
+/// Damaged snapshots are skipped in favour of older ones.
+pub fn load_latest(dir: impl AsRef<Path>, max_seq: u64) -> Result<Option<Snapshot>, SnapshotError> {
+    let dir = dir.as_ref();
+    if !dir.exists() {
+        return Ok(None);
+    }
+
+    let mut snapshots = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if let Some(seq) = entry.file_name().to_str().and_then(file_seq) {
+            if seq <= max_seq {
+                snapshots.push((seq, entry.path()));
+            }
+        }
+    }
+    snapshots.sort_unstable_by_key(|(seq, _)| Reverse(*seq));
+
+    for (_, path) in snapshots {
+        match Snapshot::decode(&fs::read(&path)?) {
+            Ok(snapshot) => return Ok(Some(snapshot)),
+            Err(SnapshotError::Io(e)) => return Err(SnapshotError::Io(e)),
+            Err(_) => continue,
+        }
+    }
+
+    Ok(None)
+}
+
+/// Opens a journal and recovers the books from the latest snapshot
+///
+/// Returns the journal, the latest snapshot, if any, and the journal
+/// entries to replay on top of it.
+pub fn recover(
+    dir: impl AsRef<Path>,
+    journal: impl AsRef<Path>,
+    sync_every: usize,
+) -> Result<(Journal, Option<Snapshot>, Vec<Entry>), SnapshotError> {
+    let (journal, mut entries) = Journal::open(journal, sync_every)?;
+
+    let snapshot = load_latest(dir, journal.next_seq() - 1)?;
+    if let Some(snapshot) = snapshot.as_ref() {
+        entries.retain(|e| e.seq > snapshot.seq);
+    }
+    Ok((journal, snapshot, entries))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{ManualClock, SharedClock};
+    use crate::codec::{Format, Reader};
+    use crate::engine::Engine;
+    use crate::fixed::{Price, Quantity};
+    use crate::orderbook::snapshot::OrderV1;
+    use crate::orderbook::{Response, Side, TimeInForce, UserAction};
+
+    fn input_actions() -> Vec<UserAction> {
+        let file = File::open("input/input.csv").unwrap();
+        Reader::new(Format::Csv, io::BufReader::new(file))
+            .map(|a| a.unwrap())
+            .collect()
+    }
+
+    /// Returns an [Engine] whose clock stands still at 0, so that replays
+    /// enter orders at the same time as restored books
+    fn new_engine() -> Engine {
+        Engine::new(true).with_clock(SharedClock::new(ManualClock::default()))
+    }
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        let mut engine = new_engine();
+        for action in &input_actions()[..30] {
+            let output = engine.new_user_action(action.clone());
+            engine.stamp(output);
+        }
+
+        let buf = Snapshot::encode(30, &engine).unwrap();
+        let snapshot = Snapshot::decode(&buf).unwrap();
+        assert_eq!(snapshot.seq, 30);
+        assert_eq!(snapshot.stamped, engine.last_seq());
+        assert_eq!(snapshot.restore(true), engine);
+
+        let mut buf = buf;
+        buf[4] = 6;
+        assert!(matches!(
+            Snapshot::decode(&buf),
+            Err(SnapshotError::UnsupportedVersion(6))
+        ));
+    }
+
+    #[test]
+    fn test_snapshot_keeps_order_times() {
+        let clock = ManualClock::new(1_000);
+        let mut engine = Engine::new(true).with_clock(SharedClock::new(clock.clone()));
+        let new_order = |order_id, time_in_force| UserAction::NewOrder {
+            user_id: 1,
+            symbol: Symbol::new("IBM"),
+            price: Price(10),
+            qty: Quantity(100),
+            side: Side::Buy,
+            order_id,
+            time_in_force,
+        };
+        engine.new_user_action(new_order(1, TimeInForce::Gtc));
+        clock.set(2_000);
+        engine.new_user_action(new_order(2, TimeInForce::Gtd(5_000)));
+
+        let snapshot = Snapshot::decode(&Snapshot::encode(2, &engine).unwrap()).unwrap();
+        let restored = snapshot.restore(true).with_clock(engine.clock().clone());
+        assert_eq!(restored, engine);
+        let order = restored.order(1, 2).unwrap();
+        assert_eq!((order.entry_time, order.expiry), (2_000, Some(5_000)));
+
+        clock.set(5_000);
+        assert_eq!(
+            restored.expired(),
+            vec![UserAction::CancelOrder {
+                user_id: 1,
+                order_id: 2
+            }]
+        );
+    }
+
+    #[test]
+    fn test_decodes_version_1() {
+        let book = BookV1 {
+            ticker: String::from("IBM"),
+            trade_active: false,
+            max_bid: 10,
+            min_ask: 0,
+            bids: vec![(
+                10,
+                vec![OrderV1 {
+                    user_id: 1,
+                    order_id: 1,
+                    price: 10,
+                    qty: 100,
+                }],
+            )],
+            asks: vec![],
+            trades: vec![],
+        };
+        let payload = rmp_serde::to_vec_named(&vec![("IBM", book)]).unwrap();
+        let mut buf = vec![];
+        buf.extend_from_slice(&MAGIC);
+        buf.extend_from_slice(&1u16.to_le_bytes());
+        buf.extend_from_slice(&7u64.to_le_bytes());
+        buf.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        buf.extend_from_slice(&payload);
+
+        let snapshot = Snapshot::decode(&buf).unwrap();
+        assert_eq!(snapshot.seq, 7);
+        let mut engine = Engine::from_books(false, snapshot.books);
+        assert_eq!(engine.book("IBM").unwrap().bids(), 1);
+        let (_, responses) = engine.new_user_action(UserAction::CancelOrder {
+            user_id: 1,
+            order_id: 1,
+        });
+        assert_eq!(
+            responses.0,
+            Some(Response::Acknowledge {
+                user_id: 1,
+                order_id: 1
+            })
+        );
+    }
+
+    #[test]
+    fn test_recover_from_snapshot_and_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal_path = dir.path().join("journal");
+        let snapshots = dir.path().join("snapshots");
+        let actions = input_actions();
+
+        let (mut journal, _) = Journal::open(&journal_path, 8).unwrap();
+        let mut engine = new_engine();
+        for action in &actions {
+            let seq = journal.append(action, engine.last_seq()).unwrap();
+            engine.new_user_action(action.clone());
+            if seq % 25 == 0 {
+                journal.sync().unwrap();
+                save(&snapshots, seq, &engine).unwrap();
+            }
+        }
+        drop(journal);
+
+        // The newest snapshot is damaged, recovery falls back to the older
+        let mut buf = fs::read(snapshots.join(file_name(75))).unwrap();
+        let last = buf.len() - 1;
+        buf[last] ^= 0xff;
+        fs::write(snapshots.join(file_name(75)), buf).unwrap();
+
+        let (_, snapshot, tail) = recover(&snapshots, &journal_path, 8).unwrap();
+        assert_eq!(tail.first().map(|e| e.seq), Some(51));
+        let mut recovered = snapshot
+            .unwrap()
+            .restore(true)
+            .with_clock(SharedClock::new(ManualClock::default()));
+        for action in tail.into_iter().filter_map(|e| e.action) {
+            recovered.new_user_action(action);
+        }
+        assert_eq!(recovered, engine);
+    }
+}
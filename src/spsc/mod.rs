@@ -0,0 +1,396 @@
+//! This mod implements a bounded, lock-free single-producer single-consumer
+//! ring buffer.
+//!
+//! [Producer::push] and [Consumer::pop] never wait for the other side. Each
+//! runs a `SeqCst` fence to see whether the other side is parked, and only
+//! then takes the short lock guarding its [Thread] handle to unpark it, so
+//! a busy ring moves values without taking any lock. The blocking
+//! [Producer::send] and [Consumer::recv] spin for a while before parking
+//! the thread, so an idle side costs no CPU while a busy one never enters
+//! the kernel.
+
+use std::cell::UnsafeCell;
+use std::io;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{fence, AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle, Thread};
+use std::time::{Duration, Instant};
+
+/// Number of empty polls before a blocked side yields its time slice
+const SPIN: u32 = 128;
+
+/// Number of yields before a blocked side parks
+const YIELD: u32 = 16;
+
+/// Longest park, bounds the wake-up delay if an unpark is missed
+const PARK: Duration = Duration::from_millis(1);
+
+#[repr(align(64))]
+/// Keeps an index on its own cache line
+struct Padded(AtomicUsize);
+
+/// Thread waiting on one side of the ring
+struct Waiter {
+    parked: AtomicBool,
+    thread: Mutex<Option<Thread>>,
+}
+
+impl Waiter {
+    fn new() -> Self {
+        Waiter {
+            parked: AtomicBool::new(false),
+            thread: Mutex::new(None),
+        }
+    }
+
+    /// Parks the current thread unless `ready` turns true
+    fn park(&self, ready: impl Fn() -> bool) {
+        *self.thread.lock().unwrap() = Some(thread::current());
+        self.parked.store(true, Ordering::SeqCst);
+        if !ready() {
+            thread::park_timeout(PARK);
+        }
+        self.parked.store(false, Ordering::SeqCst);
+    }
+
+    /// Wakes the waiting thread, if any
+    ///
+    /// Only locks the thread handle when the waiting thread is parked.
+    fn wake(&self) {
+        fence(Ordering::SeqCst);
+        if self.parked.load(Ordering::SeqCst) {
+            if let Some(thread) = self.thread.lock().unwrap().as_ref() {
+                thread.unpark();
+            }
+        }
+    }
+}
+
+/// State shared by the two ends
+struct Ring<T> {
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    mask: usize,
+    /// Next slot to read, only written by the consumer
+    head: Padded,
+    /// Next slot to write, only written by the producer
+    tail: Padded,
+    closed: AtomicBool,
+    consumer: Waiter,
+    producer: Waiter,
+}
+
+// Each slot is accessed by one side at a time, handed over through the
+// release/acquire pair on `head` and `tail`.
+unsafe impl<T: Send> Send for Ring<T> {}
+unsafe impl<T: Send> Sync for Ring<T> {}
+
+impl<T> Drop for Ring<T> {
+    fn drop(&mut self) {
+        let tail = *self.tail.0.get_mut();
+        let mut head = *self.head.0.get_mut();
+        while head != tail {
+            unsafe { (*self.slots[head & self.mask].get()).assume_init_drop() };
+            head = head.wrapping_add(1);
+        }
+    }
+}
+
+/// Creates a ring holding at least `capacity` values
+///
+/// The capacity is rounded up to a power of two.
+pub fn channel<T: Send>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    let capacity = capacity.max(1).next_power_of_two();
+    let ring = Arc::new(Ring {
+        slots: (0..capacity)
+            .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+            .collect(),
+        mask: capacity - 1,
+        head: Padded(AtomicUsize::new(0)),
+        tail: Padded(AtomicUsize::new(0)),
+        closed: AtomicBool::new(false),
+        consumer: Waiter::new(),
+        producer: Waiter::new(),
+    });
+
+    (
+        Producer {
+            ring: Arc::clone(&ring),
+            tail: 0,
+            head: 0,
+        },
+        Consumer {
+            ring,
+            head: 0,
+            tail: 0,
+        },
+    )
+}
+
+/// Waits until `ready` returns a value, spinning, yielding then parking
+fn wait<T>(waiter: &Waiter, mut ready: impl FnMut() -> Option<T>, check: impl Fn() -> bool) -> T {
+    let mut polls = 0;
+    loop {
+        if let Some(value) = ready() {
+            return value;
+        }
+        polls += 1;
+        if polls < SPIN {
+            std::hint::spin_loop();
+        } else if polls < SPIN + YIELD {
+            thread::yield_now();
+        } else {
+            waiter.park(&check);
+        }
+    }
+}
+
+/// This struct is the writing end of a ring
+pub struct Producer<T> {
+    ring: Arc<Ring<T>>,
+    /// Local copy of the tail
+    tail: usize,
+    /// Cached head, refreshed when the ring looks full
+    head: usize,
+}
+
+impl<T: Send> Producer<T> {
+    /// Returns whether the consumer is gone
+    pub fn is_closed(&self) -> bool {
+        self.ring.closed.load(Ordering::Acquire)
+    }
+
+    /// Tries to append a value without blocking
+    ///
+    /// Gives the value back when the ring is full or the consumer is gone.
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        if self.is_closed() {
+            return Err(value);
+        }
+        if self.tail.wrapping_sub(self.head) > self.ring.mask {
+            self.head = self.ring.head.0.load(Ordering::Acquire);
+            if self.tail.wrapping_sub(self.head) > self.ring.mask {
+                return Err(value);
+            }
+        }
+
+        unsafe { (*self.ring.slots[self.tail & self.ring.mask].get()).write(value) };
+        self.tail = self.tail.wrapping_add(1);
+        self.ring.tail.0.store(self.tail, Ordering::Release);
+        self.ring.consumer.wake();
+
+        Ok(())
+    }
+
+    /// Appends a value, waiting for room
+    ///
+    /// Gives the value back when the consumer is gone.
+    pub fn send(&mut self, value: T) -> Result<(), T> {
+        let mut value = Some(value);
+        let ring = Arc::clone(&self.ring);
+        let tail = self.tail;
+        wait(
+            &ring.producer,
+            || match self.push(value.take().unwrap()) {
+                Ok(()) => Some(Ok(())),
+                Err(v) if self.is_closed() => Some(Err(v)),
+                Err(v) => {
+                    value = Some(v);
+                    None
+                }
+            },
+            || {
+                ring.closed.load(Ordering::SeqCst)
+                    || tail.wrapping_sub(ring.head.0.load(Ordering::SeqCst)) <= ring.mask
+            },
+        )
+    }
+}
+
+impl<T> Drop for Producer<T> {
+    fn drop(&mut self) {
+        self.ring.closed.store(true, Ordering::Release);
+        self.ring.consumer.wake();
+    }
+}
+
+/// This struct is the reading end of a ring
+pub struct Consumer<T> {
+    ring: Arc<Ring<T>>,
+    /// Local copy of the head
+    head: usize,
+    /// Cached tail, refreshed when the ring looks empty
+    tail: usize,
+}
+
+impl<T: Send> Consumer<T> {
+    /// Tries to take the oldest value without blocking
+    pub fn pop(&mut self) -> Option<T> {
+        if self.head == self.tail {
+            self.tail = self.ring.tail.0.load(Ordering::Acquire);
+            if self.head == self.tail {
+                return None;
+            }
+        }
+
+        let value =
+            unsafe { (*self.ring.slots[self.head & self.ring.mask].get()).assume_init_read() };
+        self.head = self.head.wrapping_add(1);
+        self.ring.head.0.store(self.head, Ordering::Release);
+        self.ring.producer.wake();
+
+        Some(value)
+    }
+
+    /// Returns whether no value is waiting
+    pub fn is_empty(&mut self) -> bool {
+        self.tail = self.ring.tail.0.load(Ordering::Acquire);
+        self.head == self.tail
+    }
+
+    /// Takes the oldest value, waiting for one
+    ///
+    /// Returns `None` once the producer is gone and the ring is drained.
+    pub fn recv(&mut self) -> Option<T> {
+        let ring = Arc::clone(&self.ring);
+        let head = self.head;
+        wait(
+            &ring.consumer,
+            || match self.pop() {
+                Some(value) => Some(Some(value)),
+                // Values pushed before the close are still delivered
+                None if ring.closed.load(Ordering::Acquire) => Some(self.pop()),
+                None => None,
+            },
+            || ring.closed.load(Ordering::SeqCst) || ring.tail.0.load(Ordering::SeqCst) != head,
+        )
+    }
+
+    /// Takes the oldest value, waiting at most `timeout` for one
+    ///
+    /// Fails with [RecvTimeoutError::Disconnected] once the producer is
+    /// gone and the ring is drained.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        let deadline = Instant::now() + timeout;
+        let ring = Arc::clone(&self.ring);
+        let head = self.head;
+        wait(
+            &ring.consumer,
+            || match self.pop() {
+                Some(value) => Some(Ok(value)),
+                // Values pushed before the close are still delivered
+                None if ring.closed.load(Ordering::Acquire) => {
+                    Some(self.pop().ok_or(RecvTimeoutError::Disconnected))
+                }
+                None if Instant::now() >= deadline => Some(Err(RecvTimeoutError::Timeout)),
+                None => None,
+            },
+            || ring.closed.load(Ordering::SeqCst) || ring.tail.0.load(Ordering::SeqCst) != head,
+        )
+    }
+}
+
+impl<T> Drop for Consumer<T> {
+    fn drop(&mut self) {
+        // Values left in the ring are dropped with it
+        let ring = &self.ring;
+        ring.head.0.store(self.head, Ordering::Release);
+        ring.closed.store(true, Ordering::Release);
+        ring.producer.wake();
+    }
+}
+
+/// Spawns a named thread, pinned to `core` when given
+pub fn spawn_pinned<F, R>(name: &str, core: Option<usize>, f: F) -> io::Result<JoinHandle<R>>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let core = match core {
+        Some(id) => Some(
+            core_affinity::get_core_ids()
+                .and_then(|ids| ids.into_iter().find(|c| c.id == id))
+                .ok_or_else(|| io::Error::other(format!("no core {}", id)))?,
+        ),
+        None => None,
+    };
+
+    thread::Builder::new()
+        .name(String::from(name))
+        .spawn(move || {
+            if let Some(core) = core {
+                core_affinity::set_for_current(core);
+            }
+            f()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fifo_and_close() {
+        let (mut tx, mut rx) = channel(3);
+        assert_eq!(rx.pop(), None);
+        for i in 0..4 {
+            tx.push(i).unwrap();
+        }
+        assert_eq!(tx.push(4), Err(4));
+        assert_eq!(rx.pop(), Some(0));
+        tx.push(4).unwrap();
+
+        drop(tx);
+        assert_eq!(
+            std::iter::from_fn(|| rx.recv()).collect::<Vec<_>>(),
+            vec![1, 2, 3, 4]
+        );
+    }
+
+    #[test]
+    fn test_recv_timeout() {
+        let (mut tx, mut rx) = channel(2);
+        let timeout = Duration::from_millis(5);
+        assert_eq!(rx.recv_timeout(timeout), Err(RecvTimeoutError::Timeout));
+        tx.push(1).unwrap();
+        drop(tx);
+        assert_eq!(rx.recv_timeout(timeout), Ok(1));
+        assert_eq!(
+            rx.recv_timeout(timeout),
+            Err(RecvTimeoutError::Disconnected)
+        );
+    }
+
+    #[test]
+    fn test_threads_keep_order() {
+        let (mut tx, mut rx) = channel(8);
+        let producer = spawn_pinned("producer", None, move || {
+            for i in 0..100_000u64 {
+                tx.send(i).unwrap();
+            }
+        })
+        .unwrap();
+
+        let mut expected = 0;
+        while let Some(i) = rx.recv() {
+            assert_eq!(i, expected);
+            expected += 1;
+        }
+        producer.join().unwrap();
+        assert_eq!(expected, 100_000);
+    }
+
+    #[test]
+    fn test_dropped_consumer_releases_values() {
+        let value = Arc::new(());
+        let (mut tx, rx) = channel(4);
+        tx.push(Arc::clone(&value)).unwrap();
+        tx.push(Arc::clone(&value)).unwrap();
+        drop(rx);
+
+        assert!(tx.send(Arc::clone(&value)).is_err());
+        drop(tx);
+        assert_eq!(Arc::strong_count(&value), 1);
+    }
+}
@@ -0,0 +1,258 @@
+//! This mod implements interned instrument symbols.
+//!
+//! A [Symbol] is a small copyable id of a name stored once in a
+//! process-wide table, so actions, responses and books can carry and
+//! compare symbols without allocating.
+//!
+//! The table only grows. Names are looked up by id without a lock, from an
+//! append-only list of segments, each twice the size of the one before.
+//! Names from outside the process are parsed with [Symbol::parse], which
+//! checks them and refuses to grow the table past [MAX_SYMBOLS].
+
+use std::collections::HashMap;
+use std::fmt::{Debug, Display, Formatter};
+use std::str::FromStr;
+use std::sync::{OnceLock, RwLock};
+
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Most names the table holds, the empty name included
+pub const MAX_SYMBOLS: usize = 1 << 16;
+
+/// Longest name in bytes
+pub const MAX_SYMBOL_LEN: usize = 16;
+
+/// Length of the first segment of names
+const FIRST_SEGMENT: usize = 64;
+
+/// Number of segments needed to hold [MAX_SYMBOLS] names
+const SEGMENTS: usize = (MAX_SYMBOLS / FIRST_SEGMENT).ilog2() as usize + 1;
+
+/// Names by id, only written while the ids are locked for writing
+static NAMES: [OnceLock<Box<[OnceLock<&'static str>]>>; SEGMENTS] =
+    [const { OnceLock::new() }; SEGMENTS];
+
+/// Returns the segment and the index in it of the name of `id`
+fn slot(id: u32) -> (usize, usize) {
+    let n = id as usize / FIRST_SEGMENT + 1;
+    let segment = n.ilog2() as usize;
+    (segment, id as usize - FIRST_SEGMENT * ((1 << segment) - 1))
+}
+
+/// Ids of the interned names, the empty name is always id 0
+fn ids() -> &'static RwLock<HashMap<&'static str, u32>> {
+    static IDS: OnceLock<RwLock<HashMap<&'static str, u32>>> = OnceLock::new();
+    IDS.get_or_init(|| {
+        store(0, "");
+        RwLock::new(HashMap::from([("", 0)]))
+    })
+}
+
+/// Stores the name of `id`, which must be the next free one
+fn store(id: u32, name: &'static str) {
+    let (segment, index) = slot(id);
+    let names = NAMES[segment].get_or_init(|| {
+        (0..FIRST_SEGMENT << segment)
+            .map(|_| OnceLock::new())
+            .collect()
+    });
+    names[index].set(name).unwrap();
+}
+
+#[derive(Clone, Debug, PartialEq)]
+/// This enum describes why a name is not accepted as a [Symbol]
+pub enum ParseSymbolError {
+    /// The name is empty, longer than [MAX_SYMBOL_LEN] or has characters
+    /// other than ASCII letters, digits, `.`, `-`, `_` and `/`
+    Invalid(String),
+    /// The table already holds [MAX_SYMBOLS] names
+    Full,
+}
+
+impl Display for ParseSymbolError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            ParseSymbolError::Invalid(name) => write!(f, "invalid symbol: {:?}", name),
+            ParseSymbolError::Full => write!(f, "too many symbols"),
+        }
+    }
+}
+
+impl std::error::Error for ParseSymbolError {}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+/// This struct is the interned id of an instrument symbol
+///
+/// Two [Symbol]s are equal when their names are. The names live for the
+/// rest of the process, ids are only meaningful inside it: [Symbol]s are
+/// serialized as their names.
+pub struct Symbol(u32);
+
+impl Symbol {
+    /// Returns the [Symbol] of `name`, interning it on first use
+    ///
+    /// Meant for names the process trusts, see [Symbol::parse] for the
+    /// others. Panics when the table is full.
+    pub fn new(name: &str) -> Self {
+        Self::intern(name).expect("symbol table is full")
+    }
+
+    /// Returns the [Symbol] of a name from outside the process, interning
+    /// it on first use
+    ///
+    /// Fails when the name is not a valid symbol, or when it is new and
+    /// the table is full.
+    pub fn parse(name: &str) -> Result<Self, ParseSymbolError> {
+        let valid = !name.is_empty()
+            && name.len() <= MAX_SYMBOL_LEN
+            && name
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'-' | b'_' | b'/'));
+        if !valid {
+            return Err(ParseSymbolError::Invalid(String::from(name)));
+        }
+        Self::intern(name)
+    }
+
+    /// Returns the [Symbol] of `name`, interning it unless the table is
+    /// full
+    fn intern(name: &str) -> Result<Self, ParseSymbolError> {
+        if let Some(&id) = ids().read().unwrap().get(name) {
+            return Ok(Symbol(id));
+        }
+
+        let mut ids = ids().write().unwrap();
+        if let Some(&id) = ids.get(name) {
+            return Ok(Symbol(id));
+        }
+        if ids.len() >= MAX_SYMBOLS {
+            return Err(ParseSymbolError::Full);
+        }
+        let name: &'static str = Box::leak(name.to_owned().into_boxed_str());
+        let id = ids.len() as u32;
+        store(id, name);
+        ids.insert(name, id);
+        Ok(Symbol(id))
+    }
+
+    /// Returns the name of the symbol
+    pub fn as_str(&self) -> &'static str {
+        // The name was stored before the id was handed out
+        let (segment, index) = slot(self.0);
+        NAMES[segment].get().unwrap()[index].get().unwrap()
+    }
+}
+
+impl Default for Symbol {
+    /// Returns the [Symbol] of the empty name
+    fn default() -> Self {
+        ids();
+        Symbol(0)
+    }
+}
+
+impl From<&str> for Symbol {
+    fn from(name: &str) -> Self {
+        Symbol::new(name)
+    }
+}
+
+impl FromStr for Symbol {
+    type Err = ParseSymbolError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Symbol::parse(name)
+    }
+}
+
+impl PartialEq<str> for Symbol {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl PartialEq<&str> for Symbol {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl Display for Symbol {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Debug for Symbol {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl Serialize for Symbol {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Symbol {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = <std::borrow::Cow<'de, str>>::deserialize(deserializer)?;
+        Symbol::parse(&name).map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interning() {
+        let ibm = Symbol::new("IBM");
+        assert_eq!(ibm, Symbol::new(&String::from("IBM")));
+        assert_ne!(ibm, Symbol::new("AAPL"));
+        assert_eq!(ibm.as_str(), "IBM");
+        assert_eq!(ibm, "IBM");
+        assert_eq!(ibm.to_string(), "IBM");
+        assert_eq!(format!("{:?}", ibm), "\"IBM\"");
+    }
+
+    #[test]
+    fn test_serialized_as_name() {
+        let ibm = Symbol::new("IBM");
+        assert_eq!(serde_json::to_string(&ibm).unwrap(), "\"IBM\"");
+        assert_eq!(serde_json::from_str::<Symbol>("\"IBM\"").unwrap(), ibm);
+
+        let bytes = rmp_serde::to_vec(&ibm).unwrap();
+        assert_eq!(rmp_serde::from_slice::<Symbol>(&bytes).unwrap(), ibm);
+
+        // Names from outside the process are checked before interning
+        assert!(serde_json::from_str::<Symbol>("\"\"").is_err());
+        assert!(serde_json::from_str::<Symbol>("\"IBM US\"").is_err());
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("IBM".parse(), Ok(Symbol::new("IBM")));
+        assert_eq!(Symbol::parse("BTC/USD-2.5_x").unwrap(), "BTC/USD-2.5_x");
+        for name in ["", "IBM US", "IBM\n", "ÎBM", "ABCDEFGHIJKLMNOPQ"] {
+            assert_eq!(
+                Symbol::parse(name),
+                Err(ParseSymbolError::Invalid(String::from(name)))
+            );
+        }
+        assert_eq!(Symbol::default(), "");
+    }
+
+    #[test]
+    fn test_slots() {
+        assert_eq!(slot(0), (0, 0));
+        assert_eq!(slot(63), (0, 63));
+        assert_eq!(slot(64), (1, 0));
+        assert_eq!(slot(191), (1, 127));
+        assert_eq!(slot(192), (2, 0));
+        let (segment, index) = slot(MAX_SYMBOLS as u32 - 1);
+        assert!(segment < SEGMENTS && index < FIRST_SEGMENT << segment);
+    }
+}
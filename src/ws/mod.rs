@@ -0,0 +1,1563 @@
+//! This mod implements the WebSocket market-data and order-entry API.
+//!
+//! Clients send JSON [ClientMessage]s and receive JSON [ServerMessage]s.
+//! Order entry requests are answered on the connection that sent them,
+//! while market data is delivered to every connection subscribed to the
+//! symbol and [Channel] it belongs to.
+//!
+//! Every connection is a session owning the orders it entered. The first
+//! order entry request naming a user id binds the user to the session
+//! until it ends, and the requests of other sessions for that user are
+//! refused, so a session only ever cancels or amends its own orders. When
+//! the connection drops or misses its heartbeats, the live orders of the
+//! session are cancelled unless it opted out with
+//! [ClientMessage::CancelOnDisconnect].
+//!
+//! Connections accepted on the admin listener of [serve_with_admin] can
+//! also send the admin requests: mass cancels and blocking users. Every
+//! other connection is refused them.
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::{self, MissedTickBehavior};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+use crate::clock::Timestamp;
+use crate::engine::{CancelFilter, Engine, Output};
+use crate::fixed::{Price, Quantity};
+use crate::orderbook::{Level, OrderBook, RejectReason, Response, Side, TimeInForce, UserAction};
+use crate::sequence::Stamped;
+use crate::symbol::Symbol;
+
+/// Market data messages buffered per connection before it starts lagging
+const MARKET_DATA_BUFFER: usize = 4096;
+
+/// Time between two sweeps of the expired orders
+const EXPIRY_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, Debug)]
+/// This struct holds the settings of the WebSocket server
+pub struct WsConfig {
+    /// Requests a connection may send per second, bursts included
+    pub max_requests_per_sec: u32,
+    /// Price levels per side in [ServerMessage::L2] updates
+    pub depth: usize,
+    /// Closes connections silent for longer, `None` never does
+    ///
+    /// The server pings every connection twice per period, so clients
+    /// answering pings stay connected.
+    pub heartbeat: Option<Duration>,
+}
+
+impl Default for WsConfig {
+    fn default() -> Self {
+        WsConfig {
+            max_requests_per_sec: 100,
+            depth: 10,
+            heartbeat: Some(Duration::from_secs(30)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+/// This enum describes the market data channels of a symbol
+pub enum Channel {
+    /// Top of book updates
+    L1,
+    /// Aggregated depth updates
+    L2,
+    /// Trades
+    Trades,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+/// This enum describes the requests a client can send
+pub enum ClientMessage {
+    /// Start receiving a market data channel of a symbol
+    Subscribe { symbol: Symbol, channel: Channel },
+    /// Stop receiving a market data channel of a symbol
+    Unsubscribe { symbol: Symbol, channel: Channel },
+    /// Maps to [UserAction::NewOrder], good till cancelled unless a time
+    /// in force is given
+    NewOrder {
+        user_id: u32,
+        symbol: Symbol,
+        price: Price,
+        qty: Quantity,
+        side: Side,
+        order_id: u32,
+        #[serde(default)]
+        time_in_force: TimeInForce,
+    },
+    /// Maps to [UserAction::CancelOrder]
+    Cancel { user_id: u32, order_id: u32 },
+    /// Replaces the price and quantity of a live order
+    ///
+    /// Maps to a [UserAction::CancelOrder] followed by a
+    /// [UserAction::NewOrder] with the same symbol, side, order id and
+    /// expiry, so the order loses its time priority. It is answered with
+    /// the outcome of the cancel and, once the cancel is acknowledged, the
+    /// outcome of the replacement. A rejected replacement leaves the order
+    /// cancelled.
+    Amend {
+        user_id: u32,
+        order_id: u32,
+        price: Price,
+        qty: Quantity,
+    },
+    /// Cancels every live order matching the given fields, see
+    /// [Engine::mass_cancel] - admin only
+    MassCancel {
+        #[serde(default)]
+        user_id: Option<u32>,
+        #[serde(default)]
+        symbol: Option<Symbol>,
+        #[serde(default)]
+        side: Option<Side>,
+    },
+    /// Keeps or cancels the live orders of the session when it ends, on
+    /// by default
+    CancelOnDisconnect { enabled: bool },
+    /// Rejects the new orders of a user - admin only
+    BlockUser { user_id: u32 },
+    /// Accepts the new orders of a user again - admin only
+    UnblockUser { user_id: u32 },
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+/// This enum describes the messages the server sends
+///
+/// Responses of the engine carry their sequence number and the time they
+/// were emitted at, see [Engine::stamp]; an [ServerMessage::L2] update
+/// carries those of the last response of its action.
+pub enum ServerMessage {
+    /// The subscription is active
+    Subscribed { symbol: Symbol, channel: Channel },
+    /// The subscription is gone
+    Unsubscribed { symbol: Symbol, channel: Channel },
+    /// An order entry request was accepted
+    Ack {
+        user_id: u32,
+        order_id: u32,
+        seq: u64,
+        timestamp: Timestamp,
+    },
+    /// An order entry request was rejected
+    Reject {
+        user_id: u32,
+        order_id: u32,
+        reason: RejectReason,
+        seq: u64,
+        timestamp: Timestamp,
+    },
+    /// New top of book on one side - price and quantity are 0 when the
+    /// side is empty
+    L1 {
+        symbol: Symbol,
+        side: Side,
+        price: Price,
+        qty: Quantity,
+        seq: u64,
+        timestamp: Timestamp,
+    },
+    /// Aggregated `[price, qty]` levels, best first
+    L2 {
+        symbol: Symbol,
+        bids: Vec<(Price, Quantity)>,
+        asks: Vec<(Price, Quantity)>,
+        seq: u64,
+        timestamp: Timestamp,
+    },
+    /// A trade happened
+    Trade {
+        symbol: Symbol,
+        buyer_id: u32,
+        buyer_order_id: u32,
+        seller_id: u32,
+        seller_order_id: u32,
+        price: Price,
+        qty: Quantity,
+        seq: u64,
+        timestamp: Timestamp,
+    },
+    /// The session changed what happens to its orders when it ends
+    CancelOnDisconnect { enabled: bool },
+    /// A user was blocked or unblocked
+    Blocked { user_id: u32, blocked: bool },
+    /// A request could not be served
+    Error { message: String },
+}
+
+#[derive(Clone, Debug)]
+/// Market data message with the subscription it belongs to
+struct MarketData {
+    symbol: Symbol,
+    channel: Channel,
+    message: ServerMessage,
+}
+
+/// Id of the session of one connection
+type SessionId = u64;
+
+/// Session of the actions of the server itself, such as expiries
+const SERVER_SESSION: SessionId = 0;
+
+/// State shared by all connections
+struct Shared {
+    engine: Mutex<Engine>,
+    /// Session of every live order entered through the gateway by
+    /// `(user_id, order_id)`, only locked while holding the engine
+    owners: Mutex<HashMap<(u32, u32), SessionId>>,
+    /// Session every user id is bound to
+    users: Mutex<HashMap<u32, SessionId>>,
+    market_data: broadcast::Sender<MarketData>,
+    config: WsConfig,
+    next_session: AtomicU64,
+}
+
+#[derive(Debug, PartialEq)]
+/// This struct is a token bucket limiting the requests of a connection
+pub struct Throttle {
+    /// Tokens added per second - also the bucket size
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Throttle {
+    /// Creates a full bucket allowing `rate` requests per second
+    pub fn new(rate: u32) -> Self {
+        Throttle {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last: Instant::now(),
+        }
+    }
+
+    /// Takes a token if one is available
+    pub fn allow(&mut self) -> bool {
+        self.allow_at(Instant::now())
+    }
+
+    /// Takes a token if one is available at `now`
+    pub(crate) fn allow_at(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Aggregated `[price, qty]` levels of one side
+type Levels = Vec<(Price, Quantity)>;
+
+/// Returns the aggregated `[price, qty]` levels of a book,
+/// best first, at most `depth` per side
+fn levels(book: &OrderBook, depth: usize) -> (Levels, Levels) {
+    let depth = book.depth(depth);
+    let prices = |levels: Vec<Level>| levels.into_iter().map(|l| (l.price, l.qty)).collect();
+    (prices(depth.bids), prices(depth.asks))
+}
+
+impl Shared {
+    /// Creates the state of a server in front of `engine`
+    fn new(engine: Engine, config: WsConfig) -> Self {
+        let (market_data, _) = broadcast::channel(MARKET_DATA_BUFFER);
+        Shared {
+            engine: Mutex::new(engine),
+            owners: Mutex::new(HashMap::new()),
+            users: Mutex::new(HashMap::new()),
+            market_data,
+            config,
+            next_session: AtomicU64::new(SERVER_SESSION + 1),
+        }
+    }
+
+    /// Applies order entry actions to the engine and publishes the market
+    /// data they produce
+    ///
+    /// Returns the replies for the requesting connection. The engine stays
+    /// locked while market data is published, so every subscriber sees the
+    /// updates in engine order.
+    fn execute(&self, session: SessionId, actions: Vec<UserAction>) -> Vec<ServerMessage> {
+        let mut engine = self.engine.lock().unwrap();
+        self.execute_locked(&mut engine, session, actions)
+    }
+
+    /// Applies order entry actions of `session` to the locked engine, see
+    /// [Shared::execute]
+    ///
+    /// Expired orders leave the books first, so they never trade.
+    fn execute_locked(
+        &self,
+        engine: &mut Engine,
+        session: SessionId,
+        actions: Vec<UserAction>,
+    ) -> Vec<ServerMessage> {
+        self.expire_locked(engine);
+
+        let mut replies = vec![];
+        for action in actions {
+            self.apply_locked(engine, session, action, &mut replies);
+        }
+        replies
+    }
+
+    /// Cancels the expired orders and publishes the market data of the
+    /// cancels
+    fn expire(&self) {
+        let mut engine = self.engine.lock().unwrap();
+        self.expire_locked(&mut engine);
+    }
+
+    /// Cancels the expired orders of the locked engine, see [Shared::expire]
+    fn expire_locked(&self, engine: &mut Engine) {
+        // Nobody is waiting for the replies
+        let mut replies = vec![];
+        for cancel in engine.expired() {
+            self.apply_locked(engine, SERVER_SESSION, cancel, &mut replies);
+        }
+    }
+
+    /// Applies one action to the locked engine, publishes its market data
+    /// and adds its replies to `replies`
+    fn apply_locked(
+        &self,
+        engine: &mut Engine,
+        session: SessionId,
+        action: UserAction,
+        replies: &mut Vec<ServerMessage>,
+    ) {
+        let output = engine.new_user_action(action.clone());
+        self.reply_locked(engine, session, &action, output, replies);
+    }
+
+    /// Stamps the responses of an action applied to the locked engine,
+    /// publishes its market data and adds its replies to `replies`, see
+    /// [Shared::apply_locked]
+    fn reply_locked(
+        &self,
+        engine: &mut Engine,
+        session: SessionId,
+        action: &UserAction,
+        output: Output,
+        replies: &mut Vec<ServerMessage>,
+    ) {
+        self.update_owners(session, action, &output.1);
+        let (symbol, responses) = engine.stamp(output);
+        let mut changed = false;
+        let mut last = None;
+
+        for stamped in [responses.0, responses.1].into_iter().flatten() {
+            let Stamped {
+                seq,
+                timestamp,
+                response,
+            } = stamped;
+            last = Some((seq, timestamp));
+            match response {
+                Response::Acknowledge { user_id, order_id } => {
+                    changed = true;
+                    replies.push(ServerMessage::Ack {
+                        user_id,
+                        order_id,
+                        seq,
+                        timestamp,
+                    });
+                }
+                Response::Reject {
+                    user_id,
+                    order_id,
+                    reason,
+                } => replies.push(ServerMessage::Reject {
+                    user_id,
+                    order_id,
+                    reason,
+                    seq,
+                    timestamp,
+                }),
+                Response::Best { side, price, qty } => {
+                    if let Some(symbol) = symbol {
+                        self.publish(
+                            symbol,
+                            Channel::L1,
+                            ServerMessage::L1 {
+                                symbol,
+                                side,
+                                price,
+                                qty,
+                                seq,
+                                timestamp,
+                            },
+                        );
+                    }
+                }
+                Response::Trade {
+                    buyer_id,
+                    buyer_order_id,
+                    seller_id,
+                    seller_order_id,
+                    price,
+                    qty,
+                } => {
+                    if let Some(symbol) = symbol {
+                        self.publish(
+                            symbol,
+                            Channel::Trades,
+                            ServerMessage::Trade {
+                                symbol,
+                                buyer_id,
+                                buyer_order_id,
+                                seller_id,
+                                seller_order_id,
+                                price,
+                                qty,
+                                seq,
+                                timestamp,
+                            },
+                        );
+                    }
+                }
+            }
+        }
+
+        if let (true, Some(symbol), Some((seq, timestamp))) = (changed, symbol, last) {
+            if let Some(book) = engine.book(symbol) {
+                let (bids, asks) = levels(book, self.config.depth);
+                self.publish(
+                    symbol,
+                    Channel::L2,
+                    ServerMessage::L2 {
+                        symbol,
+                        bids,
+                        asks,
+                        seq,
+                        timestamp,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Keeps the session of the live orders up to date after `action`
+    fn update_owners(
+        &self,
+        session: SessionId,
+        action: &UserAction,
+        responses: &(Option<Response>, Option<Response>),
+    ) {
+        let mut owners = self.owners.lock().unwrap();
+        match (action, responses) {
+            // The resting order of the trade is gone, the incoming one
+            // never rested
+            (
+                UserAction::NewOrder { .. },
+                (
+                    Some(Response::Acknowledge { .. }),
+                    Some(Response::Trade {
+                        buyer_id,
+                        buyer_order_id,
+                        seller_id,
+                        seller_order_id,
+                        ..
+                    }),
+                ),
+            ) => {
+                owners.remove(&(*buyer_id, *buyer_order_id));
+                owners.remove(&(*seller_id, *seller_order_id));
+            }
+            (
+                UserAction::NewOrder {
+                    user_id, order_id, ..
+                },
+                (Some(Response::Acknowledge { .. }), _),
+            ) => {
+                owners.insert((*user_id, *order_id), session);
+            }
+            (
+                UserAction::CancelOrder { user_id, order_id },
+                (Some(Response::Acknowledge { .. }), _),
+            ) => {
+                owners.remove(&(*user_id, *order_id));
+            }
+            (_, _) => (),
+        }
+    }
+
+    /// Binds `user_id` to `session` unless it is bound to another session
+    ///
+    /// Returns whether the session may act for the user.
+    fn bind_user(&self, session: SessionId, user_id: u32) -> bool {
+        *self.users.lock().unwrap().entry(user_id).or_insert(session) == session
+    }
+
+    /// Forgets the orders and users of a session that ended, cancelling
+    /// the orders unless `cancel` is false
+    ///
+    /// Every cancel publishes its market data like any other.
+    fn disconnect(&self, session: SessionId, cancel: bool) {
+        let mut engine = self.engine.lock().unwrap();
+        self.users
+            .lock()
+            .unwrap()
+            .retain(|_, owner| *owner != session);
+        let mut orders: Vec<(u32, u32)> = {
+            let mut owners = self.owners.lock().unwrap();
+            let orders = owners
+                .iter()
+                .filter(|(_, owner)| **owner == session)
+                .map(|(order, _)| *order)
+                .collect();
+            owners.retain(|_, owner| *owner != session);
+            orders
+        };
+
+        if cancel {
+            orders.sort_unstable();
+            let cancels = orders
+                .into_iter()
+                .map(|(user_id, order_id)| UserAction::CancelOrder { user_id, order_id })
+                .collect();
+            self.execute_locked(&mut engine, session, cancels);
+        }
+    }
+
+    fn publish(&self, symbol: Symbol, channel: Channel, message: ServerMessage) {
+        // No receiver just means nobody is connected
+        let _ = self.market_data.send(MarketData {
+            symbol,
+            channel,
+            message,
+        });
+    }
+
+    /// Translates an order entry [ClientMessage] to [UserAction]s and
+    /// executes them
+    ///
+    /// Requests for a user bound to another session are refused.
+    fn order_entry(&self, session: SessionId, request: ClientMessage) -> Vec<ServerMessage> {
+        let user_id = match request {
+            ClientMessage::NewOrder { user_id, .. }
+            | ClientMessage::Cancel { user_id, .. }
+            | ClientMessage::Amend { user_id, .. } => user_id,
+            _ => return vec![],
+        };
+        if !self.bind_user(session, user_id) {
+            return vec![ServerMessage::Error {
+                message: format!("user {} belongs to another session", user_id),
+            }];
+        }
+
+        match request {
+            ClientMessage::NewOrder {
+                user_id,
+                symbol,
+                price,
+                qty,
+                side,
+                order_id,
+                time_in_force,
+            } => self.execute(
+                session,
+                vec![UserAction::NewOrder {
+                    user_id,
+                    symbol,
+                    price,
+                    qty,
+                    side,
+                    order_id,
+                    time_in_force,
+                }],
+            ),
+            ClientMessage::Cancel { user_id, order_id } => {
+                self.execute(session, vec![UserAction::CancelOrder { user_id, order_id }])
+            }
+            ClientMessage::Amend {
+                user_id,
+                order_id,
+                price,
+                qty,
+            } => self.amend(session, user_id, order_id, price, qty),
+            _ => vec![],
+        }
+    }
+
+    /// Replaces a live order, see [ClientMessage::Amend] and
+    /// [Engine::amend]
+    ///
+    /// The order is cancelled and replaced under one lock of the engine,
+    /// so it can not fill in between.
+    fn amend(
+        &self,
+        session: SessionId,
+        user_id: u32,
+        order_id: u32,
+        price: Price,
+        qty: Quantity,
+    ) -> Vec<ServerMessage> {
+        let mut engine = self.engine.lock().unwrap();
+        self.expire_locked(&mut engine);
+
+        let mut replies = vec![];
+        for (action, output) in engine.amend(user_id, order_id, price, qty) {
+            self.reply_locked(&mut engine, session, &action, output, &mut replies);
+        }
+        replies
+    }
+
+    /// Serves an admin [ClientMessage]
+    ///
+    /// A mass cancel is answered with the outcome of every cancelled order
+    /// and publishes the market data of each cancel.
+    fn admin(&self, session: SessionId, request: ClientMessage) -> Vec<ServerMessage> {
+        let mut engine = self.engine.lock().unwrap();
+        match request {
+            ClientMessage::MassCancel {
+                user_id,
+                symbol,
+                side,
+            } => {
+                let filter = CancelFilter {
+                    user_id,
+                    symbol,
+                    side,
+                };
+                // Selected and cancelled under the same lock
+                let cancels = engine.cancels(&filter);
+                self.execute_locked(&mut engine, session, cancels)
+            }
+            ClientMessage::BlockUser { user_id } => {
+                engine.block_user(user_id);
+                vec![ServerMessage::Blocked {
+                    user_id,
+                    blocked: true,
+                }]
+            }
+            ClientMessage::UnblockUser { user_id } => {
+                engine.unblock_user(user_id);
+                vec![ServerMessage::Blocked {
+                    user_id,
+                    blocked: false,
+                }]
+            }
+            _ => vec![],
+        }
+    }
+}
+
+/// Serves one WebSocket connection until the client goes away, then ends
+/// its session
+///
+/// Only `admin` connections may send admin requests.
+async fn handle_connection(
+    stream: TcpStream,
+    shared: Arc<Shared>,
+    admin: bool,
+) -> Result<(), io::Error> {
+    let ws = tokio_tungstenite::accept_async(stream)
+        .await
+        .map_err(io::Error::other)?;
+    let session = shared.next_session.fetch_add(1, Ordering::Relaxed);
+    let mut cancel_on_disconnect = true;
+
+    let result = serve_session(ws, &shared, session, admin, &mut cancel_on_disconnect).await;
+    shared.disconnect(session, cancel_on_disconnect);
+    result
+}
+
+/// Serves the requests of one session until the client goes away or
+/// misses its heartbeats
+async fn serve_session(
+    ws: WebSocketStream<TcpStream>,
+    shared: &Shared,
+    session: SessionId,
+    admin: bool,
+    cancel_on_disconnect: &mut bool,
+) -> Result<(), io::Error> {
+    let (mut sink, mut source) = ws.split();
+    let mut market_data = shared.market_data.subscribe();
+    let mut subscriptions = HashSet::new();
+    let mut throttle = Throttle::new(shared.config.max_requests_per_sec);
+
+    let heartbeat = shared.config.heartbeat;
+    let period = heartbeat.map_or(Duration::from_secs(1), |timeout| timeout / 2);
+    let mut pings = time::interval_at(time::Instant::now() + period, period);
+    pings.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut last_seen = Instant::now();
+
+    loop {
+        let replies = tokio::select! {
+            message = source.next() => {
+                last_seen = Instant::now();
+                match message {
+                    Some(Ok(Message::Text(text))) => {
+                        if !throttle.allow() {
+                            vec![ServerMessage::Error {
+                                message: String::from("throttled"),
+                            }]
+                        } else {
+                            match serde_json::from_str::<ClientMessage>(&text) {
+                                Ok(ClientMessage::Subscribe { symbol, channel }) => {
+                                    subscriptions.insert((symbol, channel));
+                                    vec![ServerMessage::Subscribed { symbol, channel }]
+                                }
+                                Ok(ClientMessage::Unsubscribe { symbol, channel }) => {
+                                    subscriptions.remove(&(symbol, channel));
+                                    vec![ServerMessage::Unsubscribed { symbol, channel }]
+                                }
+                                // The engine lock is never held across an await
+                                Ok(
+                                    request @ (ClientMessage::MassCancel { .. }
+                                    | ClientMessage::BlockUser { .. }
+                                    | ClientMessage::UnblockUser { .. }),
+                                ) => match admin {
+                                    true => shared.admin(session, request),
+                                    false => vec![ServerMessage::Error {
+                                        message: String::from("admin requests are disabled"),
+                                    }],
+                                },
+                                Ok(ClientMessage::CancelOnDisconnect { enabled }) => {
+                                    *cancel_on_disconnect = enabled;
+                                    vec![ServerMessage::CancelOnDisconnect { enabled }]
+                                }
+                                Ok(request) => shared.order_entry(session, request),
+                                Err(e) => vec![ServerMessage::Error {
+                                    message: e.to_string(),
+                                }],
+                            }
+                        }
+                    }
+                    Some(Ok(Message::Close(_))) | None => break,
+                    Some(Ok(_)) => vec![],
+                    Some(Err(_)) => break,
+                }
+            },
+            _ = pings.tick(), if heartbeat.is_some() => match heartbeat {
+                // The client stopped answering pings
+                Some(timeout) if last_seen.elapsed() > timeout => break,
+                _ => {
+                    if sink.send(Message::Ping(vec![])).await.is_err() {
+                        break;
+                    }
+                    vec![]
+                }
+            },
+            data = market_data.recv() => match data {
+                Ok(data) if subscriptions.contains(&(data.symbol, data.channel)) => {
+                    vec![data.message]
+                }
+                Ok(_) => vec![],
+                Err(RecvError::Lagged(n)) => vec![ServerMessage::Error {
+                    message: format!("lagged, {} market data messages dropped", n),
+                }],
+                Err(RecvError::Closed) => break,
+            },
+        };
+
+        for reply in replies {
+            let text = serde_json::to_string(&reply)?;
+            if sink.send(Message::Text(text)).await.is_err() {
+                return Ok(());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs the WebSocket server on `listener` in front of `engine`
+///
+/// Admin requests are refused, see [serve_with_admin].
+pub async fn serve(listener: TcpListener, engine: Engine, config: WsConfig) -> io::Result<()> {
+    run(listener, None, engine, config).await
+}
+
+/// Runs the WebSocket server on `listener` and serves admin connections
+/// on `admin`
+///
+/// Only connections accepted on `admin` may send admin requests, so it is
+/// meant to be bound to an address that only operators can reach.
+pub async fn serve_with_admin(
+    listener: TcpListener,
+    admin: TcpListener,
+    engine: Engine,
+    config: WsConfig,
+) -> io::Result<()> {
+    run(listener, Some(admin), engine, config).await
+}
+
+/// Runs the WebSocket server, see [serve] and [serve_with_admin]
+async fn run(
+    listener: TcpListener,
+    admin: Option<TcpListener>,
+    engine: Engine,
+    config: WsConfig,
+) -> io::Result<()> {
+    let shared = Arc::new(Shared::new(engine, config));
+
+    // Orders also expire while no request comes in
+    let sweeper = Arc::clone(&shared);
+    tokio::spawn(async move {
+        let mut sweeps = time::interval(EXPIRY_INTERVAL);
+        loop {
+            sweeps.tick().await;
+            sweeper.expire();
+        }
+    });
+
+    match admin {
+        Some(admin) => {
+            tokio::try_join!(
+                accept(listener, Arc::clone(&shared), false),
+                accept(admin, shared, true)
+            )?;
+            Ok(())
+        }
+        None => accept(listener, shared, false).await,
+    }
+}
+
+/// Serves the connections of `listener` until accepting fails, `admin`
+/// ones may send admin requests
+async fn accept(listener: TcpListener, shared: Arc<Shared>, admin: bool) -> io::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let shared = Arc::clone(&shared);
+        tokio::spawn(async move {
+            let _ = handle_connection(stream, shared, admin).await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::OrderIdScope;
+    use std::net::SocketAddr;
+    use tokio::time::timeout;
+    use tokio_tungstenite::{connect_async, MaybeTlsStream};
+
+    type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    async fn start(config: WsConfig) -> SocketAddr {
+        start_engine(Engine::new(true), config).await
+    }
+
+    async fn start_engine(engine: Engine, config: WsConfig) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, engine, config));
+
+        addr
+    }
+
+    async fn connect(addr: SocketAddr) -> Client {
+        connect_async(format!("ws://{}", addr)).await.unwrap().0
+    }
+
+    async fn send(client: &mut Client, request: ClientMessage) {
+        let text = serde_json::to_string(&request).unwrap();
+        client.send(Message::Text(text)).await.unwrap();
+    }
+
+    /// Returns the next message with its stamp zeroed, so that tests
+    /// compare the rest of it
+    async fn recv(client: &mut Client) -> ServerMessage {
+        let mut message = recv_stamped(client).await;
+        if let ServerMessage::Ack { seq, timestamp, .. }
+        | ServerMessage::Reject { seq, timestamp, .. }
+        | ServerMessage::L1 { seq, timestamp, .. }
+        | ServerMessage::L2 { seq, timestamp, .. }
+        | ServerMessage::Trade { seq, timestamp, .. } = &mut message
+        {
+            (*seq, *timestamp) = (0, 0);
+        }
+        message
+    }
+
+    async fn recv_stamped(client: &mut Client) -> ServerMessage {
+        loop {
+            let message = timeout(Duration::from_secs(5), client.next())
+                .await
+                .expect("no message")
+                .unwrap()
+                .unwrap();
+            if let Message::Text(text) = message {
+                return serde_json::from_str(&text).unwrap();
+            }
+        }
+    }
+
+    fn new_order(user_id: u32, price: u64, side: &str, order_id: u32) -> ClientMessage {
+        ClientMessage::NewOrder {
+            user_id,
+            symbol: Symbol::new("IBM"),
+            price: Price(price),
+            qty: Quantity(100),
+            side: side.parse().unwrap(),
+            order_id,
+            time_in_force: TimeInForce::Gtc,
+        }
+    }
+
+    #[test]
+    fn test_json_format() {
+        let request: ClientMessage = serde_json::from_str(
+            r#"{"type":"new_order","user_id":1,"symbol":"IBM","price":10,"qty":100,"side":"B","order_id":1}"#,
+        )
+        .unwrap();
+        assert_eq!(request, new_order(1, 10, "B", 1));
+
+        let request: ClientMessage =
+            serde_json::from_str(r#"{"type":"subscribe","symbol":"IBM","channel":"l2"}"#).unwrap();
+        assert_eq!(
+            request,
+            ClientMessage::Subscribe {
+                symbol: Symbol::new("IBM"),
+                channel: Channel::L2
+            }
+        );
+
+        let reply = ServerMessage::L2 {
+            symbol: Symbol::new("IBM"),
+            bids: vec![(Price(10), Quantity(200))],
+            asks: vec![],
+            seq: 3,
+            timestamp: 1_000,
+        };
+        assert_eq!(
+            serde_json::to_string(&reply).unwrap(),
+            r#"{"type":"l2","symbol":"IBM","bids":[[10,200]],"asks":[],"seq":3,"timestamp":1000}"#
+        );
+    }
+
+    #[test]
+    fn test_throttle_refills() {
+        let mut throttle = Throttle::new(2);
+        let start = throttle.last;
+
+        assert!(throttle.allow_at(start));
+        assert!(throttle.allow_at(start));
+        assert!(!throttle.allow_at(start));
+        assert!(throttle.allow_at(start + Duration::from_millis(500)));
+        assert!(!throttle.allow_at(start + Duration::from_millis(500)));
+    }
+
+    #[tokio::test]
+    async fn test_market_data_and_order_entry() {
+        let addr = start(WsConfig::default()).await;
+        let mut watcher = connect(addr).await;
+        let mut trader = connect(addr).await;
+
+        for channel in [Channel::L1, Channel::L2, Channel::Trades] {
+            let symbol = Symbol::new("IBM");
+            send(&mut watcher, ClientMessage::Subscribe { symbol, channel }).await;
+            assert_eq!(
+                recv(&mut watcher).await,
+                ServerMessage::Subscribed { symbol, channel }
+            );
+        }
+
+        send(&mut trader, new_order(1, 10, "B", 1)).await;
+        assert_eq!(
+            recv(&mut trader).await,
+            ServerMessage::Ack {
+                user_id: 1,
+                order_id: 1,
+                seq: 0,
+                timestamp: 0
+            }
+        );
+        assert_eq!(
+            recv(&mut watcher).await,
+            ServerMessage::L1 {
+                symbol: Symbol::new("IBM"),
+                side: Side::Buy,
+                price: Price(10),
+                qty: Quantity(100),
+                seq: 0,
+                timestamp: 0
+            }
+        );
+        assert_eq!(
+            recv(&mut watcher).await,
+            ServerMessage::L2 {
+                symbol: Symbol::new("IBM"),
+                bids: vec![(Price(10), Quantity(100))],
+                asks: vec![],
+                seq: 0,
+                timestamp: 0
+            }
+        );
+
+        // Amend to a better price
+        send(
+            &mut trader,
+            ClientMessage::Amend {
+                user_id: 1,
+                order_id: 1,
+                price: Price(11),
+                qty: Quantity(100),
+            },
+        )
+        .await;
+        // The cancel and the replacement are both acknowledged
+        for _ in 0..2 {
+            assert_eq!(
+                recv(&mut trader).await,
+                ServerMessage::Ack {
+                    user_id: 1,
+                    order_id: 1,
+                    seq: 0,
+                    timestamp: 0
+                }
+            );
+        }
+        // Cancel leaves the side empty, then the replacement shows up
+        assert!(matches!(
+            recv(&mut watcher).await,
+            ServerMessage::L1 {
+                price: Price(0),
+                ..
+            }
+        ));
+        assert!(matches!(recv(&mut watcher).await, ServerMessage::L2 { .. }));
+        assert!(matches!(
+            recv(&mut watcher).await,
+            ServerMessage::L1 {
+                price: Price(11),
+                ..
+            }
+        ));
+        assert!(matches!(recv(&mut watcher).await, ServerMessage::L2 { .. }));
+
+        send(&mut trader, new_order(2, 11, "S", 1)).await;
+        assert_eq!(
+            recv(&mut trader).await,
+            ServerMessage::Ack {
+                user_id: 2,
+                order_id: 1,
+                seq: 0,
+                timestamp: 0
+            }
+        );
+        assert_eq!(
+            recv(&mut watcher).await,
+            ServerMessage::Trade {
+                symbol: Symbol::new("IBM"),
+                buyer_id: 1,
+                buyer_order_id: 1,
+                seller_id: 2,
+                seller_order_id: 1,
+                price: Price(11),
+                qty: Quantity(100),
+                seq: 0,
+                timestamp: 0
+            }
+        );
+
+        // Unsubscribed channels are not delivered
+        send(
+            &mut watcher,
+            ClientMessage::Unsubscribe {
+                symbol: Symbol::new("IBM"),
+                channel: Channel::L1,
+            },
+        )
+        .await;
+        assert!(matches!(recv(&mut watcher).await, ServerMessage::L2 { .. }));
+        assert!(matches!(
+            recv(&mut watcher).await,
+            ServerMessage::Unsubscribed { .. }
+        ));
+        send(&mut trader, new_order(3, 9, "B", 1)).await;
+        recv(&mut trader).await;
+        assert!(matches!(recv(&mut watcher).await, ServerMessage::L2 { .. }));
+
+        send(
+            &mut trader,
+            ClientMessage::Cancel {
+                user_id: 9,
+                order_id: 9,
+            },
+        )
+        .await;
+        assert_eq!(
+            recv(&mut trader).await,
+            ServerMessage::Reject {
+                user_id: 9,
+                order_id: 9,
+                reason: RejectReason::UnknownOrder,
+                seq: 0,
+                timestamp: 0
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_amend_reports_both_outcomes() {
+        let mut client = connect(start(WsConfig::default()).await).await;
+        send(&mut client, new_order(1, 10, "B", 1)).await;
+        send(&mut client, new_order(2, 12, "S", 1)).await;
+        for _ in 0..2 {
+            assert!(matches!(recv(&mut client).await, ServerMessage::Ack { .. }));
+        }
+
+        // The replacement crosses the ask without matching its quantity
+        let amend = ClientMessage::Amend {
+            user_id: 1,
+            order_id: 1,
+            price: Price(12),
+            qty: Quantity(50),
+        };
+        send(&mut client, amend.clone()).await;
+        assert_eq!(
+            recv(&mut client).await,
+            ServerMessage::Ack {
+                user_id: 1,
+                order_id: 1,
+                seq: 0,
+                timestamp: 0
+            }
+        );
+        assert_eq!(
+            recv(&mut client).await,
+            ServerMessage::Reject {
+                user_id: 1,
+                order_id: 1,
+                reason: RejectReason::NoMatchingQty,
+                seq: 0,
+                timestamp: 0
+            }
+        );
+
+        // The order is gone
+        send(&mut client, amend).await;
+        assert_eq!(
+            recv(&mut client).await,
+            ServerMessage::Reject {
+                user_id: 1,
+                order_id: 1,
+                reason: RejectReason::UnknownOrder,
+                seq: 0,
+                timestamp: 0
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_amend_under_session_ids() {
+        let engine = Engine::new(true).with_order_id_scope(OrderIdScope::Session);
+        let mut client = connect(start_engine(engine, WsConfig::default()).await).await;
+        send(&mut client, new_order(1, 10, "B", 1)).await;
+        assert!(matches!(recv(&mut client).await, ServerMessage::Ack { .. }));
+
+        send(
+            &mut client,
+            ClientMessage::Amend {
+                user_id: 1,
+                order_id: 1,
+                price: Price(11),
+                qty: Quantity(50),
+            },
+        )
+        .await;
+        for _ in 0..2 {
+            assert_eq!(
+                recv(&mut client).await,
+                ServerMessage::Ack {
+                    user_id: 1,
+                    order_id: 1,
+                    seq: 0,
+                    timestamp: 0
+                }
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connection_is_throttled() {
+        let addr = start(WsConfig {
+            max_requests_per_sec: 2,
+            ..Default::default()
+        })
+        .await;
+        let mut client = connect(addr).await;
+
+        for order_id in 1..=3 {
+            send(&mut client, new_order(1, 10, "B", order_id)).await;
+        }
+        assert!(matches!(recv(&mut client).await, ServerMessage::Ack { .. }));
+        assert!(matches!(recv(&mut client).await, ServerMessage::Ack { .. }));
+        assert_eq!(
+            recv(&mut client).await,
+            ServerMessage::Error {
+                message: String::from("throttled")
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_admin_requests() {
+        let mut client = connect(start(WsConfig::default()).await).await;
+        send(&mut client, ClientMessage::BlockUser { user_id: 1 }).await;
+        assert_eq!(
+            recv(&mut client).await,
+            ServerMessage::Error {
+                message: String::from("admin requests are disabled")
+            }
+        );
+
+        // Only connections of the admin listener may send them
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let admin_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let admin_addr = admin_listener.local_addr().unwrap();
+        tokio::spawn(serve_with_admin(
+            listener,
+            admin_listener,
+            Engine::new(true),
+            WsConfig::default(),
+        ));
+        let mut watcher = connect(addr).await;
+        send(&mut watcher, ClientMessage::BlockUser { user_id: 1 }).await;
+        assert_eq!(
+            recv(&mut watcher).await,
+            ServerMessage::Error {
+                message: String::from("admin requests are disabled")
+            }
+        );
+        let mut admin = connect(admin_addr).await;
+        let symbol = Symbol::new("IBM");
+        send(
+            &mut watcher,
+            ClientMessage::Subscribe {
+                symbol,
+                channel: Channel::L1,
+            },
+        )
+        .await;
+        recv(&mut watcher).await;
+
+        for (user_id, price) in [(1, 10), (1, 11), (2, 9)] {
+            send(&mut admin, new_order(user_id, price, "B", 1 + price as u32)).await;
+            assert!(matches!(recv(&mut admin).await, ServerMessage::Ack { .. }));
+        }
+        // The order at 9 leaves the best bid alone
+        for _ in 0..2 {
+            recv(&mut watcher).await;
+        }
+
+        // Every cancelled order is acked and moves the best bid
+        let request: ClientMessage =
+            serde_json::from_str(r#"{"type":"mass_cancel","user_id":1}"#).unwrap();
+        send(&mut admin, request).await;
+        for order_id in [12, 11] {
+            assert_eq!(
+                recv(&mut admin).await,
+                ServerMessage::Ack {
+                    user_id: 1,
+                    order_id,
+                    seq: 0,
+                    timestamp: 0
+                }
+            );
+        }
+        for (price, qty) in [(10, 100), (9, 100)] {
+            assert_eq!(
+                recv(&mut watcher).await,
+                ServerMessage::L1 {
+                    symbol,
+                    side: Side::Buy,
+                    price: Price(price),
+                    qty: Quantity(qty),
+                    seq: 0,
+                    timestamp: 0
+                }
+            );
+        }
+
+        send(&mut admin, ClientMessage::BlockUser { user_id: 1 }).await;
+        assert_eq!(
+            recv(&mut admin).await,
+            ServerMessage::Blocked {
+                user_id: 1,
+                blocked: true
+            }
+        );
+        send(&mut admin, new_order(1, 10, "B", 1)).await;
+        assert_eq!(
+            recv(&mut admin).await,
+            ServerMessage::Reject {
+                user_id: 1,
+                order_id: 1,
+                reason: RejectReason::UserBlocked,
+                seq: 0,
+                timestamp: 0
+            }
+        );
+    }
+
+    #[test]
+    fn test_sessions_own_their_orders() {
+        let shared = Shared::new(Engine::new(true), WsConfig::default());
+        let order = |user_id, price, side: &str, order_id| UserAction::NewOrder {
+            user_id,
+            symbol: Symbol::new("IBM"),
+            price: Price(price),
+            qty: Quantity(100),
+            side: side.parse().unwrap(),
+            order_id,
+            time_in_force: TimeInForce::Gtc,
+        };
+        let live = |user_id, order_id| {
+            shared
+                .engine
+                .lock()
+                .unwrap()
+                .order(user_id, order_id)
+                .is_some()
+        };
+
+        // Session 1 enters orders for two users, session 2 opted out
+        shared.execute(
+            1,
+            vec![
+                order(1, 10, "B", 1),
+                order(2, 9, "B", 1),
+                order(1, 8, "B", 2),
+            ],
+        );
+        shared.execute(2, vec![order(3, 10, "B", 1)]);
+        let cancel = UserAction::CancelOrder {
+            user_id: 1,
+            order_id: 2,
+        };
+        shared.execute(1, vec![cancel]);
+
+        // The order of session 2 fills one of session 1
+        shared.execute(2, vec![order(4, 9, "S", 1)]);
+        assert_eq!(
+            shared.owners.lock().unwrap().clone(),
+            HashMap::from([((1, 1), 1), ((3, 1), 2)])
+        );
+
+        shared.disconnect(2, false);
+        shared.disconnect(1, true);
+        assert!(!live(1, 1));
+        assert!(live(3, 1));
+        assert!(shared.owners.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_sessions_act_for_their_users() {
+        let shared = Shared::new(Engine::new(true), WsConfig::default());
+        let refused = |replies: Vec<ServerMessage>| {
+            assert_eq!(
+                replies,
+                [ServerMessage::Error {
+                    message: String::from("user 1 belongs to another session")
+                }]
+            );
+        };
+
+        let replies = shared.order_entry(1, new_order(1, 10, "B", 1));
+        assert!(matches!(replies[..], [ServerMessage::Ack { .. }]));
+
+        // Session 2 can not touch the orders of user 1
+        let cancel = ClientMessage::Cancel {
+            user_id: 1,
+            order_id: 1,
+        };
+        refused(shared.order_entry(2, cancel.clone()));
+        refused(shared.order_entry(
+            2,
+            ClientMessage::Amend {
+                user_id: 1,
+                order_id: 1,
+                price: Price(11),
+                qty: Quantity(100),
+            },
+        ));
+        refused(shared.order_entry(2, new_order(1, 9, "B", 2)));
+        assert_eq!(
+            shared.owners.lock().unwrap().clone(),
+            HashMap::from([((1, 1), 1)])
+        );
+
+        // Until session 1 ends and leaves the order live
+        shared.disconnect(1, false);
+        let replies = shared.order_entry(2, cancel);
+        assert!(matches!(
+            replies[..],
+            [ServerMessage::Ack {
+                user_id: 1,
+                order_id: 1,
+                ..
+            }]
+        ));
+        assert_eq!(
+            shared.users.lock().unwrap().clone(),
+            HashMap::from([(1, 2)])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cancel_on_disconnect() {
+        let addr = start(WsConfig {
+            heartbeat: Some(Duration::from_millis(200)),
+            ..Default::default()
+        })
+        .await;
+        let mut watcher = connect(addr).await;
+        let symbol = Symbol::new("IBM");
+        send(
+            &mut watcher,
+            ClientMessage::Subscribe {
+                symbol,
+                channel: Channel::L1,
+            },
+        )
+        .await;
+        recv(&mut watcher).await;
+        let best_bid = |price| ServerMessage::L1 {
+            symbol,
+            side: Side::Buy,
+            price: Price(price),
+            qty: Quantity(if price == 0 { 0 } else { 100 }),
+            seq: 0,
+            timestamp: 0,
+        };
+
+        // The connection closes
+        let mut trader = connect(addr).await;
+        send(&mut trader, new_order(1, 10, "B", 1)).await;
+        recv(&mut trader).await;
+        assert_eq!(recv(&mut watcher).await, best_bid(10));
+        trader.close(None).await.unwrap();
+        assert_eq!(recv(&mut watcher).await, best_bid(0));
+
+        // The client stops answering pings, while the watcher keeps
+        // answering them
+        let mut silent = connect(addr).await;
+        send(&mut silent, new_order(1, 11, "B", 2)).await;
+        recv(&mut silent).await;
+        assert_eq!(recv(&mut watcher).await, best_bid(11));
+        assert_eq!(recv(&mut watcher).await, best_bid(0));
+
+        // Opted out sessions keep their orders
+        let mut keeper = connect(addr).await;
+        send(
+            &mut keeper,
+            ClientMessage::CancelOnDisconnect { enabled: false },
+        )
+        .await;
+        assert_eq!(
+            recv(&mut keeper).await,
+            ServerMessage::CancelOnDisconnect { enabled: false }
+        );
+        send(&mut keeper, new_order(2, 12, "B", 1)).await;
+        recv(&mut keeper).await;
+        assert_eq!(recv(&mut watcher).await, best_bid(12));
+        keeper.close(None).await.unwrap();
+
+        // The user is free once the session ended
+        let cancel = ClientMessage::Cancel {
+            user_id: 2,
+            order_id: 1,
+        };
+        let reply = loop {
+            send(&mut watcher, cancel.clone()).await;
+            match recv(&mut watcher).await {
+                ServerMessage::Error { .. } => time::sleep(Duration::from_millis(10)).await,
+                reply => break reply,
+            }
+        };
+        assert_eq!(
+            reply,
+            ServerMessage::Ack {
+                user_id: 2,
+                order_id: 1,
+                seq: 0,
+                timestamp: 0
+            }
+        );
+        assert_eq!(recv(&mut watcher).await, best_bid(0));
+        drop(silent);
+    }
+
+    #[tokio::test]
+    async fn test_orders_expire() {
+        use crate::clock::{ManualClock, SharedClock};
+
+        let clock = ManualClock::new(1_000_000_000);
+        let engine = Engine::new(true).with_clock(SharedClock::new(clock.clone()));
+        let mut client = connect(start_engine(engine, WsConfig::default()).await).await;
+        let symbol = Symbol::new("IBM");
+        send(
+            &mut client,
+            ClientMessage::Subscribe {
+                symbol,
+                channel: Channel::L1,
+            },
+        )
+        .await;
+        recv(&mut client).await;
+
+        let request: ClientMessage = serde_json::from_str(
+            r#"{"type":"new_order","user_id":1,"symbol":"IBM","price":10,"qty":100,"side":"B","order_id":1,"time_in_force":{"gtd":2000000000}}"#,
+        )
+        .unwrap();
+        send(&mut client, request).await;
+        assert_eq!(
+            recv_stamped(&mut client).await,
+            ServerMessage::Ack {
+                user_id: 1,
+                order_id: 1,
+                seq: 1,
+                timestamp: 1_000_000_000
+            }
+        );
+        assert!(matches!(
+            recv_stamped(&mut client).await,
+            ServerMessage::L1 {
+                price: Price(10),
+                seq: 2,
+                ..
+            }
+        ));
+
+        // The sweep cancels the order once its time has come, the ack of
+        // the cancel takes number 3
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(
+            recv_stamped(&mut client).await,
+            ServerMessage::L1 {
+                symbol,
+                side: Side::Buy,
+                price: Price(0),
+                qty: Quantity(0),
+                seq: 4,
+                timestamp: 2_000_000_000
+            }
+        );
+    }
+}